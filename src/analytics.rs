@@ -0,0 +1,233 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::coordinates::{square_units_to_cells, units_to_cells};
+use crate::dungeondraft_v1::{
+    id_string, is_loop, node_id, node_points, node_position, nodes, Vector2, UNITS_PER_CELL,
+};
+use crate::geometry::{boundary_distance, bounds, polygon_area};
+
+/// Rooms at least this many times longer than they are wide are reported as corridors.
+const CORRIDOR_ASPECT_RATIO: f64 = 3.0;
+/// How far a door may sit from a room's walls and still count as one of its doors.
+const DOOR_TOLERANCE: f64 = UNITS_PER_CELL / 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomKind {
+    Room,
+    Corridor,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomReport {
+    pub id: String,
+    pub kind: RoomKind,
+    /// Floor area in cells
+    pub area: f64,
+    /// Length in cells, only reported for corridors
+    pub length: Option<f64>,
+    pub doors: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Connection {
+    pub from: String,
+    pub to: String,
+    pub door: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LevelReport {
+    pub level: String,
+    pub rooms: Vec<RoomReport>,
+    pub connections: Vec<Connection>,
+}
+
+struct Room {
+    id: String,
+    points: Vec<Vector2>,
+}
+
+/// The longest and shortest sides of the bounds of a floor.
+fn extent(points: &[Vector2]) -> (f64, f64) {
+    let (min, max) = bounds(points).unwrap_or_default();
+    let (w, h) = (max.x - min.x, max.y - min.y);
+    (w.max(h), w.min(h))
+}
+
+/// Whether a floor is a room, or a corridor many times longer than it is wide.
+pub fn room_kind(points: &[Vector2]) -> RoomKind {
+    let (long, short) = extent(points);
+    if short > 0.0 && long / short >= CORRIDOR_ASPECT_RATIO {
+        RoomKind::Corridor
+    } else {
+        RoomKind::Room
+    }
+}
+
+fn report_room(room: &Room, doors: usize) -> RoomReport {
+    let area = square_units_to_cells(polygon_area(&room.points));
+    let kind = room_kind(&room.points);
+    let (long, _) = extent(&room.points);
+    RoomReport {
+        id: room.id.clone(),
+        kind,
+        area,
+        length: (kind == RoomKind::Corridor).then_some(units_to_cells(long)),
+        doors,
+    }
+}
+
+fn analyze_level(name: &str, level: &Value) -> LevelReport {
+    // Every closed wall loop is treated as a room
+    let rooms: Vec<Room> = nodes(level, "walls")
+        .filter(|wall| is_loop(wall))
+        .filter_map(|wall| {
+            Some(Room {
+                id: node_id(wall)?,
+                points: node_points(wall).filter(|p| p.len() > 2)?,
+            })
+        })
+        .collect();
+
+    let mut door_counts = vec![0; rooms.len()];
+    let mut connections = Vec::new();
+    let portals = nodes(level, "portals")
+        .chain(nodes(level, "walls").flat_map(|wall| nodes(wall, "portals")));
+    for portal in portals {
+        let Some(position) = node_position(portal) else {
+            continue;
+        };
+        let wall_id = portal.get("wall_id").and_then(id_string);
+        let touching: Vec<usize> = rooms
+            .iter()
+            .enumerate()
+            .filter(|(_, room)| {
+                wall_id.as_ref() == Some(&room.id)
+                    || boundary_distance(&room.points, position) <= DOOR_TOLERANCE
+            })
+            .map(|(i, _)| i)
+            .collect();
+        let door = node_id(portal).unwrap_or_default();
+        for (n, &a) in touching.iter().enumerate() {
+            door_counts[a] += 1;
+            for &b in &touching[n + 1..] {
+                connections.push(Connection {
+                    from: rooms[a].id.clone(),
+                    to: rooms[b].id.clone(),
+                    door: door.clone(),
+                });
+            }
+        }
+    }
+
+    LevelReport {
+        level: name.to_string(),
+        rooms: rooms
+            .iter()
+            .zip(door_counts)
+            .map(|(room, doors)| report_room(room, doors))
+            .collect(),
+        connections,
+    }
+}
+
+/// Computes room areas, corridor lengths, door counts, and room connectivity for every level.
+pub fn analyze(map: &Value) -> Vec<LevelReport> {
+    map.pointer("/world/levels")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(name, level)| analyze_level(name, level))
+        .collect()
+}
+
+/// Renders the connectivity graph of every level as Graphviz DOT.
+pub fn to_dot(reports: &[LevelReport]) -> String {
+    let mut dot = String::from("graph dungeon {\n");
+    for report in reports {
+        dot.push_str(&format!("    subgraph \"cluster_{}\" {{\n", report.level));
+        dot.push_str(&format!("        label=\"Level {}\";\n", report.level));
+        for room in &report.rooms {
+            let shape = match room.kind {
+                RoomKind::Room => "box",
+                RoomKind::Corridor => "ellipse",
+            };
+            dot.push_str(&format!(
+                "        \"{}/{}\" [label=\"{}\\n{:.1} cells\", shape={}];\n",
+                report.level, room.id, room.id, room.area, shape
+            ));
+        }
+        for connection in &report.connections {
+            dot.push_str(&format!(
+                "        \"{}/{}\" -- \"{}/{}\" [label=\"door {}\"];\n",
+                report.level, connection.from, report.level, connection.to, connection.door
+            ));
+        }
+        dot.push_str("    }\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map() -> Value {
+        json!({
+            "world": {
+                "levels": {
+                    "0": {
+                        "walls": [
+                            {
+                                "node_id": "1",
+                                "loop": true,
+                                "points": "PoolVector2Array( 0, 0, 1024, 0, 1024, 1024, 0, 1024 )",
+                                "portals": [
+                                    { "node_id": "3", "wall_id": "1", "position": "Vector2( 1024, 512 )" }
+                                ]
+                            },
+                            {
+                                "node_id": "2",
+                                "loop": true,
+                                "points": "PoolVector2Array( 1024, 384, 2560, 384, 2560, 640, 1024, 640 )"
+                            }
+                        ]
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_analyze() {
+        let reports = analyze(&map());
+        assert_eq!(reports.len(), 1);
+        let rooms = &reports[0].rooms;
+        assert_eq!(rooms[0].kind, RoomKind::Room);
+        assert_eq!(rooms[0].area, 16.0);
+        assert_eq!(rooms[0].length, None);
+        assert_eq!(rooms[0].doors, 1);
+        assert_eq!(rooms[1].kind, RoomKind::Corridor);
+        assert_eq!(rooms[1].area, 6.0);
+        assert_eq!(rooms[1].length, Some(6.0));
+        assert_eq!(rooms[1].doors, 1);
+
+        let connections = &reports[0].connections;
+        assert_eq!(connections.len(), 1);
+        assert_eq!(
+            (connections[0].from.as_str(), connections[0].to.as_str()),
+            ("1", "2")
+        );
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = to_dot(&analyze(&map()));
+        assert!(dot.starts_with("graph dungeon {"));
+        assert!(dot.contains("\"0/1\" -- \"0/2\" [label=\"door 3\"];"));
+    }
+}
@@ -0,0 +1,412 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+use tracing::debug;
+
+use crate::coordinates::CoordinateMapper;
+use crate::doors::Door;
+use crate::dungeondraft_v1::Vector2;
+use crate::geometry::{centroid, distance};
+use crate::lights::{Fixture, FixtureKind};
+
+/// Annotated circles become polygons with this many vertices.
+const CIRCLE_VERTICES: usize = 16;
+
+/// What an annotation label becomes in the map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Room,
+    Wall,
+    /// Secret when true
+    Door(bool),
+    Fixture(FixtureKind),
+}
+
+fn role(label: &str) -> Option<Role> {
+    match label.trim().to_lowercase().as_str() {
+        "room" | "floor" => Some(Role::Room),
+        "wall" => Some(Role::Wall),
+        "door" | "portal" => Some(Role::Door(false)),
+        "secret door" | "secret" => Some(Role::Door(true)),
+        "window" => Some(Role::Fixture(FixtureKind::Window)),
+        "light" | "sconce" | "torch" | "lamp" => Some(Role::Fixture(FixtureKind::Sconce)),
+        _ => None,
+    }
+}
+
+/// Whether a label is one annotations can be given.
+pub fn is_known_label(label: &str) -> bool {
+    role(label).is_some()
+}
+
+/// The geometry of an annotation in image pixels.
+#[derive(Debug, PartialEq)]
+enum Geometry {
+    Polygon(Vec<Vector2>),
+    Polyline(Vec<Vector2>),
+    Point(Vector2),
+}
+
+/// Map features drawn in a labeling tool, in map units.
+///
+/// Annotations are labeled `room`, `wall`, `door`, `secret door`, `window`, or
+/// `light`, and other labels are kept as notes for the GM. Closed walls are
+/// rooms like any other.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotations {
+    pub rooms: Vec<Vec<Vector2>>,
+    /// Walls left open at their ends
+    pub walls: Vec<Vec<Vector2>>,
+    pub doors: Vec<Door>,
+    pub fixtures: Vec<Fixture>,
+    /// Other labels, such as the names of rooms, at the middle of their shapes
+    pub labels: Vec<(String, Vector2)>,
+}
+
+impl Annotations {
+    /// Adds a polygon drawn in image pixels as what its label says it is.
+    pub fn add_polygon(&mut self, label: &str, points: Vec<Vector2>, mapper: &CoordinateMapper) {
+        self.add(label, Geometry::Polygon(points), mapper);
+    }
+
+    fn add(&mut self, label: &str, geometry: Geometry, mapper: &CoordinateMapper) {
+        let Some(role) = role(label) else {
+            let position = match geometry {
+                Geometry::Point(p) => Some(p),
+                Geometry::Polygon(points) | Geometry::Polyline(points) => centroid(&points),
+            };
+            match position {
+                Some(p) if !label.trim().is_empty() => {
+                    debug!("Keeping the label '{}' as a note", label);
+                    self.labels
+                        .push((label.trim().to_string(), mapper.point(p)));
+                }
+                _ => debug!("Skipping annotation labeled '{}'", label),
+            }
+            return;
+        };
+        let scale = |points: &[Vector2]| mapper.points(points);
+        match (role, geometry) {
+            (Role::Room | Role::Wall, Geometry::Polygon(points)) if points.len() > 2 => {
+                self.rooms.push(scale(&points))
+            }
+            (Role::Wall, Geometry::Polyline(points)) if points.len() > 1 => {
+                self.walls.push(scale(&points))
+            }
+            (Role::Door(secret), Geometry::Polyline(points)) if points.len() > 1 => {
+                self.doors.push(Door {
+                    secret,
+                    ..door_along(&scale(&points))
+                })
+            }
+            (Role::Door(secret), Geometry::Polygon(points)) if points.len() > 2 => {
+                self.doors.push(Door {
+                    secret,
+                    ..door_across(&scale(&points))
+                })
+            }
+            (Role::Fixture(kind), geometry) => {
+                let position = match geometry {
+                    Geometry::Point(p) => Some(p),
+                    Geometry::Polygon(points) | Geometry::Polyline(points) => centroid(&points),
+                };
+                if let Some(p) = position {
+                    self.fixtures.push(Fixture {
+                        kind,
+                        position: mapper.point(p),
+                    });
+                }
+            }
+            (role, geometry) => debug!("Skipping {:?} annotated as {:?}", role, geometry),
+        }
+    }
+}
+
+/// A door drawn as a line across its doorway from the hinge, optionally on
+/// to the free end of its leaf. Without one the leaf is square to the doorway.
+fn door_along(points: &[Vector2]) -> Door {
+    let (hinge, latch) = (points[0], points[1]);
+    let leaf = points.get(2).copied().unwrap_or(Vector2 {
+        x: hinge.x + (latch.y - hinge.y),
+        y: hinge.y - (latch.x - hinge.x),
+    });
+    Door {
+        hinge,
+        latch,
+        leaf,
+        secret: false,
+    }
+}
+
+/// A door boxed by a polygon, spanning its longest side through its middle.
+fn door_across(points: &[Vector2]) -> Door {
+    let count = points.len();
+    let longest = (0..count)
+        .max_by(|&a, &b| {
+            distance(points[a], points[(a + 1) % count])
+                .total_cmp(&distance(points[b], points[(b + 1) % count]))
+        })
+        .unwrap_or(0);
+    let (a, b) = (points[longest], points[(longest + 1) % count]);
+    let center = centroid(points).unwrap_or(a);
+    let half = Vector2 {
+        x: (b.x - a.x) / 2.0,
+        y: (b.y - a.y) / 2.0,
+    };
+    door_along(&[
+        Vector2 {
+            x: center.x - half.x,
+            y: center.y - half.y,
+        },
+        Vector2 {
+            x: center.x + half.x,
+            y: center.y + half.y,
+        },
+    ])
+}
+
+fn point(value: &Value) -> Option<Vector2> {
+    Some(Vector2 {
+        x: value.get(0)?.as_f64()?,
+        y: value.get(1)?.as_f64()?,
+    })
+}
+
+fn circle(center: Vector2, edge: Vector2) -> Vec<Vector2> {
+    let radius = distance(center, edge);
+    (0..CIRCLE_VERTICES)
+        .map(|i| {
+            let angle = 2.0 * PI * i as f64 / CIRCLE_VERTICES as f64;
+            Vector2 {
+                x: center.x + radius * angle.cos(),
+                y: center.y + radius * angle.sin(),
+            }
+        })
+        .collect()
+}
+
+fn rectangle(x: f64, y: f64, width: f64, height: f64) -> Vec<Vector2> {
+    vec![
+        Vector2 { x, y },
+        Vector2 { x: x + width, y },
+        Vector2 {
+            x: x + width,
+            y: y + height,
+        },
+        Vector2 { x, y: y + height },
+    ]
+}
+
+/// Reads the shapes of a LabelMe JSON file.
+pub fn parse_labelme(document: &Value, mapper: &CoordinateMapper) -> Result<Annotations, String> {
+    let shapes = document["shapes"]
+        .as_array()
+        .ok_or("LabelMe file has no shapes")?;
+    let mut annotations = Annotations::default();
+    for shape in shapes {
+        let label = shape["label"].as_str().unwrap_or_default();
+        let points: Vec<Vector2> = shape["points"]
+            .as_array()
+            .map(|points| points.iter().filter_map(point).collect())
+            .unwrap_or_default();
+        let geometry = match (
+            shape["shape_type"].as_str().unwrap_or("polygon"),
+            &points[..],
+        ) {
+            ("polygon", _) => Geometry::Polygon(points),
+            ("rectangle", [a, b]) => Geometry::Polygon(rectangle(
+                a.x.min(b.x),
+                a.y.min(b.y),
+                (b.x - a.x).abs(),
+                (b.y - a.y).abs(),
+            )),
+            ("circle", [center, edge]) => Geometry::Polygon(circle(*center, *edge)),
+            ("line" | "linestrip", _) => Geometry::Polyline(points),
+            ("point", [p]) => Geometry::Point(*p),
+            (kind, _) => {
+                debug!("Skipping LabelMe {} shape labeled '{}'", kind, label);
+                continue;
+            }
+        };
+        annotations.add(label, geometry, mapper);
+    }
+    Ok(annotations)
+}
+
+/// Reads the annotations of one image of a COCO file, the one named
+/// `image_name` or else the only image.
+pub fn parse_coco(
+    document: &Value,
+    image_name: Option<&str>,
+    mapper: &CoordinateMapper,
+) -> Result<Annotations, String> {
+    let images = document["images"]
+        .as_array()
+        .ok_or("COCO file has no images")?;
+    let named = image_name.and_then(|name| {
+        images.iter().find(|image| {
+            image["file_name"]
+                .as_str()
+                .and_then(|file| Path::new(file).file_name())
+                .is_some_and(|file| file.to_string_lossy() == name)
+        })
+    });
+    let image = match (named, &images[..]) {
+        (Some(image), _) => image,
+        (None, [image]) => image,
+        _ => {
+            return Err(format!(
+                "COCO file has no image named '{}'",
+                image_name.unwrap_or_default()
+            ))
+        }
+    };
+    let categories = document["categories"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let category = |id: &Value| {
+        categories
+            .iter()
+            .find(|category| category["id"] == *id)
+            .and_then(|category| category["name"].as_str())
+            .unwrap_or_default()
+    };
+
+    let mut annotations = Annotations::default();
+    for annotation in document["annotations"]
+        .as_array()
+        .ok_or("COCO file has no annotations")?
+        .iter()
+        .filter(|annotation| annotation["image_id"] == image["id"])
+    {
+        let label = category(&annotation["category_id"]);
+        match annotation["segmentation"].as_array() {
+            Some(polygons) => {
+                for polygon in polygons.iter().filter_map(Value::as_array) {
+                    let coords: Vec<f64> = polygon.iter().filter_map(Value::as_f64).collect();
+                    let points = coords
+                        .chunks_exact(2)
+                        .map(|xy| Vector2 { x: xy[0], y: xy[1] })
+                        .collect();
+                    annotations.add(label, Geometry::Polygon(points), mapper);
+                }
+            }
+            // Run-length encoded masks are placed by their bounding box
+            None => {
+                let bbox: Vec<f64> = annotation["bbox"]
+                    .as_array()
+                    .map(|bbox| bbox.iter().filter_map(Value::as_f64).collect())
+                    .unwrap_or_default();
+                if let [x, y, width, height] = bbox[..] {
+                    let points = rectangle(x, y, width, height);
+                    annotations.add(label, Geometry::Polygon(points), mapper);
+                }
+            }
+        }
+    }
+    Ok(annotations)
+}
+
+/// Reads a LabelMe or COCO annotation file made for the image at `image_path`.
+pub fn read_annotations(
+    path: &Path,
+    image_path: &Path,
+    mapper: &CoordinateMapper,
+) -> Result<Annotations, Box<dyn std::error::Error>> {
+    let document: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let annotations = if document.get("shapes").is_some() {
+        parse_labelme(&document, mapper)?
+    } else if document.get("images").is_some() {
+        let image_name = image_path.file_name().map(|name| name.to_string_lossy());
+        parse_coco(&document, image_name.as_deref(), mapper)?
+    } else {
+        return Err(format!("{} is not a LabelMe or COCO file", path.display()).into());
+    };
+    debug!(
+        "Read {} rooms, {} walls, {} doors, and {} fixtures from {}",
+        annotations.rooms.len(),
+        annotations.walls.len(),
+        annotations.doors.len(),
+        annotations.fixtures.len(),
+        path.display()
+    );
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_labelme() {
+        let document = json!({
+            "imagePath": "crypt.png",
+            "shapes": [
+                { "label": "room", "shape_type": "polygon", "points": [[0, 0], [64, 0], [64, 64]] },
+                { "label": "Room", "shape_type": "rectangle", "points": [[128, 64], [64, 0]] },
+                { "label": "wall", "shape_type": "linestrip", "points": [[0, 128], [128, 128]] },
+                { "label": "door", "shape_type": "line", "points": [[64, 16], [64, 48]] },
+                { "label": "secret door", "shape_type": "line", "points": [[0, 80], [32, 80]] },
+                { "label": "light", "shape_type": "point", "points": [[32, 32]] },
+                { "label": "table", "shape_type": "polygon", "points": [[1, 1], [2, 1], [2, 2]] }
+            ]
+        });
+        let annotations = parse_labelme(&document, &CoordinateMapper::new(64.0)).unwrap();
+        assert_eq!(annotations.rooms.len(), 2);
+        assert_eq!(annotations.rooms[1][0], Vector2 { x: 256.0, y: 0.0 });
+        assert_eq!(
+            annotations.walls,
+            vec![vec![
+                Vector2 { x: 0.0, y: 512.0 },
+                Vector2 { x: 512.0, y: 512.0 },
+            ]]
+        );
+        let door = annotations.doors[0];
+        assert_eq!(door.position(), Vector2 { x: 256.0, y: 128.0 });
+        assert_eq!(door.leaf, Vector2 { x: 384.0, y: 64.0 });
+        assert!(!door.secret && annotations.doors[1].secret);
+        assert_eq!(
+            annotations.fixtures,
+            vec![Fixture {
+                kind: FixtureKind::Sconce,
+                position: Vector2 { x: 128.0, y: 128.0 }
+            }]
+        );
+        // Other labels are kept at the middle of their shapes
+        assert_eq!(annotations.labels.len(), 1);
+        assert_eq!(annotations.labels[0].0, "table");
+    }
+
+    #[test]
+    fn test_parse_coco() {
+        let document = json!({
+            "images": [
+                { "id": 1, "file_name": "maps/crypt.png" },
+                { "id": 2, "file_name": "maps/tavern.png" }
+            ],
+            "categories": [{ "id": 7, "name": "room" }, { "id": 8, "name": "door" }],
+            "annotations": [
+                { "image_id": 1, "category_id": 7, "segmentation": [[0, 0, 64, 0, 64, 64, 0, 64]] },
+                { "image_id": 2, "category_id": 7, "segmentation": [[0, 0, 32, 0, 32, 32]] },
+                { "image_id": 2, "category_id": 8, "segmentation": { "counts": [], "size": [64, 64] }, "bbox": [28, 0, 8, 32] }
+            ]
+        });
+        let crypt = parse_coco(&document, Some("crypt.png"), &CoordinateMapper::new(64.0)).unwrap();
+        assert_eq!(crypt.rooms.len(), 1);
+        assert_eq!(crypt.rooms[0][2], Vector2 { x: 256.0, y: 256.0 });
+
+        let tavern =
+            parse_coco(&document, Some("tavern.png"), &CoordinateMapper::new(64.0)).unwrap();
+        assert_eq!(tavern.rooms.len(), 1);
+        // The door spans the long side of its box
+        let door = tavern.doors[0];
+        assert_eq!(door.hinge.x, door.latch.x);
+        assert_eq!(door.position(), Vector2 { x: 128.0, y: 64.0 });
+
+        assert!(parse_coco(&document, Some("keep.png"), &CoordinateMapper::new(64.0)).is_err());
+    }
+}
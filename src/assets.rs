@@ -0,0 +1,551 @@
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+use crate::pack::{AssetPack, PACK_EXTENSION};
+
+const PACK_PREFIX: &str = "res://packs/";
+const RES_PREFIX: &str = "res://";
+/// Where an asset pack tags its assets, by tag name.
+const TAGS_FILE: &str = "data/default.dungeondraft_tags";
+
+/// Asset reference renames applied to a map by `migrate-assets`.
+///
+/// Exact path renames take priority over pack id renames.
+#[derive(Debug, Default)]
+pub struct AssetMigration {
+    packs: Vec<(String, String)>,
+    paths: Vec<(String, String)>,
+}
+
+impl AssetMigration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pack(mut self, old: &str, new: &str) -> Self {
+        self.packs.push((old.to_string(), new.to_string()));
+        self
+    }
+
+    pub fn path(mut self, old: &str, new: &str) -> Self {
+        self.paths.push((old.to_string(), new.to_string()));
+        self
+    }
+
+    fn rewrite(&self, reference: &str) -> Option<String> {
+        if let Some((_, new)) = self.paths.iter().find(|(old, _)| old == reference) {
+            return Some(new.clone());
+        }
+        let pack_id = pack_id(reference)?;
+        let asset = &reference[PACK_PREFIX.len() + pack_id.len() + 1..];
+        self.packs
+            .iter()
+            .find(|(old, _)| old == pack_id)
+            .map(|(_, new)| format!("{}{}/{}", PACK_PREFIX, new, asset))
+    }
+
+    fn rewrite_value(&self, value: &mut Value) -> usize {
+        match value {
+            Value::String(s) => match self.rewrite(s) {
+                Some(new) => {
+                    *s = new;
+                    1
+                }
+                None => 0,
+            },
+            Value::Array(values) => values.iter_mut().map(|v| self.rewrite_value(v)).sum(),
+            Value::Object(fields) => {
+                // Some collections (e.g. materials) are keyed by asset path
+                let mut count = 0;
+                let mut rewritten = Map::new();
+                for (key, mut v) in std::mem::take(fields) {
+                    count += self.rewrite_value(&mut v);
+                    let key = match self.rewrite(&key) {
+                        Some(new) => {
+                            count += 1;
+                            new
+                        }
+                        None => key,
+                    };
+                    rewritten.insert(key, v);
+                }
+                *fields = rewritten;
+                count
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// The id of the asset pack a `res://packs/` reference is in.
+pub fn pack_id(reference: &str) -> Option<&str> {
+    let rest = reference.strip_prefix(PACK_PREFIX)?;
+    rest.split_once('/').map(|(id, _)| id)
+}
+
+/// The reference to an asset of a pack, such as `textures/objects/tree.png`.
+pub fn pack_reference(pack_id: &str, asset: &str) -> String {
+    format!(
+        "{}{}/{}",
+        PACK_PREFIX,
+        pack_id,
+        asset.trim_start_matches(RES_PREFIX).trim_start_matches('/')
+    )
+}
+
+/// Parses an `old=new` rename given on the command line.
+pub fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) if !old.trim().is_empty() && !new.trim().is_empty() => {
+            Ok((old.trim().to_string(), new.trim().to_string()))
+        }
+        _ => Err(format!("expected old=new but found '{}'", s)),
+    }
+}
+
+/// Reads `old_path,new_path` renames from a CSV file, one per line.
+///
+/// Blank lines and lines starting with `#` are ignored.
+pub fn read_renames(path: &Path) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut renames = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(',') {
+            Some((old, new)) => renames.push((old.trim().to_string(), new.trim().to_string())),
+            None => {
+                return Err(format!("{}:{}: expected old,new", path.display(), number + 1).into())
+            }
+        }
+    }
+    Ok(renames)
+}
+
+/// Rewrites every asset reference in the map, returning how many were changed.
+pub fn migrate(map: &mut Value, migration: &AssetMigration) -> usize {
+    let mut count = 0;
+    if let Some(manifest) = map
+        .pointer_mut("/header/asset_manifest")
+        .and_then(Value::as_array_mut)
+    {
+        for pack in manifest.iter_mut() {
+            let Some(Value::String(id)) = pack.get_mut("id") else {
+                continue;
+            };
+            if let Some((_, new)) = migration.packs.iter().find(|(old, _)| old == id) {
+                *id = new.clone();
+                count += 1;
+            }
+        }
+    }
+    if let Some(world) = map.get_mut("world") {
+        count += migration.rewrite_value(world);
+    }
+    count
+}
+
+fn collect_references(value: &Value, found: &mut BTreeMap<String, usize>) {
+    let mut add = |reference: &str| {
+        if reference.starts_with(RES_PREFIX) {
+            *found.entry(reference.to_string()).or_default() += 1;
+        }
+    };
+    match value {
+        Value::String(s) => add(s),
+        Value::Array(values) => values.iter().for_each(|v| collect_references(v, found)),
+        Value::Object(fields) => {
+            // Some collections (e.g. materials) are keyed by asset path
+            for (key, v) in fields {
+                add(key);
+                collect_references(v, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Every asset reference of a map's world, with how many times it is used.
+pub fn asset_references(map: &Value) -> BTreeMap<String, usize> {
+    let mut found = BTreeMap::new();
+    collect_references(&map["world"], &mut found);
+    found
+}
+
+/// Resolves `res://` asset references to files in unpacked asset directories.
+///
+/// A directory containing a `pack.json` is an asset pack whose files are found
+/// by pack id. Any other directory with a `textures` folder provides the
+/// default assets. Packed `.dungeondraft_pack` files are read for their
+/// names and the references they hold.
+#[derive(Debug, Default)]
+pub struct AssetResolver {
+    roots: Vec<PathBuf>,
+    packs: HashMap<String, PathBuf>,
+    archives: HashMap<String, AssetPack>,
+}
+
+/// Every file under `dir`, by its path relative to `root`.
+fn files(root: &Path, dir: &Path, found: &mut Vec<String>) {
+    for path in std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+    {
+        if path.is_dir() {
+            files(root, &path, found);
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            // References use forward slashes on every platform
+            let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+            found.push(parts.join("/"));
+        }
+    }
+}
+
+/// The assets of a pack tagged `tag` in its tags file, ignoring case.
+fn tagged(pack: &Path, tag: &str) -> Vec<String> {
+    let Ok(contents) = std::fs::read(pack.join(TAGS_FILE)) else {
+        return Vec::new();
+    };
+    tagged_in(&contents, tag).unwrap_or_else(|| {
+        debug!("skipping unreadable tags of {}", pack.display());
+        Vec::new()
+    })
+}
+
+/// The assets tagged `tag` in the contents of a tags file, if it is valid.
+fn tagged_in(contents: &[u8], tag: &str) -> Option<Vec<String>> {
+    let tags: Value = serde_json::from_slice(contents).ok()?;
+    Some(
+        tags["tags"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(tag))
+            .flat_map(|(_, assets)| assets.as_array().into_iter().flatten())
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// Whether an asset's file name mentions `tag`, ignoring case.
+fn named(asset: &str, tag: &str) -> bool {
+    let name = asset.rsplit('/').next().unwrap_or(asset).to_lowercase();
+    name.contains(&tag.to_lowercase())
+}
+
+fn read_pack_data(dir: &Path) -> Option<Value> {
+    let contents = std::fs::read_to_string(dir.join("pack.json")).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// A pack's name, such as "Tavern 1.2 by Ink", leaving out what it lacks.
+fn pack_label(name: &str, version: &str, author: &str) -> String {
+    let mut label = name.to_string();
+    if !version.is_empty() {
+        label = format!("{} {}", label, version);
+    }
+    if !author.is_empty() {
+        label = format!("{} by {}", label, author);
+    }
+    label
+}
+
+fn read_pack_id(dir: &Path) -> Option<String> {
+    read_pack_data(dir)?.get("id")?.as_str().map(str::to_string)
+}
+
+impl AssetResolver {
+    /// Detects asset packs in the given directories and their immediate subdirectories.
+    pub fn detect(dirs: &[PathBuf]) -> Self {
+        let mut resolver = Self::default();
+        for dir in dirs {
+            let (children, files): (Vec<PathBuf>, Vec<PathBuf>) = std::fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .partition(|path| path.is_dir());
+            for file in files
+                .iter()
+                .filter(|file| file.extension().is_some_and(|ext| ext == PACK_EXTENSION))
+            {
+                match AssetPack::read(file) {
+                    Ok(pack) => {
+                        debug!("found packed asset pack {} in {}", pack.id, file.display());
+                        resolver.archives.insert(pack.id.clone(), pack);
+                    }
+                    Err(e) => warn!("skipping {}: {}", file.display(), e),
+                }
+            }
+            for candidate in std::iter::once(dir.clone()).chain(children) {
+                if let Some(id) = read_pack_id(&candidate) {
+                    debug!("found asset pack {} in {}", id, candidate.display());
+                    resolver.packs.insert(id, candidate);
+                } else if candidate.join("textures").is_dir() {
+                    debug!("found default assets in {}", candidate.display());
+                    resolver.roots.push(candidate);
+                }
+            }
+        }
+        resolver
+    }
+
+    /// The references of every texture of the packs and default assets tagged
+    /// `tag` or with it in their file name, sorted.
+    pub fn search(&self, tag: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        for (id, dir) in &self.packs {
+            let prefix = format!("{}{}/", PACK_PREFIX, id);
+            let mut assets = tagged(dir, tag);
+            let mut textures = Vec::new();
+            files(dir, &dir.join("textures"), &mut textures);
+            assets.extend(textures.into_iter().filter(|asset| named(asset, tag)));
+            found.extend(assets.into_iter().map(|asset| {
+                // Tags may name assets by their full reference
+                match asset.starts_with(RES_PREFIX) {
+                    true => asset,
+                    false => format!("{}{}", prefix, asset),
+                }
+            }));
+        }
+        for (id, pack) in &self.archives {
+            let prefix = format!("{}{}/", PACK_PREFIX, id);
+            let tags = pack
+                .read_file(&format!("{}{}", prefix, TAGS_FILE))
+                .ok()
+                .and_then(|contents| tagged_in(&contents, tag))
+                .unwrap_or_default();
+            found.extend(
+                tags.into_iter()
+                    .map(|asset| match asset.starts_with(RES_PREFIX) {
+                        true => asset,
+                        false => format!("{}{}", prefix, asset),
+                    }),
+            );
+            let textures = format!("{}textures/", prefix);
+            found.extend(
+                pack.references()
+                    .filter(|reference| reference.starts_with(&textures) && named(reference, tag))
+                    .map(str::to_string),
+            );
+        }
+        for root in &self.roots {
+            let mut textures = Vec::new();
+            files(root, &root.join("textures"), &mut textures);
+            found.extend(
+                textures
+                    .into_iter()
+                    .filter(|asset| named(asset, tag))
+                    .map(|asset| format!("{}{}", RES_PREFIX, asset)),
+            );
+        }
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// The name of an asset pack by its id, followed by its version and
+    /// author when it has them, if it is one of the detected packs.
+    pub fn pack_label(&self, id: &str) -> Option<String> {
+        if let Some(pack) = self.archives.get(id) {
+            return Some(pack_label(&pack.name, &pack.version, &pack.author));
+        }
+        let data = read_pack_data(self.packs.get(id)?)?;
+        let field = |name: &str| data[name].as_str().unwrap_or_default().to_string();
+        let name = data.get("name")?.as_str()?;
+        Some(pack_label(name, &field("version"), &field("author")))
+    }
+
+    /// Whether an asset reference points to a file of the detected packs or
+    /// default assets, packed or not.
+    pub fn exists(&self, reference: &str) -> bool {
+        let archived = pack_id(reference)
+            .and_then(|id| self.archives.get(id))
+            .is_some_and(|pack| pack.contains(reference));
+        archived || self.resolve(reference).is_some()
+    }
+
+    /// Whether a pack was detected, so its assets can be looked for in it.
+    pub fn knows_pack(&self, id: &str) -> bool {
+        self.packs.contains_key(id) || self.archives.contains_key(id)
+    }
+
+    /// Whether a directory of Dungeondraft's own assets was detected.
+    pub fn has_default_assets(&self) -> bool {
+        !self.roots.is_empty()
+    }
+
+    /// Finds the file an asset reference points to, if it exists on disk.
+    pub fn resolve(&self, reference: &str) -> Option<PathBuf> {
+        if let Some(rest) = reference.strip_prefix(PACK_PREFIX) {
+            let (pack_id, asset) = rest.split_once('/')?;
+            let path = self.packs.get(pack_id)?.join(asset);
+            return path.is_file().then_some(path);
+        }
+        let asset = reference.strip_prefix(RES_PREFIX)?;
+        self.roots
+            .iter()
+            .map(|root| root.join(asset))
+            .find(|path| path.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map() -> Value {
+        json!({
+            "header": {
+                "asset_manifest": [ { "name": "Pack", "id": "3DDXdf2M" } ]
+            },
+            "world": {
+                "levels": {
+                    "0": {
+                        "objects": [
+                            { "texture": "res://packs/3DDXdf2M/textures/objects/tree.png" },
+                            { "texture": "res://textures/objects/barrel.png" }
+                        ],
+                        "materials": {
+                            "res://packs/3DDXdf2M/textures/materials/dirt.png": []
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_migrate_pack() {
+        let mut map = map();
+        let migration = AssetMigration::new().pack("3DDXdf2M", "NEWPACK1");
+        assert_eq!(migrate(&mut map, &migration), 3);
+        assert_eq!(map["header"]["asset_manifest"][0]["id"], json!("NEWPACK1"));
+        let level = &map["world"]["levels"]["0"];
+        assert_eq!(
+            level["objects"][0]["texture"],
+            json!("res://packs/NEWPACK1/textures/objects/tree.png")
+        );
+        assert!(level["materials"]
+            .get("res://packs/NEWPACK1/textures/materials/dirt.png")
+            .is_some());
+    }
+
+    #[test]
+    fn test_migrate_path() {
+        let mut map = map();
+        let migration = AssetMigration::new().path(
+            "res://textures/objects/barrel.png",
+            "res://textures/objects/crate.png",
+        );
+        assert_eq!(migrate(&mut map, &migration), 1);
+        assert_eq!(
+            map["world"]["levels"]["0"]["objects"][1]["texture"],
+            json!("res://textures/objects/crate.png")
+        );
+    }
+
+    #[test]
+    fn test_resolve_assets() {
+        let dir = std::env::temp_dir().join(format!("ddgen-assets-{}", std::process::id()));
+        let pack = dir.join("MyPack");
+        std::fs::create_dir_all(pack.join("textures/objects")).unwrap();
+        std::fs::create_dir_all(dir.join("Default/textures/walls")).unwrap();
+        std::fs::write(pack.join("pack.json"), r#"{ "id": "3DDXdf2M" }"#).unwrap();
+        std::fs::write(pack.join("textures/objects/tree.png"), "").unwrap();
+        std::fs::write(dir.join("Default/textures/walls/stone.png"), "").unwrap();
+
+        let resolver = AssetResolver::detect(std::slice::from_ref(&dir));
+        assert_eq!(
+            resolver.resolve("res://packs/3DDXdf2M/textures/objects/tree.png"),
+            Some(pack.join("textures/objects/tree.png"))
+        );
+        assert_eq!(
+            resolver.resolve("res://textures/walls/stone.png"),
+            Some(dir.join("Default/textures/walls/stone.png"))
+        );
+        assert_eq!(resolver.resolve("res://textures/walls/missing.png"), None);
+        assert_eq!(resolver.resolve("res://packs/unknown/textures/a.png"), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_search_assets() {
+        let dir = std::env::temp_dir().join(format!("ddgen-search-{}", std::process::id()));
+        let pack = dir.join("Tavern");
+        std::fs::create_dir_all(pack.join("textures/objects")).unwrap();
+        std::fs::create_dir_all(pack.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("Default/textures/objects")).unwrap();
+        std::fs::write(
+            pack.join("pack.json"),
+            r#"{ "id": "TAVERN01", "name": "Tavern", "author": "Ink", "version": "1.2" }"#,
+        )
+        .unwrap();
+        std::fs::write(pack.join("textures/objects/stool.png"), "").unwrap();
+        std::fs::write(pack.join("textures/objects/Armchair_Red.png"), "").unwrap();
+        std::fs::write(
+            pack.join(TAGS_FILE),
+            r#"{ "tags": { "Chair": ["textures/objects/stool.png"] } }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("Default/textures/objects/chair_wood.png"), "").unwrap();
+        std::fs::write(dir.join("Default/textures/objects/table.png"), "").unwrap();
+
+        let resolver = AssetResolver::detect(std::slice::from_ref(&dir));
+        assert_eq!(
+            resolver.search("chair"),
+            vec![
+                "res://packs/TAVERN01/textures/objects/Armchair_Red.png",
+                "res://packs/TAVERN01/textures/objects/stool.png",
+                "res://textures/objects/chair_wood.png",
+            ]
+        );
+        assert!(resolver.search("barrel").is_empty());
+        assert_eq!(
+            resolver.pack_label("TAVERN01"),
+            Some("Tavern 1.2 by Ink".to_string())
+        );
+        assert_eq!(resolver.pack_label("unknown"), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pack_reference() {
+        let tree = pack_reference("3DDXdf2M", "textures/objects/tree.png");
+        assert_eq!(tree, "res://packs/3DDXdf2M/textures/objects/tree.png");
+        assert_eq!(
+            pack_reference("3DDXdf2M", "res://textures/objects/tree.png"),
+            tree
+        );
+        assert_eq!(pack_id(&tree), Some("3DDXdf2M"));
+        assert_eq!(pack_id("res://textures/objects/barrel.png"), None);
+    }
+
+    #[test]
+    fn test_asset_references() {
+        let references = asset_references(&map());
+        assert_eq!(references.len(), 3);
+        assert_eq!(references["res://textures/objects/barrel.png"], 1);
+        assert!(references.contains_key("res://packs/3DDXdf2M/textures/materials/dirt.png"));
+    }
+
+    #[test]
+    fn test_parse_rename() {
+        assert_eq!(
+            parse_rename("old=new").unwrap(),
+            ("old".to_string(), "new".to_string())
+        );
+        assert!(parse_rename("old").is_err());
+        assert!(parse_rename("=new").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use std::path::Path;
+
+use opencv::core::{self, Mat, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::debug;
+use tract_onnx::prelude::*;
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+use crate::geometry::polygon_area;
+use crate::images::trace_outlines;
+
+/// The longest side of the images a promptable model embeds.
+const PROMPT_SIZE: usize = 1024;
+/// The channels and width of the embedding of an image.
+const EMBEDDING_SHAPE: [usize; 4] = [1, 256, 64, 64];
+/// The width of a mask given to the model to refine, of which none is given.
+const MASK_INPUT_SIZE: usize = 256;
+/// Points given to the model at most, as many as a room needs to be picked out.
+const MAX_PROMPT_POINTS: usize = 16;
+/// The mean and standard deviation of the RGB the model was trained on.
+const PIXEL_MEAN: [f32; 3] = [123.675, 116.28, 103.53];
+const PIXEL_STD: [f32; 3] = [58.395, 57.12, 57.375];
+/// Outlines of regions are simplified to within this share of their perimeter.
+const REGION_EPSILON: f64 = 0.005;
+
+/// A point marked on an image, in pixels, inside the region wanted or outside it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prompt {
+    pub position: Vector2,
+    pub inside: bool,
+}
+
+/// The coordinates and labels of prompts as a SAM decoder takes them, in the
+/// pixels of the embedded image, padded out with points labeled -1 that the
+/// model ignores.
+pub fn prompt_tensors(prompts: &[Prompt], scale: f64) -> (Vec<f32>, Vec<f32>) {
+    let mut coords = Vec::with_capacity(2 * (MAX_PROMPT_POINTS + 1));
+    let mut labels = Vec::with_capacity(MAX_PROMPT_POINTS + 1);
+    for prompt in prompts.iter().take(MAX_PROMPT_POINTS) {
+        coords.extend([
+            (prompt.position.x * scale) as f32,
+            (prompt.position.y * scale) as f32,
+        ]);
+        labels.push(if prompt.inside { 1.0 } else { 0.0 });
+    }
+    // Without a box prompt, at least one padding point is expected
+    while labels.len() <= MAX_PROMPT_POINTS {
+        coords.extend([0.0, 0.0]);
+        labels.push(-1.0);
+    }
+    (coords, labels)
+}
+
+/// A SAM style promptable segmentation model, an encoder embedding an image
+/// and a decoder picking out the region at points marked on it.
+///
+/// The encoder is given a 1x3x1024x1024 tensor of the image's RGB, normalized
+/// by the mean and deviation SAM was trained with, its longest side scaled to
+/// 1024 pixels and the rest padded. The decoder is the SAM ONNX export, taking
+/// the embedding, point coordinates and labels, a mask input, whether it is
+/// used, and the size of the original image, and returning mask logits of that
+/// size.
+pub struct PromptableModel {
+    name: String,
+    encoder: Arc<TypedRunnableModel>,
+    decoder: InferenceModel,
+}
+
+impl std::fmt::Debug for PromptableModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PromptableModel")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl PromptableModel {
+    pub fn load(encoder: &Path, decoder: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let size = PROMPT_SIZE;
+        let encoder_model = tract_onnx::onnx()
+            .model_for_path(encoder)
+            .and_then(|model| {
+                model
+                    .with_input_fact(0, f32::fact([1, 3, size, size]).into())?
+                    .into_optimized()?
+                    .into_runnable()
+            })
+            .map_err(|e| format!("{}: {}", encoder.display(), e))?;
+        let decoder_model = tract_onnx::onnx()
+            .model_for_path(decoder)
+            .map_err(|e| format!("{}: {}", decoder.display(), e))?;
+        Ok(Self {
+            name: decoder.display().to_string(),
+            encoder: encoder_model,
+            decoder: decoder_model,
+        })
+    }
+
+    /// Embeds an image to be prompted, the slow half of picking out a region.
+    pub fn embed(&self, image: &Mat) -> Result<PromptedImage, Box<dyn std::error::Error>> {
+        let (rows, cols) = (image.rows() as usize, image.cols() as usize);
+        let scale = PROMPT_SIZE as f64 / rows.max(cols).max(1) as f64;
+        let (width, height) = (
+            ((cols as f64 * scale).round() as usize).clamp(1, PROMPT_SIZE),
+            ((rows as f64 * scale).round() as usize).clamp(1, PROMPT_SIZE),
+        );
+        let mut resized = Mat::default();
+        imgproc::resize(
+            image,
+            &mut resized,
+            Size::new(width as i32, height as i32),
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        let mut rgb = Mat::default();
+        imgproc::cvt_color(&resized, &mut rgb, imgproc::COLOR_BGR2RGB, 0)?;
+        let pixels = rgb.data_bytes()?;
+        let input: Tensor = tract_ndarray::Array4::from_shape_fn(
+            (1, 3, PROMPT_SIZE, PROMPT_SIZE),
+            |(_, channel, y, x)| {
+                if x < width && y < height {
+                    (pixels[(y * width + x) * 3 + channel] as f32 - PIXEL_MEAN[channel])
+                        / PIXEL_STD[channel]
+                } else {
+                    0.0
+                }
+            },
+        )
+        .into();
+        debug!("Embedding a {}x{} image with {}", cols, rows, self.name);
+        let embedding = self.encoder.run(tvec!(input.into()))?.remove(0);
+        let points = MAX_PROMPT_POINTS + 1;
+        let decoder = self
+            .decoder
+            .clone()
+            .with_input_fact(0, f32::fact(EMBEDDING_SHAPE).into())?
+            .with_input_fact(1, f32::fact([1, points, 2]).into())?
+            .with_input_fact(2, f32::fact([1, points]).into())?
+            .with_input_fact(
+                3,
+                f32::fact([1, 1, MASK_INPUT_SIZE, MASK_INPUT_SIZE]).into(),
+            )?
+            .with_input_fact(4, f32::fact([1]).into())?
+            // The size of the masks depends on it, so it is fixed for the image
+            .with_input_fact(5, tensor1(&[rows as f32, cols as f32]).into())?
+            .into_optimized()?
+            .into_runnable()?;
+        Ok(PromptedImage {
+            embedding: embedding.into_tensor(),
+            decoder,
+            scale,
+            rows,
+            cols,
+        })
+    }
+}
+
+/// An image embedded by a promptable model, ready to pick regions out of.
+pub struct PromptedImage {
+    embedding: Tensor,
+    decoder: Arc<TypedRunnableModel>,
+    scale: f64,
+    rows: usize,
+    cols: usize,
+}
+
+impl PromptedImage {
+    /// The outline of the region marked by prompts, in map units, the largest
+    /// if the mask is in pieces.
+    pub fn region(
+        &self,
+        prompts: &[Prompt],
+        mapper: &CoordinateMapper,
+    ) -> Result<Option<Vec<Vector2>>, Box<dyn std::error::Error>> {
+        let points = MAX_PROMPT_POINTS + 1;
+        let (coords, labels) = prompt_tensors(prompts, self.scale);
+        let inputs = tvec!(
+            self.embedding.clone().into(),
+            tract_ndarray::Array3::from_shape_vec((1, points, 2), coords)?
+                .into_tensor()
+                .into(),
+            tract_ndarray::Array2::from_shape_vec((1, points), labels)?
+                .into_tensor()
+                .into(),
+            Tensor::zero::<f32>(&[1, 1, MASK_INPUT_SIZE, MASK_INPUT_SIZE])?.into(),
+            tensor1(&[0.0f32]).into(),
+            tensor1(&[self.rows as f32, self.cols as f32]).into(),
+        );
+        let outputs = self.decoder.run(inputs)?;
+        let logits = outputs[0].to_plain_array_view::<f32>()?;
+        if logits.len() < self.rows * self.cols {
+            return Err(format!("masks of shape {:?} are too small", logits.shape()).into());
+        }
+        // The first mask, of the pixels scoring above even odds
+        let mask: Vec<u8> = logits
+            .iter()
+            .take(self.rows * self.cols)
+            .map(|&logit| if logit > 0.0 { 255 } else { 0 })
+            .collect();
+        let mask = Mat::from_slice(&mask)?.reshape(1, self.rows as i32)?;
+        let outlines = trace_outlines(
+            &mask.try_clone()?,
+            imgproc::RETR_EXTERNAL,
+            1.0,
+            REGION_EPSILON,
+            mapper,
+        )?;
+        debug!(
+            "Picked out {} regions at {} points, {} pixels",
+            outlines.len(),
+            prompts.len(),
+            core::count_non_zero(&mask)?
+        );
+        Ok(outlines
+            .into_iter()
+            .filter(|outline| outline.len() > 2)
+            .max_by(|a, b| polygon_area(a).abs().total_cmp(&polygon_area(b).abs())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_tensors() {
+        let prompts = [
+            Prompt {
+                position: Vector2 { x: 100.0, y: 50.0 },
+                inside: true,
+            },
+            Prompt {
+                position: Vector2 { x: 10.0, y: 20.0 },
+                inside: false,
+            },
+        ];
+        let (coords, labels) = prompt_tensors(&prompts, 0.5);
+        assert_eq!(labels.len(), MAX_PROMPT_POINTS + 1);
+        assert_eq!(coords.len(), 2 * labels.len());
+        assert_eq!(&coords[..4], &[50.0, 25.0, 5.0, 10.0]);
+        assert_eq!(&labels[..3], &[1.0, 0.0, -1.0]);
+        // Even when out of room a padding point is kept
+        let (_, labels) = prompt_tensors(&[prompts[0]; 20], 1.0);
+        assert_eq!(labels.len(), MAX_PROMPT_POINTS + 1);
+        assert_eq!(labels[MAX_PROMPT_POINTS], -1.0);
+    }
+}
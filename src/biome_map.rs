@@ -0,0 +1,273 @@
+use std::path::Path;
+
+use opencv::core::{self, Mat, Scalar, Size, Vec3b};
+use opencv::imgproc;
+use opencv::prelude::*;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{Color, Vector2};
+use crate::images::{read_image, trace_outlines};
+use crate::world::BIOMES;
+
+/// DungeonDraft blends at most this many terrain textures.
+const TERRAIN_SLOTS: usize = 4;
+
+/// What the regions painted in one color of a biome map become, configured under `biomes`.
+///
+/// Each rule assigns exactly one of `terrain`, `material`, or `water`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BiomeRule {
+    /// `rrggbb` hex
+    pub color: String,
+    #[serde(default)]
+    pub terrain: Option<String>,
+    #[serde(default)]
+    pub material: Option<String>,
+    #[serde(default)]
+    pub water: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Region {
+    /// Index into the terrain textures
+    Terrain(usize),
+    Material(String),
+    Water,
+}
+
+/// The biome rules checked and resolved to RGB colors and terrain slots.
+#[derive(Debug)]
+struct Palette {
+    colors: Vec<[u8; 3]>,
+    regions: Vec<Region>,
+    terrain: Vec<String>,
+}
+
+/// Water regions, material regions, and terrain slots read from a biome map.
+#[derive(Debug, Default)]
+pub struct BiomeRegions {
+    /// Texture of each terrain slot
+    pub terrain: Vec<String>,
+    /// Terrain slot of every splat pixel
+    pub slots: Vec<usize>,
+    pub water: Vec<Vec<Vector2>>,
+    pub materials: Vec<(String, Vec<Vector2>)>,
+}
+
+/// The rules used when none are configured, painting the default biome colors as terrain.
+pub fn default_rules() -> Vec<BiomeRule> {
+    BIOMES
+        .iter()
+        .map(|biome| BiomeRule {
+            color: format!(
+                "{:02x}{:02x}{:02x}",
+                biome.color[0], biome.color[1], biome.color[2]
+            ),
+            terrain: Some(biome.texture.to_string()),
+            material: None,
+            water: false,
+        })
+        .collect()
+}
+
+/// The index of the palette color closest to an RGB color.
+pub fn nearest_color(color: [u8; 3], palette: &[[u8; 3]]) -> usize {
+    let distance = |other: &[u8; 3]| -> i32 {
+        (0..3)
+            .map(|i| (color[i] as i32 - other[i] as i32).pow(2))
+            .sum()
+    };
+    (0..palette.len())
+        .min_by_key(|&i| distance(&palette[i]))
+        .unwrap_or(0)
+}
+
+fn palette(rules: &[BiomeRule]) -> Result<Palette, String> {
+    if rules.is_empty() {
+        return Err("no biome colors are configured".to_string());
+    }
+    if rules.len() > u8::MAX as usize {
+        return Err(format!("at most {} biome colors are supported", u8::MAX));
+    }
+    let mut palette = Palette {
+        colors: Vec::new(),
+        regions: Vec::new(),
+        terrain: Vec::new(),
+    };
+    for rule in rules {
+        let color: Color = rule.color.parse()?;
+        let region = match (&rule.terrain, &rule.material, rule.water) {
+            (Some(texture), None, false) => {
+                let slot = match palette.terrain.iter().position(|t| t == texture) {
+                    Some(slot) => slot,
+                    None => {
+                        palette.terrain.push(texture.clone());
+                        palette.terrain.len() - 1
+                    }
+                };
+                Region::Terrain(slot)
+            }
+            (None, Some(texture), false) => Region::Material(texture.clone()),
+            (None, None, true) => Region::Water,
+            _ => {
+                return Err(format!(
+                    "biome {} must assign exactly one of terrain, material, or water",
+                    rule.color
+                ))
+            }
+        };
+        palette.colors.push([color.r, color.g, color.b]);
+        palette.regions.push(region);
+    }
+    if palette.terrain.len() > TERRAIN_SLOTS {
+        return Err(format!(
+            "at most {} terrain textures are supported but {} are configured",
+            TERRAIN_SLOTS,
+            palette.terrain.len()
+        ));
+    }
+    Ok(palette)
+}
+
+/// Reads the regions of a biome map covering a map `width` cells wide.
+///
+/// Each pixel belongs to the rule with the nearest color. The terrain is
+/// sampled at `splat_size`, and water and materials are traced as outlines.
+pub fn read_biome_regions(
+    biome_map: &Mat,
+    rules: &[BiomeRule],
+    width: u32,
+    splat_size: Size,
+) -> Result<BiomeRegions, Box<dyn std::error::Error>> {
+    let palette = palette(rules)?;
+    let mut labels = Mat::new_rows_cols_with_default(
+        biome_map.rows(),
+        biome_map.cols(),
+        core::CV_8UC1,
+        Scalar::all(0.0),
+    )?;
+    for row in 0..biome_map.rows() {
+        for col in 0..biome_map.cols() {
+            let bgr = biome_map.at_2d::<Vec3b>(row, col)?;
+            *labels.at_2d_mut::<u8>(row, col)? =
+                nearest_color([bgr[2], bgr[1], bgr[0]], &palette.colors) as u8;
+        }
+    }
+
+    // Nearest neighbor scaling keeps painted borders from blending into other labels
+    let mut splat = Mat::default();
+    imgproc::resize(
+        &labels,
+        &mut splat,
+        splat_size,
+        0.0,
+        0.0,
+        imgproc::INTER_NEAREST,
+    )?;
+    let mut slots = Vec::with_capacity((splat_size.width * splat_size.height) as usize);
+    for row in 0..splat_size.height {
+        for col in 0..splat_size.width {
+            slots.push(
+                match palette.regions[*splat.at_2d::<u8>(row, col)? as usize] {
+                    Region::Terrain(slot) => slot,
+                    _ => 0,
+                },
+            );
+        }
+    }
+
+    let mapper = CoordinateMapper::new(biome_map.cols() as f64 / width.max(1) as f64);
+    let mut regions = BiomeRegions {
+        terrain: palette.terrain.clone(),
+        slots,
+        ..Default::default()
+    };
+    for (label, region) in palette.regions.iter().enumerate() {
+        if let Region::Terrain(_) = region {
+            continue;
+        }
+        let mut mask = Mat::default();
+        core::compare(&labels, &Scalar::all(label as f64), &mut mask, core::CMP_EQ)?;
+        let outlines = trace_outlines(&mask, imgproc::RETR_EXTERNAL, 1.0, 0.005, &mapper)?;
+        debug!(
+            "Biome {} has {} regions",
+            rules[label].color,
+            outlines.len()
+        );
+        match region {
+            Region::Water => regions.water.extend(outlines),
+            Region::Material(texture) => regions
+                .materials
+                .extend(outlines.into_iter().map(|o| (texture.clone(), o))),
+            Region::Terrain(_) => {}
+        }
+    }
+    Ok(regions)
+}
+
+pub fn read_biome_map(path: &Path) -> Result<Mat, Box<dyn std::error::Error>> {
+    debug!("Reading biome map {}", path.display());
+    let biome_map = read_image(path, opencv::imgcodecs::IMREAD_COLOR)?;
+    if biome_map.empty() {
+        return Err(format!("failed to read biome map {}", path.display()).into());
+    }
+    Ok(biome_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Vec<BiomeRule> {
+        serde_json::from_str(
+            r#"[
+                { "color": "3c8c3c", "terrain": "res://textures/terrain/terrain_grass.png" },
+                { "color": "2060c0", "water": true },
+                { "color": "a0a0a0", "material": "res://textures/materials/stone.png" },
+                { "color": "2f6f2f", "terrain": "res://textures/terrain/terrain_grass.png" }
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_palette() {
+        let palette = palette(&rules()).unwrap();
+        assert_eq!(palette.terrain.len(), 1);
+        assert_eq!(palette.colors[1], [0x20, 0x60, 0xc0]);
+        assert_eq!(
+            palette.regions,
+            vec![
+                Region::Terrain(0),
+                Region::Water,
+                Region::Material("res://textures/materials/stone.png".to_string()),
+                Region::Terrain(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_palette() {
+        assert!(palette(&[]).is_err());
+        let mut rules = rules();
+        rules[0].water = true;
+        assert!(palette(&rules).is_err());
+        rules[0].color = "nope".to_string();
+        assert!(palette(&rules).is_err());
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let palette = palette(&default_rules()).unwrap();
+        assert_eq!(palette.terrain.len(), BIOMES.len());
+    }
+
+    #[test]
+    fn test_nearest_color() {
+        let palette = [[0, 0, 0], [255, 255, 255], [255, 0, 0]];
+        assert_eq!(nearest_color([10, 10, 10], &palette), 0);
+        assert_eq!(nearest_color([200, 30, 30], &palette), 2);
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use eframe::egui;
+use opencv::core::{self, Mat, Rect};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::{debug, info};
+
+const SELECTION_COLOR: egui::Color32 = egui::Color32::from_rgb(0, 220, 0);
+/// Selections smaller than this many pixels across are taken as stray clicks.
+const MIN_SELECTION: f32 = 8.0;
+
+/// The screen, shown full screen to drag a rectangle over the map on it.
+struct RegionPicker {
+    texture: egui::TextureHandle,
+    /// Where the drag started, in screen pixels
+    start: Option<egui::Pos2>,
+    /// The rectangle dragged, in screen pixels, once the drag ends
+    picked: Arc<Mutex<Option<egui::Rect>>>,
+}
+
+impl eframe::App for RegionPicker {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        if ui.input(|input| input.key_pressed(egui::Key::Escape)) {
+            ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+        egui::CentralPanel::default_margins().show(ui, |ui| {
+            let size = self.texture.size_vec2();
+            let scale = (ui.available_width() / size.x).min(ui.available_height() / size.y);
+            let response = ui.add(
+                egui::Image::new(&self.texture)
+                    .fit_to_exact_size(size * scale)
+                    .sense(egui::Sense::drag()),
+            );
+            let origin = response.rect.min;
+            let pointer = response.interact_pointer_pos();
+            if response.drag_started() {
+                self.start = pointer;
+            }
+            let Some(start) = self.start else {
+                return;
+            };
+            let end = pointer.or(response.hover_pos()).unwrap_or(start);
+            let dragged = egui::Rect::from_two_pos(start, end);
+            ui.painter_at(response.rect).rect_stroke(
+                dragged,
+                0.0,
+                egui::Stroke::new(2.0, SELECTION_COLOR),
+                egui::StrokeKind::Outside,
+            );
+            if response.drag_stopped() {
+                self.start = None;
+                if dragged.width() < MIN_SELECTION || dragged.height() < MIN_SELECTION {
+                    return;
+                }
+                let to_screen =
+                    |p: egui::Pos2| egui::pos2((p.x - origin.x) / scale, (p.y - origin.y) / scale);
+                *self.picked.lock().unwrap() = Some(egui::Rect::from_two_pos(
+                    to_screen(dragged.min),
+                    to_screen(dragged.max),
+                ));
+                ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+        });
+    }
+}
+
+/// Captures the primary screen as a BGR image.
+fn capture_screen() -> Result<Mat, Box<dyn std::error::Error>> {
+    let monitors = xcap::Monitor::all()?;
+    let monitor = monitors
+        .iter()
+        .find(|monitor| monitor.is_primary())
+        .or(monitors.first())
+        .ok_or("found no screen to capture")?;
+    debug!("Capturing screen {}", monitor.name());
+    let screen = monitor.capture_image()?;
+    let rows = screen.height() as i32;
+    let rgba = Mat::from_slice(screen.as_raw())?.reshape(4, rows)?;
+    let mut bgr = Mat::default();
+    imgproc::cvt_color(&rgba, &mut bgr, imgproc::COLOR_RGBA2BGR, 0)?;
+    Ok(bgr)
+}
+
+/// Captures the screen and opens it full screen to drag a rectangle over the
+/// map on it, such as one shown in a PDF reader or browser, writing the
+/// region picked as a PNG in the current directory to generate from.
+pub fn capture_region() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let screen = capture_screen()?;
+    let mut rgb = Mat::default();
+    imgproc::cvt_color(&screen, &mut rgb, imgproc::COLOR_BGR2RGB, 0)?;
+    let size = [screen.cols() as usize, screen.rows() as usize];
+    let pixels = egui::ColorImage::from_rgb(size, rgb.data_bytes()?);
+    let picked = Arc::new(Mutex::new(None));
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_title("dungeondraft-generator - drag over the map, Escape to cancel")
+            .with_fullscreen(true),
+        ..Default::default()
+    };
+    let app_picked = Arc::clone(&picked);
+    eframe::run_native(
+        "dungeondraft-generator",
+        options,
+        Box::new(move |cc| {
+            let texture = cc
+                .egui_ctx
+                .load_texture("screen", pixels, egui::TextureOptions::LINEAR);
+            Ok(Box::new(RegionPicker {
+                texture,
+                start: None,
+                picked: app_picked,
+            }))
+        }),
+    )?;
+    let picked = picked
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or("no region of the screen was picked")?;
+    let region = Rect::new(
+        picked.min.x.round() as i32,
+        picked.min.y.round() as i32,
+        picked.width().round() as i32,
+        picked.height().round() as i32,
+    ) & Rect::new(0, 0, screen.cols(), screen.rows());
+    let path = PathBuf::from(format!(
+        "capture-{}.png",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    ));
+    let path_str = path.to_str().ok_or("invalid capture path")?;
+    imwrite(path_str, &Mat::roi(&screen, region)?, &core::Vector::new())?;
+    info!(
+        "Captured a {}x{} region of the screen to {}",
+        region.width,
+        region.height,
+        path.display()
+    );
+    Ok(path)
+}
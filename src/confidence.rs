@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::f64::consts::PI;
+
+use crate::dungeondraft_v1::Vector2;
+use crate::geometry::{boundary_distance, distance, polygon_area};
+use crate::images::ShapeClass;
+
+/// Shapes scoring below this are held back for review by default.
+pub const MIN_CONFIDENCE: f64 = 0.5;
+/// Gradient magnitude of a clearly drawn edge, half of a black to white step
+/// for a 3x3 Sobel filter.
+const STRONG_EDGE: f64 = 510.0;
+
+/// How sure detection is of a shape, each part from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Confidence {
+    /// Whether the outline encloses a region rather than doubling back on itself
+    pub closure: f64,
+    /// How strongly the outline stands out from the image
+    pub edge: f64,
+    /// How closely the simplified vertices follow the outline
+    pub fit: f64,
+    /// How well the vertices match the class of the shape
+    pub class: f64,
+}
+
+impl Confidence {
+    /// The overall score, as sure as the least certain part.
+    pub fn score(&self) -> f64 {
+        self.closure.min(self.edge).min(self.fit).min(self.class)
+    }
+}
+
+/// Parses a confidence threshold from 0 to 1.
+pub fn parse_confidence(s: &str) -> Result<f64, String> {
+    s.parse()
+        .ok()
+        .filter(|confidence: &f64| (0.0..=1.0).contains(confidence))
+        .ok_or_else(|| format!("invalid confidence '{}', expected 0 to 1", s))
+}
+
+/// The share of the traced outline that is not retraced, as open curves are
+/// traced along one side and back along the other.
+pub fn closure_score(contour: &[Vector2]) -> f64 {
+    if contour.is_empty() {
+        return 0.0;
+    }
+    let unique: HashSet<(i64, i64)> = contour
+        .iter()
+        .map(|p| (p.x.round() as i64, p.y.round() as i64))
+        .collect();
+    // A curve traced there and back again repeats every point once
+    let share = unique.len() as f64 / contour.len() as f64;
+    ((share - 0.5) * 2.0).clamp(0.0, 1.0)
+}
+
+/// The mean gradient magnitude along an outline relative to a clearly drawn edge.
+pub fn edge_score(gradients: &[f64]) -> f64 {
+    if gradients.is_empty() {
+        return 0.0;
+    }
+    let mean = gradients.iter().sum::<f64>() / gradients.len() as f64;
+    (mean / STRONG_EDGE).clamp(0.0, 1.0)
+}
+
+/// How closely the vertices follow the outline, from the mean distance of
+/// outline points to the simplified polygon relative to the allowed tolerance.
+pub fn fit_score(contour: &[Vector2], vertices: &[Vector2], tolerance: f64) -> f64 {
+    if contour.is_empty() || vertices.len() < 2 {
+        return 0.0;
+    }
+    if tolerance <= 0.0 {
+        return 1.0;
+    }
+    let mean = contour
+        .iter()
+        .map(|p| boundary_distance(vertices, *p))
+        .sum::<f64>()
+        / contour.len() as f64;
+    (1.0 - mean / tolerance).clamp(0.0, 1.0)
+}
+
+/// How well the vertices match their class, whether rectangles are square
+/// cornered and circles round.
+pub fn class_score(vertices: &[Vector2]) -> f64 {
+    match ShapeClass::of(vertices.len()) {
+        ShapeClass::Rectangle => {
+            let count = vertices.len();
+            let skew: f64 = (0..count)
+                .map(|i| {
+                    let previous = vertices[(i + count - 1) % count];
+                    let (corner, next) = (vertices[i], vertices[(i + 1) % count]);
+                    let (ax, ay) = (previous.x - corner.x, previous.y - corner.y);
+                    let (bx, by) = (next.x - corner.x, next.y - corner.y);
+                    let lengths = ax.hypot(ay) * bx.hypot(by);
+                    if lengths == 0.0 {
+                        1.0
+                    } else {
+                        ((ax * bx + ay * by) / lengths).abs()
+                    }
+                })
+                .sum::<f64>()
+                / count as f64;
+            1.0 - skew
+        }
+        ShapeClass::Circle => {
+            let perimeter: f64 = vertices
+                .iter()
+                .zip(vertices.iter().cycle().skip(1))
+                .map(|(a, b)| distance(*a, *b))
+                .sum();
+            if perimeter == 0.0 {
+                return 0.0;
+            }
+            (4.0 * PI * polygon_area(vertices) / perimeter.powi(2)).clamp(0.0, 1.0)
+        }
+        // Told apart by their vertex count alone
+        ShapeClass::Triangle | ShapeClass::Polygon => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(coords: &[(f64, f64)]) -> Vec<Vector2> {
+        coords.iter().map(|&(x, y)| Vector2 { x, y }).collect()
+    }
+
+    #[test]
+    fn test_parse_confidence() {
+        assert_eq!(parse_confidence("0.75"), Ok(0.75));
+        assert_eq!(parse_confidence("1"), Ok(1.0));
+        assert!(parse_confidence("1.5").is_err());
+        assert!(parse_confidence("high").is_err());
+    }
+
+    #[test]
+    fn test_closure_score() {
+        let closed = points(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)]);
+        assert_eq!(closure_score(&closed), 1.0);
+        // An open line traced there and back
+        let open = points(&[(0.0, 0.0), (5.0, 0.0), (10.0, 0.0), (5.0, 0.0)]);
+        assert_eq!(closure_score(&open), 0.5);
+        assert_eq!(closure_score(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_edge_and_fit_scores() {
+        assert_eq!(edge_score(&[1020.0, 800.0]), 1.0);
+        assert_eq!(edge_score(&[255.0]), 0.5);
+
+        let square = points(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)]);
+        assert_eq!(fit_score(&square, &square, 2.0), 1.0);
+        let wobbly = points(&[(5.0, 1.0), (10.0, 5.0)]);
+        assert_eq!(fit_score(&wobbly, &square, 2.0), 0.75);
+    }
+
+    #[test]
+    fn test_class_score() {
+        let square = points(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)]);
+        assert!((class_score(&square) - 1.0).abs() < 1e-9);
+        let rhombus = points(&[(0.0, 0.0), (10.0, 0.0), (15.0, 8.0), (5.0, 8.0)]);
+        assert!(class_score(&rhombus) < 0.5);
+        let octagon: Vec<Vector2> = (0..8)
+            .map(|i| {
+                let angle = i as f64 * PI / 4.0;
+                Vector2 {
+                    x: angle.cos(),
+                    y: angle.sin(),
+                }
+            })
+            .collect();
+        assert!(class_score(&octagon) > 0.9);
+
+        let confidence = Confidence {
+            closure: 1.0,
+            edge: 0.4,
+            fit: 0.9,
+            class: 1.0,
+        };
+        assert_eq!(confidence.score(), 0.4);
+    }
+}
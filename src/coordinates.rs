@@ -0,0 +1,462 @@
+use crate::dungeondraft_v1::{Vector2, UNITS_PER_CELL};
+
+/// How converted coordinates are rounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    /// Kept exactly as converted
+    Exact,
+    /// Rounded to whole DungeonDraft units
+    Units,
+    /// Snapped to the nearest `1 / n` of a cell, `1` snapping to grid corners
+    Subcells(u32),
+}
+
+impl Rounding {
+    fn apply(self, units: f64) -> f64 {
+        match self {
+            Rounding::Exact => units,
+            Rounding::Units => units.round(),
+            Rounding::Subcells(n) => {
+                let step = UNITS_PER_CELL / n.max(1) as f64;
+                (units / step).round() * step
+            }
+        }
+    }
+}
+
+/// Parses a rounding policy, `none`, `units`, or the number of steps per cell
+/// to snap to.
+pub fn parse_rounding(s: &str) -> Result<Rounding, String> {
+    match s {
+        "none" => Ok(Rounding::Exact),
+        "units" => Ok(Rounding::Units),
+        _ => s
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .map(Rounding::Subcells)
+            .ok_or_else(|| {
+                format!(
+                    "invalid rounding '{}', expected none, units, or steps per cell",
+                    s
+                )
+            }),
+    }
+}
+
+/// Parses the size of the cells of a grid in pixels, which the pixels of an
+/// image are divided by.
+pub fn parse_pixels_per_cell(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|pixels: &f64| *pixels > 0.0 && pixels.is_finite())
+        .ok_or_else(|| format!("invalid cell size '{}', expected more than 0 pixels", s))
+}
+
+/// Parses how many cells an inch of an image is printed at, which its DPI
+/// is divided by.
+pub fn parse_cells_per_inch(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|cells: &f64| *cells > 0.0 && cells.is_finite())
+        .ok_or_else(|| format!("invalid cells per inch '{}', expected more than 0", s))
+}
+
+/// Parses an `x,y` offset in pixels.
+pub fn parse_offset(s: &str) -> Result<Vector2, String> {
+    let parts: Vec<f64> = s
+        .split(',')
+        .map(|part| part.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid offset '{}', expected x,y", s))?;
+    match parts[..] {
+        [x, y] => Ok(Vector2 { x, y }),
+        _ => Err(format!("invalid offset '{}', expected x,y", s)),
+    }
+}
+
+/// The DungeonDraft units spanned by a length in cells.
+pub fn cells_to_units(cells: f64) -> f64 {
+    cells * UNITS_PER_CELL
+}
+
+/// The cells spanned by a length in DungeonDraft units.
+pub fn units_to_cells(units: f64) -> f64 {
+    units / UNITS_PER_CELL
+}
+
+/// The cells covered by an area in square DungeonDraft units.
+pub fn square_units_to_cells(area: f64) -> f64 {
+    area / (UNITS_PER_CELL * UNITS_PER_CELL)
+}
+
+/// Turns an image about its center to straighten a grid drawn at an angle,
+/// onto a canvas large enough to hold the whole turned image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    /// Counter-clockwise, as seen on screen
+    pub degrees: f64,
+    /// The center of the image
+    pub from: Vector2,
+    /// The center of the turned canvas
+    pub to: Vector2,
+}
+
+impl Rotation {
+    pub fn new(degrees: f64, width: i32, height: i32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (width, height) = (width as f64, height as f64);
+        Self {
+            degrees,
+            from: Vector2 {
+                x: width / 2.0,
+                y: height / 2.0,
+            },
+            to: Vector2 {
+                x: (width * cos.abs() + height * sin.abs()) / 2.0,
+                y: (width * sin.abs() + height * cos.abs()) / 2.0,
+            },
+        }
+    }
+
+    /// The size of the turned canvas.
+    pub fn size(&self) -> (f64, f64) {
+        (self.to.x * 2.0, self.to.y * 2.0)
+    }
+
+    /// Where an image pixel lands on the turned canvas.
+    pub fn apply(&self, p: Vector2) -> Vector2 {
+        let (sin, cos) = self.degrees.to_radians().sin_cos();
+        // Image rows run down, so turning counter-clockwise on screen is a
+        // negative angle
+        let (dx, dy) = (p.x - self.from.x, p.y - self.from.y);
+        Vector2 {
+            x: cos * dx + sin * dy + self.to.x,
+            y: -sin * dx + cos * dy + self.to.y,
+        }
+    }
+
+    /// Where a pixel of the turned canvas came from in the image.
+    pub fn invert(&self, p: Vector2) -> Vector2 {
+        let (sin, cos) = self.degrees.to_radians().sin_cos();
+        let (dx, dy) = (p.x - self.to.x, p.y - self.to.y);
+        Vector2 {
+            x: cos * dx - sin * dy + self.from.x,
+            y: sin * dx + cos * dy + self.from.y,
+        }
+    }
+}
+
+/// The diamond grid of an isometric image, whose cells are seen from a corner
+/// with their edges at `degrees` below and above horizontal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Isometric {
+    /// The width of a diamond in pixels
+    pub tile_width: f64,
+    pub degrees: f64,
+    /// The cell the top left of the image is in, so the whole image lands on
+    /// the map
+    pub offset: Vector2,
+}
+
+impl Isometric {
+    pub fn new(tile_width: f64, degrees: f64) -> Self {
+        Self {
+            tile_width,
+            degrees,
+            offset: Vector2 { x: 0.0, y: 0.0 },
+        }
+    }
+
+    fn tile_height(&self) -> f64 {
+        self.tile_width * self.degrees.to_radians().tan()
+    }
+
+    /// The cells crossed going a distance in pixels square to the grid lines.
+    pub fn cells_across(&self, distance: f64) -> f64 {
+        distance * self.tile_width.recip().hypot(self.tile_height().recip())
+    }
+
+    /// The top-down cell an image pixel is drawn in, fractionally.
+    pub fn cells(&self, p: Vector2) -> Vector2 {
+        let (x, y) = (p.x / self.tile_width, p.y / self.tile_height());
+        Vector2 {
+            x: x + y - self.offset.x,
+            y: y - x - self.offset.y,
+        }
+    }
+
+    /// Where a top-down cell position is drawn in the image.
+    pub fn pixels(&self, cells: Vector2) -> Vector2 {
+        let (u, v) = (cells.x + self.offset.x, cells.y + self.offset.y);
+        Vector2 {
+            x: (u - v) * self.tile_width / 2.0,
+            y: (u + v) * self.tile_height() / 2.0,
+        }
+    }
+
+    /// Shifts the grid so grid lines a `phase` of a cell along land on cell
+    /// boundaries and the whole image is within the map.
+    pub fn aligned(self, phase: Vector2, width: i32, height: i32) -> Self {
+        let unshifted = Self::new(self.tile_width, self.degrees);
+        let corners = corners(width, height).map(|corner| unshifted.cells(corner));
+        let min = |axis: fn(&Vector2) -> f64| corners.iter().map(axis).fold(f64::MAX, f64::min);
+        let (min_x, min_y) = (min(|p| p.x), min(|p| p.y));
+        Self {
+            offset: Vector2 {
+                x: min_x - (min_x - phase.x).rem_euclid(1.0),
+                y: min_y - (min_y - phase.y).rem_euclid(1.0),
+            },
+            ..self
+        }
+    }
+}
+
+fn corners(width: i32, height: i32) -> [Vector2; 4] {
+    let (width, height) = (width as f64, height as f64);
+    [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)].map(|(x, y)| Vector2 { x, y })
+}
+
+/// Converts image pixels to grid cells and DungeonDraft units.
+///
+/// An isometric image is first projected top-down. An image with its grid drawn at an angle is first turned by the `rotation`
+/// to straighten it. The `offset` is then where the grid starts, in pixels, so
+/// a grid line drawn there lands on a cell boundary of the map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoordinateMapper {
+    pub pixels_per_cell: f64,
+    pub isometric: Option<Isometric>,
+    pub rotation: Option<Rotation>,
+    pub offset: Vector2,
+    pub rounding: Rounding,
+}
+
+impl CoordinateMapper {
+    pub fn new(pixels_per_cell: f64) -> Self {
+        Self {
+            pixels_per_cell,
+            isometric: None,
+            rotation: None,
+            offset: Vector2 { x: 0.0, y: 0.0 },
+            rounding: Rounding::Exact,
+        }
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// The cell an image pixel falls in, fractionally.
+    pub fn pixels_to_cells(&self, x: f64, y: f64) -> Vector2 {
+        if let Some(isometric) = self.isometric {
+            return isometric.cells(Vector2 { x, y });
+        }
+        let Vector2 { x, y } = match self.rotation {
+            Some(rotation) => rotation.apply(Vector2 { x, y }),
+            None => Vector2 { x, y },
+        };
+        Vector2 {
+            x: (x - self.offset.x) / self.pixels_per_cell,
+            y: (y - self.offset.y) / self.pixels_per_cell,
+        }
+    }
+
+    /// The map position of an image pixel, rounded by the rounding policy.
+    pub fn pixels_to_units(&self, x: f64, y: f64) -> Vector2 {
+        let cells = self.pixels_to_cells(x, y);
+        Vector2 {
+            x: self.rounding.apply(cells_to_units(cells.x)),
+            y: self.rounding.apply(cells_to_units(cells.y)),
+        }
+    }
+
+    /// Converts a point in image pixels to map units.
+    pub fn point(&self, p: Vector2) -> Vector2 {
+        self.pixels_to_units(p.x, p.y)
+    }
+
+    /// Converts points in image pixels to map units.
+    pub fn points(&self, points: &[Vector2]) -> Vec<Vector2> {
+        points.iter().map(|p| self.point(*p)).collect()
+    }
+
+    /// The image pixel a map position is drawn at.
+    pub fn units_to_pixels(&self, p: Vector2) -> Vector2 {
+        if let Some(isometric) = self.isometric {
+            return isometric.pixels(Vector2 {
+                x: units_to_cells(p.x),
+                y: units_to_cells(p.y),
+            });
+        }
+        let p = Vector2 {
+            x: units_to_cells(p.x) * self.pixels_per_cell + self.offset.x,
+            y: units_to_cells(p.y) * self.pixels_per_cell + self.offset.y,
+        };
+        match self.rotation {
+            Some(rotation) => rotation.invert(p),
+            None => p,
+        }
+    }
+
+    /// The whole cells needed to cover an image of the given size, turned by
+    /// the rotation or projected top-down, at least one.
+    pub fn map_size(&self, width: i32, height: i32) -> (u32, u32) {
+        if let Some(isometric) = self.isometric {
+            let cells = corners(width, height).map(|corner| isometric.cells(corner));
+            let max = |axis: fn(&Vector2) -> f64| {
+                let max = cells.iter().map(axis).fold(0.0, f64::max);
+                max.ceil().max(1.0) as u32
+            };
+            return (max(|p| p.x), max(|p| p.y));
+        }
+        let (width, height) = match self.rotation {
+            Some(rotation) => rotation.size(),
+            None => (width as f64, height as f64),
+        };
+        let cells = |pixels: f64, offset: f64| {
+            ((pixels - offset) / self.pixels_per_cell).ceil().max(1.0) as u32
+        };
+        (cells(width, self.offset.x), cells(height, self.offset.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixels_to_units() {
+        let mapper = CoordinateMapper::new(64.0);
+        assert_eq!(
+            mapper.pixels_to_units(32.0, 64.0),
+            Vector2 { x: 128.0, y: 256.0 }
+        );
+        assert_eq!(
+            mapper.pixels_to_cells(96.0, 0.0),
+            Vector2 { x: 1.5, y: 0.0 }
+        );
+        let mapper = CoordinateMapper {
+            offset: Vector2 { x: 16.0, y: 8.0 },
+            ..mapper
+        };
+        assert_eq!(
+            mapper.pixels_to_units(16.0, 72.0),
+            Vector2 { x: 0.0, y: 256.0 }
+        );
+        let p = Vector2 { x: 300.0, y: -20.0 };
+        assert_eq!(mapper.point(mapper.units_to_pixels(p)), p);
+    }
+
+    #[test]
+    fn test_rounding() {
+        let mapper = CoordinateMapper::new(3.0);
+        assert!((mapper.pixels_to_units(1.0, 0.0).x - 85.333).abs() < 0.001);
+        let mapper = mapper.with_rounding(Rounding::Units);
+        assert_eq!(mapper.pixels_to_units(1.0, 0.0).x, 85.0);
+        let mapper = mapper.with_rounding(Rounding::Subcells(2));
+        assert_eq!(
+            mapper.pixels_to_units(1.0, 2.0),
+            Vector2 { x: 128.0, y: 128.0 }
+        );
+        let mapper = mapper.with_rounding(Rounding::Subcells(1));
+        assert_eq!(
+            mapper.pixels_to_units(1.0, 2.0),
+            Vector2 { x: 0.0, y: 256.0 }
+        );
+    }
+
+    #[test]
+    fn test_parse_rounding() {
+        assert_eq!(parse_rounding("none"), Ok(Rounding::Exact));
+        assert_eq!(parse_rounding("units"), Ok(Rounding::Units));
+        assert_eq!(parse_rounding("4"), Ok(Rounding::Subcells(4)));
+        assert!(parse_rounding("0").is_err());
+        assert!(parse_rounding("half").is_err());
+    }
+
+    #[test]
+    fn test_square_units_to_cells() {
+        let side = cells_to_units(3.0);
+        assert_eq!(square_units_to_cells(side * side), 9.0);
+        assert_eq!(units_to_cells(side), 3.0);
+    }
+
+    #[test]
+    fn test_parse_pixels_per_cell() {
+        assert_eq!(parse_pixels_per_cell("64"), Ok(64.0));
+        assert_eq!(parse_pixels_per_cell("70.5"), Ok(70.5));
+        assert!(parse_pixels_per_cell("0").is_err());
+        assert!(parse_pixels_per_cell("-64").is_err());
+        assert!(parse_pixels_per_cell("inf").is_err());
+        assert!(parse_pixels_per_cell("wide").is_err());
+    }
+
+    #[test]
+    fn test_parse_cells_per_inch() {
+        assert_eq!(parse_cells_per_inch("0.5"), Ok(0.5));
+        assert!(parse_cells_per_inch("0").is_err());
+        assert!(parse_cells_per_inch("-2").is_err());
+        assert!(parse_cells_per_inch("NaN").is_err());
+    }
+
+    #[test]
+    fn test_parse_offset() {
+        assert_eq!(parse_offset("12, -3.5"), Ok(Vector2 { x: 12.0, y: -3.5 }));
+        assert!(parse_offset("12").is_err());
+        assert!(parse_offset("a,b").is_err());
+    }
+
+    #[test]
+    fn test_map_size() {
+        let mapper = CoordinateMapper::new(64.0);
+        assert_eq!(mapper.map_size(640, 65), (10, 2));
+        assert_eq!(mapper.map_size(0, 0), (1, 1));
+        let mapper = CoordinateMapper {
+            offset: Vector2 { x: 32.0, y: 0.0 },
+            ..mapper
+        };
+        assert_eq!(mapper.map_size(640, 64), (10, 1));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let rotation = Rotation::new(90.0, 200, 100);
+        let (width, height) = rotation.size();
+        assert!((width - 100.0).abs() < 1e-9 && (height - 200.0).abs() < 1e-9);
+        // Turning counter-clockwise brings the top right corner to the top left
+        let corner = rotation.apply(Vector2 { x: 200.0, y: 0.0 });
+        assert!(corner.x.abs() < 1e-9 && corner.y.abs() < 1e-9);
+
+        let mapper = CoordinateMapper {
+            rotation: Some(Rotation::new(30.0, 640, 480)),
+            ..CoordinateMapper::new(64.0)
+        };
+        let p = Vector2 { x: 100.0, y: 50.0 };
+        let back = mapper.units_to_pixels(mapper.point(p));
+        assert!((back.x - p.x).abs() < 1e-9 && (back.y - p.y).abs() < 1e-9);
+        assert_eq!(mapper.map_size(640, 480), (13, 12));
+    }
+
+    #[test]
+    fn test_isometric() {
+        let isometric = Isometric::new(64.0, 0.5f64.atan().to_degrees());
+        let cells = isometric.cells(Vector2 { x: 32.0, y: 16.0 });
+        assert!((cells.x - 1.0).abs() < 1e-9 && cells.y.abs() < 1e-9);
+        assert!((isometric.cells_across(64.0 / 5f64.sqrt()) - 1.0).abs() < 1e-9);
+
+        let isometric = isometric.aligned(Vector2 { x: 0.25, y: 0.5 }, 640, 320);
+        assert!((isometric.offset.x + 0.75).abs() < 1e-9);
+        assert!((isometric.offset.y + 10.5).abs() < 1e-9);
+        let mapper = CoordinateMapper {
+            isometric: Some(isometric),
+            ..CoordinateMapper::new(64.0)
+        };
+        let p = Vector2 { x: 100.0, y: 50.0 };
+        let back = mapper.units_to_pixels(mapper.point(p));
+        assert!((back.x - p.x).abs() < 1e-9 && (back.y - p.y).abs() < 1e-9);
+        assert_eq!(mapper.map_size(640, 320), (21, 21));
+    }
+}
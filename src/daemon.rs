@@ -0,0 +1,292 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, error, info, info_span};
+
+use crate::biome_map::BiomeRule;
+use crate::dungeon::DungeonOptions;
+use crate::remote::fetch_input;
+use crate::serve::{generate_output, Format, GenerateRequest};
+
+/// Job files describe a job with options, other files in the inbox are
+/// generated as dungeons with the default options.
+const JOB_SUFFIX: &str = ".job.json";
+/// Inbox subdirectory holding claimed jobs while they are worked on.
+const PROCESSING_DIR: &str = "processing";
+/// Inbox subdirectory finished jobs are moved to.
+const DONE_DIR: &str = "done";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DaemonOptions {
+    /// How many jobs are worked on at once
+    pub jobs: usize,
+    /// How often the inbox is checked for new jobs
+    pub interval: Duration,
+}
+
+/// A job file, naming its input and the options it is generated with.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Job {
+    /// An image, GeoJSON file, or http(s) URL, kept outside the inbox so it
+    /// is not taken for a job of its own
+    pub input: PathBuf,
+    pub mode: String,
+    pub format: Format,
+    pub pixels_per_cell: f64,
+    pub min_speckle_area: i32,
+    pub min_confidence: f64,
+    pub buildings: Vec<String>,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        let dungeon = DungeonOptions::default();
+        Self {
+            input: PathBuf::new(),
+            mode: "dungeon".to_string(),
+            format: Format::DungeondraftMap,
+            pixels_per_cell: dungeon.pixels_per_cell,
+            min_speckle_area: dungeon.min_speckle_area,
+            min_confidence: dungeon.min_confidence,
+            buildings: Vec::new(),
+        }
+    }
+}
+
+impl Job {
+    /// Reads a job file, or makes a default job of any other file.
+    pub fn for_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !is_job_file(path) {
+            return Ok(Job {
+                input: path.to_path_buf(),
+                ..Default::default()
+            });
+        }
+        let job: Job = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if job.input.as_os_str().is_empty() {
+            return Err("job has no input".into());
+        }
+        job.request().validate()?;
+        Ok(job)
+    }
+
+    fn request(&self) -> GenerateRequest {
+        GenerateRequest {
+            mode: self.mode.clone(),
+            format: self.format,
+            dungeon: DungeonOptions {
+                pixels_per_cell: self.pixels_per_cell,
+                min_speckle_area: self.min_speckle_area,
+                min_confidence: self.min_confidence,
+                ..Default::default()
+            },
+            buildings: self.buildings.clone(),
+        }
+    }
+}
+
+fn is_job_file(path: &Path) -> bool {
+    path.to_str().is_some_and(|s| s.ends_with(JOB_SUFFIX))
+}
+
+/// The name results of a job file are written under, without an extension.
+pub fn job_name(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    match name.strip_suffix(JOB_SUFFIX) {
+        Some(stem) => stem.to_string(),
+        None => Path::new(name)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+fn status(job: &Path, state: &str, output: Option<&Path>, error: Option<String>) -> Value {
+    json!({
+        "job": job.file_name().and_then(|name| name.to_str()),
+        "state": state,
+        "output": output.map(|path| path.display().to_string()),
+        "error": error,
+        "updated": chrono::Local::now().to_rfc3339(),
+    })
+}
+
+fn write_status(outbox: &Path, name: &str, status: &Value) {
+    let path = outbox.join(format!("{}.status.json", name));
+    let written = serde_json::to_string_pretty(status)
+        .map_err(|e| e.to_string())
+        .and_then(|s| fs::write(&path, s).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        error!("failed to write {}: {}", path.display(), e);
+    }
+}
+
+/// Generates the map of a claimed job file into the outbox.
+fn run_job(
+    path: &Path,
+    outbox: &Path,
+    biomes: &[BiomeRule],
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let job = Job::for_file(path)?;
+    let input = fetch_input(&job.input)?;
+    let request = job.request();
+    let map = generate_output(&input, &request, biomes)?;
+    let output = outbox.join(format!("{}.{}", job_name(path), request.format.extension()));
+    if output.exists() {
+        crate::create_backup(&output)?;
+    }
+    fs::write(&output, map)?;
+    Ok(output)
+}
+
+fn work(path: PathBuf, inbox: &Path, outbox: &Path, biomes: &[BiomeRule]) {
+    let name = job_name(&path);
+    let _span = info_span!("job", name = %name).entered();
+    info!("Starting job {}", name);
+    write_status(outbox, &name, &status(&path, "running", None, None));
+    let finished = match run_job(&path, outbox, biomes) {
+        Ok(output) => {
+            info!("Finished job {}, wrote {}", name, output.display());
+            status(&path, "done", Some(&output), None)
+        }
+        Err(e) => {
+            error!("job {} failed: {}", name, e);
+            status(&path, "failed", None, Some(e.to_string()))
+        }
+    };
+    write_status(outbox, &name, &finished);
+    if let Some(file_name) = path.file_name() {
+        if let Err(e) = fs::rename(&path, inbox.join(DONE_DIR).join(file_name)) {
+            error!("failed to move {} out of the way: {}", path.display(), e);
+        }
+    }
+}
+
+/// The files waiting in the inbox, oldest first, moved into the processing
+/// directory so they are only picked up once.
+fn claim_jobs(inbox: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut waiting: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(inbox)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| {
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            (modified.unwrap_or(std::time::UNIX_EPOCH), entry.path())
+        })
+        .collect();
+    waiting.sort();
+    let mut claimed = Vec::new();
+    for (_, path) in waiting {
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+        let target = inbox.join(PROCESSING_DIR).join(file_name);
+        match fs::rename(&path, &target) {
+            Ok(()) => claimed.push(target),
+            Err(e) => debug!("skipping {}: {}", path.display(), e),
+        }
+    }
+    Ok(claimed)
+}
+
+/// Watches the inbox for jobs until the process is stopped, writing each
+/// job's map and a `<job>.status.json` of its progress to the outbox.
+///
+/// Files should be moved into the inbox once complete, as they are claimed
+/// as soon as they appear.
+pub fn run_daemon(
+    inbox: &Path,
+    outbox: &Path,
+    options: &DaemonOptions,
+    biomes: &[BiomeRule],
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(inbox.join(PROCESSING_DIR))?;
+    fs::create_dir_all(inbox.join(DONE_DIR))?;
+    fs::create_dir_all(outbox)?;
+
+    let (sender, receiver) = mpsc::channel::<PathBuf>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let biomes = Arc::new(biomes.to_vec());
+    for _ in 0..options.jobs.max(1) {
+        let receiver = Arc::clone(&receiver);
+        let biomes = Arc::clone(&biomes);
+        let (inbox, outbox) = (inbox.to_path_buf(), outbox.to_path_buf());
+        thread::spawn(move || loop {
+            let next = receiver.lock().map(|receiver| receiver.recv());
+            match next {
+                Ok(Ok(path)) => work(path, &inbox, &outbox, &biomes),
+                _ => break,
+            }
+        });
+    }
+
+    info!(
+        "Watching {} for jobs, writing results to {}",
+        inbox.display(),
+        outbox.display()
+    );
+    loop {
+        for path in claim_jobs(inbox)? {
+            let name = job_name(&path);
+            write_status(outbox, &name, &status(&path, "queued", None, None));
+            sender.send(path)?;
+        }
+        thread::sleep(options.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_name() {
+        assert_eq!(job_name(Path::new("inbox/crypt.job.json")), "crypt");
+        assert_eq!(job_name(Path::new("inbox/crypt.png")), "crypt");
+        assert_eq!(job_name(Path::new("inbox/crypt.tar.gz")), "crypt.tar");
+    }
+
+    #[test]
+    fn test_job_for_file() {
+        let directory = std::env::temp_dir().join(format!("daemon-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let image = directory.join("crypt.png");
+        assert_eq!(Job::for_file(&image).unwrap().input, image);
+
+        let path = directory.join("town.job.json");
+        fs::write(
+            &path,
+            r#"{ "input": "town.geojson", "mode": "town", "format": "uvtt" }"#,
+        )
+        .unwrap();
+        let job = Job::for_file(&path).unwrap();
+        assert_eq!(job.input, Path::new("town.geojson"));
+        assert_eq!(job.format, Format::Uvtt);
+        assert_eq!(
+            job.pixels_per_cell,
+            DungeonOptions::default().pixels_per_cell
+        );
+
+        fs::write(&path, r#"{ "input": "x.png", "mode": "space" }"#).unwrap();
+        assert!(Job::for_file(&path).is_err());
+        fs::write(&path, r#"{ "mode": "town" }"#).unwrap();
+        assert!(Job::for_file(&path).is_err());
+        fs::write(&path, r#"{ "input": "x.png", "pixels_per_cell": 0 }"#).unwrap();
+        assert!(Job::for_file(&path).is_err());
+        fs::write(&path, r#"{ "input": "x.png", "min_confidence": 1.5 }"#).unwrap();
+        assert!(Job::for_file(&path).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
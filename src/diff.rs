@@ -0,0 +1,325 @@
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+use crate::coordinates::units_to_cells;
+use crate::dungeondraft_v1::{fingerprint, metadata, node_id, node_points, node_position, nodes};
+
+/// The node collections of a level compared, portals set in walls included
+/// with the level's own.
+const DIFF_COLLECTIONS: &[&str] = &[
+    "walls", "portals", "objects", "lights", "paths", "patterns", "texts",
+];
+/// Node fields left out of comparisons, the ids changing as maps are
+/// regenerated and portals being compared on their own.
+const IGNORED_FIELDS: &[&str] = &["node_id", "wall_id", "portals"];
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// Changed in the fields named
+    Changed(Vec<String>),
+}
+
+/// A node added, removed, or changed in a level.
+#[derive(Debug, PartialEq)]
+pub struct NodeChange {
+    pub level: String,
+    pub collection: &'static str,
+    pub id: Option<String>,
+    /// Where the node is, or starts, in cells
+    pub at: Option<(f64, f64)>,
+    pub change: Change,
+}
+
+impl std::fmt::Display for NodeChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = match self.change {
+            Change::Added => '+',
+            Change::Removed => '-',
+            Change::Changed(_) => '~',
+        };
+        write!(f, "{} level {} {}", sign, self.level, self.collection)?;
+        if let Some(id) = &self.id {
+            write!(f, " {}", id)?;
+        }
+        if let Some((x, y)) = self.at {
+            write!(f, " at ({}, {})", x, y)?;
+        }
+        if let Change::Changed(fields) = &self.change {
+            write!(f, ": {}", fields.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A header or world field that differs between two maps.
+#[derive(Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "(none)".to_string());
+        write!(
+            f,
+            "~ {}: {} -> {}",
+            self.field,
+            show(&self.before),
+            show(&self.after)
+        )
+    }
+}
+
+/// The structural differences between two maps.
+#[derive(Debug, Default, PartialEq)]
+pub struct MapDiff {
+    pub fields: Vec<FieldChange>,
+    pub nodes: Vec<NodeChange>,
+}
+
+impl MapDiff {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.nodes.is_empty()
+    }
+
+    /// The number of nodes added, removed, and changed.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let count =
+            |kind: fn(&Change) -> bool| self.nodes.iter().filter(|node| kind(&node.change)).count();
+        (
+            count(|change| *change == Change::Added),
+            count(|change| *change == Change::Removed),
+            count(|change| matches!(change, Change::Changed(_))),
+        )
+    }
+}
+
+impl std::fmt::Display for MapDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for field in &self.fields {
+            writeln!(f, "{}", field)?;
+        }
+        for node in &self.nodes {
+            writeln!(f, "{}", node)?;
+        }
+        let (added, removed, changed) = self.counts();
+        write!(
+            f,
+            "{} nodes added, {} removed, {} changed",
+            added, removed, changed
+        )
+    }
+}
+
+/// The header fields and world size of a map, by name.
+fn map_fields(map: &Value) -> Vec<(String, String)> {
+    let mut fields = metadata(map);
+    for dimension in ["width", "height"] {
+        if let Some(size) = map["world"][dimension].as_i64() {
+            fields.push((format!("world.{}", dimension), size.to_string()));
+        }
+    }
+    fields
+}
+
+fn diff_fields(a: &Value, b: &Value) -> Vec<FieldChange> {
+    let (a, b) = (map_fields(a), map_fields(b));
+    let lookup = |fields: &[(String, String)], name: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.clone())
+    };
+    let names: Vec<&String> = a.iter().chain(&b).map(|(field, _)| field).collect();
+    let mut seen = BTreeSet::new();
+    names
+        .into_iter()
+        .filter(|name| seen.insert(name.as_str()))
+        .filter_map(|name| {
+            let (before, after) = (lookup(&a, name), lookup(&b, name));
+            (before != after).then(|| FieldChange {
+                field: name.clone(),
+                before,
+                after,
+            })
+        })
+        .collect()
+}
+
+/// The nodes of a collection of a level, portals set in walls included.
+fn level_nodes<'a>(level: &'a Value, collection: &str) -> Vec<&'a Value> {
+    let mut found: Vec<&Value> = nodes(level, collection).collect();
+    if collection == "portals" {
+        found.extend(nodes(level, "walls").flat_map(|wall| nodes(wall, "portals")));
+    }
+    found
+}
+
+/// A node without the fields left out of comparisons.
+fn compared(node: &Value) -> Value {
+    let mut node = node.clone();
+    if let Some(fields) = node.as_object_mut() {
+        for field in IGNORED_FIELDS {
+            fields.remove(*field);
+        }
+    }
+    node
+}
+
+/// The fields of two versions of a node that differ.
+fn changed_fields(a: &Value, b: &Value) -> Vec<String> {
+    let (Some(a), Some(b)) = (a.as_object(), b.as_object()) else {
+        return vec![];
+    };
+    let names: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| !IGNORED_FIELDS.contains(&name.as_str()) && a.get(*name) != b.get(*name))
+        .cloned()
+        .collect()
+}
+
+fn node_change(level: &str, collection: &'static str, node: &Value, change: Change) -> NodeChange {
+    let at = node_position(node)
+        .or_else(|| node_points(node).and_then(|points| points.first().copied()))
+        .map(|p| (units_to_cells(p.x), units_to_cells(p.y)));
+    NodeChange {
+        level: level.to_string(),
+        collection,
+        id: node_id(node),
+        at,
+        change,
+    }
+}
+
+/// Compares a collection of a level of two maps. Nodes the same but for
+/// their ids are left out, then nodes with the same id are changed, and the
+/// rest are added or removed.
+fn diff_collection(level: &str, collection: &'static str, a: &Value, b: &Value) -> Vec<NodeChange> {
+    let mut before: Vec<(&Value, String)> = level_nodes(a, collection)
+        .into_iter()
+        .map(|node| (node, fingerprint(&compared(node))))
+        .collect();
+    let mut after: Vec<(&Value, String)> = level_nodes(b, collection)
+        .into_iter()
+        .map(|node| (node, fingerprint(&compared(node))))
+        .collect();
+    before.retain(|(_, print)| {
+        let same = after.iter().position(|(_, other)| other == print);
+        if let Some(index) = same {
+            after.remove(index);
+        }
+        same.is_none()
+    });
+    let mut changes = Vec::new();
+    before.retain(|(node, _)| {
+        let id = node_id(node);
+        let matching = after
+            .iter()
+            .position(|(other, _)| id.is_some() && node_id(other) == id);
+        match matching {
+            Some(index) => {
+                let (other, _) = after.remove(index);
+                let fields = changed_fields(node, other);
+                changes.push(node_change(
+                    level,
+                    collection,
+                    other,
+                    Change::Changed(fields),
+                ));
+                false
+            }
+            None => true,
+        }
+    });
+    for (node, _) in before {
+        changes.push(node_change(level, collection, node, Change::Removed));
+    }
+    for (node, _) in after {
+        changes.push(node_change(level, collection, node, Change::Added));
+    }
+    changes
+}
+
+/// Compares two maps structurally: their header fields and world size, and
+/// the walls, portals, objects, and other nodes of each level.
+pub fn diff_maps(a: &Value, b: &Value) -> MapDiff {
+    let level_keys = |map: &Value| -> Vec<String> {
+        map["world"]["levels"]
+            .as_object()
+            .map(|levels| levels.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+    let mut levels = level_keys(a);
+    for key in level_keys(b) {
+        if !levels.contains(&key) {
+            levels.push(key);
+        }
+    }
+    let mut nodes = Vec::new();
+    for key in &levels {
+        let (level_a, level_b) = (&a["world"]["levels"][key], &b["world"]["levels"][key]);
+        for collection in DIFF_COLLECTIONS {
+            nodes.extend(diff_collection(key, collection, level_a, level_b));
+        }
+    }
+    MapDiff {
+        fields: diff_fields(a, b),
+        nodes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_maps() {
+        let a = json!({
+            "header": { "title": "Crypt" },
+            "world": { "width": 4, "height": 4, "levels": { "0": {
+                "walls": [
+                    { "node_id": "1", "points": "PoolVector2Array( 0, 0, 256, 0 )", "texture": "stone", "portals": [] },
+                    { "node_id": "2", "points": "PoolVector2Array( 0, 256, 256, 256 )", "texture": "stone", "portals": [] }
+                ],
+                "objects": [{ "node_id": "3", "position": "Vector2( 512, 512 )", "texture": "table" }]
+            } } }
+        });
+        let b = json!({
+            "header": { "title": "Crypt" },
+            "world": { "width": 6, "height": 4, "levels": { "0": {
+                "walls": [
+                    { "node_id": "a", "points": "PoolVector2Array( 0, 0, 256, 0 )", "texture": "stone", "portals": [] },
+                    { "node_id": "2", "points": "PoolVector2Array( 0, 256, 256, 256 )", "texture": "brick", "portals": [
+                        { "node_id": "b", "wall_id": "2", "position": "Vector2( 128, 256 )" }
+                    ] }
+                ]
+            } } }
+        });
+        let diff = diff_maps(&a, &b);
+        assert_eq!(
+            diff.fields,
+            vec![FieldChange {
+                field: "world.width".to_string(),
+                before: Some("4".to_string()),
+                after: Some("6".to_string()),
+            }]
+        );
+        assert_eq!(diff.counts(), (1, 1, 1));
+        let lines: Vec<String> = diff.nodes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "~ level 0 walls 2 at (0, 1): texture",
+                "+ level 0 portals b at (0.5, 1)",
+                "- level 0 objects 3 at (2, 2)",
+            ]
+        );
+        assert!(diff_maps(&a, &a).is_empty());
+    }
+}
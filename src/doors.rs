@@ -0,0 +1,510 @@
+use opencv::core::{self, Mat, Scalar, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::VectorOfVectorOfPoint;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{portal_node, PortalStyle, Vector2, UNITS_PER_CELL};
+use crate::geometry::{bounds, distance, segment_distance};
+use crate::preprocess::ink_mask;
+
+/// How far glyph pixels may stray from the ideal arc and leaf, relative to the door width.
+const GLYPH_TOLERANCE: f64 = 0.15;
+/// The share of glyph pixels that must lie on the arc or leaf.
+const MIN_GLYPH_FIT: f64 = 0.9;
+/// Closed doors are drawn as a bar at least this many times as long as it is thick.
+const MIN_LEAF_ASPECT: f64 = 2.0;
+
+/// Doorways wider than this many cells take double doors.
+const MIN_DOUBLE_DOOR_WIDTH: f64 = 1.5;
+
+/// The kinds of door, each placed with its own portal settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoorKind {
+    Normal,
+    Double,
+    Secret,
+}
+
+/// The portal settings of each kind of door, such as the door asset of a
+/// pack, from the `portals` config.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PortalStyles {
+    pub normal: PortalStyle,
+    pub double: PortalStyle,
+    pub secret: PortalStyle,
+}
+
+impl PortalStyles {
+    pub fn of(&self, kind: DoorKind) -> &PortalStyle {
+        match kind {
+            DoorKind::Normal => &self.normal,
+            DoorKind::Double => &self.double,
+            DoorKind::Secret => &self.secret,
+        }
+    }
+}
+
+/// A door across its doorway from the hinge to the latch, with its leaf swung open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Door {
+    pub hinge: Vector2,
+    /// The end of the doorway opposite the hinge, where the arc meets the wall
+    pub latch: Vector2,
+    /// The free end of the open leaf, on the side the door swings into
+    pub leaf: Vector2,
+    /// Whether the door is hidden, such as one marked secret on the map
+    pub secret: bool,
+}
+
+impl Door {
+    fn scaled(&self, mapper: &CoordinateMapper) -> Door {
+        Door {
+            hinge: mapper.point(self.hinge),
+            latch: mapper.point(self.latch),
+            leaf: mapper.point(self.leaf),
+            ..*self
+        }
+    }
+
+    /// The middle of the doorway.
+    pub fn position(&self) -> Vector2 {
+        Vector2 {
+            x: (self.hinge.x + self.latch.x) / 2.0,
+            y: (self.hinge.y + self.latch.y) / 2.0,
+        }
+    }
+
+    /// A secret door, or a double door when its doorway is wide.
+    pub fn kind(&self) -> DoorKind {
+        if self.secret {
+            DoorKind::Secret
+        } else if distance(self.hinge, self.latch) > MIN_DOUBLE_DOOR_WIDTH * UNITS_PER_CELL {
+            DoorKind::Double
+        } else {
+            DoorKind::Normal
+        }
+    }
+
+    /// A portal spanning the doorway from the hinge, swinging to the side of the leaf.
+    pub fn portal(&self) -> Value {
+        let (dx, dy) = (self.latch.x - self.hinge.x, self.latch.y - self.hinge.y);
+        let (sx, sy) = (self.leaf.x - self.hinge.x, self.leaf.y - self.hinge.y);
+        let length = sx.hypot(sy).max(f64::EPSILON);
+        // Doors swinging clockwise from the doorway are mirrored
+        let flip = dx * sy - dy * sx < 0.0;
+        portal_node(
+            self.position(),
+            dy.atan2(dx),
+            distance(self.hinge, self.latch) / 2.0,
+            Vector2 {
+                x: sx / length,
+                y: sy / length,
+            },
+            flip,
+        )
+    }
+}
+
+/// Matches the pixels of a glyph against a door hinged at a corner of its bounding box.
+///
+/// Every pixel must lie on the arc or on the two sides meeting at the hinge,
+/// the arc must be drawn, and the corner opposite the hinge must be empty so a
+/// plain square is not mistaken for a door. The drawn side is the leaf and the
+/// empty side is the doorway.
+pub fn match_door(points: &[Vector2]) -> Option<Door> {
+    let (min, max) = bounds(points)?;
+    let (width, height) = (max.x - min.x, max.y - min.y);
+    if width <= 0.0 || height <= 0.0 || !(0.75..=1.0 / 0.75).contains(&(width / height)) {
+        return None;
+    }
+    let radius = (width + height) / 2.0;
+    let tolerance = GLYPH_TOLERANCE * radius;
+    let corner = |x: bool, y: bool| Vector2 {
+        x: if x { max.x } else { min.x },
+        y: if y { max.y } else { min.y },
+    };
+    // Pixels along a side, away from both of its ends
+    let along = |a: Vector2, b: Vector2| {
+        points
+            .iter()
+            .filter(|p| {
+                segment_distance(**p, a, b) < tolerance
+                    && distance(**p, a) > tolerance
+                    && distance(**p, b) > tolerance
+            })
+            .count()
+    };
+
+    let mut best: Option<(f64, Door)> = None;
+    for (x, y) in [(false, false), (true, false), (true, true), (false, true)] {
+        let hinge = corner(x, y);
+        let (side_x, side_y) = (corner(!x, y), corner(x, !y));
+        if points
+            .iter()
+            .any(|p| distance(*p, corner(!x, !y)) < tolerance)
+        {
+            continue;
+        }
+        let on_arc = |p: &Vector2| (distance(*p, hinge) - radius).abs() < tolerance;
+        let on_side = |p: &Vector2| {
+            segment_distance(*p, hinge, side_x) < tolerance
+                || segment_distance(*p, hinge, side_y) < tolerance
+        };
+        if !points.iter().any(|p| on_arc(p) && !on_side(p)) {
+            continue;
+        }
+        let fit =
+            points.iter().filter(|p| on_arc(p) || on_side(p)).count() as f64 / points.len() as f64;
+        if fit < MIN_GLYPH_FIT || best.is_some_and(|(score, _)| score >= fit) {
+            continue;
+        }
+        let (drawn_x, drawn_y) = (along(hinge, side_x), along(hinge, side_y));
+        let door = if drawn_x > 4 * drawn_y {
+            Door {
+                hinge,
+                latch: side_y,
+                leaf: side_x,
+                secret: false,
+            }
+        } else if drawn_y > 4 * drawn_x {
+            Door {
+                hinge,
+                latch: side_x,
+                leaf: side_y,
+                secret: false,
+            }
+        } else {
+            continue;
+        };
+        best = Some((fit, door));
+    }
+    best.map(|(_, door)| door)
+}
+
+/// Matches the pixels of a glyph against a closed door, drawn as a small bar
+/// or rectangle lying across its doorway.
+///
+/// Every pixel must lie on the outline of the bar and each of its corners
+/// must be drawn, so ovals and strokes are not mistaken for doors. The door
+/// spans the bar lengthwise, between the middles of its ends.
+pub fn match_door_leaf(points: &[Vector2]) -> Option<Door> {
+    let (min, max) = bounds(points)?;
+    let (width, height) = (max.x - min.x, max.y - min.y);
+    let (length, thickness) = (width.max(height), width.min(height));
+    if thickness <= 0.0 || length < MIN_LEAF_ASPECT * thickness {
+        return None;
+    }
+    let tolerance = (GLYPH_TOLERANCE * thickness).max(1.0);
+    let corners = [
+        min,
+        Vector2 { x: max.x, y: min.y },
+        max,
+        Vector2 { x: min.x, y: max.y },
+    ];
+    if !corners
+        .iter()
+        .all(|corner| points.iter().any(|p| distance(*p, *corner) <= tolerance))
+    {
+        return None;
+    }
+    let on_outline = |p: &Vector2| {
+        (0..4).any(|i| segment_distance(*p, corners[i], corners[(i + 1) % 4]) <= tolerance)
+    };
+    let fit = points.iter().filter(|p| on_outline(p)).count() as f64 / points.len() as f64;
+    if fit < MIN_GLYPH_FIT {
+        return None;
+    }
+    let middle = Vector2 {
+        x: (min.x + max.x) / 2.0,
+        y: (min.y + max.y) / 2.0,
+    };
+    // Closed, the leaf swings to either side, so it is taken to open down or right
+    let (hinge, latch, leaf) = if width >= height {
+        let hinge = Vector2 {
+            x: min.x,
+            y: middle.y,
+        };
+        let leaf = Vector2 {
+            x: min.x,
+            y: middle.y + length,
+        };
+        (hinge, Vector2 { x: max.x, ..hinge }, leaf)
+    } else {
+        let hinge = Vector2 {
+            x: middle.x,
+            y: min.y,
+        };
+        let leaf = Vector2 {
+            x: middle.x + length,
+            y: min.y,
+        };
+        (hinge, Vector2 { y: max.y, ..hinge }, leaf)
+    };
+    Some(Door {
+        hinge,
+        latch,
+        leaf,
+        secret: false,
+    })
+}
+
+/// Whether a door lies across a gap in a wall: the wall goes on past both
+/// ends of the doorway, `reach` away, and is open on either side of it.
+fn spans_gap(door: &Door, reach: f64, is_wall: impl Fn(Vector2) -> bool) -> bool {
+    let length = distance(door.hinge, door.latch).max(f64::EPSILON);
+    let (dx, dy) = (
+        (door.latch.x - door.hinge.x) / length,
+        (door.latch.y - door.hinge.y) / length,
+    );
+    let at = |from: Vector2, along: f64, across: f64| Vector2 {
+        x: from.x + dx * along - dy * across,
+        y: from.y + dy * along + dx * across,
+    };
+    let middle = door.position();
+    is_wall(at(door.hinge, -reach, 0.0))
+        && is_wall(at(door.latch, reach, 0.0))
+        && !is_wall(at(middle, 0.0, 2.0 * reach))
+        && !is_wall(at(middle, 0.0, -2.0 * reach))
+}
+
+/// Finds door glyphs, whether swinging open or closed across a gap in a
+/// wall, and returns them in map units along with a copy of the image where
+/// each glyph is replaced by a wall across its doorway, so walls are traced
+/// straight through doors instead of around their arcs.
+#[instrument(skip_all)]
+pub fn detect_doors(
+    image: &Mat,
+    mapper: &CoordinateMapper,
+) -> Result<(Vec<Door>, Mat), Box<dyn std::error::Error>> {
+    let pixels_per_cell = mapper.pixels_per_cell;
+    let ink = ink_mask(image)?;
+
+    // Opening with a kernel about as wide as a wall keeps the walls and drops
+    // the thin strokes of door glyphs
+    let wall_thickness = (pixels_per_cell / 8.0).round().max(3.0) as i32;
+    let kernel = imgproc::get_structuring_element(
+        imgproc::MORPH_RECT,
+        Size::new(wall_thickness, wall_thickness),
+        core::Point::new(-1, -1),
+    )?;
+    let mut walls = Mat::default();
+    imgproc::morphology_ex(
+        &ink,
+        &mut walls,
+        imgproc::MORPH_OPEN,
+        &kernel,
+        core::Point::new(-1, -1),
+        1,
+        core::BORDER_CONSTANT,
+        imgproc::morphology_default_border_value()?,
+    )?;
+    let mut strokes = Mat::default();
+    core::subtract(&ink, &walls, &mut strokes, &core::no_array(), -1)?;
+
+    let mut contours = VectorOfVectorOfPoint::new();
+    imgproc::find_contours(
+        &strokes,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_NONE,
+        core::Point::new(0, 0),
+    )?;
+    let mut cleaned = image.try_clone()?;
+    let mut doors = Vec::new();
+    let mut leaves = 0;
+    for (index, contour) in contours.iter().enumerate() {
+        let rect = imgproc::bounding_rect(&contour)?;
+        let size = rect.width.max(rect.height) as f64;
+        if size < pixels_per_cell / 2.0 || size > 3.0 * pixels_per_cell {
+            continue;
+        }
+        let points: Vec<Vector2> = contour
+            .iter()
+            .map(|p| Vector2 {
+                x: p.x as f64,
+                y: p.y as f64,
+            })
+            .collect();
+        let is_wall = |p: Vector2| {
+            walls
+                .at_2d::<u8>(p.y.round() as i32, p.x.round() as i32)
+                .is_ok_and(|ink| *ink > 0)
+        };
+        let door = match match_door(&points) {
+            Some(door) => door,
+            None => match match_door_leaf(&points) {
+                Some(door) if spans_gap(&door, wall_thickness as f64, is_wall) => {
+                    leaves += 1;
+                    door
+                }
+                _ => continue,
+            },
+        };
+        let white = Scalar::all(255.0);
+        for thickness in [imgproc::FILLED, wall_thickness] {
+            imgproc::draw_contours(
+                &mut cleaned,
+                &contours,
+                index as i32,
+                white,
+                thickness,
+                imgproc::LINE_8,
+                &core::no_array(),
+                0,
+                core::Point::new(0, 0),
+            )?;
+        }
+        let pixel = |p: Vector2| core::Point::new(p.x.round() as i32, p.y.round() as i32);
+        imgproc::line(
+            &mut cleaned,
+            pixel(door.hinge),
+            pixel(door.latch),
+            Scalar::all(0.0),
+            wall_thickness,
+            imgproc::LINE_8,
+            0,
+        )?;
+        doors.push(door.scaled(mapper));
+    }
+    debug!(
+        "Detected {} door glyphs, {} of them closed across a gap",
+        doors.len(),
+        leaves
+    );
+    Ok((doors, cleaned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: f64, y: f64) -> Vector2 {
+        Vector2 { x, y }
+    }
+
+    /// A door hinged at the origin with its leaf drawn up the y axis and the arc sweeping to `(64, 0)`.
+    fn glyph() -> Vec<Vector2> {
+        let mut points: Vec<Vector2> = (0..=64).map(|y| v(0.0, y as f64)).collect();
+        points.extend((0..=32).map(|i| {
+            let angle = i as f64 / 32.0 * std::f64::consts::FRAC_PI_2;
+            v(64.0 * angle.cos(), 64.0 * angle.sin())
+        }));
+        points
+    }
+
+    #[test]
+    fn test_match_door() {
+        let door = match_door(&glyph()).unwrap();
+        assert_eq!(door.hinge, v(0.0, 0.0));
+        assert_eq!(door.latch, v(64.0, 0.0));
+        assert_eq!(door.leaf, v(0.0, 64.0));
+
+        let portal = door.portal();
+        assert_eq!(portal["position"], "Vector2( 32, 0 )");
+        assert_eq!(portal["rotation"], 0.0);
+        assert_eq!(portal["radius"], 32.0);
+        assert_eq!(portal["direction"], "Vector2( 0, 1 )");
+        assert_eq!(portal["flip"], false);
+    }
+
+    #[test]
+    fn test_door_kind() {
+        let door = Door {
+            hinge: v(0.0, 0.0),
+            latch: v(UNITS_PER_CELL, 0.0),
+            leaf: v(0.0, UNITS_PER_CELL),
+            secret: false,
+        };
+        assert_eq!(door.kind(), DoorKind::Normal);
+        let wide = Door {
+            latch: v(2.0 * UNITS_PER_CELL, 0.0),
+            ..door
+        };
+        assert_eq!(wide.kind(), DoorKind::Double);
+        let secret = Door {
+            secret: true,
+            ..wide
+        };
+        assert_eq!(secret.kind(), DoorKind::Secret);
+        let styles = PortalStyles {
+            secret: PortalStyle {
+                texture: Some("res://textures/portals/secret.png".to_string()),
+                closed: Some(true),
+                freestanding: true,
+            },
+            ..Default::default()
+        };
+        let portal = styles.of(secret.kind()).apply(secret.portal());
+        assert_eq!(portal["texture"], "res://textures/portals/secret.png");
+        assert_eq!(
+            styles.of(door.kind()).apply(door.portal())["texture"],
+            door.portal()["texture"]
+        );
+    }
+
+    #[test]
+    fn test_match_mirrored_door() {
+        // Mirroring the glyph left to right moves the hinge and flips the swing
+        let points: Vec<Vector2> = glyph().iter().map(|p| v(64.0 - p.x, p.y)).collect();
+        let door = match_door(&points).unwrap();
+        assert_eq!(door.hinge, v(64.0, 0.0));
+        assert_eq!(door.latch, v(0.0, 0.0));
+        assert_eq!(door.portal()["flip"], true);
+    }
+
+    #[test]
+    fn test_match_door_leaf() {
+        // A bar 48 pixels long and 8 thick lying across a doorway
+        let mut bar: Vec<Vector2> = (0..=48).map(|i| v(i as f64, 0.0)).collect();
+        bar.extend((0..=8).map(|i| v(48.0, i as f64)));
+        bar.extend((0..=48).map(|i| v(i as f64, 8.0)));
+        bar.extend((0..=8).map(|i| v(0.0, i as f64)));
+        let door = match_door_leaf(&bar).unwrap();
+        assert_eq!(door.hinge, v(0.0, 4.0));
+        assert_eq!(door.latch, v(48.0, 4.0));
+        assert_eq!(door.portal()["position"], "Vector2( 24, 4 )");
+
+        // The wall runs on past both ends of the bar, with floor on either side
+        let wall = |p: Vector2| p.y.abs() < 6.0 && !(0.0..=48.0).contains(&p.x);
+        let door = Door {
+            hinge: v(0.0, 0.0),
+            latch: v(48.0, 0.0),
+            ..door
+        };
+        assert!(spans_gap(&door, 4.0, wall));
+        assert!(!spans_gap(&door, 4.0, |_| false));
+        assert!(!spans_gap(&door, 4.0, |_| true));
+
+        // A square is not long enough, and an oval has no corners
+        let square: Vec<Vector2> = bar.iter().map(|p| v(p.x / 6.0, p.y)).collect();
+        assert_eq!(match_door_leaf(&square), None);
+        let oval: Vec<Vector2> = (0..64)
+            .map(|i| {
+                let angle = i as f64 / 64.0 * std::f64::consts::TAU;
+                v(24.0 + 24.0 * angle.cos(), 4.0 + 4.0 * angle.sin())
+            })
+            .collect();
+        assert_eq!(match_door_leaf(&oval), None);
+    }
+
+    #[test]
+    fn test_reject_non_doors() {
+        // A square outline has its far corner drawn
+        let mut square: Vec<Vector2> = (0..=64).map(|i| v(i as f64, 0.0)).collect();
+        square.extend((0..=64).map(|i| v(64.0, i as f64)));
+        square.extend((0..=64).map(|i| v(i as f64, 64.0)));
+        square.extend((0..=64).map(|i| v(0.0, i as f64)));
+        assert_eq!(match_door(&square), None);
+        // An arc without a leaf has no side to hinge the door on
+        let arc: Vec<Vector2> = glyph().into_iter().skip(65).collect();
+        assert_eq!(match_door(&arc), None);
+        // A long thin stroke is not square enough
+        let line: Vec<Vector2> = (0..=64).map(|i| v(i as f64, (i % 2) as f64)).collect();
+        assert_eq!(match_door(&line), None);
+    }
+}
@@ -0,0 +1,1129 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use opencv::core::Size;
+use opencv::prelude::*;
+use serde_json::{json, Value};
+use tracing::{debug, info, instrument};
+
+use crate::annotations::Annotations;
+use crate::confidence::MIN_CONFIDENCE;
+use crate::coordinates::{
+    cells_to_units, square_units_to_cells, units_to_cells, CoordinateMapper, Rotation, Rounding,
+};
+use crate::doors::{detect_doors, Door, PortalStyles};
+use crate::dungeondraft_v1::{
+    hole_wall_node, path_node, pattern_node, text_node, textured, wall_node, MapBuilder, Textures,
+    Vector2, WallBuilder, WallStyles, DEFAULT_FONT_SIZE, ROUND_JOINT, SPLAT_PER_CELL,
+    UNITS_PER_CELL,
+};
+use crate::fills::trace_fills;
+use crate::geometry::{
+    boundary_distance, centerline, difference, is_organic, merge_short_edges, nearest_edge,
+    polygon_area, repair, self_intersection, smooth_outline, thickness, union, wind,
+};
+use crate::images::{
+    find_shapes, read_dpi, read_map_image, trace_color_regions, trace_hsv_regions, ColorRange,
+    DetectionOptions, Detector, HsvRange, Shape, MAX_GAP,
+};
+use crate::lights::{detect_fixtures, plan_lights, Fixture};
+use crate::ocr::{placed_labels, read_labels, strip_labels};
+use crate::pipeline::Pipeline;
+use crate::plugins::Plugin;
+use crate::preprocess::{
+    apply_morphology, detect_grid_offset, detect_grid_rotation, detect_grid_size,
+    detect_isometric_grid, detect_scale_bar, normalize_illumination, remove_grid, remove_speckles,
+    remove_symbols, rotate_image, Morphology,
+};
+use crate::report::RunReport;
+use crate::review::{Decision, Review, ReviewShape};
+use crate::scripting::Script;
+#[cfg(feature = "onnx")]
+use crate::segmentation::SegmentationModel;
+use crate::stairs::{detect_stairs, Staircase};
+#[cfg(feature = "onnx")]
+use crate::symbols::SymbolDetector;
+use crate::walls::trace_wall_lines;
+use crate::world::{biome_slots, paint_terrain, stroke_path, BIOMES};
+
+/// Image pixels per grid cell of a dungeon floorplan.
+const DUNGEON_PIXELS_PER_CELL: f64 = 64.0;
+/// Battle maps are printed at a cell to the inch.
+pub const CELLS_PER_INCH: f64 = 1.0;
+/// Wall edges shorter than this share of a cell are jitter from tracing pixels.
+const MIN_WALL_LENGTH: f64 = 0.25;
+/// Blobs of fewer pixels than this are noise from dithering or scanning.
+const MIN_SPECKLE_AREA: i32 = 16;
+/// Shapes thinner than this share of a cell are a wall standing on its own
+/// rather than a room.
+const MAX_FREESTANDING_THICKNESS: f64 = 0.25;
+/// Rooms with most of their walls further than this many degrees from square
+/// to the grid are drawn freehand, such as caves.
+const ORGANIC_TOLERANCE: f64 = 10.0;
+/// The points of the curved walls of freehand rooms are about this share of
+/// a cell apart.
+const CURVE_SPACING: f64 = 0.5;
+
+/// What is done with rooms that overlap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overlapping {
+    /// Each room is walled as it is
+    Keep,
+    /// Overlapping rooms are merged into a single walled floor
+    Union,
+    /// Smaller rooms, such as corridors, are cut out of the larger rooms they
+    /// overlap, so no two rooms share floor
+    Clip,
+}
+
+impl Overlapping {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "keep" => Some(Self::Keep),
+            "union" => Some(Self::Union),
+            "clip" => Some(Self::Clip),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DungeonOptions {
+    /// Image pixels per grid cell
+    pub pixels_per_cell: f64,
+    /// How positions in the map are rounded
+    pub rounding: Rounding,
+    /// The size of the cells of the grid drawn in the image, in pixels,
+    /// overriding `pixels_per_cell` however it was read
+    pub grid_size: Option<f64>,
+    /// Where the grid drawn in the image starts, in pixels, detected when unset
+    pub grid_offset: Option<Vector2>,
+    /// How far the image is turned counter-clockwise to straighten its grid,
+    /// in degrees, detected when not given
+    pub grid_rotation: Option<f64>,
+    /// Whether the image is drawn isometrically, on a diamond grid of cells
+    /// `pixels_per_cell` wide, to be projected top-down
+    pub isometric: bool,
+    /// What is done with rooms that overlap
+    pub overlapping: Overlapping,
+    /// Wall edges shorter than this share of a cell are merged away
+    pub min_wall_length: f64,
+    /// Gaps up to this many pixels wide in the boundaries of rooms are closed
+    pub max_gap: f64,
+    /// How walls are told apart from floors
+    pub detector: Detector,
+    /// The thresholds shapes are detected with
+    pub detection: DetectionOptions,
+    /// Blobs of fewer pixels than this are cleaned up
+    pub min_speckle_area: i32,
+    /// Morphological steps applied to the linework before shapes are found
+    pub morphology: Vec<Morphology>,
+    /// Shapes detected with less confidence than this are left out for review
+    pub min_confidence: f64,
+    /// Whether the text on the image is read and painted over before the
+    /// walls are traced, and written into the map as text
+    pub ocr: bool,
+    /// Whether shapes within other shapes, such as pillars and rooms inside
+    /// outer walls, are walled too rather than left out
+    pub nested_shapes: bool,
+    /// Whether flights of short parallel treads are looked for and placed as
+    /// stairs objects
+    pub stairs: bool,
+    /// Hand-drawn rooms, doors, and lights used in place of detection
+    pub annotations: Option<Annotations>,
+    /// Whether detection also runs alongside the annotations
+    pub merge_detection: bool,
+    /// Whether the labels annotated on the image, such as the names of rooms,
+    /// are written into the map as text
+    pub annotation_labels: bool,
+    /// Reviewed shapes used in place of the detected ones
+    pub review: Option<Review>,
+    /// Hooks run on every detected shape
+    pub script: Option<Arc<Script>>,
+    /// Stages tracing the rooms in place of shape detection
+    pub pipeline: Option<Pipeline>,
+    /// Plugins run in turn on the detected shapes
+    pub plugins: Vec<Arc<Plugin>>,
+    /// The textures of the walls, floors, and doors
+    pub textures: Textures,
+    /// The settings of the walls around rooms and of those standing inside them
+    pub wall_styles: WallStyles,
+    /// The settings of the portals of each kind of door
+    pub portal_styles: PortalStyles,
+    /// Whether freehand rooms such as caves are walled with smooth curves
+    /// rather than the points traced around them
+    pub curved_walls: bool,
+    /// Whether the terrain is painted from the colors of the image, as it is
+    /// on world maps, for outdoor maps with grass, sand, or rock around them
+    pub terrain: bool,
+    /// The colors the water in the image is drawn in
+    pub water_color: Option<ColorRange>,
+    /// The material textures painted over regions drawn in each range of colors
+    pub material_colors: Vec<(String, HsvRange)>,
+    /// The path textures drawn along the strokes, such as roads and rivers,
+    /// drawn in each range of colors
+    pub path_colors: Vec<(String, HsvRange)>,
+    /// The pattern textures filling the shapes, such as rugs and tiled
+    /// borders, drawn in each range of colors
+    pub pattern_colors: Vec<(String, HsvRange)>,
+    /// A model segmenting the floors, doors, and water in place of shape
+    /// detection
+    #[cfg(feature = "onnx")]
+    pub segmentation: Option<Arc<SegmentationModel>>,
+    /// A model detecting symbols placed as objects and doors
+    #[cfg(feature = "onnx")]
+    pub symbols: Option<Arc<SymbolDetector>>,
+}
+
+impl Default for DungeonOptions {
+    fn default() -> Self {
+        Self {
+            pixels_per_cell: DUNGEON_PIXELS_PER_CELL,
+            rounding: Rounding::Exact,
+            grid_size: None,
+            grid_offset: None,
+            grid_rotation: None,
+            isometric: false,
+            overlapping: Overlapping::Keep,
+            min_wall_length: MIN_WALL_LENGTH,
+            max_gap: MAX_GAP,
+            detector: Detector::Canny,
+            detection: DetectionOptions::default(),
+            min_speckle_area: MIN_SPECKLE_AREA,
+            morphology: Vec::new(),
+            min_confidence: MIN_CONFIDENCE,
+            ocr: false,
+            nested_shapes: false,
+            stairs: false,
+            annotations: None,
+            merge_detection: false,
+            annotation_labels: false,
+            review: None,
+            script: None,
+            pipeline: None,
+            plugins: Vec::new(),
+            textures: Textures::default(),
+            wall_styles: WallStyles::default(),
+            portal_styles: PortalStyles::default(),
+            curved_walls: true,
+            terrain: false,
+            water_color: None,
+            material_colors: Vec::new(),
+            path_colors: Vec::new(),
+            pattern_colors: Vec::new(),
+            #[cfg(feature = "onnx")]
+            segmentation: None,
+            #[cfg(feature = "onnx")]
+            symbols: None,
+        }
+    }
+}
+
+impl DungeonOptions {
+    /// Reads the pixels per cell from the DPI of an image printed at
+    /// `cells_per_inch`, or better still from a scale bar drawn on it, or
+    /// best of all from the grid drawn over it, unless a grid size is given.
+    pub fn read_scale(
+        &mut self,
+        path: &Path,
+        image: &Mat,
+        cells_per_inch: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(size) = self.grid_size {
+            self.pixels_per_cell = size;
+            return Ok(());
+        }
+        if let Some(dpi) = read_dpi(path) {
+            self.pixels_per_cell = dpi / cells_per_inch;
+            info!(
+                "Reading {} pixels per cell from the {} DPI of {}",
+                self.pixels_per_cell,
+                dpi,
+                path.display()
+            );
+        }
+        if let Some(length) = detect_scale_bar(image)? {
+            self.pixels_per_cell = length;
+            info!(
+                "Reading {:.1} pixels per cell from the scale bar of {}",
+                length,
+                path.display()
+            );
+        }
+        // The cells of an isometric grid are diamonds, measured when projecting
+        if self.isometric {
+            return Ok(());
+        }
+        if let Some(size) = detect_grid_size(image)? {
+            self.pixels_per_cell = size;
+            info!(
+                "Reading {:.1} pixels per cell from the grid of {}",
+                size,
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// The options with those a map was generated with in their place, as
+    /// `generate_dungeon_with_report` records them. The scale, thresholds,
+    /// and policies are read back, while the grid is found in the image again
+    /// and the files and colors given are left as they are.
+    pub fn with_recorded(mut self, parameters: &Value) -> Self {
+        let number = |key: &str, value: f64| parameters[key].as_f64().unwrap_or(value);
+        let limit =
+            |key: &str, value: Option<f64>| parameters.get(key).map_or(value, Value::as_f64);
+        let flag = |key: &str, value: bool| parameters[key].as_bool().unwrap_or(value);
+        let name = |key: &str| parameters[key].as_str().map(str::to_lowercase);
+        self.pixels_per_cell = number("pixels_per_cell", self.pixels_per_cell);
+        if let Some(rounding) = name("rounding") {
+            self.rounding = match rounding.as_str() {
+                "exact" => Rounding::Exact,
+                "units" => Rounding::Units,
+                steps => steps
+                    .strip_prefix("subcells(")
+                    .and_then(|steps| steps.strip_suffix(')'))
+                    .and_then(|steps| steps.parse().ok())
+                    .map_or(self.rounding, Rounding::Subcells),
+            };
+        }
+        self.isometric = flag("isometric", self.isometric);
+        if let Some(overlapping) =
+            name("overlapping").and_then(|policy| Overlapping::parse(&policy))
+        {
+            self.overlapping = overlapping;
+        }
+        self.min_wall_length = number("min_wall_length", self.min_wall_length);
+        self.max_gap = number("max_gap", self.max_gap);
+        let detection = &mut self.detection;
+        detection.canny_low = number("canny_low", detection.canny_low);
+        detection.canny_high = number("canny_high", detection.canny_high);
+        detection.epsilon = number("epsilon", detection.epsilon);
+        detection.min_area = number("min_area", detection.min_area);
+        detection.max_area = limit("max_area", detection.max_area);
+        detection.min_aspect = number("min_aspect", detection.min_aspect);
+        detection.max_aspect = limit("max_aspect", detection.max_aspect);
+        detection.min_perimeter = number("min_perimeter", detection.min_perimeter);
+        detection.max_perimeter = limit("max_perimeter", detection.max_perimeter);
+        detection.min_convexity = number("min_convexity", detection.min_convexity);
+        if let Some(area) = parameters["min_speckle_area"].as_i64() {
+            self.min_speckle_area = area as i32;
+        }
+        if let Some(steps) = parameters["morphology"].as_array() {
+            self.morphology = steps
+                .iter()
+                .filter_map(|step| step.as_str()?.to_lowercase().parse().ok())
+                .collect();
+        }
+        self.min_confidence = number("min_confidence", self.min_confidence);
+        self.ocr = flag("ocr", self.ocr);
+        self.nested_shapes = flag("nested_shapes", self.nested_shapes);
+        self.stairs = flag("stairs", self.stairs);
+        self.curved_walls = flag("curved_walls", self.curved_walls);
+        self.terrain = flag("terrain", self.terrain);
+        self
+    }
+
+    /// Converts positions in the image to the map, lining the grid drawn in
+    /// the image up with the map's.
+    #[instrument(skip_all)]
+    pub fn mapper(&self, image: &Mat) -> Result<CoordinateMapper, Box<dyn std::error::Error>> {
+        let mapper = CoordinateMapper::new(self.pixels_per_cell).with_rounding(self.rounding);
+        if self.isometric {
+            return Ok(CoordinateMapper {
+                isometric: Some(detect_isometric_grid(image, self.pixels_per_cell)?),
+                ..mapper
+            });
+        }
+        let degrees = match self.grid_rotation {
+            Some(degrees) => degrees,
+            None => detect_grid_rotation(image)?,
+        };
+        let rotation = (degrees != 0.0).then(|| Rotation::new(degrees, image.cols(), image.rows()));
+        let offset = match (self.grid_offset, rotation) {
+            (Some(offset), _) => offset,
+            (None, Some(rotation)) => {
+                detect_grid_offset(&rotate_image(image, &rotation)?, self.pixels_per_cell)?
+            }
+            (None, None) => detect_grid_offset(image, self.pixels_per_cell)?,
+        };
+        Ok(CoordinateMapper {
+            rotation,
+            offset,
+            ..mapper
+        })
+    }
+}
+
+/// The rooms, doors, light fixtures, and water of a dungeon, in map units.
+#[derive(Debug, Default)]
+struct Features {
+    rooms: Vec<Vec<Vector2>>,
+    /// Walls standing on their own rather than around a room
+    freestanding: Vec<Vec<Vector2>>,
+    doors: Vec<Door>,
+    fixtures: Vec<Fixture>,
+    stairs: Vec<Staircase>,
+    water: Vec<Vec<Vector2>>,
+    /// Text read from the image, at the middle of each label
+    labels: Vec<(String, Vector2)>,
+}
+
+/// The rooms with any crossing their own walls split into the simple rooms
+/// they enclose, which DungeonDraft draws correctly.
+#[instrument(skip_all)]
+fn repair_rooms(rooms: Vec<Vec<Vector2>>) -> Vec<Vec<Vector2>> {
+    rooms
+        .into_iter()
+        .flat_map(|room| match self_intersection(&room) {
+            Some(p) => {
+                debug!("Repairing a room crossing its own walls at {}", p);
+                repair(&room)
+            }
+            None => vec![room],
+        })
+        .filter(|room| room.len() > 2)
+        .collect()
+}
+
+/// The rooms, and the lines along the middle of the shapes too thin to be
+/// rooms, walls standing on their own such as half-walls and railings.
+#[instrument(skip_all)]
+fn split_freestanding(rooms: Vec<Vec<Vector2>>) -> (Vec<Vec<Vector2>>, Vec<Vec<Vector2>>) {
+    let max_thickness = cells_to_units(MAX_FREESTANDING_THICKNESS);
+    let (walls, rooms): (Vec<_>, Vec<_>) = rooms
+        .into_iter()
+        .partition(|room| thickness(room) < max_thickness);
+    let walls = walls
+        .iter()
+        .filter_map(|wall| centerline(wall))
+        .filter(|(a, b)| a != b)
+        .map(|(a, b)| vec![a, b])
+        .collect();
+    (rooms, walls)
+}
+
+/// The wall around a room in map units, looped back to its first point and
+/// styled as the walls around rooms, or as cave walls with round joints when
+/// the room is `curved`.
+fn room_wall(
+    room: &[Vector2],
+    curved: bool,
+    options: &DungeonOptions,
+) -> Result<Value, &'static str> {
+    let style = match curved {
+        true => options.wall_styles.cave(),
+        false => options.wall_styles.boundary.clone(),
+    };
+    let mut wall = room
+        .iter()
+        .fold(WallBuilder::new(), |wall, corner| {
+            wall.point(corner.x, corner.y)
+        })
+        .closed(true)
+        .texture(&options.textures.wall)
+        .style(&style)
+        .build()?;
+    if curved {
+        wall["joint"] = ROUND_JOINT.into();
+    }
+    Ok(wall)
+}
+
+/// Rooms that no longer overlap, and the outlines of the holes left inside
+/// them, such as courtyards surrounded by merged rooms.
+#[instrument(skip_all)]
+fn resolve_overlaps(
+    rooms: Vec<Vec<Vector2>>,
+    overlapping: Overlapping,
+) -> (Vec<Vec<Vector2>>, Vec<Vec<Vector2>>) {
+    let regions = match overlapping {
+        Overlapping::Keep => return (rooms, Vec::new()),
+        Overlapping::Union => union(&rooms),
+        Overlapping::Clip => {
+            let mut rooms = rooms;
+            rooms.sort_by(|a, b| polygon_area(a).total_cmp(&polygon_area(b)));
+            (0..rooms.len())
+                .flat_map(|i| difference(&rooms[i], &rooms[..i]))
+                .collect()
+        }
+    };
+    let mut holes = Vec::new();
+    let rooms = regions
+        .into_iter()
+        .map(|region| {
+            holes.extend(region.holes);
+            region.outline
+        })
+        .collect();
+    (rooms, holes)
+}
+
+/// The doors of a dungeon image, and the image cleaned up to only its walls.
+#[instrument(skip_all)]
+fn clean_walls(
+    image: &Mat,
+    options: &DungeonOptions,
+    mapper: &CoordinateMapper,
+) -> Result<(Vec<Door>, Mat), Box<dyn std::error::Error>> {
+    let pixels_per_cell = options.pixels_per_cell;
+    let walls = normalize_illumination(image, (pixels_per_cell / 2.0) as i32)?;
+    let walls = remove_grid(&walls)?;
+    let walls = remove_speckles(&walls, options.min_speckle_area)?;
+    let (doors, walls) = detect_doors(&walls, mapper)?;
+    let walls = remove_symbols(&walls, pixels_per_cell)?;
+    Ok((doors, apply_morphology(&walls, &options.morphology)?))
+}
+
+/// A detected shape as given to scripts and plugins, measured in cells.
+fn detected_shape(id: usize, shape: &Shape, mapper: &CoordinateMapper) -> Value {
+    let outline = shape.outline(mapper);
+    json!({
+        "id": id,
+        "class": shape.class().name(),
+        "confidence": shape.confidence().score(),
+        "parent": shape.parent(),
+        "children": shape.children(),
+        "area": square_units_to_cells(polygon_area(&outline)),
+        "points": outline
+            .iter()
+            .map(|p| [units_to_cells(p.x), units_to_cells(p.y)])
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// The outline in map units of a shape as given to scripts and plugins.
+fn shape_outline(shape: &Value) -> Vec<Vector2> {
+    shape["points"]
+        .as_array()
+        .map(|points| {
+            points
+                .iter()
+                .filter_map(|p| {
+                    Some(Vector2 {
+                        x: cells_to_units(p.get(0)?.as_f64()?),
+                        y: cells_to_units(p.get(1)?.as_f64()?),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Finds the features drawn in a dungeon image, with the shapes of a review,
+/// the rooms traced by a pipeline, or the regions of a segmentation model
+/// taking the place of detected ones.
+#[instrument(skip_all)]
+fn detect(
+    image: &Mat,
+    options: &DungeonOptions,
+    mapper: &CoordinateMapper,
+    report: &mut RunReport,
+) -> Result<Features, Box<dyn std::error::Error>> {
+    let stripped;
+    let (image, labels) = if options.ocr {
+        let started = Instant::now();
+        let labels = read_labels(image)?;
+        stripped = strip_labels(image, &labels)?;
+        report.stage("read labels", started, None, Some(labels.len()));
+        (&stripped, placed_labels(&labels, mapper))
+    } else {
+        (image, Vec::new())
+    };
+    let started = Instant::now();
+    let (mut doors, walls) = clean_walls(image, options, mapper)?;
+    report.stage("clean walls", started, None, None);
+    let started = Instant::now();
+    let mut fixtures = detect_fixtures(image, mapper)?;
+    report.stage("detect fixtures", started, None, None);
+    let mut stairs = Vec::new();
+    if options.stairs {
+        let started = Instant::now();
+        stairs = detect_stairs(image, mapper)?;
+        report.stage("detect stairs", started, None, Some(stairs.len()));
+    }
+    if let Some(review) = &options.review {
+        let reviewed = review.annotations(mapper);
+        doors.extend(reviewed.doors);
+        fixtures.extend(reviewed.fixtures);
+        return Ok(Features {
+            rooms: reviewed.rooms,
+            doors,
+            fixtures,
+            stairs,
+            labels,
+            ..Default::default()
+        });
+    }
+    if let Some(pipeline) = &options.pipeline {
+        let started = Instant::now();
+        let rooms = pipeline.run(image, mapper)?;
+        report.stage("pipeline", started, None, Some(rooms.len()));
+        return Ok(Features {
+            rooms,
+            doors,
+            fixtures,
+            stairs,
+            labels,
+            ..Default::default()
+        });
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(model) = &options.segmentation {
+        let started = Instant::now();
+        let segments = model.segment(image, mapper)?;
+        report.stage("segmentation", started, None, Some(segments.floors.len()));
+        doors.extend(segments.doors);
+        return Ok(Features {
+            rooms: segments.floors,
+            doors,
+            fixtures,
+            stairs,
+            labels,
+            water: segments.water,
+            ..Default::default()
+        });
+    }
+    if let Detector::Fills(colors) = &options.detector {
+        let started = Instant::now();
+        let fills = trace_fills(image, colors, options.water_color.as_ref(), mapper)?;
+        report.stage("segment fills", started, None, Some(fills.floors.len()));
+        // The thick walls are those around the floors, walled with them
+        let (_, freestanding) = split_freestanding(fills.walls);
+        return Ok(Features {
+            rooms: fills.floors,
+            freestanding,
+            doors,
+            fixtures,
+            stairs,
+            water: fills.water,
+            labels,
+        });
+    }
+    if options.detector == Detector::Lines {
+        let started = Instant::now();
+        let (rooms, freestanding) = trace_wall_lines(&walls, options.max_gap, mapper)?;
+        report.stage(
+            "trace lines",
+            started,
+            None,
+            Some(rooms.len() + freestanding.len()),
+        );
+        return Ok(Features {
+            rooms,
+            freestanding,
+            doors,
+            fixtures,
+            stairs,
+            labels,
+            ..Default::default()
+        });
+    }
+    let started = Instant::now();
+    let found = find_shapes(
+        &walls,
+        &options.detector,
+        options.max_gap,
+        &options.detection,
+    )?;
+    report.stage("detect shapes", started, None, Some(found.len()));
+    let started = Instant::now();
+    let mut shapes = Vec::new();
+    for (id, shape) in found.iter().enumerate() {
+        let score = shape.confidence().score();
+        if let Some(parent) = shape.parent().filter(|_| !options.nested_shapes) {
+            debug!("Left out shape #{} within shape #{}", id, parent);
+        } else if score >= options.min_confidence {
+            shapes.push(detected_shape(id, shape, mapper));
+        } else {
+            report.warn(format!(
+                "Left out shape #{} for review, detected with confidence {:.2}",
+                id, score
+            ));
+        }
+    }
+    report.stage(
+        "filter confidence",
+        started,
+        Some(found.len()),
+        Some(shapes.len()),
+    );
+    for plugin in &options.plugins {
+        let (started, count) = (Instant::now(), shapes.len());
+        shapes = plugin.shapes(shapes)?;
+        report.stage("plugin", started, Some(count), Some(shapes.len()));
+    }
+    let (started, count) = (Instant::now(), shapes.len());
+    let rooms: Vec<Vec<Vector2>> = shapes
+        .into_iter()
+        .filter_map(|shape| match &options.script {
+            Some(script) if script.has_shape_hook() => script.shape(&shape).transpose(),
+            _ => Some(Ok(shape)),
+        })
+        .map(|shape| shape.map(|shape| shape_outline(&shape)))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|outline| outline.len() > 2)
+        .collect();
+    report.stage("outline rooms", started, Some(count), Some(rooms.len()));
+    Ok(Features {
+        rooms,
+        doors,
+        fixtures,
+        stairs,
+        labels,
+        ..Default::default()
+    })
+}
+
+/// Generates a dungeon map where detected shapes become walled rooms, with
+/// doors where door glyphs are drawn, lit by the windows and sconces drawn in
+/// the image. Annotations take the place of detection unless merged with it.
+pub fn generate_dungeon(
+    image: &Mat,
+    options: &DungeonOptions,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    generate_dungeon_with_report(image, options, &mut RunReport::default())
+}
+
+/// Generates a dungeon map as `generate_dungeon` does, recording the options
+/// applied and each stage in a report.
+#[instrument(skip_all, fields(pixels_per_cell = options.pixels_per_cell))]
+pub fn generate_dungeon_with_report(
+    image: &Mat,
+    options: &DungeonOptions,
+    report: &mut RunReport,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mapper = options.mapper(image)?;
+    report.stage("align grid", started, None, None);
+    report.parameters = json!({
+        "pixels_per_cell": options.pixels_per_cell,
+        "rounding": format!("{:?}", options.rounding),
+        "grid_offset": [mapper.offset.x, mapper.offset.y],
+        "grid_rotation": mapper.rotation.map_or(0.0, |rotation| rotation.degrees),
+        "isometric": options.isometric,
+        "overlapping": format!("{:?}", options.overlapping),
+        "min_wall_length": options.min_wall_length,
+        "max_gap": options.max_gap,
+        "detector": format!("{:?}", options.detector),
+        "canny_low": options.detection.canny_low,
+        "canny_high": options.detection.canny_high,
+        "epsilon": options.detection.epsilon,
+        "min_area": options.detection.min_area,
+        "max_area": options.detection.max_area,
+        "min_aspect": options.detection.min_aspect,
+        "max_aspect": options.detection.max_aspect,
+        "min_perimeter": options.detection.min_perimeter,
+        "max_perimeter": options.detection.max_perimeter,
+        "min_convexity": options.detection.min_convexity,
+        "min_speckle_area": options.min_speckle_area,
+        "morphology": options
+            .morphology
+            .iter()
+            .map(|step| format!("{:?}:{}", step.op, step.kernel))
+            .collect::<Vec<_>>(),
+        "min_confidence": options.min_confidence,
+        "ocr": options.ocr,
+        "nested_shapes": options.nested_shapes,
+        "stairs": options.stairs,
+        "annotations": options.annotations.is_some(),
+        "merge_detection": options.merge_detection,
+        "annotation_labels": options.annotation_labels,
+        "review": options.review.is_some(),
+        "pipeline": options.pipeline.is_some(),
+        "plugins": options.plugins.len(),
+        "curved_walls": options.curved_walls,
+        "terrain": options.terrain,
+        "water_color": options.water_color.is_some(),
+        "material_colors": options.material_colors.len(),
+        "path_colors": options.path_colors.len(),
+        "pattern_colors": options.pattern_colors.len(),
+    });
+    let (width, height) = mapper.map_size(image.cols(), image.rows());
+    debug!("Generating a {}x{} cell dungeon map", width, height);
+
+    let mut builder = MapBuilder::new(width, height)?;
+    if options.terrain {
+        let started = Instant::now();
+        let splat_size = Size::new(
+            (width * SPLAT_PER_CELL) as i32,
+            (height * SPLAT_PER_CELL) as i32,
+        );
+        let terrain: Vec<_> = BIOMES.iter().map(|b| (b.name, b.texture)).collect();
+        paint_terrain(&mut builder, &biome_slots(image, splat_size)?, &terrain)?;
+        report.stage("paint terrain", started, None, None);
+    }
+    let Features {
+        mut rooms,
+        mut freestanding,
+        mut doors,
+        mut fixtures,
+        stairs,
+        mut water,
+        labels,
+    } = if options.annotations.is_none() || options.merge_detection {
+        detect(image, options, &mapper, report)?
+    } else {
+        Features::default()
+    };
+    for staircase in &stairs {
+        builder.add_node("objects", staircase.node());
+    }
+    for (text, position) in &labels {
+        builder.add_node("texts", text_node(*position, text, DEFAULT_FONT_SIZE));
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(detector) = &options.symbols {
+        let started = Instant::now();
+        let symbols = detector.detect(image, &mapper)?;
+        report.stage(
+            "detect symbols",
+            started,
+            None,
+            Some(symbols.objects.len() + symbols.doors.len()),
+        );
+        doors.extend(symbols.doors);
+        for (texture, position) in &symbols.objects {
+            builder.add_node(
+                "objects",
+                crate::dungeondraft_v1::object_node(*position, texture),
+            );
+        }
+    }
+    if let Some(annotations) = &options.annotations {
+        rooms.extend(annotations.rooms.iter().cloned());
+        doors.extend(annotations.doors.iter().copied());
+        fixtures.extend(annotations.fixtures.iter().cloned());
+        freestanding.extend(annotations.walls.iter().cloned());
+        if options.annotation_labels {
+            for (text, position) in &annotations.labels {
+                builder.add_node("texts", text_node(*position, text, DEFAULT_FONT_SIZE));
+            }
+        }
+    }
+    for wall in &freestanding {
+        builder.add_node(
+            "walls",
+            options
+                .wall_styles
+                .freestanding
+                .apply(textured(wall_node(wall, false), &options.textures.wall)),
+        );
+    }
+    let (started, count) = (Instant::now(), rooms.len());
+    let min_length = cells_to_units(options.min_wall_length);
+    let rooms = rooms
+        .iter()
+        .map(|room| merge_short_edges(room, min_length, true))
+        .collect();
+    let rooms = repair_rooms(rooms);
+    report.stage("repair rooms", started, Some(count), Some(rooms.len()));
+    let (started, count) = (Instant::now(), rooms.len());
+    let (rooms, walls) = split_freestanding(rooms);
+    report.stage(
+        "split freestanding walls",
+        started,
+        Some(count),
+        Some(rooms.len()),
+    );
+    for wall in &walls {
+        builder.add_node(
+            "walls",
+            options
+                .wall_styles
+                .freestanding
+                .apply(textured(wall_node(wall, false), &options.textures.wall)),
+        );
+    }
+    let (started, count) = (Instant::now(), rooms.len());
+    let (rooms, holes) = resolve_overlaps(rooms, options.overlapping);
+    report.stage("resolve overlaps", started, Some(count), Some(rooms.len()));
+    let started = Instant::now();
+    let curved: Vec<bool> = rooms
+        .iter()
+        .map(|room| options.curved_walls && is_organic(room, ORGANIC_TOLERANCE))
+        .collect();
+    let spacing = cells_to_units(CURVE_SPACING);
+    // Doors are placed by the index of the edge of the wall they are in, so
+    // the rooms are smoothed and wound as their walls will be
+    let rooms: Vec<Vec<Vector2>> = rooms
+        .iter()
+        .zip(&curved)
+        .map(|(room, &curved)| match curved {
+            true => wind(&smooth_outline(room, spacing), true),
+            false => wind(room, true),
+        })
+        .collect();
+    report.stage(
+        "curve walls",
+        started,
+        None,
+        Some(curved.iter().filter(|&&curved| curved).count()),
+    );
+    for hole in &holes {
+        builder.add_node(
+            "walls",
+            options
+                .wall_styles
+                .boundary
+                .apply(textured(hole_wall_node(hole), &options.textures.wall)),
+        );
+    }
+    // Regions smaller than a quarter of a cell are specks of color
+    let min_region_area = (options.pixels_per_cell / 2.0).powi(2);
+    // Maps drawn with fill colors have their water split out with their floors
+    let filled = matches!(options.detector, Detector::Fills(_));
+    if let Some(range) = options.water_color.as_ref().filter(|_| !filled) {
+        let started = Instant::now();
+        let pools = trace_color_regions(image, range, min_region_area, &mapper)?;
+        report.stage("trace water", started, None, Some(pools.len()));
+        water.extend(pools);
+    }
+    for pool in &water {
+        builder.add_water(pool);
+    }
+    if let Some(floor) = &options.textures.floor {
+        for room in &rooms {
+            builder.add_material(floor, room);
+        }
+    }
+    for (texture, range) in &options.material_colors {
+        let started = Instant::now();
+        let regions = trace_hsv_regions(image, range, min_region_area, &mapper)?;
+        report.stage("trace materials", started, None, Some(regions.len()));
+        debug!("Painting {} regions with {}", regions.len(), texture);
+        for region in &regions {
+            builder.add_material(texture, region);
+        }
+    }
+    for (texture, range) in &options.pattern_colors {
+        let started = Instant::now();
+        let shapes = trace_hsv_regions(image, range, min_region_area, &mapper)?;
+        report.stage("trace patterns", started, None, Some(shapes.len()));
+        for shape in &shapes {
+            builder.add_node("patterns", pattern_node(shape, texture));
+        }
+    }
+    for (texture, range) in &options.path_colors {
+        let started = Instant::now();
+        let strokes = trace_hsv_regions(image, range, min_region_area, &mapper)?;
+        report.stage("trace paths", started, None, Some(strokes.len()));
+        // A stroke is as wide as its outline is thick, down its middle
+        for stroke in &strokes {
+            let line = stroke_path(stroke);
+            if line.len() >= 2 {
+                builder.add_node("paths", path_node(&line, texture, thickness(stroke)));
+            }
+        }
+    }
+    let wall_ids: Vec<String> = rooms
+        .iter()
+        .zip(&curved)
+        .map(|(room, &curved)| Ok(builder.add_node("walls", room_wall(room, curved, options)?)))
+        .collect::<Result<_, &str>>()?;
+
+    let started = Instant::now();
+    for door in &doors {
+        let style = options.portal_styles.of(door.kind());
+        let portal = style.apply(textured(door.portal(), &options.textures.portal));
+        let position = door.position();
+        let nearest = (0..rooms.len()).min_by(|&a, &b| {
+            boundary_distance(&rooms[a], position)
+                .total_cmp(&boundary_distance(&rooms[b], position))
+        });
+        match nearest
+            .filter(|&room| boundary_distance(&rooms[room], position) <= UNITS_PER_CELL / 2.0)
+            .filter(|_| !style.freestanding)
+        {
+            Some(room) => {
+                let edge = nearest_edge(&rooms[room], position).unwrap_or(0);
+                builder.add_portal(&wall_ids[room], edge, portal);
+            }
+            // Doors away from every wall stand on their own
+            None => {
+                builder.add_node("portals", portal);
+            }
+        }
+    }
+
+    report.stage("place doors", started, None, None);
+    let started = Instant::now();
+    let lights = plan_lights(&rooms, &fixtures);
+    report.stage("plan lights", started, None, None);
+    info!(
+        "Generated {} rooms, {} freestanding walls, {} doors, and {} lights from {} fixtures",
+        rooms.len(),
+        walls.len(),
+        doors.len(),
+        lights.len(),
+        fixtures.len()
+    );
+    for light in lights {
+        builder.add_node("lights", light);
+    }
+    Ok(builder.build())
+}
+
+/// Lists every shape detected in a dungeon image for review, with the
+/// decision generation would make about it.
+pub fn try_review_dungeon(
+    image_path: &Path,
+    options: &DungeonOptions,
+) -> Result<Review, Box<dyn std::error::Error>> {
+    let image = read_map_image(image_path)?;
+    if image.empty() {
+        return Err(format!("failed to read image {}", image_path.display()).into());
+    }
+    let image = match options.ocr {
+        true => strip_labels(&image, &read_labels(&image)?)?,
+        false => image,
+    };
+    let (_, walls) = clean_walls(&image, options, &options.mapper(&image)?)?;
+    let found = find_shapes(
+        &walls,
+        &options.detector,
+        options.max_gap,
+        &options.detection,
+    )?;
+    let shapes = found
+        .iter()
+        .enumerate()
+        .map(|(id, shape)| {
+            let mut reviewed = ReviewShape::new(
+                id,
+                shape.class().name(),
+                shape.confidence().score(),
+                &shape.pixel_vertices(),
+                options.min_confidence,
+            );
+            if shape.parent().is_some() && !options.nested_shapes {
+                reviewed.decision = Decision::Reject;
+            }
+            reviewed
+        })
+        .collect();
+    Ok(Review {
+        image: image_path.display().to_string(),
+        shapes,
+    })
+}
+
+pub fn try_generate_dungeon(
+    image_path: &Path,
+    options: &DungeonOptions,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    debug!("Generating a dungeon map from {}", image_path.display());
+    let image = read_map_image(image_path)?;
+    if image.empty() {
+        return Err(format!("failed to read image {}", image_path.display()).into());
+    }
+    generate_dungeon(&image, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dungeondraft_v1::Wall;
+
+    #[test]
+    fn test_room_wall() {
+        // A square two cells across in an image of 32 pixels per cell
+        let square = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 64.0, y: 0.0 },
+            Vector2 { x: 64.0, y: 64.0 },
+            Vector2 { x: 0.0, y: 64.0 },
+        ];
+        let room = CoordinateMapper::new(32.0).points(&square);
+        let options = DungeonOptions::default();
+        let node = room_wall(&room, false, &options).unwrap();
+        let wall: Wall = serde_json::from_value(node.clone()).unwrap();
+        assert!(wall.closed);
+        assert_eq!(wall.points.len(), 5);
+        assert_eq!(wall.points.first(), wall.points.last());
+        assert!(wall.points.iter().all(|p| p.x == 0.0 || p.x == 512.0));
+        assert_eq!(wall.texture, options.textures.wall);
+        assert_ne!(node["joint"], ROUND_JOINT);
+
+        let curved = room_wall(&room, true, &options).unwrap();
+        assert_eq!(curved["joint"], ROUND_JOINT);
+        // Too few points to close around a floor
+        assert!(room_wall(&room[..2], false, &options).is_err());
+    }
+
+    #[test]
+    fn test_generate_walls() {
+        // A room 128 pixels across, drawn as a thick outline
+        let mut image = Mat::new_rows_cols_with_default(
+            256,
+            256,
+            opencv::core::CV_8UC3,
+            opencv::core::Scalar::all(255.0),
+        )
+        .unwrap();
+        opencv::imgproc::rectangle(
+            &mut image,
+            opencv::core::Rect::new(64, 64, 128, 128),
+            opencv::core::Scalar::all(0.0),
+            4,
+            opencv::imgproc::LINE_8,
+            0,
+        )
+        .unwrap();
+        for pixels_per_cell in [32.0, 64.0] {
+            let options = DungeonOptions {
+                pixels_per_cell,
+                ..Default::default()
+            };
+            let map = generate_dungeon(&image, &options).unwrap();
+            let walls: Vec<Wall> =
+                serde_json::from_value(map["world"]["levels"]["0"]["walls"].clone()).unwrap();
+            let room = walls
+                .iter()
+                .find(|wall| wall.closed)
+                .expect("a walled room");
+            assert_eq!(room.points.first(), room.points.last());
+            // Scaled to as many cells across as the image has pixels per cell
+            let (min, max) = crate::geometry::bounds(&room.points).unwrap();
+            let cells = units_to_cells(max.x - min.x);
+            assert!((cells - 128.0 / pixels_per_cell).abs() < 0.25, "{}", cells);
+        }
+    }
+
+    #[test]
+    fn test_with_recorded() {
+        let options = DungeonOptions {
+            pixels_per_cell: 50.0,
+            rounding: Rounding::Subcells(2),
+            overlapping: Overlapping::Clip,
+            max_gap: 8.0,
+            detection: DetectionOptions {
+                canny_low: 30.0,
+                max_area: Some(9000.0),
+                ..Default::default()
+            },
+            morphology: vec!["close:5".parse().unwrap()],
+            stairs: true,
+            curved_walls: false,
+            ..Default::default()
+        };
+        let image = Mat::new_rows_cols_with_default(
+            200,
+            200,
+            opencv::core::CV_8UC3,
+            opencv::core::Scalar::all(255.0),
+        )
+        .unwrap();
+        let mut report = RunReport::default();
+        generate_dungeon_with_report(&image, &options, &mut report).unwrap();
+        let recorded = DungeonOptions::default().with_recorded(&report.parameters);
+        assert_eq!(recorded, options);
+        // Maps generated some other way leave the options as they are
+        assert_eq!(
+            DungeonOptions::default().with_recorded(&Value::Null),
+            DungeonOptions::default()
+        );
+    }
+}
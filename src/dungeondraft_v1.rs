@@ -1,7 +1,16 @@
 use chrono::Datelike;
 use chrono::Timelike;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 
-pub const _MAPFILE: &str = r#"
+use crate::assets::pack_id;
+use crate::geometry::{chaikin, close_ring, segment_distance, wind};
+use crate::map_format::{downgrade, upgrade, CURRENT_FORMAT};
+
+pub const MAPFILE: &str = r#"
 {
 	"header": {
 		"creation_build": "1.1.0.3 fluffy phoenix",
@@ -1410,223 +1419,3171 @@ pub const _MAPFILE_CROSSHEADSTUDIOS: &str = r#"
 }
 "#;
 
-#[derive(Debug)]
-pub struct CreationDate {
-    year: i32,
-    month: u32,
-    day: u32,
-    weekday: u32,
-    dst: bool,
-    hour: u32,
-    minute: u32,
-    second: u32,
+/// Reads a .dungeondraft_map file as untyped JSON, brought up to the
+/// current map format whatever format it was saved in.
+pub fn read_map(path: &Path) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    Ok(read_map_format(path)?.0)
 }
 
-pub struct CreationDateBuilder {
-    year: Option<i32>,
-    month: Option<u32>,
-    day: Option<u32>,
-    weekday: Option<u32>,
-    dst: Option<bool>,
-    hour: Option<u32>,
-    minute: Option<u32>,
-    second: Option<u32>,
+/// Reads a .dungeondraft_map file as `read_map` does, along with the map
+/// format it was saved in, to write it back in.
+pub fn read_map_format(
+    path: &Path,
+) -> Result<(serde_json::Value, i64), Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(path)?;
+    let reader = io::BufReader::new(file);
+    let mut map = serde_json::from_reader(reader)?;
+    let format = upgrade(&mut map)?;
+    Ok((map, format))
 }
 
-impl CreationDateBuilder {
-    pub fn new() -> Self {
-        Self {
-            year: None,
-            month: None,
-            day: None,
-            weekday: None,
-            dst: None,
-            hour: None,
-            minute: None,
-            second: None,
+pub fn write_map(path: &Path, map: &serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::create(path)?;
+    serialize_map(io::BufWriter::new(file), map)
+}
+
+/// Writes a map in the current format as map `format`, for older releases
+/// of DungeonDraft.
+pub fn write_map_format(
+    path: &Path,
+    map: &serde_json::Value,
+    format: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if format == CURRENT_FORMAT {
+        return write_map(path, map);
+    }
+    let mut map = map.clone();
+    downgrade(&mut map, format)?;
+    write_map(path, &map)
+}
+
+/// Serializes a map as tab indented JSON, the same layout DungeonDraft saves with.
+pub fn serialize_map(
+    writer: impl io::Write,
+    map: &serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"\t");
+    let mut serializer = serde_json::Serializer::with_formatter(writer, formatter);
+    map.serialize(&mut serializer)?;
+    Ok(())
+}
+
+/// DungeonDraft units per grid cell.
+pub const UNITS_PER_CELL: f64 = 256.0;
+
+/// A Godot `Vector2`, serialized in map files as `"Vector2( x, y )"`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Vector2 {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Splits the comma separated numbers out of a Godot constructor such as `Vector2( 1, 2 )`.
+fn parse_godot_numbers(s: &str, constructor: &str) -> Result<Vec<f64>, String> {
+    let args = s
+        .trim()
+        .strip_prefix(constructor)
+        .and_then(|rest| rest.trim_start().strip_prefix('('))
+        .and_then(|rest| rest.strip_suffix(')'))
+        .ok_or(format!("expected {}( ... ) but found '{}'", constructor, s))?;
+    args.split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            n.parse()
+                .map_err(|_| format!("invalid number '{}' in '{}'", n, s))
+        })
+        .collect()
+}
+
+impl std::fmt::Display for Vector2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Vector2( {}, {} )", self.x, self.y)
+    }
+}
+
+impl FromStr for Vector2 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_godot_numbers(s, "Vector2")?[..] {
+            [x, y] => Ok(Vector2 { x, y }),
+            _ => Err(format!("expected two components in '{}'", s)),
         }
     }
+}
 
-    pub fn year(mut self, year: i32) -> Self {
-        self.year = Some(year);
-        self
+/// Parses a `"PoolVector2Array( x1, y1, x2, y2, ... )"` value.
+pub fn parse_vector2_array(s: &str) -> Result<Vec<Vector2>, String> {
+    let numbers = parse_godot_numbers(s, "PoolVector2Array")?;
+    if numbers.len() % 2 != 0 {
+        return Err(format!("odd number of components in '{}'", s));
     }
+    Ok(numbers
+        .chunks(2)
+        .map(|xy| Vector2 { x: xy[0], y: xy[1] })
+        .collect())
+}
 
-    pub fn month(mut self, month: u32) -> Self {
-        self.month = Some(month);
-        self
+/// Formats points as a `"PoolVector2Array( x1, y1, x2, y2, ... )"` value.
+pub fn format_vector2_array(points: &[Vector2]) -> String {
+    let numbers: Vec<String> = points.iter().map(|p| format!("{}, {}", p.x, p.y)).collect();
+    format!("PoolVector2Array( {} )", numbers.join(", "))
+}
+
+/// Formats bytes as a `"PoolByteArray( 1, 2, ... )"` value.
+pub fn format_byte_array(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "PoolByteArray()".to_string();
     }
+    let numbers: Vec<String> = bytes.iter().map(u8::to_string).collect();
+    format!("PoolByteArray( {} )", numbers.join(", "))
+}
 
-    pub fn day(mut self, day: u32) -> Self {
-        self.day = Some(day);
-        self
+/// Parses a `"PoolByteArray( 1, 2, ... )"` value.
+pub fn parse_byte_array(s: &str) -> Result<Vec<u8>, String> {
+    parse_godot_numbers(s, "PoolByteArray")?
+        .into_iter()
+        .map(|n| u8::try_from(n as i64).map_err(|_| format!("byte {} out of range in '{}'", n, s)))
+        .collect()
+}
+
+/// Parses a `"PoolIntArray( 1, 2, ... )"` value.
+pub fn parse_int_array(s: &str) -> Result<Vec<i64>, String> {
+    Ok(parse_godot_numbers(s, "PoolIntArray")?
+        .into_iter()
+        .map(|n| n as i64)
+        .collect())
+}
+
+/// A color serialized in map files as an `aarrggbb` hex string.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub a: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim().trim_start_matches('#');
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or(format!("invalid color '{}'", s))
+        };
+        match hex.len() {
+            6 => Ok(Color {
+                a: 0xff,
+                r: channel(0)?,
+                g: channel(2)?,
+                b: channel(4)?,
+            }),
+            8 => Ok(Color {
+                a: channel(0)?,
+                r: channel(2)?,
+                g: channel(4)?,
+                b: channel(6)?,
+            }),
+            _ => Err(format!("invalid color '{}'", s)),
+        }
     }
+}
 
-    pub fn weekday(mut self, weekday: u32) -> Self {
-        self.weekday = Some(weekday);
-        self
+/// Iterates the nodes of a collection such as `walls` or `lights`, skipping missing collections.
+pub fn nodes<'a>(
+    parent: &'a serde_json::Value,
+    collection: &str,
+) -> impl Iterator<Item = &'a serde_json::Value> {
+    parent
+        .get(collection)
+        .and_then(serde_json::Value::as_array)
+        .into_iter()
+        .flatten()
+}
+
+/// Node ids are usually strings but are accepted as numbers too.
+pub fn id_string(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
     }
+}
 
-    pub fn dst(mut self, dst: bool) -> Self {
-        self.dst = Some(dst);
-        self
+pub fn node_id(node: &serde_json::Value) -> Option<String> {
+    node.get("node_id").and_then(id_string)
+}
+
+pub fn node_position(node: &serde_json::Value) -> Option<Vector2> {
+    node.get("position")?.as_str()?.parse().ok()
+}
+
+pub fn node_points(node: &serde_json::Value) -> Option<Vec<Vector2>> {
+    parse_vector2_array(node.get("points")?.as_str()?).ok()
+}
+
+pub fn is_loop(wall: &serde_json::Value) -> bool {
+    wall.get("loop")
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(false)
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}",
+            self.a, self.r, self.g, self.b
+        )
     }
+}
 
-    pub fn hour(mut self, hour: u32) -> Self {
-        self.hour = Some(hour);
-        self
+/// Serializes a value through its `Display` form, as map files store
+/// vectors and colors as strings.
+fn serialize_display<T: std::fmt::Display, S: serde::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// Deserializes a value from a string through its `FromStr` form.
+fn deserialize_from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: FromStr<Err = String>,
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+impl Serialize for Vector2 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
     }
+}
 
-    pub fn minute(mut self, minute: u32) -> Self {
-        self.minute = Some(minute);
-        self
+impl<'de> Deserialize<'de> for Vector2 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_from_str(deserializer)
     }
+}
 
-    pub fn second(mut self, second: u32) -> Self {
-        self.second = Some(second);
-        self
+impl Serialize for Color {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
     }
+}
 
-    pub fn build(self) -> Result<CreationDate, &'static str> {
-        Ok(CreationDate {
-            year: self.year.ok_or("Year is not set")?,
-            month: self.month.ok_or("Month is not set")?,
-            day: self.day.ok_or("Day is not set")?,
-            weekday: self.weekday.ok_or("Weekday is not set")?,
-            dst: self.dst.ok_or("DST is not set")?,
-            hour: self.hour.ok_or("Hour is not set")?,
-            minute: self.minute.ok_or("Minute is not set")?,
-            second: self.second.ok_or("Second is not set")?,
-        })
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_from_str(deserializer)
     }
+}
 
-	pub fn now() -> Result<CreationDate, &'static str> {
-        let now = chrono::Local::now();
-        let year = now.year();
-        let month = now.month();
-        let day = now.day();
-        let weekday = now.weekday().number_from_monday();
-        let hour = now.hour();
-        let minute = now.minute();
-        let second = now.second();
+/// Serde for points stored as a `"PoolVector2Array( ... )"` value.
+mod vector2_array {
+    use serde::Deserialize;
 
-        Ok(CreationDate {
-            year,
-            month,
-            day,
-            weekday,
-            dst: false,
-            hour,
-            minute,
-            second,
-        })
+    use super::{format_vector2_array, parse_vector2_array, Vector2};
+
+    pub fn serialize<S: serde::Serializer>(
+        points: &[Vector2],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_vector2_array(points))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vector2>, D::Error> {
+        parse_vector2_array(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Serde for `PoolByteArray` strings.
+mod byte_array {
+    use serde::Deserialize;
 
-    #[test]
-    fn test_year() {
-        let date = CreationDateBuilder::new().year(2023).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Month is not set");
+    use super::{format_byte_array, parse_byte_array};
+
+    pub fn serialize<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_byte_array(bytes))
     }
 
-    #[test]
-    fn test_month() {
-        let date = CreationDateBuilder::new().month(9).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        parse_byte_array(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
     }
+}
 
-    #[test]
-    fn test_day() {
-        let date = CreationDateBuilder::new().day(1).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+/// Serde for node ids, written as strings but accepted as numbers too.
+mod node_id {
+    use serde::Deserialize;
+
+    pub fn serialize<S: serde::Serializer>(
+        id: &Option<String>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match id {
+            Some(id) => serializer.serialize_str(id),
+            None => serializer.serialize_none(),
+        }
     }
 
-    #[test]
-    fn test_weekday() {
-        let date = CreationDateBuilder::new().weekday(5).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<String>, D::Error> {
+        Ok(Option::<serde_json::Value>::deserialize(deserializer)?
+            .as_ref()
+            .and_then(super::id_string))
     }
+}
 
-    #[test]
-    fn test_dst() {
-        let date = CreationDateBuilder::new().dst(false).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+/// A typed .dungeondraft_map file, for code that reads and changes maps
+/// rather than poking at untyped JSON.
+///
+/// Each node keeps the fields it doesn't model in `other`, so a map
+/// reads and writes back without losing anything, though modelled fields
+/// are written first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MapFile {
+    pub header: Header,
+    pub world: World,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Header {
+    pub creation_build: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct World {
+    pub format: i64,
+    /// The width of the map in cells
+    pub width: i64,
+    /// The height of the map in cells
+    pub height: i64,
+    /// The next node id to hand out, in hex
+    pub next_node_id: String,
+    /// The units per grid cell, when not `UNITS_PER_CELL`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grid_size: Option<std::num::NonZeroU32>,
+    /// The levels of the map by their index, `"0"` being the ground
+    pub levels: std::collections::BTreeMap<String, Level>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Level {
+    pub label: String,
+    pub walls: Vec<Wall>,
+    /// Portals standing apart from walls, such as freestanding doors
+    pub portals: Vec<Portal>,
+    pub lights: Vec<Light>,
+    pub objects: Vec<Object>,
+    pub paths: Vec<MapPath>,
+    /// Shapes filled with a pattern texture, such as tiles or a rug
+    pub patterns: Vec<Pattern>,
+    pub texts: Vec<Text>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terrain: Option<Terrain>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub water: Option<Water>,
+    /// The regions painted with each material texture
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub materials: Option<std::collections::BTreeMap<String, Vec<Material>>>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The terrain of a level, up to four textures blended by a splat of one
+/// RGBA pixel per [`SPLAT_PER_CELL`] of a cell, each channel weighting a slot.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Terrain {
+    pub enabled: bool,
+    pub expand_slots: bool,
+    pub smooth_blending: bool,
+    pub texture_1: String,
+    pub texture_2: String,
+    pub texture_3: String,
+    pub texture_4: String,
+    /// Empty until the terrain is first painted
+    #[serde(with = "byte_array")]
+    pub splat: Vec<u8>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Wall {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    #[serde(with = "vector2_array")]
+    pub points: Vec<Vector2>,
+    /// Whether the wall loops back to its first point
+    #[serde(rename = "loop")]
+    pub closed: bool,
+    pub texture: String,
+    pub color: Color,
+    pub portals: Vec<Portal>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Portal {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// The wall the portal is set in, if any
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub wall_id: Option<String>,
+    /// The edge of the wall the portal is set in, by its starting point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_index: Option<usize>,
+    pub position: Vector2,
+    /// The angle of the doorway in radians
+    pub rotation: f64,
+    /// Half the width of the doorway
+    pub radius: f64,
+    /// The side the door swings into
+    pub direction: Vector2,
+    pub texture: String,
+    pub flip: bool,
+    pub closed: bool,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Light {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    pub position: Vector2,
+    pub texture: String,
+    pub color: Color,
+    pub intensity: f64,
+    /// The reach of the light in cells
+    pub range: f64,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Object {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    pub position: Vector2,
+    /// In radians
+    pub rotation: f64,
+    #[serde(default = "unit_scale")]
+    pub scale: Vector2,
+    /// Whether the object is flipped horizontally
+    pub mirror: bool,
+    /// A `res://` reference to the image, in an asset pack or Dungeondraft's own
+    pub texture: String,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+fn unit_scale() -> Vector2 {
+    Vector2 { x: 1.0, y: 1.0 }
+}
+
+/// A path, named apart from `std::path::Path`, such as a road or river.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MapPath {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    #[serde(with = "vector2_array")]
+    pub edit_points: Vec<Vector2>,
+    pub texture: String,
+    pub width: f64,
+    #[serde(rename = "loop")]
+    pub closed: bool,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A polygon filled with a pattern texture.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pattern {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// Where the points are placed from
+    pub position: Vector2,
+    #[serde(with = "vector2_array")]
+    pub points: Vec<Vector2>,
+    pub texture: String,
+    pub color: Color,
+    /// Whether the shape is drawn with an outline
+    pub outline: bool,
+    /// How far the texture is turned within the shape, in radians
+    pub rotation: f64,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Text {
+    #[serde(with = "node_id", skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// Where the text is centered
+    pub position: Vector2,
+    pub text: String,
+    pub font_name: String,
+    pub font_size: f64,
+    pub font_color: Color,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl MapFile {
+    pub fn from_value(map: serde_json::Value) -> Result<MapFile, serde_json::Error> {
+        serde_json::from_value(map)
     }
 
-    #[test]
-    fn test_hour() {
-        let date = CreationDateBuilder::new().hour(8).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+    /// The map as untyped JSON, for the functions working on that.
+    pub fn to_value(&self) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(self)
     }
 
-    #[test]
-    fn test_minute() {
-        let date = CreationDateBuilder::new().minute(58).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+    /// Sets a field of the header or world by its dotted path, such as
+    /// `world.width` or `world.levels.0.environment.ambient_light`, checking
+    /// the value fits the model.
+    ///
+    /// Only fields the map already has can be set, along with the header's
+    /// `METADATA_FIELDS` and the world's `grid_size`, so a misspelt field
+    /// isn't quietly added.
+    pub fn set(&mut self, field: &str, value: serde_json::Value) -> Result<(), String> {
+        if !field.starts_with("header.") && !field.starts_with("world.") {
+            return Err(format!(
+                "expected a field of header or world but found '{}'",
+                field
+            ));
+        }
+        let mut map = self.to_value().map_err(|e| e.to_string())?;
+        let pointer = format!("/{}", field.replace('.', "/"));
+        let optional = field
+            .strip_prefix("header.")
+            .filter(|name| METADATA_FIELDS.contains(name))
+            .map(|name| ("header", name))
+            .or_else(|| {
+                field
+                    .strip_prefix("world.")
+                    .filter(|name| *name == "grid_size")
+                    .map(|name| ("world", name))
+            });
+        match (map.pointer_mut(&pointer), optional) {
+            (Some(existing), _) => *existing = value,
+            (None, Some((section, name))) => map[section][name] = value,
+            (None, None) => return Err(format!("the map has no field '{}'", field)),
+        }
+        *self = MapFile::from_value(map)
+            .map_err(|e| format!("invalid value for '{}': {}", field, e))?;
+        Ok(())
     }
 
-    #[test]
-    fn test_second() {
-        let date = CreationDateBuilder::new().second(15).build();
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Year is not set");
+    /// Hands out the next node id of the map.
+    fn take_node_id(&mut self) -> Result<String, String> {
+        let id = u64::from_str_radix(&self.world.next_node_id, 16)
+            .map_err(|_| "map has an invalid next_node_id".to_string())?;
+        self.world.next_node_id = format!("{:x}", id + 1);
+        Ok(format!("{:x}", id))
     }
 
-    #[test]
-    fn test_complete_build() {
-        let date = CreationDateBuilder::new()
-            .year(2023)
-            .month(9)
-            .day(1)
-            .weekday(5)
-            .dst(false)
-            .hour(8)
-            .minute(58)
-            .second(15)
-            .build();
+    /// Hands out the next node id of the map for a node added to `level`.
+    fn take_level_node_id(&mut self, level: &str) -> Result<String, String> {
+        if !self.world.levels.contains_key(level) {
+            return Err(format!("map has no level {}", level));
+        }
+        self.take_node_id()
+    }
 
-        assert!(date.is_ok());
-        let date = date.unwrap();
-        assert_eq!(date.year, 2023);
-        assert_eq!(date.month, 9);
-        assert_eq!(date.day, 1);
-        assert_eq!(date.weekday, 5);
-        assert_eq!(date.dst, false);
-        assert_eq!(date.hour, 8);
-        assert_eq!(date.minute, 58);
-        assert_eq!(date.second, 15);
+    /// Adds a light to a level, returning its new id.
+    pub fn add_light(&mut self, level: &str, mut light: Light) -> Result<String, String> {
+        let id = self.take_level_node_id(level)?;
+        light.node_id = Some(id.clone());
+        if let Some(level) = self.world.levels.get_mut(level) {
+            level.lights.push(light);
+        }
+        Ok(id)
     }
 
-    #[test]
-    fn test_partial_build() {
-        let date = CreationDateBuilder::new()
-            .year(2023)
-            .month(9)
-            .day(1)
-            .dst(false)
-            .build();
+    /// Adds a text label to a level, returning its new id.
+    pub fn add_text(&mut self, level: &str, mut text: Text) -> Result<String, String> {
+        let id = self.take_level_node_id(level)?;
+        text.node_id = Some(id.clone());
+        if let Some(level) = self.world.levels.get_mut(level) {
+            level.texts.push(text);
+        }
+        Ok(id)
+    }
 
-        assert!(date.is_err());
-        assert_eq!(date.err().unwrap(), "Weekday is not set");
+    /// Adds an object to a level, returning its new id.
+    ///
+    /// An object from an asset pack the header's asset manifest doesn't list
+    /// adds the pack to it, so Dungeondraft knows the map needs it.
+    pub fn add_object(&mut self, level: &str, mut object: Object) -> Result<String, String> {
+        if !self.world.levels.contains_key(level) {
+            return Err(format!("map has no level {}", level));
+        }
+        if let Some(pack) = object.pack_id() {
+            let manifest = self
+                .header
+                .other
+                .entry("asset_manifest")
+                .or_insert_with(|| serde_json::json!([]));
+            if let Some(packs) = manifest.as_array_mut() {
+                if !packs.iter().any(|listed| listed["id"] == pack) {
+                    packs.push(serde_json::json!({ "name": pack, "id": pack }));
+                }
+            }
+        }
+        let id = self.take_node_id()?;
+        object.node_id = Some(id.clone());
+        if let Some(level) = self.world.levels.get_mut(level) {
+            level.objects.push(object);
+        }
+        Ok(id)
+    }
+}
+
+impl Text {
+    /// A label centered on `position` in the default font, as `text_node` writes it.
+    pub fn new(position: Vector2, text: &str, font_size: f64) -> Text {
+        let mut other = serde_json::Map::new();
+        other.insert("box_shape".to_string(), 0.into());
+        other.insert("trim".to_string(), false.into());
+        Text {
+            node_id: None,
+            position,
+            text: text.to_string(),
+            font_name: DEFAULT_FONT.to_string(),
+            font_size,
+            // Opaque black, as DEFAULT_TEXT_COLOR
+            font_color: Color {
+                a: 0xff,
+                r: 0,
+                g: 0,
+                b: 0,
+            },
+            other,
+        }
     }
+}
 
-    #[test]
-    fn test_now() {
-        let date = CreationDateBuilder::now();
+impl Object {
+    /// An object casting a shadow on the user layer, as `object_node` places it.
+    pub fn new(position: Vector2, texture: &str) -> Object {
+        serde_json::from_value(object_node(position, texture)).expect("object nodes fit the model")
+    }
 
-        assert!(date.is_ok());
+    /// The id of the asset pack the object is from, if it isn't one of
+    /// Dungeondraft's own.
+    pub fn pack_id(&self) -> Option<&str> {
+        pack_id(&self.texture)
+    }
+}
+
+impl Light {
+    /// A light with its `range` in cells, casting shadows as `light_node` does.
+    pub fn new(position: Vector2, color: Color, range: f64, intensity: f64) -> Light {
+        let mut other = serde_json::Map::new();
+        other.insert("rotation".to_string(), 0.into());
+        other.insert("shadows".to_string(), true.into());
+        Light {
+            node_id: None,
+            position,
+            texture: DEFAULT_LIGHT_TEXTURE.to_string(),
+            color,
+            intensity,
+            range,
+            other,
+        }
     }
 }
 
+/// The water of a level, its bodies of water a tree of those within others.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Water {
+    pub disable_border: bool,
+    /// Empty until the first body of water is added
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree: Option<WaterTree>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaterTree {
+    #[serde(rename = "ref")]
+    pub reference: i64,
+    pub children: Vec<WaterBody>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
 
+impl Default for WaterTree {
+    fn default() -> Self {
+        Self {
+            reference: -1,
+            children: Vec::new(),
+            other: serde_json::Map::new(),
+        }
+    }
+}
+
+/// A body of water, with the islands and pools inside it as its children.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaterBody {
+    #[serde(with = "vector2_array")]
+    pub polygon: Vec<Vector2>,
+    pub deep_color: Color,
+    pub shallow_color: Color,
+    /// How far from the shore the shallow color blends into the deep, in map units
+    pub blend_distance: f64,
+    pub children: Vec<WaterBody>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl WaterBody {
+    /// A body of water in the default colors filling a polygon.
+    pub fn new(polygon: &[Vector2]) -> WaterBody {
+        WaterBody {
+            polygon: outline(polygon),
+            deep_color: DEFAULT_DEEP_WATER_COLOR.parse().unwrap_or_default(),
+            shallow_color: DEFAULT_SHALLOW_WATER_COLOR.parse().unwrap_or_default(),
+            blend_distance: UNITS_PER_CELL,
+            ..Default::default()
+        }
+    }
+}
+
+/// A region painted with a material texture.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Material {
+    #[serde(with = "vector2_array")]
+    pub polygon: Vec<Vector2>,
+    pub smooth: bool,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Material {
+    pub fn new(polygon: &[Vector2]) -> Material {
+        Material {
+            polygon: outline(polygon),
+            smooth: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Terrain {
+    /// Sets the texture of a slot, from 0 to 3.
+    pub fn set_texture(&mut self, slot: usize, texture: &str) -> Result<(), String> {
+        let field = match slot {
+            0 => &mut self.texture_1,
+            1 => &mut self.texture_2,
+            2 => &mut self.texture_3,
+            3 => &mut self.texture_4,
+            _ => return Err(format!("terrain slot {} is not one of 0 to 3", slot)),
+        };
+        *field = texture.to_string();
+        Ok(())
+    }
+}
+
+/// Builds RGBA splat data that fully weights each pixel's terrain slot.
+pub fn splat_bytes(slots: &[usize]) -> Vec<u8> {
+    slots
+        .iter()
+        .flat_map(|&slot| {
+            let mut weights = [0u8; 4];
+            weights[slot.min(3)] = 255;
+            weights
+        })
+        .collect()
+}
+
+impl Level {
+    /// The portals of the level, those set in walls included.
+    pub fn all_portals(&self) -> impl Iterator<Item = &Portal> {
+        self.portals
+            .iter()
+            .chain(self.walls.iter().flat_map(|wall| wall.portals.iter()))
+    }
+
+    /// The bodies of water of the level, not counting those within others.
+    pub fn water_bodies(&self) -> &[WaterBody] {
+        self.water
+            .as_ref()
+            .and_then(|water| water.tree.as_ref())
+            .map_or(&[], |tree| &tree.children)
+    }
+
+    /// How many regions are painted with materials, of every texture.
+    pub fn material_count(&self) -> usize {
+        self.materials
+            .iter()
+            .flat_map(|materials| materials.values())
+            .map(Vec::len)
+            .sum()
+    }
+}
+
+pub const DEFAULT_WALL_TEXTURE: &str = "res://textures/walls/battlements.png";
+pub const DEFAULT_WALL_COLOR: &str = "ff605f58";
+pub const DEFAULT_ROOF_TEXTURE: &str = "res://textures/roofs/tiles_red.png";
+pub const DEFAULT_LIGHT_TEXTURE: &str = "res://textures/lights/soft.png";
+/// The range of lights in cells when none is given.
+pub const DEFAULT_LIGHT_RANGE: f64 = 4.0;
+/// Terrain splat pixels per grid cell.
+pub const SPLAT_PER_CELL: u32 = 4;
+pub const DEFAULT_PORTAL_TEXTURE: &str = "res://textures/portals/door_00.png";
+pub const DEFAULT_ROAD_TEXTURE: &str = "res://textures/paths/path_cobble.png";
+pub const DEFAULT_ROUTE_TEXTURE: &str = "res://textures/paths/path_dirt.png";
+pub const DEFAULT_CLIFF_TEXTURE: &str = "res://textures/walls/cliff.png";
+pub const DEFAULT_SETTLEMENT_TEXTURE: &str =
+    "res://textures/objects/worldmap/settlement_village.png";
+pub const DEFAULT_DEEP_WATER_COLOR: &str = "ff3aa19a";
+pub const DEFAULT_SHALLOW_WATER_COLOR: &str = "ff3ac1a9";
+pub const DEFAULT_FONT: &str = "Libre Baskerville";
+pub const DEFAULT_TEXT_COLOR: &str = "ff000000";
+/// The size of labels in map units when none is given.
+pub const DEFAULT_FONT_SIZE: f64 = 40.0;
+/// The joint rounding the corners of a wall, as Godot's Line2D numbers them,
+/// its default joint being bevelled.
+pub const ROUND_JOINT: i64 = 2;
+/// The layer user placed objects and paths go on.
+const USER_LAYER: i64 = 100;
+
+/// The points of an outline as DungeonDraft stores closed polygons, running
+/// clockwise and ending back where they start.
+fn outline(points: &[Vector2]) -> Vec<Vector2> {
+    close_ring(&wind(points, true))
+}
+
+/// A wall along the points, a loop around them when `closed`.
+pub fn wall_node(points: &[Vector2], closed: bool) -> serde_json::Value {
+    let points = if closed {
+        outline(points)
+    } else {
+        points.to_vec()
+    };
+    serde_json::json!({
+        "points": format_vector2_array(&points),
+        "texture": DEFAULT_WALL_TEXTURE,
+        "color": DEFAULT_WALL_COLOR,
+        "loop": closed,
+        "type": 0,
+        "joint": 1,
+        "normalize_uv": true,
+        "shadow": true,
+        "portals": [],
+    })
+}
+
+/// Builds a wall node point by point, in map units, for making walls without
+/// writing their JSON by hand. The wall gets its id as it is added to a map
+/// with `MapBuilder::add_wall`.
+#[derive(Debug, Clone, Default)]
+pub struct WallBuilder {
+    points: Vec<Vector2>,
+    closed: bool,
+    style: WallStyle,
+}
+
+impl WallBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn point(mut self, x: f64, y: f64) -> Self {
+        self.points.push(Vector2 { x, y });
+        self
+    }
+
+    pub fn points(mut self, points: &[Vector2]) -> Self {
+        self.points.extend_from_slice(points);
+        self
+    }
+
+    /// Loops the wall back to its first point, wound as DungeonDraft winds
+    /// walls around floors.
+    pub fn closed(mut self, closed: bool) -> Self {
+        self.closed = closed;
+        self
+    }
+
+    pub fn texture(mut self, texture: &str) -> Self {
+        self.style.texture = Some(texture.to_string());
+        self
+    }
+
+    /// The color of the wall as `aarrggbb`.
+    pub fn color(mut self, color: &str) -> Self {
+        self.style.color = Some(color.to_string());
+        self
+    }
+
+    pub fn shadow(mut self, shadow: bool) -> Self {
+        self.style.shadow = Some(shadow);
+        self
+    }
+
+    /// Takes the settings `style` sets in place of those set so far.
+    pub fn style(mut self, style: &WallStyle) -> Self {
+        self.style = self.style.under(style);
+        self
+    }
+
+    pub fn build(self) -> Result<serde_json::Value, &'static str> {
+        match (self.closed, self.points.len()) {
+            (true, 0..=2) => Err("A closed wall needs at least three points"),
+            (false, 0..=1) => Err("A wall needs at least two points"),
+            _ => Ok(self.style.apply(wall_node(&self.points, self.closed))),
+        }
+    }
+}
+
+/// The texture or asset each kind of generated node is given, from the
+/// `textures` config, such as those of an asset pack.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Textures {
+    pub wall: String,
+    /// The material rooms are floored with, left unpainted when unset
+    pub floor: Option<String>,
+    pub portal: String,
+    pub roof: String,
+    /// The paths of town streets
+    pub road: String,
+    /// The paths of world map routes
+    pub route: String,
+    pub cliff: String,
+    pub settlement: String,
+}
+
+impl Default for Textures {
+    fn default() -> Self {
+        Self {
+            wall: DEFAULT_WALL_TEXTURE.to_string(),
+            floor: None,
+            portal: DEFAULT_PORTAL_TEXTURE.to_string(),
+            roof: DEFAULT_ROOF_TEXTURE.to_string(),
+            road: DEFAULT_ROAD_TEXTURE.to_string(),
+            route: DEFAULT_ROUTE_TEXTURE.to_string(),
+            cliff: DEFAULT_CLIFF_TEXTURE.to_string(),
+            settlement: DEFAULT_SETTLEMENT_TEXTURE.to_string(),
+        }
+    }
+}
+
+/// The node given `texture` in place of the one it was made with.
+pub fn textured(mut node: serde_json::Value, texture: &str) -> serde_json::Value {
+    node["texture"] = texture.into();
+    node
+}
+
+/// Settings given to walls in place of the defaults of `wall_node`, such as a
+/// thinner texture.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WallStyle {
+    pub texture: Option<String>,
+    pub color: Option<String>,
+    pub shadow: Option<bool>,
+    /// How far the shadow falls from the wall, in map units
+    pub shadow_size: Option<f64>,
+    /// The color of the line drawn around the wall, as `aarrggbb`
+    pub outline: Option<String>,
+}
+
+/// The fields of a `WallStyle` that can be set.
+pub const WALL_STYLE_FIELDS: &[&str] = &["texture", "color", "shadow", "shadow_size", "outline"];
+
+impl WallStyle {
+    pub fn apply(&self, mut wall: serde_json::Value) -> serde_json::Value {
+        if let Some(texture) = &self.texture {
+            wall["texture"] = texture.as_str().into();
+        }
+        if let Some(color) = &self.color {
+            wall["color"] = color.as_str().into();
+        }
+        if let Some(shadow) = self.shadow {
+            wall["shadow"] = shadow.into();
+        }
+        if let Some(size) = self.shadow_size {
+            wall["shadow_size"] = size.into();
+        }
+        if let Some(outline) = &self.outline {
+            wall["outline_color"] = outline.as_str().into();
+        }
+        wall
+    }
+
+    /// The style with the settings `over` it sets in place of its own.
+    pub fn under(&self, over: &WallStyle) -> WallStyle {
+        WallStyle {
+            texture: over.texture.clone().or_else(|| self.texture.clone()),
+            color: over.color.clone().or_else(|| self.color.clone()),
+            shadow: over.shadow.or(self.shadow),
+            shadow_size: over.shadow_size.or(self.shadow_size),
+            outline: over.outline.clone().or_else(|| self.outline.clone()),
+        }
+    }
+
+    /// Sets one of `WALL_STYLE_FIELDS`.
+    pub fn set(&mut self, field: &str, value: &serde_json::Value) -> Result<(), String> {
+        let text = || {
+            value.as_str().map(str::to_string).ok_or(format!(
+                "expected text for wall {} but found {}",
+                field, value
+            ))
+        };
+        match field {
+            "texture" => self.texture = Some(text()?),
+            "color" => self.color = Some(text()?),
+            "outline" => self.outline = Some(text()?),
+            "shadow" => {
+                self.shadow = Some(value.as_bool().ok_or(format!(
+                    "expected true or false for wall shadow but found {}",
+                    value
+                ))?)
+            }
+            "shadow_size" => {
+                self.shadow_size = Some(value.as_f64().ok_or(format!(
+                    "expected a number for wall shadow_size but found {}",
+                    value
+                ))?)
+            }
+            _ => {
+                return Err(format!(
+                    "unknown wall style field '{}', expected one of {}",
+                    field,
+                    WALL_STYLE_FIELDS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings given to portals in place of the defaults of `portal_node`, and
+/// whether they stand on their own rather than in the wall they are in.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PortalStyle {
+    pub texture: Option<String>,
+    pub closed: Option<bool>,
+    pub freestanding: bool,
+}
+
+impl PortalStyle {
+    pub fn apply(&self, mut portal: serde_json::Value) -> serde_json::Value {
+        if let Some(texture) = &self.texture {
+            portal["texture"] = texture.as_str().into();
+        }
+        if let Some(closed) = self.closed {
+            portal["closed"] = closed.into();
+        }
+        portal
+    }
+}
+
+/// The styles of the walls around rooms and of the walls standing on their
+/// own inside them, such as half-walls, railings, and partitions, and of the
+/// walls around freehand rooms such as caves over those around other rooms.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WallStyles {
+    pub boundary: WallStyle,
+    pub freestanding: WallStyle,
+    pub cave: WallStyle,
+}
+
+/// The classes of walls of `WallStyles`.
+pub const WALL_CLASSES: &[&str] = &["boundary", "freestanding", "cave"];
+
+impl Default for WallStyles {
+    fn default() -> Self {
+        Self {
+            boundary: WallStyle::default(),
+            // Too low to cast a shadow over the room
+            freestanding: WallStyle {
+                shadow: Some(false),
+                ..Default::default()
+            },
+            cave: WallStyle::default(),
+        }
+    }
+}
+
+impl WallStyles {
+    /// The style of the walls around freehand rooms, the cave style over the
+    /// boundary style.
+    pub fn cave(&self) -> WallStyle {
+        self.boundary.under(&self.cave)
+    }
+
+    /// Sets a field of a class of walls from a `CLASS.FIELD` key, such as
+    /// `cave.shadow`.
+    pub fn set(&mut self, key: &str, value: &serde_json::Value) -> Result<(), String> {
+        let (class, field) = key
+            .split_once('.')
+            .ok_or(format!("expected CLASS.FIELD but found '{}'", key))?;
+        let style = match class {
+            "boundary" => &mut self.boundary,
+            "freestanding" => &mut self.freestanding,
+            "cave" => &mut self.cave,
+            _ => {
+                return Err(format!(
+                    "unknown wall class '{}', expected one of {}",
+                    class,
+                    WALL_CLASSES.join(", ")
+                ))
+            }
+        };
+        style.set(field, value)
+    }
+}
+
+/// How generated water looks, from the `water` config, in place of the
+/// editor's defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WaterStyle {
+    pub deep_color: String,
+    pub shallow_color: String,
+    /// How far from the shore the shallow color blends into the deep, in cells
+    pub shore_width: f64,
+    /// Whether the shore is drawn with a border
+    pub border: bool,
+    /// How fast the waves move, 0 for still water
+    pub wave_speed: f64,
+    /// How much the waves ripple the water, 0 for a flat surface
+    pub wave_strength: f64,
+}
+
+/// The fields of a `WaterStyle` that can be set.
+pub const WATER_STYLE_FIELDS: &[&str] = &[
+    "deep_color",
+    "shallow_color",
+    "shore_width",
+    "border",
+    "wave_speed",
+    "wave_strength",
+];
+
+impl Default for WaterStyle {
+    fn default() -> Self {
+        Self {
+            deep_color: DEFAULT_DEEP_WATER_COLOR.to_string(),
+            shallow_color: DEFAULT_SHALLOW_WATER_COLOR.to_string(),
+            shore_width: 1.0,
+            border: true,
+            wave_speed: 1.0,
+            wave_strength: 1.0,
+        }
+    }
+}
+
+impl WaterStyle {
+    /// Sets one of `WATER_STYLE_FIELDS`.
+    pub fn set(&mut self, field: &str, value: &serde_json::Value) -> Result<(), String> {
+        let number = || {
+            value.as_f64().ok_or(format!(
+                "expected a number for water {} but found {}",
+                field, value
+            ))
+        };
+        let text = || {
+            value.as_str().map(str::to_string).ok_or(format!(
+                "expected text for water {} but found {}",
+                field, value
+            ))
+        };
+        match field {
+            "deep_color" => self.deep_color = text()?,
+            "shallow_color" => self.shallow_color = text()?,
+            "shore_width" => self.shore_width = number()?,
+            "wave_speed" => self.wave_speed = number()?,
+            "wave_strength" => self.wave_strength = number()?,
+            "border" => {
+                self.border = value.as_bool().ok_or(format!(
+                    "expected true or false for water border but found {}",
+                    value
+                ))?
+            }
+            _ => {
+                return Err(format!(
+                    "unknown water style field '{}', expected one of {}",
+                    field,
+                    WATER_STYLE_FIELDS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Styles the bodies of water of a water tree, and those within them.
+    fn apply_bodies(&self, bodies: &mut [serde_json::Value]) {
+        for body in bodies {
+            body["deep_color"] = self.deep_color.as_str().into();
+            body["shallow_color"] = self.shallow_color.as_str().into();
+            body["blend_distance"] = (self.shore_width * UNITS_PER_CELL).into();
+            if let Some(children) = body["children"].as_array_mut() {
+                self.apply_bodies(children);
+            }
+        }
+    }
+
+    /// Styles the water of every level of a map.
+    pub fn apply(&self, map: &mut serde_json::Value) {
+        let Some(levels) = map["world"]["levels"].as_object_mut() else {
+            return;
+        };
+        for level in levels.values_mut() {
+            let water = &mut level["water"];
+            if !water.is_object() {
+                continue;
+            }
+            water["disable_border"] = (!self.border).into();
+            water["wave_speed"] = self.wave_speed.into();
+            water["wave_strength"] = self.wave_strength.into();
+            if let Some(bodies) = water["tree"]["children"].as_array_mut() {
+                self.apply_bodies(bodies);
+            }
+        }
+    }
+}
+
+/// A closed door across the doorway centered on `position` and running along `rotation`.
+///
+/// `direction` points to the side the door swings into, and `flip` moves the
+/// hinge to the other end of the doorway.
+pub fn portal_node(
+    position: Vector2,
+    rotation: f64,
+    radius: f64,
+    direction: Vector2,
+    flip: bool,
+) -> serde_json::Value {
+    serde_json::json!({
+        "position": position.to_string(),
+        "rotation": rotation,
+        "scale": "Vector2( 1, 1 )",
+        "direction": direction.to_string(),
+        "texture": DEFAULT_PORTAL_TEXTURE,
+        "radius": radius,
+        "flip": flip,
+        "closed": true,
+    })
+}
+
+pub fn object_node(position: Vector2, texture: &str) -> serde_json::Value {
+    serde_json::json!({
+        "position": position.to_string(),
+        "rotation": 0,
+        "scale": "Vector2( 1, 1 )",
+        "mirror": false,
+        "texture": texture,
+        "layer": USER_LAYER,
+        "shadow": true,
+        "block_light": false,
+    })
+}
+
+/// A light with its `range` in cells.
+pub fn light_node(position: Vector2, color: &str, range: f64, intensity: f64) -> serde_json::Value {
+    serde_json::json!({
+        "position": position.to_string(),
+        "rotation": 0,
+        "texture": DEFAULT_LIGHT_TEXTURE,
+        "color": color,
+        "intensity": intensity,
+        "range": range,
+        "shadows": true,
+    })
+}
+
+pub fn path_node(points: &[Vector2], texture: &str, width: f64) -> serde_json::Value {
+    serde_json::json!({
+        "position": "Vector2( 0, 0 )",
+        "rotation": 0,
+        "scale": "Vector2( 1, 1 )",
+        "edit_points": format_vector2_array(points),
+        "smoothness": 1,
+        "texture": texture,
+        "width": width,
+        "layer": USER_LAYER,
+        "fade_in": false,
+        "fade_out": false,
+        "grow": false,
+        "shrink": false,
+        "loop": false,
+    })
+}
+
+/// A polygon filled with a pattern texture, in white with no outline.
+pub fn pattern_node(polygon: &[Vector2], texture: &str) -> serde_json::Value {
+    serde_json::json!({
+        "position": "Vector2( 0, 0 )",
+        "shape_rotation": 0,
+        "scale": "Vector2( 1, 1 )",
+        "points": format_vector2_array(&outline(polygon)),
+        "layer": USER_LAYER,
+        "color": "ffffffff",
+        "outline": false,
+        "texture": texture,
+        "rotation": 0,
+    })
+}
+
+/// Smooths the paths of every level of a map with `iterations` of corner
+/// cutting, so roads and routes traced from pixels don't come out as
+/// staircases. Looping paths are left as they are.
+pub fn smooth_paths(map: &mut serde_json::Value, iterations: usize) {
+    let Some(levels) = map["world"]["levels"].as_object_mut() else {
+        return;
+    };
+    for level in levels.values_mut() {
+        let Some(paths) = level["paths"].as_array_mut() else {
+            continue;
+        };
+        for path in paths.iter_mut().filter(|path| !is_loop(path)) {
+            let Some(points) = path["edit_points"]
+                .as_str()
+                .and_then(|points| parse_vector2_array(points).ok())
+            else {
+                continue;
+            };
+            path["edit_points"] = format_vector2_array(&chaikin(&points, iterations)).into();
+        }
+    }
+}
+
+/// A wall looping around a hole in a floor, such as a courtyard, running the
+/// other way to the walls around floors.
+pub fn hole_wall_node(points: &[Vector2]) -> serde_json::Value {
+    let mut wall = wall_node(points, true);
+    wall["points"] = format_vector2_array(&close_ring(&wind(points, false))).into();
+    wall
+}
+
+pub fn roof_node(points: &[Vector2], texture: &str) -> serde_json::Value {
+    serde_json::json!({
+        "points": format_vector2_array(&outline(points)),
+        "texture": texture,
+        "type": 0,
+        "width": UNITS_PER_CELL,
+    })
+}
+
+/// A text label centered on `position`.
+pub fn text_node(position: Vector2, text: &str, font_size: f64) -> serde_json::Value {
+    serde_json::json!({
+        "position": position.to_string(),
+        "text": text,
+        "font_name": DEFAULT_FONT,
+        "font_size": font_size,
+        "font_color": DEFAULT_TEXT_COLOR,
+        "box_shape": 0,
+        "trim": false,
+    })
+}
+
+/// The header fields describing a map that can be set, along with its
+/// DungeonDraft creation build.
+pub const METADATA_FIELDS: &[&str] = &["title", "creator", "notes", "creation_build"];
+
+/// Sets a header field of `METADATA_FIELDS`.
+pub fn set_metadata(map: &mut serde_json::Value, field: &str, value: &str) -> Result<(), String> {
+    if !METADATA_FIELDS.contains(&field) {
+        return Err(format!(
+            "unknown header field '{}', expected one of {}",
+            field,
+            METADATA_FIELDS.join(", ")
+        ));
+    }
+    map["header"][field] = value.into();
+    Ok(())
+}
+
+/// The header fields of `METADATA_FIELDS` a map has, and what generated it.
+pub fn metadata(map: &serde_json::Value) -> Vec<(String, String)> {
+    let header = &map["header"];
+    let mut fields: Vec<(String, String)> = METADATA_FIELDS
+        .iter()
+        .filter_map(|field| Some((field.to_string(), header[*field].as_str()?.to_string())))
+        .collect();
+    if let Some(generator) = header["generator"].as_object() {
+        fields.extend(generator.iter().filter_map(|(key, value)| {
+            Some((format!("generator.{}", key), value.as_str()?.to_string()))
+        }));
+    }
+    fields
+}
+
+/// Stamps the header with the name and version of this generator and the
+/// parameters it ran with, along with their hash, to tell how a map was made
+/// and let `regenerate` make it the same way.
+pub fn stamp_generator(
+    map: &mut serde_json::Value,
+    parameters: &serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let hash =
+        crate::models::sha256_hex(serde_json::to_string(parameters)?.as_bytes(), io::sink())?;
+    map["header"]["generator"] = serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "parameters": hash,
+        "settings": parameters,
+    });
+    Ok(())
+}
+
+/// The parameters `stamp_generator` recorded a map was generated with, null
+/// for maps made some other way.
+pub fn generator_settings(map: &serde_json::Value) -> &serde_json::Value {
+    &map["header"]["generator"]["settings"]
+}
+
+/// The environment variable reproducible builds take their timestamp from.
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Builds a new map from the empty default map, handing out node ids as nodes are added.
+pub struct MapBuilder {
+    map: serde_json::Value,
+    next_node_id: u64,
+}
+
+impl MapBuilder {
+    pub fn new(width: u32, height: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut map: serde_json::Value = serde_json::from_str(MAPFILE)?;
+        map["header"]["creation_date"] = serde_json::to_value(CreationDateBuilder::now()?)?;
+        map["world"]["width"] = width.into();
+        map["world"]["height"] = height.into();
+        Ok(Self {
+            map,
+            next_node_id: 0,
+        })
+    }
+
+    pub fn world_mut(&mut self) -> &mut serde_json::Value {
+        &mut self.map["world"]
+    }
+
+    /// The level generated nodes are added to.
+    pub fn level_mut(&mut self) -> &mut serde_json::Value {
+        &mut self.map["world"]["levels"]["0"]
+    }
+
+    /// Adds the wall built by `wall`, returning its new id.
+    pub fn add_wall(&mut self, wall: WallBuilder) -> Result<String, &'static str> {
+        Ok(self.add_node("walls", wall.build()?))
+    }
+
+    /// Adds a node to a collection such as `walls` or `roofs.roofs`, returning its new id.
+    pub fn add_node(&mut self, collection: &str, mut node: serde_json::Value) -> String {
+        // DungeonDraft stores node ids as hex strings
+        let id = format!("{:x}", self.next_node_id);
+        self.next_node_id += 1;
+        node["node_id"] = id.clone().into();
+        let pointer = format!("/{}", collection.replace('.', "/"));
+        if let Some(nodes) = self
+            .level_mut()
+            .pointer_mut(&pointer)
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            nodes.push(node);
+        }
+        id
+    }
+
+    /// Adds a portal to the edge starting at `point_index` of a wall added earlier, returning its new id.
+    pub fn add_portal(
+        &mut self,
+        wall_id: &str,
+        point_index: usize,
+        mut portal: serde_json::Value,
+    ) -> String {
+        let id = format!("{:x}", self.next_node_id);
+        self.next_node_id += 1;
+        portal["node_id"] = id.clone().into();
+        portal["wall_id"] = wall_id.into();
+        portal["point_index"] = point_index.into();
+        if let Some(portals) = self.level_mut()["walls"]
+            .as_array_mut()
+            .and_then(|walls| {
+                walls
+                    .iter_mut()
+                    .find(|wall| node_id(wall).as_deref() == Some(wall_id))
+            })
+            .and_then(|wall| wall["portals"].as_array_mut())
+        {
+            portals.push(portal);
+        }
+        id
+    }
+
+    /// Adds a body of water outlined by the polygon.
+    pub fn add_water(&mut self, polygon: &[Vector2]) {
+        let water = &mut self.level_mut()["water"];
+        if water.get("tree").is_none() {
+            water["tree"] = serde_json::json!({ "ref": -1, "children": [] });
+        }
+        if let Some(children) = water["tree"]["children"].as_array_mut() {
+            children.push(
+                serde_json::to_value(WaterBody::new(polygon)).expect("bodies of water serialize"),
+            );
+        }
+    }
+
+    /// Paints the polygon with a material texture such as stone or dirt.
+    pub fn add_material(&mut self, texture: &str, polygon: &[Vector2]) {
+        let materials = &mut self.level_mut()["materials"];
+        if !materials[texture].is_array() {
+            materials[texture] = serde_json::json!([]);
+        }
+        if let Some(regions) = materials[texture].as_array_mut() {
+            regions
+                .push(serde_json::to_value(Material::new(polygon)).expect("materials serialize"));
+        }
+    }
+
+    pub fn build(mut self) -> serde_json::Value {
+        self.map["world"]["next_node_id"] = format!("{:x}", self.next_node_id).into();
+        self.map
+    }
+}
+
+/// The node collections of a level carried over from a generated map when it
+/// is inserted into a template.
+const TEMPLATE_COLLECTIONS: &[&str] = &[
+    "walls",
+    "portals",
+    "patterns",
+    "paths",
+    "objects",
+    "lights",
+    "texts",
+    "roofs.roofs",
+    "shapes.polygons",
+    "shapes.walls",
+];
+
+/// Offsets the hex node ids, and the wall ids of portals, of generated nodes.
+fn offset_node_ids(value: &mut serde_json::Value, offset: u64) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value.as_str() {
+                    Some(id) if key == "node_id" || key == "wall_id" => {
+                        if let Ok(id) = u64::from_str_radix(id, 16) {
+                            *value = format!("{:x}", id + offset).into();
+                        }
+                    }
+                    _ => offset_node_ids(value, offset),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                offset_node_ids(value, offset);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The id the next node of a map gets.
+fn next_node_id(map: &serde_json::Value) -> Result<u64, std::num::ParseIntError> {
+    map["world"]["next_node_id"]
+        .as_str()
+        .map_or(Ok(0), |id| u64::from_str_radix(id, 16))
+}
+
+/// Grows the world of a map to fit another map if it is larger.
+fn grow_world(map: &mut serde_json::Value, other: &serde_json::Value) {
+    let world = &mut map["world"];
+    for dimension in ["width", "height"] {
+        let size = world[dimension]
+            .as_u64()
+            .max(other["world"][dimension].as_u64());
+        if let Some(size) = size {
+            world[dimension] = size.into();
+        }
+    }
+}
+
+/// The keys of nodes, water, and materials holding positions in a level.
+const POSITION_KEYS: &[&str] = &["position", "points", "polygon"];
+
+/// Moves everything placed in a level by an offset in map units.
+pub fn translate_level(level: &mut serde_json::Value, by: Vector2) {
+    let moved = |p: Vector2| Vector2 {
+        x: p.x + by.x,
+        y: p.y + by.y,
+    };
+    match level {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                let text = value
+                    .as_str()
+                    .filter(|_| POSITION_KEYS.contains(&key.as_str()));
+                if let Some(text) = text {
+                    if let Ok(position) = text.parse::<Vector2>() {
+                        *value = moved(position).to_string().into();
+                    } else if let Ok(points) = parse_vector2_array(text) {
+                        let points: Vec<Vector2> = points.into_iter().map(moved).collect();
+                        *value = format_vector2_array(&points).into();
+                    }
+                } else {
+                    translate_level(value, by);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                translate_level(value, by);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Adds the ground level of a generated map to a map as a new level above its
+/// others, returning the new level's key.
+///
+/// Generated node ids are moved past the map's, and the world grows to fit
+/// the generated map if it is larger.
+pub fn add_level(
+    map: &mut serde_json::Value,
+    generated: &serde_json::Value,
+    label: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let offset = next_node_id(map).map_err(|_| "map has an invalid next_node_id")?;
+    let count = next_node_id(generated)?;
+    let levels = map["world"]["levels"]
+        .as_object_mut()
+        .ok_or("map has no levels")?;
+    let key = (0..)
+        .map(|index: u32| index.to_string())
+        .find(|key| !levels.contains_key(key))
+        .unwrap_or_default();
+    let mut level = generated["world"]["levels"]["0"].clone();
+    offset_node_ids(&mut level, offset);
+    level["label"] = label.into();
+    levels.insert(key.clone(), level);
+    grow_world(map, generated);
+    map["world"]["next_node_id"] = format!("{:x}", offset + count).into();
+    Ok(key)
+}
+
+/// Adds a node to a collection such as `texts` of a level of a built map, as
+/// `MapBuilder::add_node` does, returning its new id.
+pub fn append_node(
+    map: &mut serde_json::Value,
+    level: &str,
+    collection: &str,
+    mut node: serde_json::Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let id = next_node_id(map).map_err(|_| "map has an invalid next_node_id")?;
+    let pointer = format!("/{}", collection.replace('.', "/"));
+    let nodes = map["world"]["levels"][level]
+        .pointer_mut(&pointer)
+        .and_then(serde_json::Value::as_array_mut)
+        .ok_or_else(|| format!("level {} has no {}", level, collection))?;
+    node["node_id"] = format!("{:x}", id).into();
+    nodes.push(node);
+    map["world"]["next_node_id"] = format!("{:x}", id + 1).into();
+    Ok(format!("{:x}", id))
+}
+
+/// The edges of a wall, closed back to its first point when it loops.
+fn wall_edges(wall: &serde_json::Value) -> Vec<(Vector2, Vector2)> {
+    let mut points = node_points(wall).unwrap_or_default();
+    if is_loop(wall) && points.len() > 2 && points.first() != points.last() {
+        points.push(points[0]);
+    }
+    points.windows(2).map(|edge| (edge[0], edge[1])).collect()
+}
+
+/// Adds a door of a cell wide at `position` on a level of a built map,
+/// returning its new id.
+///
+/// A door within half a cell of a wall is set in the edge of the wall nearest
+/// it, turned along the edge unless `rotation` is given, and one further
+/// from every wall stands on its own.
+pub fn add_door(
+    map: &mut serde_json::Value,
+    level: &str,
+    position: Vector2,
+    rotation: Option<f64>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let id = next_node_id(map).map_err(|_| "map has an invalid next_node_id")?;
+    let target = &mut map["world"]["levels"][level];
+    if !target.is_object() {
+        return Err(format!("map has no level {}", level).into());
+    }
+    let nearest = nodes(target, "walls")
+        .enumerate()
+        .flat_map(|(wall, node)| {
+            wall_edges(node)
+                .into_iter()
+                .enumerate()
+                .map(move |(edge, (a, b))| (wall, edge, a, b))
+        })
+        .map(|(wall, edge, a, b)| (segment_distance(position, a, b), wall, edge, a, b))
+        .filter(|(distance, ..)| *distance <= UNITS_PER_CELL / 2.0)
+        .min_by(|a, b| a.0.total_cmp(&b.0));
+    let along = nearest.map(|(_, _, _, a, b)| (b.y - a.y).atan2(b.x - a.x));
+    let rotation = rotation.or(along).unwrap_or(0.0);
+    let direction = Vector2 {
+        x: -rotation.sin(),
+        y: rotation.cos(),
+    };
+    let mut portal = portal_node(position, rotation, UNITS_PER_CELL / 2.0, direction, false);
+    portal["node_id"] = format!("{:x}", id).into();
+    match nearest {
+        Some((_, wall, edge, _, _)) => {
+            let wall = &mut target["walls"][wall];
+            portal["wall_id"] = wall["node_id"].clone();
+            portal["point_index"] = edge.into();
+            if !wall["portals"].is_array() {
+                wall["portals"] = serde_json::json!([]);
+            }
+            if let Some(portals) = wall["portals"].as_array_mut() {
+                portals.push(portal);
+            }
+        }
+        None => {
+            if !target["portals"].is_array() {
+                target["portals"] = serde_json::json!([]);
+            }
+            if let Some(portals) = target["portals"].as_array_mut() {
+                portals.push(portal);
+            }
+        }
+    }
+    map["world"]["next_node_id"] = format!("{:x}", id + 1).into();
+    Ok(format!("{:x}", id))
+}
+
+/// Inserts the nodes, water, and materials of a generated map into a template
+/// map, keeping the template's header, world settings, terrain, and lighting.
+///
+/// The generated ground goes into the template's level `level`, and any
+/// floors stacked above it into the levels above that. Levels the template
+/// lacks are added whole.
+///
+/// Generated node ids are moved past the template's, and the world grows to
+/// fit the generated map if it is larger. Returns how far the ids were moved.
+pub fn insert_into_template(
+    template: &mut serde_json::Value,
+    generated: &serde_json::Value,
+    level: u32,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let offset = next_node_id(template).map_err(|_| "template has an invalid next_node_id")?;
+    let count = next_node_id(generated)?;
+    let generated_levels = generated["world"]["levels"]
+        .as_object()
+        .ok_or("generated map has no levels")?;
+    let levels = template["world"]["levels"]
+        .as_object_mut()
+        .ok_or("template has no levels")?;
+    for (key, generated_level) in generated_levels {
+        let index: u32 = key
+            .parse()
+            .map_err(|_| format!("generated map has an invalid level '{}'", key))?;
+        let mut generated_level = generated_level.clone();
+        offset_node_ids(&mut generated_level, offset);
+        let target_key = (level + index).to_string();
+        match levels.get_mut(&target_key) {
+            Some(target) => {
+                merge_nodes(target, &generated_level);
+                merge_water(target, &generated_level);
+                merge_materials(target, &generated_level);
+            }
+            None => {
+                generated_level["label"] = level_label(level + index).into();
+                levels.insert(target_key, generated_level);
+            }
+        }
+    }
+
+    grow_world(template, generated);
+    template["world"]["next_node_id"] = format!("{:x}", offset + count).into();
+    if let Some(record) = generated["header"].get(GENERATED_KEY) {
+        template["header"][GENERATED_KEY] = record.clone();
+    }
+    Ok(offset)
+}
+
+/// The label of a level by its index, as levels are stacked.
+fn level_label(index: u32) -> String {
+    match index {
+        0 => "Ground".to_string(),
+        floor => format!("Floor {}", floor),
+    }
+}
+
+/// Adds the nodes of a level to another level.
+fn merge_nodes(target: &mut serde_json::Value, level: &serde_json::Value) {
+    for collection in TEMPLATE_COLLECTIONS {
+        merge_collection(target, level, collection);
+    }
+}
+
+/// Adds the nodes of a collection such as `roofs.roofs` of a level to another level.
+fn merge_collection(target: &mut serde_json::Value, level: &serde_json::Value, collection: &str) {
+    let pointer = format!("/{}", collection.replace('.', "/"));
+    let Some(nodes) = level.pointer(&pointer).and_then(|nodes| nodes.as_array()) else {
+        return;
+    };
+    if target.pointer(&pointer).is_none() {
+        let (parent, name) = collection.split_once('.').unwrap_or(("", collection));
+        let parent = if parent.is_empty() {
+            &mut *target
+        } else {
+            &mut target[parent]
+        };
+        parent[name] = serde_json::json!([]);
+    }
+    if let Some(existing) = target
+        .pointer_mut(&pointer)
+        .and_then(serde_json::Value::as_array_mut)
+    {
+        existing.extend(nodes.iter().cloned());
+    }
+}
+
+/// Adds the bodies of water of a level to another level.
+fn merge_water(target: &mut serde_json::Value, level: &serde_json::Value) {
+    if let Some(water) = level["water"]["tree"]["children"].as_array() {
+        let tree = &mut target["water"]["tree"];
+        if !tree.is_object() {
+            *tree = serde_json::json!({ "ref": -1, "children": [] });
+        }
+        if let Some(children) = tree["children"].as_array_mut() {
+            children.extend(water.iter().cloned());
+        }
+    }
+}
+
+/// Adds the material regions of a level to another level.
+fn merge_materials(target: &mut serde_json::Value, level: &serde_json::Value) {
+    if let Some(materials) = level["materials"].as_object() {
+        for (texture, regions) in materials {
+            let existing = &mut target["materials"][texture];
+            if !existing.is_array() {
+                *existing = serde_json::json!([]);
+            }
+            if let (Some(existing), Some(regions)) = (existing.as_array_mut(), regions.as_array()) {
+                existing.extend(regions.iter().cloned());
+            }
+        }
+    }
+}
+
+/// The kinds of content of a level `merge_maps` takes from each map.
+pub const MERGE_KINDS: &[&str] = &[
+    "walls",
+    "portals",
+    "patterns",
+    "paths",
+    "objects",
+    "lights",
+    "texts",
+    "roofs",
+    "shapes",
+    "water",
+    "materials",
+];
+
+/// The collections of `TEMPLATE_COLLECTIONS` holding a kind of `MERGE_KINDS`.
+fn kind_collections(kind: &str) -> impl Iterator<Item = &&'static str> + '_ {
+    TEMPLATE_COLLECTIONS
+        .iter()
+        .filter(move |collection| collection.split('.').next() == Some(kind))
+}
+
+/// Adds a kind of content of a level to another level.
+fn merge_kind(target: &mut serde_json::Value, level: &serde_json::Value, kind: &str) {
+    match kind {
+        "water" => merge_water(target, level),
+        "materials" => merge_materials(target, level),
+        _ => {
+            for collection in kind_collections(kind) {
+                merge_collection(target, level, collection);
+            }
+        }
+    }
+}
+
+/// Removes a kind of content from a level.
+fn clear_kind(level: &mut serde_json::Value, kind: &str) {
+    match kind {
+        "water" => {
+            if let Some(water) = level["water"].as_object_mut() {
+                water.remove("tree");
+            }
+        }
+        "materials" => level["materials"] = serde_json::json!({}),
+        _ => {
+            for collection in kind_collections(kind) {
+                let pointer = format!("/{}", collection.replace('.', "/"));
+                if let Some(nodes) = level
+                    .pointer_mut(&pointer)
+                    .and_then(serde_json::Value::as_array_mut)
+                {
+                    nodes.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Merges an overlay map into a base map, level by level, keeping the kinds
+/// of `MERGE_KINDS` in `from_base` of the base and adding those in
+/// `from_overlay` of the overlay. Overlay levels the base lacks are added.
+///
+/// The header and world settings of the base are kept. Overlay node ids are
+/// moved past the base's, and the world grows to fit the overlay if it is
+/// larger.
+pub fn merge_maps(
+    base: &mut serde_json::Value,
+    overlay: &serde_json::Value,
+    from_base: &[&str],
+    from_overlay: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(kind) = from_base
+        .iter()
+        .chain(from_overlay)
+        .find(|kind| !MERGE_KINDS.contains(*kind))
+    {
+        return Err(format!(
+            "unknown kind '{}', expected one of {}",
+            kind,
+            MERGE_KINDS.join(", ")
+        )
+        .into());
+    }
+    let offset = next_node_id(base).map_err(|_| "base map has an invalid next_node_id")?;
+    let count = next_node_id(overlay).map_err(|_| "overlay map has an invalid next_node_id")?;
+    let levels = base["world"]["levels"]
+        .as_object_mut()
+        .ok_or("base map has no levels")?;
+    for level in levels.values_mut() {
+        for kind in MERGE_KINDS.iter().filter(|kind| !from_base.contains(*kind)) {
+            clear_kind(level, kind);
+        }
+    }
+    let overlay_levels = overlay["world"]["levels"]
+        .as_object()
+        .ok_or("overlay map has no levels")?;
+    for (key, level) in overlay_levels {
+        let mut level = level.clone();
+        offset_node_ids(&mut level, offset);
+        match levels.get_mut(key) {
+            Some(target) => {
+                for kind in from_overlay {
+                    merge_kind(target, &level, kind);
+                }
+            }
+            None => {
+                for kind in MERGE_KINDS
+                    .iter()
+                    .filter(|kind| !from_overlay.contains(*kind))
+                {
+                    clear_kind(&mut level, kind);
+                }
+                levels.insert(key.clone(), level);
+            }
+        }
+    }
+    grow_world(base, overlay);
+    base["world"]["next_node_id"] = format!("{:x}", offset + count).into();
+    Ok(())
+}
+
+/// Where the header records what was generated, to tell it apart from what
+/// was added or changed in DungeonDraft when the map is regenerated.
+const GENERATED_KEY: &str = "generated";
+
+/// Removes the node ids, and the wall ids of portals, of generated nodes.
+fn strip_node_ids(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.remove("node_id");
+            object.remove("wall_id");
+            object.values_mut().for_each(strip_node_ids);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(strip_node_ids),
+        _ => {}
+    }
+}
+
+/// A short hash of generated content, leaving out node ids, which change as
+/// it is moved from map to map.
+pub fn fingerprint(value: &serde_json::Value) -> String {
+    let mut value = value.clone();
+    strip_node_ids(&mut value);
+    Sha256::digest(value.to_string().as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The fingerprints of the nodes of a level, then of its water and its
+/// materials as a whole.
+fn level_fingerprints(level: &serde_json::Value) -> Vec<String> {
+    let mut fingerprints: Vec<String> = TEMPLATE_COLLECTIONS
+        .iter()
+        .filter_map(|collection| level.pointer(&format!("/{}", collection.replace('.', "/"))))
+        .filter_map(serde_json::Value::as_array)
+        .flatten()
+        .map(fingerprint)
+        .collect();
+    fingerprints.push(fingerprint(&level["water"]["tree"]));
+    fingerprints.push(fingerprint(&level["materials"]));
+    fingerprints
+}
+
+/// Records the fingerprints of everything in a generated map in its header,
+/// so `regenerate` can tell it from what is added or changed later.
+pub fn record_generated(map: &mut serde_json::Value) {
+    let fingerprints: Vec<String> = map["world"]["levels"]
+        .as_object()
+        .into_iter()
+        .flat_map(|levels| levels.values())
+        .flat_map(level_fingerprints)
+        .collect();
+    map["header"][GENERATED_KEY] = fingerprints.into();
+}
+
+/// How many nodes of a map were replaced by regenerating it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Regeneration {
+    /// Generated nodes left as they were, and replaced
+    pub replaced: usize,
+    /// Nodes added or changed since the map was generated, and kept
+    pub kept: usize,
+    /// Nodes newly generated
+    pub added: usize,
+}
+
+/// Replaces what was generated in a map, and is still as it was generated,
+/// with a newly generated map, keeping the nodes added or changed since.
+///
+/// The generated ground goes into the map's level `level`, and any floors
+/// stacked above it into the levels above that, as `insert_into_template`
+/// puts them. Water and materials are replaced as a whole in each level, and
+/// kept as a whole when any of them were changed. Newly generated node ids
+/// are moved past the map's, and the world grows to fit if the new map is
+/// larger.
+pub fn regenerate(
+    map: &mut serde_json::Value,
+    generated: &serde_json::Value,
+    level: u32,
+) -> Result<Regeneration, Box<dyn std::error::Error>> {
+    let mut record: Vec<String> = map["header"][GENERATED_KEY]
+        .as_array()
+        .ok_or("map has no record of what was generated in it")?
+        .iter()
+        .filter_map(|fingerprint| fingerprint.as_str().map(str::to_string))
+        .collect();
+    // Takes a fingerprint out of the record, once for each time it was generated
+    let mut was_generated = |value: &serde_json::Value| {
+        let fingerprint = fingerprint(value);
+        let found = record.iter().position(|recorded| *recorded == fingerprint);
+        found.map(|i| record.swap_remove(i)).is_some()
+    };
+    let offset = next_node_id(map).map_err(|_| "map has an invalid next_node_id")?;
+    let count = next_node_id(generated)?;
+    let mut regeneration = Regeneration::default();
+    let levels = map["world"]["levels"]
+        .as_object_mut()
+        .ok_or("map has no levels")?;
+    let mut changed_water = Vec::new();
+    let mut changed_materials = Vec::new();
+    for (key, level) in levels.iter_mut() {
+        for collection in TEMPLATE_COLLECTIONS {
+            let pointer = format!("/{}", collection.replace('.', "/"));
+            if let Some(nodes) = level
+                .pointer_mut(&pointer)
+                .and_then(serde_json::Value::as_array_mut)
+            {
+                let before = nodes.len();
+                nodes.retain(|node| !was_generated(node));
+                regeneration.replaced += before - nodes.len();
+                regeneration.kept += nodes.len();
+            }
+        }
+        if was_generated(&level["water"]["tree"]) {
+            if let Some(water) = level["water"].as_object_mut() {
+                water.remove("tree");
+            }
+        } else {
+            changed_water.push(key.clone());
+        }
+        if was_generated(&level["materials"]) {
+            level["materials"] = serde_json::json!({});
+        } else {
+            changed_materials.push(key.clone());
+        }
+    }
+
+    let mut generated_levels = generated["world"]["levels"].clone();
+    offset_node_ids(&mut generated_levels, offset);
+    for (key, generated_level) in generated_levels.as_object().into_iter().flatten() {
+        let index: u32 = key
+            .parse()
+            .map_err(|_| format!("generated map has an invalid level '{}'", key))?;
+        let key = (level + index).to_string();
+        regeneration.added += TEMPLATE_COLLECTIONS
+            .iter()
+            .filter_map(|collection| {
+                generated_level.pointer(&format!("/{}", collection.replace('.', "/")))
+            })
+            .filter_map(serde_json::Value::as_array)
+            .map(Vec::len)
+            .sum::<usize>();
+        let Some(target) = levels.get_mut(&key) else {
+            let mut generated_level = generated_level.clone();
+            generated_level["label"] = level_label(level + index).into();
+            levels.insert(key, generated_level);
+            continue;
+        };
+        merge_nodes(target, generated_level);
+        match generated_level["water"].get("tree") {
+            Some(tree) if !changed_water.contains(&key) => target["water"]["tree"] = tree.clone(),
+            _ => {}
+        }
+        if !changed_materials.contains(&key) {
+            target["materials"] = generated_level["materials"].clone();
+        }
+    }
+
+    grow_world(map, generated);
+    map["world"]["next_node_id"] = format!("{:x}", offset + count).into();
+    let mut fresh = generated.clone();
+    record_generated(&mut fresh);
+    map["header"][GENERATED_KEY] = fresh["header"][GENERATED_KEY].take();
+    Ok(regeneration)
+}
+
+/// Pins everything in a generated map that would otherwise change from run to
+/// run, so the same inputs always give a byte-identical map.
+///
+/// Generation scatters decorations from a seed and hands out node ids in
+/// order, leaving only the creation date, which is set from
+/// `SOURCE_DATE_EPOCH` or else the Unix epoch.
+pub fn make_reproducible(map: &mut serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+    let seconds = match std::env::var(SOURCE_DATE_EPOCH) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid {} '{}'", SOURCE_DATE_EPOCH, value))?,
+        Err(_) => 0,
+    };
+    let time = chrono::DateTime::from_timestamp(seconds, 0)
+        .ok_or_else(|| format!("{} is out of range", SOURCE_DATE_EPOCH))?;
+    map["header"]["creation_date"] = serde_json::to_value(CreationDateBuilder::at(&time))?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreationDate {
+    year: i32,
+    month: u32,
+    day: u32,
+    weekday: u32,
+    dst: bool,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+pub struct CreationDateBuilder {
+    year: Option<i32>,
+    month: Option<u32>,
+    day: Option<u32>,
+    weekday: Option<u32>,
+    dst: Option<bool>,
+    hour: Option<u32>,
+    minute: Option<u32>,
+    second: Option<u32>,
+}
+
+impl CreationDateBuilder {
+    pub fn new() -> Self {
+        Self {
+            year: None,
+            month: None,
+            day: None,
+            weekday: None,
+            dst: None,
+            hour: None,
+            minute: None,
+            second: None,
+        }
+    }
+
+    pub fn year(mut self, year: i32) -> Self {
+        self.year = Some(year);
+        self
+    }
+
+    pub fn month(mut self, month: u32) -> Self {
+        self.month = Some(month);
+        self
+    }
+
+    pub fn day(mut self, day: u32) -> Self {
+        self.day = Some(day);
+        self
+    }
+
+    pub fn weekday(mut self, weekday: u32) -> Self {
+        self.weekday = Some(weekday);
+        self
+    }
+
+    pub fn dst(mut self, dst: bool) -> Self {
+        self.dst = Some(dst);
+        self
+    }
+
+    pub fn hour(mut self, hour: u32) -> Self {
+        self.hour = Some(hour);
+        self
+    }
+
+    pub fn minute(mut self, minute: u32) -> Self {
+        self.minute = Some(minute);
+        self
+    }
+
+    pub fn second(mut self, second: u32) -> Self {
+        self.second = Some(second);
+        self
+    }
+
+    pub fn build(self) -> Result<CreationDate, &'static str> {
+        Ok(CreationDate {
+            year: self.year.ok_or("Year is not set")?,
+            month: self.month.ok_or("Month is not set")?,
+            day: self.day.ok_or("Day is not set")?,
+            weekday: self.weekday.ok_or("Weekday is not set")?,
+            dst: self.dst.ok_or("DST is not set")?,
+            hour: self.hour.ok_or("Hour is not set")?,
+            minute: self.minute.ok_or("Minute is not set")?,
+            second: self.second.ok_or("Second is not set")?,
+        })
+    }
+
+    pub fn now() -> Result<CreationDate, &'static str> {
+        Ok(Self::at(&chrono::Local::now()))
+    }
+
+    /// The creation date of a map made at the given time.
+    pub fn at(time: &(impl Datelike + Timelike)) -> CreationDate {
+        CreationDate {
+            year: time.year(),
+            month: time.month(),
+            day: time.day(),
+            weekday: time.weekday().number_from_monday(),
+            dst: false,
+            hour: time.hour(),
+            minute: time.minute(),
+            second: time.second(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_year() {
+        let date = CreationDateBuilder::new().year(2023).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Month is not set");
+    }
+
+    #[test]
+    fn test_month() {
+        let date = CreationDateBuilder::new().month(9).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_day() {
+        let date = CreationDateBuilder::new().day(1).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_weekday() {
+        let date = CreationDateBuilder::new().weekday(5).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_dst() {
+        let date = CreationDateBuilder::new().dst(false).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_hour() {
+        let date = CreationDateBuilder::new().hour(8).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_minute() {
+        let date = CreationDateBuilder::new().minute(58).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_second() {
+        let date = CreationDateBuilder::new().second(15).build();
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Year is not set");
+    }
+
+    #[test]
+    fn test_complete_build() {
+        let date = CreationDateBuilder::new()
+            .year(2023)
+            .month(9)
+            .day(1)
+            .weekday(5)
+            .dst(false)
+            .hour(8)
+            .minute(58)
+            .second(15)
+            .build();
+
+        assert!(date.is_ok());
+        let date = date.unwrap();
+        assert_eq!(date.year, 2023);
+        assert_eq!(date.month, 9);
+        assert_eq!(date.day, 1);
+        assert_eq!(date.weekday, 5);
+        assert_eq!(date.dst, false);
+        assert_eq!(date.hour, 8);
+        assert_eq!(date.minute, 58);
+        assert_eq!(date.second, 15);
+    }
+
+    #[test]
+    fn test_partial_build() {
+        let date = CreationDateBuilder::new()
+            .year(2023)
+            .month(9)
+            .day(1)
+            .dst(false)
+            .build();
+
+        assert!(date.is_err());
+        assert_eq!(date.err().unwrap(), "Weekday is not set");
+    }
+
+    #[test]
+    fn test_parse_vector2() {
+        let v: Vector2 = "Vector2( 4480, 2560 )".parse().unwrap();
+        assert_eq!(
+            v,
+            Vector2 {
+                x: 4480.0,
+                y: 2560.0
+            }
+        );
+        assert!("Vector2( 1 )".parse::<Vector2>().is_err());
+        assert!("Vector3( 1, 2, 3 )".parse::<Vector2>().is_err());
+    }
+
+    #[test]
+    fn test_parse_vector2_array() {
+        let points = parse_vector2_array("PoolVector2Array( 0, 0, 256, 0, 256, 512.5 )").unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[2], Vector2 { x: 256.0, y: 512.5 });
+        assert!(parse_vector2_array("PoolVector2Array(  )")
+            .unwrap()
+            .is_empty());
+        assert!(parse_vector2_array("PoolVector2Array( 0, 0, 1 )").is_err());
+    }
+
+    #[test]
+    fn test_format_vector2() {
+        let points = vec![Vector2 { x: 0.0, y: 1.5 }, Vector2 { x: 256.0, y: -2.0 }];
+        assert_eq!(points[1].to_string(), "Vector2( 256, -2 )");
+        let formatted = format_vector2_array(&points);
+        assert_eq!(formatted, "PoolVector2Array( 0, 1.5, 256, -2 )");
+        assert_eq!(parse_vector2_array(&formatted).unwrap(), points);
+        assert_eq!(format_byte_array(&[0, 255]), "PoolByteArray( 0, 255 )");
+    }
+
+    #[test]
+    fn test_map_builder() {
+        let mut builder = MapBuilder::new(4, 3).unwrap();
+        let first = builder.add_node("walls", serde_json::json!({ "loop": true }));
+        let second = builder.add_node("objects", serde_json::json!({}));
+        builder.add_node("roofs.roofs", serde_json::json!({}));
+        assert_eq!((first.as_str(), second.as_str()), ("0", "1"));
+
+        let map = builder.build();
+        assert_eq!(map["world"]["width"], 4);
+        assert_eq!(map["world"]["height"], 3);
+        assert_eq!(map["world"]["next_node_id"], "3");
+        let level = &map["world"]["levels"]["0"];
+        assert_eq!(level["walls"][0]["node_id"], "0");
+        assert_eq!(level["roofs"]["roofs"][0]["node_id"], "2");
+    }
+
+    #[test]
+    fn test_insert_into_template() {
+        let mut template = MapBuilder::new(10, 2).unwrap();
+        template.add_node("objects", serde_json::json!({ "texture": "statue" }));
+        template.add_node("lights", serde_json::json!({}));
+        let mut template = template.build();
+        template["world"]["levels"]["0"]["environment"]["ambient_light"] = "ff202020".into();
+
+        let mut generated = MapBuilder::new(4, 6).unwrap();
+        let wall = generated.add_node("walls", wall_node(&[Vector2 { x: 0.0, y: 0.0 }], true));
+        generated.add_portal(&wall, 0, serde_json::json!({}));
+        generated.add_material("stone", &[Vector2 { x: 0.0, y: 0.0 }]);
+        let generated = generated.build();
+
+        assert_eq!(
+            insert_into_template(&mut template, &generated, 0).unwrap(),
+            2
+        );
+        assert_eq!(template["world"]["width"], 10);
+        assert_eq!(template["world"]["height"], 6);
+        assert_eq!(template["world"]["next_node_id"], "4");
+        let level = &template["world"]["levels"]["0"];
+        assert_eq!(level["environment"]["ambient_light"], "ff202020");
+        assert_eq!(level["objects"][0]["node_id"], "0");
+        assert_eq!(level["walls"][0]["node_id"], "2");
+        assert_eq!(level["walls"][0]["portals"][0]["node_id"], "3");
+        assert_eq!(level["walls"][0]["portals"][0]["wall_id"], "2");
+        assert_eq!(level["materials"]["stone"].as_array().unwrap().len(), 1);
+
+        assert_eq!(
+            insert_into_template(&mut template, &generated, 2).unwrap(),
+            4
+        );
+        assert_eq!(template["world"]["next_node_id"], "6");
+        let level = &template["world"]["levels"]["2"];
+        assert_eq!(level["label"], "Floor 2");
+        assert_eq!(level["walls"][0]["node_id"], "4");
+        assert_eq!(level["walls"][0]["portals"][0]["wall_id"], "4");
+        assert!(template["world"]["levels"].get("1").is_none());
+    }
+
+    #[test]
+    fn test_regenerate() {
+        let room = |size: f64| {
+            wall_node(
+                &[
+                    Vector2 { x: 0.0, y: 0.0 },
+                    Vector2 { x: size, y: 0.0 },
+                    Vector2 { x: size, y: size },
+                ],
+                true,
+            )
+        };
+        let mut generated = MapBuilder::new(4, 4).unwrap();
+        generated.add_node("walls", room(256.0));
+        generated.add_node("walls", room(512.0));
+        generated.add_node("lights", serde_json::json!({ "range": 1 }));
+        let mut map = generated.build();
+        record_generated(&mut map);
+        // Edited in DungeonDraft: a light moved and a statue added
+        map["world"]["levels"]["0"]["lights"][0]["range"] = 2.into();
+        append_node(
+            &mut map,
+            "0",
+            "objects",
+            serde_json::json!({ "texture": "statue" }),
+        )
+        .unwrap();
+
+        let mut regenerated = MapBuilder::new(6, 4).unwrap();
+        regenerated.add_node("walls", room(768.0));
+        regenerated.add_node("lights", serde_json::json!({ "range": 1 }));
+        let regenerated = regenerated.build();
+
+        let regeneration = regenerate(&mut map, &regenerated, 0).unwrap();
+        assert_eq!(
+            regeneration,
+            Regeneration {
+                replaced: 2,
+                kept: 2,
+                added: 2,
+            }
+        );
+        let level = &map["world"]["levels"]["0"];
+        assert_eq!(level["walls"].as_array().unwrap().len(), 1);
+        assert_eq!(level["walls"][0]["node_id"], "4");
+        assert_eq!(level["lights"].as_array().unwrap().len(), 2);
+        assert_eq!(level["objects"][0]["texture"], "statue");
+        assert_eq!(map["world"]["width"], 6);
+        assert_eq!(map["world"]["next_node_id"], "6");
+
+        // Regenerating again replaces only what was just generated
+        let regeneration = regenerate(&mut map, &regenerated, 0).unwrap();
+        assert_eq!(regeneration.replaced, 2);
+        assert_eq!(regeneration.kept, 2);
+        assert!(regenerate(&mut regenerated.clone(), &regenerated, 0).is_err());
+    }
+
+    #[test]
+    fn test_regenerate_level() {
+        let mut generated = MapBuilder::new(4, 4).unwrap();
+        generated.add_node("lights", serde_json::json!({ "range": 1 }));
+        let mut generated = generated.build();
+        record_generated(&mut generated);
+        let mut map = MapBuilder::new(4, 4).unwrap().build();
+        insert_into_template(&mut map, &generated, 1).unwrap();
+        append_node(&mut map, "0", "lights", serde_json::json!({ "range": 3 })).unwrap();
+
+        // Replaces what was generated in level 1, leaving the ground alone
+        let regeneration = regenerate(&mut map, &generated, 1).unwrap();
+        assert_eq!(regeneration.replaced, 1);
+        assert_eq!(regeneration.added, 1);
+        let levels = &map["world"]["levels"];
+        assert_eq!(levels["0"]["lights"].as_array().unwrap().len(), 1);
+        assert_eq!(levels["1"]["lights"].as_array().unwrap().len(), 1);
+        assert_eq!(levels["1"]["label"], "Floor 1");
+    }
+
+    #[test]
+    fn test_add_level() {
+        let mut map = MapBuilder::new(4, 4).unwrap();
+        map.add_node("lights", serde_json::json!({}));
+        let mut map = map.build();
+
+        let mut upper = MapBuilder::new(6, 2).unwrap();
+        upper.add_node("walls", wall_node(&[Vector2 { x: 0.0, y: 0.0 }], false));
+        upper.add_node(
+            "objects",
+            object_node(Vector2 { x: 256.0, y: 0.0 }, "stairs"),
+        );
+        let mut upper = upper.build();
+        translate_level(
+            &mut upper["world"]["levels"]["0"],
+            Vector2 { x: 10.0, y: 20.0 },
+        );
+
+        assert_eq!(add_level(&mut map, &upper, "First floor").unwrap(), "1");
+        assert_eq!(map["world"]["width"], 6);
+        assert_eq!(map["world"]["next_node_id"], "3");
+        let level = &map["world"]["levels"]["1"];
+        assert_eq!(level["label"], "First floor");
+        assert_eq!(level["walls"][0]["node_id"], "1");
+        assert_eq!(level["walls"][0]["points"], "PoolVector2Array( 10, 20 )");
+        assert_eq!(level["objects"][0]["position"], "Vector2( 266, 20 )");
+        // Scales are not positions
+        assert_eq!(level["objects"][0]["scale"], "Vector2( 1, 1 )");
+    }
+
+    #[test]
+    fn test_map_builder_portals() {
+        let mut builder = MapBuilder::new(2, 2).unwrap();
+        let square = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 512.0, y: 0.0 },
+            Vector2 { x: 512.0, y: 512.0 },
+        ];
+        let wall = builder.add_node("walls", wall_node(&square, true));
+        let portal = portal_node(
+            Vector2 { x: 256.0, y: 0.0 },
+            0.0,
+            128.0,
+            Vector2 { x: 0.0, y: 1.0 },
+            false,
+        );
+        let door = builder.add_portal(&wall, 0, portal);
+
+        let map = builder.build();
+        let portal = &map["world"]["levels"]["0"]["walls"][0]["portals"][0];
+        assert_eq!(portal["node_id"], door.as_str());
+        assert_eq!(portal["wall_id"], wall.as_str());
+        assert_eq!(portal["point_index"], 0);
+        assert_eq!(portal["direction"], "Vector2( 0, 1 )");
+    }
+
+    #[test]
+    fn test_wall_winding_round_trip() {
+        // A room and a courtyard in it, as DungeonDraft saves them
+        let saved = serde_json::json!({
+            "room": "PoolVector2Array( 0, 0, 1024, 0, 1024, 1024, 0, 1024, 0, 0 )",
+            "courtyard": "PoolVector2Array( 256, 256, 256, 768, 768, 768, 768, 256, 256, 256 )",
+        });
+        let room = node_points(&serde_json::json!({ "points": saved["room"] })).unwrap();
+        assert_eq!(wall_node(&room, true)["points"], saved["room"]);
+        let courtyard = node_points(&serde_json::json!({ "points": saved["courtyard"] })).unwrap();
+        assert_eq!(hole_wall_node(&courtyard)["points"], saved["courtyard"]);
+
+        // Traced either way and left open, they are saved the same
+        let traced: Vec<Vector2> = room[..4].iter().rev().copied().collect();
+        assert_eq!(
+            wall_node(&traced, true)["points"],
+            "PoolVector2Array( 0, 1024, 0, 0, 1024, 0, 1024, 1024, 0, 1024 )"
+        );
+        assert_eq!(
+            hole_wall_node(&courtyard[..4])["points"],
+            saved["courtyard"]
+        );
+        // Open walls are left as they are
+        assert_eq!(
+            wall_node(&traced[..2], false)["points"],
+            "PoolVector2Array( 0, 1024, 1024, 1024 )"
+        );
+    }
+
+    #[test]
+    fn test_map_builder_regions() {
+        let square = vec![
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 256.0 },
+        ];
+        let mut builder = MapBuilder::new(1, 1).unwrap();
+        builder.add_water(&square);
+        builder.add_water(&square);
+        builder.add_material("res://textures/materials/stone.png", &square);
+
+        let map = builder.build();
+        let level = &map["world"]["levels"]["0"];
+        let water = level["water"]["tree"]["children"].as_array().unwrap();
+        assert_eq!(water.len(), 2);
+        assert_eq!(
+            water[0]["polygon"],
+            "PoolVector2Array( 0, 0, 256, 0, 256, 256, 0, 0 )"
+        );
+        assert_eq!(level["water"]["disable_border"], false);
+        let stone = &level["materials"]["res://textures/materials/stone.png"];
+        assert_eq!(stone.as_array().unwrap().len(), 1);
+        assert!(map["header"]["creation_date"]["year"].is_number());
+    }
+
+    #[test]
+    fn test_parse_int_array() {
+        assert_eq!(
+            parse_int_array("PoolIntArray( -1, 0, 12 )").unwrap(),
+            vec![-1, 0, 12]
+        );
+        assert!(parse_int_array("PoolIntArray()").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_color() {
+        let color: Color = "ff605f58".parse().unwrap();
+        assert_eq!(
+            color,
+            Color {
+                a: 0xff,
+                r: 0x60,
+                g: 0x5f,
+                b: 0x58
+            }
+        );
+        assert_eq!("#102030".parse::<Color>().unwrap().a, 0xff);
+        assert!("ff60".parse::<Color>().is_err());
+        assert!("zz605f58".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn test_creation_date_at() {
+        let time = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let date = serde_json::to_value(CreationDateBuilder::at(&time)).unwrap();
+        assert_eq!(
+            date,
+            serde_json::json!({
+                "year": 2023, "month": 11, "day": 14, "weekday": 2, "dst": false,
+                "hour": 22, "minute": 13, "second": 20
+            })
+        );
+    }
+
+    #[test]
+    fn test_wall_styles() {
+        let mut styles = WallStyles::default();
+        styles.set("boundary.outline", &"ff202020".into()).unwrap();
+        styles.set("boundary.shadow_size", &48.into()).unwrap();
+        styles.set("cave.shadow", &false.into()).unwrap();
+        assert!(styles.set("cave.shadow", &"no".into()).is_err());
+        assert!(styles.set("cave.height", &1.into()).is_err());
+        assert!(styles.set("roof.shadow", &true.into()).is_err());
+        assert!(styles.set("shadow", &true.into()).is_err());
+
+        let wall = styles.cave().apply(wall_node(&[Vector2::default()], false));
+        assert_eq!(wall["shadow"], false);
+        assert_eq!(wall["shadow_size"], 48.0);
+        assert_eq!(wall["outline_color"], "ff202020");
+        let wall = styles
+            .boundary
+            .apply(wall_node(&[Vector2::default()], false));
+        assert_eq!(wall["shadow"], true);
+    }
+
+    #[test]
+    fn test_smooth_paths() {
+        let road = vec![
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 256.0 },
+        ];
+        let mut builder = MapBuilder::new(1, 1).unwrap();
+        builder.add_node("paths", path_node(&road, DEFAULT_WALL_TEXTURE, 32.0));
+        let mut looped = path_node(&road, DEFAULT_WALL_TEXTURE, 32.0);
+        looped["loop"] = true.into();
+        builder.add_node("paths", looped);
+        let mut map = builder.build();
+        smooth_paths(&mut map, 2);
+
+        let paths = &map["world"]["levels"]["0"]["paths"];
+        let smoothed = parse_vector2_array(paths[0]["edit_points"].as_str().unwrap()).unwrap();
+        assert_eq!(smoothed.len(), 6);
+        assert_eq!(smoothed.first(), road.first());
+        assert_eq!(smoothed.last(), road.last());
+        assert_eq!(paths[1]["edit_points"], format_vector2_array(&road));
+    }
+
+    #[test]
+    fn test_water_style() {
+        let square = vec![
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 256.0 },
+        ];
+        let mut builder = MapBuilder::new(1, 1).unwrap();
+        builder.add_water(&square);
+        let mut map = builder.build();
+        let mut style = WaterStyle::default();
+        style.set("deep_color", &"ff102040".into()).unwrap();
+        style.set("shore_width", &0.5.into()).unwrap();
+        style.set("border", &false.into()).unwrap();
+        assert!(style.set("border", &"off".into()).is_err());
+        assert!(style.set("depth", &1.into()).is_err());
+        style.apply(&mut map);
+
+        let water = &map["world"]["levels"]["0"]["water"];
+        assert_eq!(water["disable_border"], true);
+        assert_eq!(water["wave_speed"], 1.0);
+        let body = &water["tree"]["children"][0];
+        assert_eq!(body["deep_color"], "ff102040");
+        assert_eq!(body["shallow_color"], DEFAULT_SHALLOW_WATER_COLOR);
+        assert_eq!(body["blend_distance"], 128.0);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut map = MapBuilder::new(4, 4).unwrap().build();
+        set_metadata(&mut map, "title", "The Sunken Crypt").unwrap();
+        assert!(set_metadata(&mut map, "rating", "5").is_err());
+        let parameters = serde_json::json!({ "mode": "dungeon", "pixels_per_cell": 64.0 });
+        stamp_generator(&mut map, &parameters).unwrap();
+        let fields = metadata(&map);
+        assert!(fields.contains(&("title".to_string(), "The Sunken Crypt".to_string())));
+        assert!(fields.contains(&(
+            "generator.version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string()
+        )));
+        let hash = map["header"]["generator"]["parameters"].clone();
+        stamp_generator(&mut map, &parameters).unwrap();
+        assert_eq!(map["header"]["generator"]["parameters"], hash);
+        assert_eq!(generator_settings(&map), &parameters);
+        assert!(generator_settings(&MapBuilder::new(4, 4).unwrap().build()).is_null());
+    }
+
+    #[test]
+    fn test_map_file() {
+        let mut value: serde_json::Value = serde_json::from_str(MAPFILE).unwrap();
+        let points = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 512.0, y: 0.0 },
+            Vector2 { x: 512.0, y: 512.0 },
+        ];
+        let mut wall = wall_node(&points, false);
+        wall["node_id"] = "1".into();
+        let door = portal_node(
+            Vector2 { x: 256.0, y: 0.0 },
+            0.5,
+            128.0,
+            Vector2 { x: 0.0, y: 1.0 },
+            false,
+        );
+        wall["portals"].as_array_mut().unwrap().push(door);
+        value["world"]["levels"]["0"]["walls"] = serde_json::json!([wall]);
+        let light = light_node(Vector2 { x: 256.0, y: 256.0 }, "ffffcc88", 4.0, 1.0);
+        value["world"]["levels"]["0"]["lights"] = serde_json::json!([light]);
+
+        let map = MapFile::from_value(value.clone()).unwrap();
+        assert_eq!(map.world.width, 35);
+        let level = &map.world.levels["0"];
+        assert_eq!(level.label, "Ground");
+        assert_eq!(level.walls[0].node_id.as_deref(), Some("1"));
+        assert_eq!(level.walls[0].points, points);
+        assert_eq!(level.all_portals().next().unwrap().rotation, 0.5);
+        assert_eq!(level.lights[0].color.to_string(), "ffffcc88");
+        assert_eq!(map.to_value().unwrap(), value);
+    }
+
+    #[test]
+    fn test_merge_maps() {
+        let square = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 256.0 },
+        ];
+        let mut builder = MapBuilder::new(4, 4).unwrap();
+        builder.add_node("walls", wall_node(&square, true));
+        builder.add_node("lights", light_node(square[0], "ffffffff", 4.0, 1.0));
+        let mut base = builder.build();
+        let mut builder = MapBuilder::new(8, 2).unwrap();
+        builder.add_node("walls", wall_node(&square, false));
+        builder.add_node(
+            "objects",
+            object_node(square[1], DEFAULT_SETTLEMENT_TEXTURE),
+        );
+        let mut overlay = builder.build();
+        let upper = overlay["world"]["levels"]["0"].clone();
+        overlay["world"]["levels"]["1"] = upper;
+
+        merge_maps(&mut base, &overlay, &["walls"], &["walls", "objects"]).unwrap();
+        let ground = &base["world"]["levels"]["0"];
+        assert_eq!(ground["walls"].as_array().unwrap().len(), 2);
+        assert_eq!(ground["walls"][1]["node_id"], "2");
+        assert!(ground["lights"].as_array().unwrap().is_empty());
+        assert_eq!(ground["objects"][0]["node_id"], "3");
+        assert_eq!(base["world"]["levels"]["1"]["walls"][0]["node_id"], "2");
+        assert_eq!(base["world"]["next_node_id"], "4");
+        assert_eq!(base["world"]["width"], 8);
+        assert!(merge_maps(&mut base, &overlay, &["doors"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_map_file_set() {
+        let mut map = MapFile::from_value(MapBuilder::new(4, 4).unwrap().build()).unwrap();
+        map.set("world.width", 40.into()).unwrap();
+        assert_eq!(map.world.width, 40);
+        map.set("header.title", "The Sunken Crypt".into()).unwrap();
+        assert_eq!(map.header.title.as_deref(), Some("The Sunken Crypt"));
+        map.set(
+            "world.levels.0.environment.ambient_light",
+            "ff808080".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            map.world.levels["0"].other["environment"]["ambient_light"],
+            "ff808080"
+        );
+        map.set("world.grid_size", 256.into()).unwrap();
+        assert_eq!(map.world.grid_size.map(|size| size.get()), Some(256));
+        let value = map.to_value().unwrap();
+        assert_eq!(value["world"]["grid_size"], 256);
+        assert_eq!(MapFile::from_value(value).unwrap(), map);
+        assert!(map.set("world.grid_size", 0.into()).is_err());
+        assert!(map.set("world.gridsize", 256.into()).is_err());
+        assert!(map.set("world.height", "tall".into()).is_err());
+        assert_eq!(map.world.height, 4);
+        assert!(map.set("width", 40.into()).is_err());
+    }
+
+    #[test]
+    fn test_wall_builder() {
+        let mut builder = MapBuilder::new(4, 4).unwrap();
+        let wall = WallBuilder::new()
+            .point(0.0, 0.0)
+            .point(512.0, 0.0)
+            .point(512.0, 512.0)
+            .closed(true)
+            .texture(DEFAULT_CLIFF_TEXTURE)
+            .color("ff000000")
+            .shadow(false);
+        assert_eq!(builder.add_wall(wall).unwrap(), "0");
+        let map = builder.build();
+        let wall = &map["world"]["levels"]["0"]["walls"][0];
+        assert_eq!(wall["node_id"], "0");
+        assert_eq!(wall["texture"], DEFAULT_CLIFF_TEXTURE);
+        assert_eq!(wall["color"], "ff000000");
+        assert_eq!(wall["shadow"], false);
+        assert!(is_loop(wall));
+        assert_eq!(node_points(wall).unwrap().len(), 4);
+        assert!(WallBuilder::new().point(0.0, 0.0).build().is_err());
+        assert!(WallBuilder::new()
+            .points(&[Vector2::default(), Vector2 { x: 1.0, y: 0.0 }])
+            .closed(true)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_add_door() {
+        let mut builder = MapBuilder::new(4, 4).unwrap();
+        let square = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 512.0, y: 0.0 },
+            Vector2 { x: 512.0, y: 512.0 },
+            Vector2 { x: 0.0, y: 512.0 },
+        ];
+        builder
+            .add_wall(WallBuilder::new().points(&square).closed(true))
+            .unwrap();
+        let mut map = builder.build();
+
+        let id = add_door(&mut map, "0", Vector2 { x: 500.0, y: 256.0 }, None).unwrap();
+        assert_eq!(id, "1");
+        let wall = &map["world"]["levels"]["0"]["walls"][0];
+        let portal = &wall["portals"][0];
+        assert_eq!(portal["wall_id"], "0");
+        let edge = portal["point_index"].as_u64().unwrap() as usize;
+        let points = node_points(wall).unwrap();
+        assert_eq!(points[edge].x, 512.0);
+        assert_eq!(points[edge + 1].x, 512.0);
+        let rotation = portal["rotation"].as_f64().unwrap();
+        assert!((rotation.abs() - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+
+        let id = add_door(&mut map, "0", Vector2 { x: 256.0, y: 256.0 }, Some(0.5)).unwrap();
+        assert_eq!(id, "2");
+        let portal = &map["world"]["levels"]["0"]["portals"][0];
+        assert_eq!(portal["rotation"], 0.5);
+        assert!(portal.get("wall_id").is_none());
+        assert_eq!(map["world"]["next_node_id"], "3");
+        assert!(add_door(&mut map, "1", Vector2::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_add_light() {
+        let mut map = MapFile::from_value(MapBuilder::new(4, 4).unwrap().build()).unwrap();
+        let color: Color = "#ffb25c".parse().unwrap();
+        let light = Light::new(Vector2 { x: 256.0, y: 256.0 }, color, 6.0, 0.8);
+        assert_eq!(map.add_light("0", light).unwrap(), "0");
+        assert!(map.add_light("1", Light::default()).is_err());
+        let value = map.to_value().unwrap();
+        let light = &value["world"]["levels"]["0"]["lights"][0];
+        assert_eq!(light["node_id"], "0");
+        assert_eq!(light["color"], "ffffb25c");
+        assert_eq!(light["range"], 6.0);
+        assert_eq!(light["shadows"], true);
+        assert_eq!(value["world"]["next_node_id"], "1");
+    }
+
+    #[test]
+    fn test_add_object() {
+        let mut map = MapFile::from_value(MapBuilder::new(4, 4).unwrap().build()).unwrap();
+        let manifest = map.header.other["asset_manifest"].as_array().unwrap().len();
+        let position = Vector2 { x: 256.0, y: 512.0 };
+        let barrel = Object::new(position, "res://textures/objects/barrel.png");
+        assert_eq!(barrel.pack_id(), None);
+        assert_eq!(map.add_object("0", barrel).unwrap(), "0");
+        let mut tree = Object::new(position, "res://packs/3DDXdf2M/textures/objects/tree.png");
+        tree.rotation = std::f64::consts::FRAC_PI_2;
+        tree.scale = Vector2 { x: 2.0, y: 2.0 };
+        assert_eq!(tree.pack_id(), Some("3DDXdf2M"));
+        assert_eq!(map.add_object("0", tree.clone()).unwrap(), "1");
+        map.add_object("0", tree).unwrap();
+        assert!(map.add_object("1", Object::default()).is_err());
+
+        let value = map.to_value().unwrap();
+        let manifest_after = value["header"]["asset_manifest"].as_array().unwrap();
+        assert_eq!(manifest_after.len(), manifest + 1);
+        assert_eq!(manifest_after[manifest]["id"], "3DDXdf2M");
+        let objects = &value["world"]["levels"]["0"]["objects"];
+        assert_eq!(objects[0]["scale"], "Vector2( 1, 1 )");
+        assert_eq!(objects[0]["layer"], USER_LAYER);
+        assert_eq!(objects[1]["node_id"], "1");
+        assert_eq!(objects[1]["scale"], "Vector2( 2, 2 )");
+        assert_eq!(objects[1]["position"], "Vector2( 256, 512 )");
+    }
+
+    #[test]
+    fn test_paths_and_patterns() {
+        let mut builder = MapBuilder::new(4, 4).unwrap();
+        let road = [
+            Vector2 { x: 0.0, y: 128.0 },
+            Vector2 {
+                x: 1024.0,
+                y: 128.0,
+            },
+        ];
+        builder.add_node(
+            "paths",
+            path_node(&road, "res://textures/paths/cobbles.png", 64.0),
+        );
+        let rug = [
+            Vector2 { x: 256.0, y: 256.0 },
+            Vector2 { x: 512.0, y: 256.0 },
+            Vector2 { x: 512.0, y: 512.0 },
+        ];
+        builder.add_node(
+            "patterns",
+            pattern_node(&rug, "res://textures/patterns/rug.png"),
+        );
+
+        let map = MapFile::from_value(builder.build()).unwrap();
+        let level = &map.world.levels["0"];
+        assert_eq!(level.paths[0].edit_points, road);
+        assert_eq!(level.paths[0].width, 64.0);
+        assert!(!level.paths[0].closed);
+        assert_eq!(level.patterns[0].node_id.as_deref(), Some("1"));
+        assert_eq!(level.patterns[0].points.len(), 4);
+        assert_eq!(level.patterns[0].color.to_string(), "ffffffff");
+
+        let value = map.to_value().unwrap();
+        let level = &value["world"]["levels"]["0"];
+        assert_eq!(level["paths"][0]["loop"], false);
+        assert_eq!(level["patterns"][0]["layer"], USER_LAYER);
+        assert_eq!(MapFile::from_value(value).unwrap(), map);
+    }
+
+    #[test]
+    fn test_add_text() {
+        let mut map = MapFile::from_value(MapBuilder::new(4, 4).unwrap().build()).unwrap();
+        let mut label = Text::new(Vector2 { x: 512.0, y: 256.0 }, "Crypt", 60.0);
+        label.font_color = "#7f0000".parse().unwrap();
+        assert_eq!(map.add_text("0", label).unwrap(), "0");
+        assert!(map.add_text("1", Text::default()).is_err());
+
+        let value = map.to_value().unwrap();
+        let text = &value["world"]["levels"]["0"]["texts"][0];
+        assert_eq!(text["node_id"], "0");
+        assert_eq!(text["text"], "Crypt");
+        assert_eq!(text["font_name"], DEFAULT_FONT);
+        assert_eq!(text["font_size"], 60.0);
+        assert_eq!(text["font_color"], "ff7f0000");
+        assert_eq!(text["box_shape"], 0);
+        assert_eq!(
+            Text::new(Vector2::default(), "Crypt", 60.0)
+                .font_color
+                .to_string(),
+            DEFAULT_TEXT_COLOR
+        );
+    }
+
+    #[test]
+    fn test_terrain_textures() {
+        let mut map = MapFile::from_value(MapBuilder::new(2, 1).unwrap().build()).unwrap();
+        let terrain = map
+            .world
+            .levels
+            .get_mut("0")
+            .unwrap()
+            .terrain
+            .as_mut()
+            .unwrap();
+        assert!(terrain.splat.is_empty());
+        terrain
+            .set_texture(2, "res://textures/terrain/terrain_grass.png")
+            .unwrap();
+        assert!(terrain
+            .set_texture(4, "res://textures/terrain/terrain_grass.png")
+            .is_err());
+        terrain.splat = splat_bytes(&[0, 2]);
+
+        let value = map.to_value().unwrap();
+        let terrain = &value["world"]["levels"]["0"]["terrain"];
+        assert_eq!(
+            terrain["texture_3"],
+            "res://textures/terrain/terrain_grass.png"
+        );
+        assert_eq!(
+            terrain["splat"],
+            "PoolByteArray( 255, 0, 0, 0, 0, 0, 255, 0 )"
+        );
+        assert_eq!(MapFile::from_value(value).unwrap(), map);
+    }
+
+    #[test]
+    fn test_water_and_materials() {
+        let square = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 0.0 },
+            Vector2 { x: 256.0, y: 256.0 },
+            Vector2 { x: 0.0, y: 256.0 },
+        ];
+        let mut builder = MapBuilder::new(4, 4).unwrap();
+        builder.add_water(&square);
+        builder.add_material("res://textures/materials/stone.png", &square);
+        let value = builder.build();
+
+        let map = MapFile::from_value(value.clone()).unwrap();
+        assert_eq!(map.to_value().unwrap(), value);
+        let level = &map.world.levels["0"];
+        assert_eq!(level.water_bodies().len(), 1);
+        assert_eq!(level.water_bodies()[0].polygon.len(), 5);
+        assert_eq!(
+            level.water_bodies()[0].deep_color.to_string(),
+            DEFAULT_DEEP_WATER_COLOR
+        );
+        assert_eq!(level.material_count(), 1);
+
+        let level = &value["world"]["levels"]["0"];
+        assert_eq!(level["water"]["tree"]["ref"], -1);
+        assert_eq!(
+            level["water"]["tree"]["children"][0]["blend_distance"],
+            UNITS_PER_CELL
+        );
+        assert_eq!(
+            level["materials"]["res://textures/materials/stone.png"][0]["smooth"],
+            true
+        );
+    }
+
+    #[test]
+    fn test_now() {
+        let date = CreationDateBuilder::now();
+
+        assert!(date.is_ok());
+    }
+}
@@ -0,0 +1,461 @@
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::coordinates::cells_to_units;
+use crate::dungeondraft_v1::Vector2;
+
+#[derive(Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Condition {
+    field: String,
+    op: Op,
+    value: String,
+}
+
+/// Selects nodes from a collection of every level, e.g. `lights[color=#ffffff]`.
+///
+/// Multiple conditions are separated by commas and must all match.
+#[derive(Debug, PartialEq)]
+pub struct Selector {
+    collection: String,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug)]
+pub enum Edit {
+    Set(Vec<(String, Value)>),
+    Delete,
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Two character operators must be tried before their one character prefixes
+        const OPS: [(&str, Op); 6] = [
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("=", Op::Eq),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        for (token, op) in OPS {
+            if let Some((field, value)) = s.split_once(token) {
+                let field = field.trim();
+                if field.is_empty() {
+                    return Err(format!("missing field name in condition '{}'", s));
+                }
+                return Ok(Condition {
+                    field: field.to_string(),
+                    op,
+                    value: value.trim().to_string(),
+                });
+            }
+        }
+        Err(format!("missing operator in condition '{}'", s))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (collection, conditions) = match s.split_once('[') {
+            Some((collection, rest)) => {
+                let rest = rest
+                    .strip_suffix(']')
+                    .ok_or(format!("missing closing ']' in selector '{}'", s))?;
+                let conditions = rest
+                    .split(',')
+                    .filter(|c| !c.trim().is_empty())
+                    .map(Condition::from_str)
+                    .collect::<Result<Vec<_>, _>>()?;
+                (collection, conditions)
+            }
+            None => (s, Vec::new()),
+        };
+        if collection.is_empty() {
+            return Err(format!("missing collection name in selector '{}'", s));
+        }
+        Ok(Selector {
+            collection: collection.to_string(),
+            conditions,
+        })
+    }
+}
+
+/// Normalizes `#rrggbb` and `#aarrggbb` into the `aarrggbb` form used by map files.
+fn normalize_color(value: &str) -> Option<String> {
+    let hex = value.strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match hex.len() {
+        6 => Some(format!("ff{}", hex.to_lowercase())),
+        8 => Some(hex.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_lowercase(),
+        other => other.to_string(),
+    }
+}
+
+impl Condition {
+    fn matches(&self, node: &Value) -> bool {
+        let Some(actual) = node.get(&self.field) else {
+            return false;
+        };
+        let expected_number = self.value.parse::<f64>().ok();
+        match self.op {
+            Op::Eq | Op::Ne => {
+                let equal = match (as_number(actual), expected_number) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => {
+                        let expected = normalize_color(&self.value)
+                            .unwrap_or_else(|| self.value.to_lowercase());
+                        as_text(actual) == expected
+                    }
+                };
+                equal == (self.op == Op::Eq)
+            }
+            _ => match (as_number(actual), expected_number) {
+                (Some(a), Some(b)) => match self.op {
+                    Op::Lt => a < b,
+                    Op::Le => a <= b,
+                    Op::Gt => a > b,
+                    Op::Ge => a >= b,
+                    _ => unreachable!(),
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Selector {
+    fn matches(&self, node: &Value) -> bool {
+        self.conditions.iter().all(|c| c.matches(node))
+    }
+}
+
+/// Parses a `key=value` assignment given to `--set`.
+///
+/// Values are read as JSON literals when possible (numbers, booleans, null),
+/// `#rrggbb` values become map colors, and anything else is kept as a string.
+pub fn parse_assignment(s: &str) -> Result<(String, Value), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(format!("expected key=value but found '{}'", s))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("missing key in assignment '{}'", s));
+    }
+    let value = value.trim();
+    let value = match normalize_color(value) {
+        Some(color) => Value::String(color),
+        None => serde_json::from_str(value).unwrap_or(Value::String(value.to_string())),
+    };
+    Ok((key.to_string(), value))
+}
+
+/// Parses a placement such as `--add-door` takes, `x,y` or `x,y,n` in cells,
+/// returning its position in map units and `n` if given.
+fn parse_placement(s: &str, what: &str) -> Result<(Vector2, Option<f64>), String> {
+    let numbers = s
+        .split(',')
+        .map(|n| {
+            n.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("invalid number '{}' in {} '{}'", n.trim(), what, s))
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+    let (x, y, extra) = match numbers[..] {
+        [x, y] => (x, y, None),
+        [x, y, extra] => (x, y, Some(extra)),
+        _ => {
+            return Err(format!(
+                "expected x,y or x,y,n for {} but found '{}'",
+                what, s
+            ))
+        }
+    };
+    let position = Vector2 {
+        x: cells_to_units(x),
+        y: cells_to_units(y),
+    };
+    Ok((position, extra))
+}
+
+/// Parses a door given to `--add-door` as `x,y` or `x,y,degrees` in cells,
+/// returning its position in map units and its rotation in radians.
+pub fn parse_door(s: &str) -> Result<(Vector2, Option<f64>), String> {
+    let (position, degrees) = parse_placement(s, "door")?;
+    Ok((position, degrees.map(f64::to_radians)))
+}
+
+/// Parses a light given to `--add-light` as `x,y` or `x,y,range` in cells,
+/// returning its position in map units and its range in cells.
+pub fn parse_light(s: &str) -> Result<(Vector2, Option<f64>), String> {
+    match parse_placement(s, "light")? {
+        (_, Some(range)) if range <= 0.0 => Err(format!(
+            "invalid range {} for light '{}', expected more than 0",
+            range, s
+        )),
+        light => Ok(light),
+    }
+}
+
+/// Parses a setting of the nodes added, such as the intensity of lights,
+/// that must be more than 0.
+pub fn parse_positive(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|value: &f64| *value > 0.0 && value.is_finite())
+        .ok_or_else(|| format!("invalid value '{}', expected more than 0", s))
+}
+
+/// Parses an object given to `--add-object` as `x,y` or `x,y,degrees` in
+/// cells, returning its position in map units and its rotation in radians.
+pub fn parse_object(s: &str) -> Result<(Vector2, f64), String> {
+    let (position, degrees) = parse_placement(s, "object")?;
+    Ok((position, degrees.unwrap_or_default().to_radians()))
+}
+
+/// Parses a label given to `--add-text` as `x,y=text` in cells, returning
+/// its position in map units and its text.
+pub fn parse_text(s: &str) -> Result<(Vector2, String), String> {
+    let (at, text) = s
+        .split_once('=')
+        .ok_or(format!("expected x,y=text for text but found '{}'", s))?;
+    match parse_placement(at, "text")? {
+        (position, None) => Ok((position, text.to_string())),
+        (_, Some(_)) => Err(format!("expected x,y=text for text but found '{}'", s)),
+    }
+}
+
+fn collection_mut<'a>(level: &'a mut Value, path: &str) -> Option<&'a mut Vec<Value>> {
+    path.split('.')
+        .try_fold(level, |value, key| value.get_mut(key))?
+        .as_array_mut()
+}
+
+/// Applies an edit to every node matched by the selector, returning how many nodes matched.
+pub fn apply(map: &mut Value, selector: &Selector, edit: &Edit) -> usize {
+    let Some(levels) = map
+        .get_mut("world")
+        .and_then(|w| w.get_mut("levels"))
+        .and_then(Value::as_object_mut)
+    else {
+        return 0;
+    };
+
+    let mut count = 0;
+    for level in levels.values_mut() {
+        let Some(nodes) = collection_mut(level, &selector.collection) else {
+            continue;
+        };
+        match edit {
+            Edit::Set(assignments) => {
+                for node in nodes.iter_mut().filter(|n| selector.matches(n)) {
+                    if let Some(fields) = node.as_object_mut() {
+                        for (key, value) in assignments {
+                            fields.insert(key.clone(), value.clone());
+                        }
+                    }
+                    count += 1;
+                }
+            }
+            Edit::Delete => {
+                let before = nodes.len();
+                nodes.retain(|n| !selector.matches(n));
+                count += before - nodes.len();
+            }
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map() -> Value {
+        json!({
+            "world": {
+                "levels": {
+                    "0": {
+                        "lights": [
+                            { "color": "ffffffff", "intensity": 1, "node_id": "1" },
+                            { "color": "ffeccd8b", "intensity": 1, "node_id": "2" }
+                        ],
+                        "walls": [
+                            { "width": 2, "node_id": "3" },
+                            { "width": 8, "node_id": "4" }
+                        ],
+                        "roofs": { "roofs": [ { "type": 0 } ] }
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        let selector: Selector = "walls[width<4]".parse().unwrap();
+        assert_eq!(selector.collection, "walls");
+        assert_eq!(
+            selector.conditions,
+            vec![Condition {
+                field: "width".to_string(),
+                op: Op::Lt,
+                value: "4".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_selector_without_conditions() {
+        let selector: Selector = "lights".parse().unwrap();
+        assert!(selector.conditions.is_empty());
+    }
+
+    #[test]
+    fn test_parse_selector_errors() {
+        assert!("walls[width<4".parse::<Selector>().is_err());
+        assert!("[width<4]".parse::<Selector>().is_err());
+        assert!("walls[width]".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            parse_assignment("intensity=0.5").unwrap(),
+            ("intensity".to_string(), json!(0.5))
+        );
+        assert_eq!(
+            parse_assignment("color=#FF0000").unwrap(),
+            ("color".to_string(), json!("ffff0000"))
+        );
+        assert_eq!(
+            parse_assignment("texture=res://textures/lights/soft.png").unwrap(),
+            (
+                "texture".to_string(),
+                json!("res://textures/lights/soft.png")
+            )
+        );
+        assert!(parse_assignment("intensity").is_err());
+    }
+
+    #[test]
+    fn test_parse_door() {
+        let (position, rotation) = parse_door("2.5, 1").unwrap();
+        assert_eq!(position, Vector2 { x: 640.0, y: 256.0 });
+        assert_eq!(rotation, None);
+        let (_, rotation) = parse_door("2,1,90").unwrap();
+        assert_eq!(rotation, Some(90f64.to_radians()));
+        assert!(parse_door("2").is_err());
+        assert!(parse_door("2,north").is_err());
+    }
+
+    #[test]
+    fn test_parse_light() {
+        let (position, range) = parse_light("1,2,6").unwrap();
+        assert_eq!(position, Vector2 { x: 256.0, y: 512.0 });
+        assert_eq!(range, Some(6.0));
+        assert!(parse_light("1,2,3,4").is_err());
+        assert!(parse_light("1,2,0").is_err());
+        assert!(parse_light("1,2,-6").is_err());
+        assert!(parse_light("NaN,2").is_err());
+        assert!(parse_light("1,2,inf").is_err());
+    }
+
+    #[test]
+    fn test_parse_positive() {
+        assert_eq!(parse_positive("0.8"), Ok(0.8));
+        assert!(parse_positive("0").is_err());
+        assert!(parse_positive("-1").is_err());
+        assert!(parse_positive("NaN").is_err());
+        assert!(parse_positive("inf").is_err());
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let (position, rotation) = parse_object("0.5,2,180").unwrap();
+        assert_eq!(position, Vector2 { x: 128.0, y: 512.0 });
+        assert_eq!(rotation, std::f64::consts::PI);
+        assert_eq!(parse_object("1,1").unwrap().1, 0.0);
+        assert!(parse_object("1").is_err());
+    }
+
+    #[test]
+    fn test_parse_text() {
+        assert_eq!(
+            parse_text("2,3.5=Hall of Kings").unwrap(),
+            (Vector2 { x: 512.0, y: 896.0 }, "Hall of Kings".to_string())
+        );
+        assert!(parse_text("2,3").is_err());
+        assert!(parse_text("2,3,4=Hall").is_err());
+    }
+
+    #[test]
+    fn test_set_matching_color() {
+        let mut map = map();
+        let selector = "lights[color=#ffffff]".parse().unwrap();
+        let edit = Edit::Set(vec![parse_assignment("intensity=0.5").unwrap()]);
+        assert_eq!(apply(&mut map, &selector, &edit), 1);
+        let lights = &map["world"]["levels"]["0"]["lights"];
+        assert_eq!(lights[0]["intensity"], json!(0.5));
+        assert_eq!(lights[1]["intensity"], json!(1));
+    }
+
+    #[test]
+    fn test_delete_matching_width() {
+        let mut map = map();
+        let selector = "walls[width<4]".parse().unwrap();
+        assert_eq!(apply(&mut map, &selector, &Edit::Delete), 1);
+        let walls = map["world"]["levels"]["0"]["walls"].as_array().unwrap();
+        assert_eq!(walls.len(), 1);
+        assert_eq!(walls[0]["node_id"], json!("4"));
+    }
+
+    #[test]
+    fn test_nested_collection() {
+        let mut map = map();
+        let selector = "roofs.roofs[type=0]".parse().unwrap();
+        assert_eq!(apply(&mut map, &selector, &Edit::Delete), 1);
+    }
+
+    #[test]
+    fn test_missing_field_never_matches() {
+        let mut map = map();
+        let selector = "walls[texture!=none]".parse().unwrap();
+        assert_eq!(apply(&mut map, &selector, &Edit::Delete), 0);
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use opencv::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+
+use crate::dungeon::{generate_dungeon, DungeonOptions};
+use crate::dungeondraft_v1::{
+    is_loop, node_points, node_position, nodes, read_map, Vector2, UNITS_PER_CELL,
+};
+use crate::geometry::{distance, segment_distance};
+use crate::images::read_map_image;
+
+/// Walls are compared on a grid of this share of a cell.
+const WALL_SAMPLE: f64 = UNITS_PER_CELL / 16.0;
+/// How far from a wall still counts as on it, so walls a little off overlap.
+const WALL_TOLERANCE: f64 = UNITS_PER_CELL / 8.0;
+/// How far a detected door may be from a reference door and still be it.
+const DOOR_TOLERANCE: f64 = UNITS_PER_CELL / 2.0;
+/// The extension of reference maps in a corpus.
+const REFERENCE_EXTENSION: &str = "dungeondraft_map";
+
+/// How closely a generated map matches a reference map, each from 0 to 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Scores {
+    /// The intersection over union of the ground the walls cover
+    pub wall_iou: f64,
+    /// The share of generated doors that are reference doors
+    pub door_precision: f64,
+    /// The share of reference doors that were generated
+    pub door_recall: f64,
+    /// One less how far off the number of rooms is, as a share of the reference
+    pub room_count_accuracy: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Evaluation {
+    pub image: String,
+    pub reference: String,
+    pub rooms: usize,
+    pub reference_rooms: usize,
+    pub scores: Scores,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorpusReport {
+    pub maps: Vec<Evaluation>,
+    /// The scores averaged over every map
+    pub mean: Scores,
+}
+
+/// Every level's wall loops and lines, by level name.
+fn walls(map: &Value) -> Vec<(String, Vec<Vector2>, bool)> {
+    levels(map)
+        .flat_map(|(name, level)| {
+            nodes(level, "walls")
+                .filter_map(move |wall| Some((name.clone(), node_points(wall)?, is_loop(wall))))
+        })
+        .collect()
+}
+
+/// Where every door of every level is, by level name, whether portals of the
+/// level or of its walls.
+fn doors(map: &Value) -> Vec<(String, Vector2)> {
+    levels(map)
+        .flat_map(|(name, level)| {
+            nodes(level, "portals")
+                .chain(nodes(level, "walls").flat_map(|wall| nodes(wall, "portals")))
+                .filter_map(move |portal| Some((name.clone(), node_position(portal)?)))
+        })
+        .collect()
+}
+
+fn levels(map: &Value) -> impl Iterator<Item = (String, &Value)> {
+    map.pointer("/world/levels")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(name, level)| (name.clone(), level))
+}
+
+/// The samples of the grid within `WALL_TOLERANCE` of a map's walls.
+fn wall_samples(map: &Value) -> HashSet<(String, i64, i64)> {
+    let mut samples = HashSet::new();
+    for (level, points, closed) in walls(map) {
+        if points.len() < 2 {
+            continue;
+        }
+        let segments = if closed {
+            points.len()
+        } else {
+            points.len() - 1
+        };
+        for i in 0..segments {
+            let (a, b) = (points[i], points[(i + 1) % points.len()]);
+            let span = |a: f64, b: f64| {
+                (
+                    ((a.min(b) - WALL_TOLERANCE) / WALL_SAMPLE).floor() as i64,
+                    ((a.max(b) + WALL_TOLERANCE) / WALL_SAMPLE).ceil() as i64,
+                )
+            };
+            let ((x0, x1), (y0, y1)) = (span(a.x, b.x), span(a.y, b.y));
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    let sample = Vector2 {
+                        x: x as f64 * WALL_SAMPLE,
+                        y: y as f64 * WALL_SAMPLE,
+                    };
+                    if segment_distance(sample, a, b) <= WALL_TOLERANCE {
+                        samples.insert((level.clone(), x, y));
+                    }
+                }
+            }
+        }
+    }
+    samples
+}
+
+/// The share of `found` within `DOOR_TOLERANCE` of a door of `expected` on the
+/// same level, each door of `expected` matched at most once.
+fn matched_share(found: &[(String, Vector2)], expected: &[(String, Vector2)]) -> f64 {
+    if found.is_empty() {
+        return 1.0;
+    }
+    let mut unmatched: Vec<&(String, Vector2)> = expected.iter().collect();
+    let mut matched = 0;
+    for (level, position) in found {
+        let nearest = unmatched
+            .iter()
+            .enumerate()
+            .filter(|(_, (l, _))| l == level)
+            .map(|(i, (_, p))| (i, distance(*position, *p)))
+            .filter(|&(_, d)| d <= DOOR_TOLERANCE)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, _)) = nearest {
+            unmatched.swap_remove(i);
+            matched += 1;
+        }
+    }
+    matched as f64 / found.len() as f64
+}
+
+fn rooms(map: &Value) -> usize {
+    walls(map).iter().filter(|(_, _, closed)| *closed).count()
+}
+
+/// Scores a generated map against a reference map of the same image.
+pub fn score(generated: &Value, reference: &Value) -> Scores {
+    let (found, expected) = (wall_samples(generated), wall_samples(reference));
+    let union = found.union(&expected).count();
+    let wall_iou = if union == 0 {
+        1.0
+    } else {
+        found.intersection(&expected).count() as f64 / union as f64
+    };
+    let (found_doors, expected_doors) = (doors(generated), doors(reference));
+    let (found_rooms, expected_rooms) = (rooms(generated) as f64, rooms(reference) as f64);
+    Scores {
+        wall_iou,
+        door_precision: matched_share(&found_doors, &expected_doors),
+        door_recall: matched_share(&expected_doors, &found_doors),
+        room_count_accuracy: (1.0 - (found_rooms - expected_rooms).abs() / expected_rooms.max(1.0))
+            .max(0.0),
+    }
+}
+
+/// The images of a corpus directory with a reference map of the same name.
+pub fn corpus_pairs(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn std::error::Error>> {
+    let mut pairs: Vec<(PathBuf, PathBuf)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext != REFERENCE_EXTENSION && ext != "bak")
+        })
+        .map(|image| {
+            let reference = image.with_extension(REFERENCE_EXTENSION);
+            (image, reference)
+        })
+        .filter(|(_, reference)| reference.is_file())
+        .collect();
+    pairs.sort();
+    Ok(pairs)
+}
+
+/// Generates a dungeon from every image of a corpus and scores it against
+/// its reference map.
+pub fn evaluate_corpus(
+    dir: &Path,
+    options: &DungeonOptions,
+    cells_per_inch: f64,
+) -> Result<CorpusReport, Box<dyn std::error::Error>> {
+    let pairs = corpus_pairs(dir)?;
+    if pairs.is_empty() {
+        return Err(format!(
+            "{} has no images with a .{} reference map",
+            dir.display(),
+            REFERENCE_EXTENSION
+        )
+        .into());
+    }
+    let mut maps = Vec::new();
+    for (image_path, reference_path) in pairs {
+        let image = read_map_image(&image_path)?;
+        if image.empty() {
+            return Err(format!("failed to read image {}", image_path.display()).into());
+        }
+        let mut options = options.clone();
+        options.read_scale(&image_path, &image, cells_per_inch)?;
+        let generated = generate_dungeon(&image, &options)?;
+        let reference = read_map(&reference_path)?;
+        let scores = score(&generated, &reference);
+        info!(
+            "{}: wall IoU {:.3}, door precision {:.3}, recall {:.3}, room count accuracy {:.3}",
+            image_path.display(),
+            scores.wall_iou,
+            scores.door_precision,
+            scores.door_recall,
+            scores.room_count_accuracy
+        );
+        maps.push(Evaluation {
+            image: image_path.display().to_string(),
+            reference: reference_path.display().to_string(),
+            rooms: rooms(&generated),
+            reference_rooms: rooms(&reference),
+            scores,
+        });
+    }
+    let n = maps.len() as f64;
+    let mean = Scores {
+        wall_iou: maps.iter().map(|m| m.scores.wall_iou).sum::<f64>() / n,
+        door_precision: maps.iter().map(|m| m.scores.door_precision).sum::<f64>() / n,
+        door_recall: maps.iter().map(|m| m.scores.door_recall).sum::<f64>() / n,
+        room_count_accuracy: maps
+            .iter()
+            .map(|m| m.scores.room_count_accuracy)
+            .sum::<f64>()
+            / n,
+    };
+    Ok(CorpusReport { maps, mean })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map(walls: Value) -> Value {
+        json!({ "world": { "levels": { "0": { "walls": walls } } } })
+    }
+
+    #[test]
+    fn test_score() {
+        let reference = map(json!([
+            {
+                "loop": true,
+                "points": "PoolVector2Array( 0, 0, 1024, 0, 1024, 1024, 0, 1024 )",
+                "portals": [
+                    { "position": "Vector2( 1024, 512 )" },
+                    { "position": "Vector2( 512, 0 )" }
+                ]
+            },
+            {
+                "loop": true,
+                "points": "PoolVector2Array( 1024, 0, 2048, 0, 2048, 1024, 1024, 1024 )"
+            }
+        ]));
+        let perfect = score(&reference, &reference);
+        assert_eq!(perfect.wall_iou, 1.0);
+        assert_eq!(perfect.door_precision, 1.0);
+        assert_eq!(perfect.door_recall, 1.0);
+        assert_eq!(perfect.room_count_accuracy, 1.0);
+
+        // One room a little off, a door near the first, and one that isn't
+        let generated = map(json!([
+            {
+                "loop": true,
+                "points": "PoolVector2Array( 8, 8, 1016, 8, 1016, 1016, 8, 1016 )",
+                "portals": [
+                    { "position": "Vector2( 1000, 540 )" },
+                    { "position": "Vector2( 0, 900 )" }
+                ]
+            }
+        ]));
+        let scores = score(&generated, &reference);
+        assert!(scores.wall_iou > 0.3 && scores.wall_iou < 0.7);
+        assert_eq!(scores.door_precision, 0.5);
+        assert_eq!(scores.door_recall, 0.5);
+        assert_eq!(scores.room_count_accuracy, 0.5);
+    }
+}
@@ -0,0 +1,175 @@
+use opencv::core::{self, Mat, Scalar};
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+use crate::images::{trace_outlines, ColorRange};
+
+/// Regions of less than this share of a cell are specks of color.
+const MIN_REGION_AREA: f64 = 0.25;
+/// Outlines of regions are simplified to within this share of their perimeter.
+const REGION_EPSILON: f64 = 0.005;
+
+/// The colors floors and walls are filled with on maps drawn with fill
+/// colors, such as white floors inside black walls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FillColors {
+    pub floor: ColorRange,
+    /// Without it, walls are whatever else is drawn, bounding the floors
+    pub wall: Option<ColorRange>,
+}
+
+/// A mask of each class of pixel of an image filled with colors, every pixel
+/// in at most one of them and the rest background.
+pub struct FillMasks {
+    pub floor: Mat,
+    pub wall: Mat,
+    pub water: Mat,
+}
+
+/// The regions of a map filled with colors, in map units.
+#[derive(Debug, Default)]
+pub struct Fills {
+    /// The floors of rooms, walled around their outlines
+    pub floors: Vec<Vec<Vector2>>,
+    /// The outlines of the regions filled with the wall color, the thin ones
+    /// being walls standing on their own rather than around a floor
+    pub walls: Vec<Vec<Vector2>>,
+    pub water: Vec<Vec<Vector2>>,
+}
+
+/// The pixels of `mask` that are not in `other`.
+fn without(mask: &Mat, other: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut outside = Mat::default();
+    core::bitwise_not(other, &mut outside, &core::no_array())?;
+    let mut kept = Mat::default();
+    core::bitwise_and(mask, &outside, &mut kept, &core::no_array())?;
+    Ok(kept)
+}
+
+/// Splits an image into floor, wall, and water masks at once, so each pixel
+/// is of one class. Walls are drawn over water, and water over floors, so
+/// where the ranges overlap the pixel goes to the one drawn on top.
+pub fn segment_fills(
+    image: &Mat,
+    colors: &FillColors,
+    water: Option<&ColorRange>,
+) -> Result<FillMasks, Box<dyn std::error::Error>> {
+    let empty = || {
+        Mat::new_rows_cols_with_default(image.rows(), image.cols(), core::CV_8UC1, Scalar::all(0.0))
+    };
+    let wall = match &colors.wall {
+        Some(range) => range.mask(image)?,
+        None => empty()?,
+    };
+    let water = match water {
+        Some(range) => without(&range.mask(image)?, &wall)?,
+        None => empty()?,
+    };
+    let floor = without(&without(&colors.floor.mask(image)?, &wall)?, &water)?;
+    Ok(FillMasks { floor, wall, water })
+}
+
+/// Finds the floors, walls, and water of a map drawn with fill colors, each
+/// traced from its own mask.
+#[instrument(skip_all)]
+pub fn trace_fills(
+    image: &Mat,
+    colors: &FillColors,
+    water: Option<&ColorRange>,
+    mapper: &CoordinateMapper,
+) -> Result<Fills, Box<dyn std::error::Error>> {
+    let masks = segment_fills(image, colors, water)?;
+    let min_area = MIN_REGION_AREA * mapper.pixels_per_cell * mapper.pixels_per_cell;
+    let regions = |mask: &Mat| -> Result<_, Box<dyn std::error::Error>> {
+        let outlines = trace_outlines(
+            mask,
+            imgproc::RETR_EXTERNAL,
+            min_area,
+            REGION_EPSILON,
+            mapper,
+        )?;
+        Ok(outlines
+            .into_iter()
+            .filter(|outline| outline.len() > 2)
+            .collect::<Vec<_>>())
+    };
+    let fills = Fills {
+        floors: regions(&masks.floor)?,
+        walls: regions(&masks.wall)?,
+        water: regions(&masks.water)?,
+    };
+    debug!(
+        "Segmented {} floors, {} walls, and {} pools of water",
+        fills.floors.len(),
+        fills.walls.len(),
+        fills.water.len()
+    );
+    Ok(fills)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::{HsvRange, LabRange};
+
+    #[test]
+    fn test_trace_fills() {
+        // A white floor inside black walls, with a blue pool and a black
+        // partition standing on the floor, on a gray background
+        let mut image = Mat::new_rows_cols_with_default(
+            200,
+            200,
+            core::CV_8UC3,
+            Scalar::new(128.0, 128.0, 128.0, 0.0),
+        )
+        .unwrap();
+        let mut fill = |x: i32, y: i32, width: i32, height: i32, bgr: [f64; 3]| {
+            imgproc::rectangle(
+                &mut image,
+                core::Rect::new(x, y, width, height),
+                Scalar::new(bgr[0], bgr[1], bgr[2], 0.0),
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )
+            .unwrap();
+        };
+        fill(10, 10, 180, 180, [0.0, 0.0, 0.0]);
+        fill(20, 20, 160, 160, [255.0, 255.0, 255.0]);
+        fill(30, 30, 40, 40, [255.0, 0.0, 0.0]);
+        fill(100, 40, 6, 100, [0.0, 0.0, 0.0]);
+        let colors = FillColors {
+            floor: ColorRange::Lab(LabRange {
+                low: [230, 118, 118],
+                high: [255, 138, 138],
+            }),
+            wall: Some(ColorRange::Lab(LabRange {
+                low: [0, 118, 118],
+                high: [40, 138, 138],
+            })),
+        };
+        let water = ColorRange::Hsv(HsvRange {
+            low: [100, 80, 40],
+            high: [130, 255, 255],
+        });
+
+        let masks = segment_fills(&image, &colors, Some(&water)).unwrap();
+        let count = |mask: &Mat| core::count_non_zero(mask).unwrap();
+        assert_eq!(count(&masks.water), 40 * 40);
+        assert_eq!(count(&masks.floor), 160 * 160 - 40 * 40 - 6 * 100);
+        assert_eq!(count(&masks.wall), 180 * 180 - 160 * 160 + 6 * 100);
+        let mut overlap = Mat::default();
+        core::bitwise_and(&masks.floor, &masks.wall, &mut overlap, &core::no_array()).unwrap();
+        assert_eq!(count(&overlap), 0);
+
+        let fills =
+            trace_fills(&image, &colors, Some(&water), &CoordinateMapper::new(20.0)).unwrap();
+        assert_eq!(fills.floors.len(), 1);
+        assert_eq!(fills.water.len(), 1);
+        // The walls around the floor, and the partition standing on it
+        assert_eq!(fills.walls.len(), 2);
+    }
+}
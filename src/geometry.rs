@@ -0,0 +1,776 @@
+use geo::orient::{Direction, Orient};
+use geo::BooleanOps;
+
+use crate::dungeondraft_v1::Vector2;
+
+/// An area of the map, outlined with holes cut out of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub outline: Vec<Vector2>,
+    pub holes: Vec<Vec<Vector2>>,
+}
+
+/// The area of a simple polygon using the shoelace formula, positive when its
+/// points run clockwise on the map, whose y axis points down.
+pub fn signed_area(points: &[Vector2]) -> f64 {
+    let twice_area: f64 = points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.x * b.y - b.x * a.y)
+        .sum();
+    twice_area / 2.0
+}
+
+/// The unsigned area of a simple polygon.
+pub fn polygon_area(points: &[Vector2]) -> f64 {
+    signed_area(points).abs()
+}
+
+/// The points of a closed polygon running clockwise on the map, or
+/// counter-clockwise, from the same first point and without repeating it.
+pub fn wind(points: &[Vector2], clockwise: bool) -> Vec<Vector2> {
+    let mut points = points.to_vec();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    if points.len() > 2 && (signed_area(&points) < 0.0) == clockwise {
+        points[1..].reverse();
+    }
+    points
+}
+
+/// The points of a closed polygon ending back at its first point.
+pub fn close_ring(points: &[Vector2]) -> Vec<Vector2> {
+    let mut points = points.to_vec();
+    if let (Some(&first), Some(last)) = (points.first(), points.last()) {
+        if first != *last {
+            points.push(first);
+        }
+    }
+    points
+}
+
+pub fn distance(a: Vector2, b: Vector2) -> f64 {
+    (a.x - b.x).hypot(a.y - b.y)
+}
+
+/// Whether the point lies inside the polygon, using the even-odd rule.
+pub fn contains_point(polygon: &[Vector2], point: Vector2) -> bool {
+    let mut inside = false;
+    for (a, b) in polygon.iter().zip(polygon.iter().cycle().skip(1)) {
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// The shortest distance from the point to the segment between `a` and `b`.
+pub fn segment_distance(point: Vector2, a: Vector2, b: Vector2) -> f64 {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return distance(point, a);
+    }
+    let t = (((point.x - a.x) * dx + (point.y - a.y) * dy) / length_squared).clamp(0.0, 1.0);
+    distance(
+        point,
+        Vector2 {
+            x: a.x + t * dx,
+            y: a.y + t * dy,
+        },
+    )
+}
+
+/// The shortest distance from the point to the edges of a closed polygon.
+pub fn boundary_distance(polygon: &[Vector2], point: Vector2) -> f64 {
+    polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(a, b)| segment_distance(point, *a, *b))
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// The index of the closed polygon edge nearest the point, counted by its starting point.
+pub fn nearest_edge(polygon: &[Vector2], point: Vector2) -> Option<usize> {
+    polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(a, b)| segment_distance(point, *a, *b))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+}
+
+/// The center of mass of a polygon, or the average of its points when it has no area.
+pub fn centroid(polygon: &[Vector2]) -> Option<Vector2> {
+    if polygon.is_empty() {
+        return None;
+    }
+    let (mut twice_area, mut x, mut y) = (0.0, 0.0, 0.0);
+    for (a, b) in polygon.iter().zip(polygon.iter().cycle().skip(1)) {
+        let cross = a.x * b.y - b.x * a.y;
+        twice_area += cross;
+        x += (a.x + b.x) * cross;
+        y += (a.y + b.y) * cross;
+    }
+    if twice_area == 0.0 {
+        let count = polygon.len() as f64;
+        return Some(Vector2 {
+            x: polygon.iter().map(|p| p.x).sum::<f64>() / count,
+            y: polygon.iter().map(|p| p.y).sum::<f64>() / count,
+        });
+    }
+    Some(Vector2 {
+        x: x / (3.0 * twice_area),
+        y: y / (3.0 * twice_area),
+    })
+}
+
+/// The length around a closed polygon.
+pub fn perimeter(polygon: &[Vector2]) -> f64 {
+    polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(a, b)| distance(*a, *b))
+        .sum()
+}
+
+/// How wide a long thin polygon is across, from its area over half its
+/// perimeter, which for a strip is its width.
+pub fn thickness(polygon: &[Vector2]) -> f64 {
+    let perimeter = perimeter(polygon);
+    if perimeter == 0.0 {
+        return 0.0;
+    }
+    2.0 * polygon_area(polygon) / perimeter
+}
+
+/// The line along the middle of a long thin polygon, its longest axis
+/// through its centroid, ending half its thickness inside its ends.
+pub fn centerline(polygon: &[Vector2]) -> Option<(Vector2, Vector2)> {
+    let center = centroid(polygon)?;
+    let (mut xx, mut xy, mut yy) = (0.0, 0.0, 0.0);
+    for p in polygon {
+        let (dx, dy) = (p.x - center.x, p.y - center.y);
+        xx += dx * dx;
+        xy += dx * dy;
+        yy += dy * dy;
+    }
+    let angle = 0.5 * (2.0 * xy).atan2(xx - yy);
+    let (sin, cos) = angle.sin_cos();
+    let along = |p: &Vector2| (p.x - center.x) * cos + (p.y - center.y) * sin;
+    let inset = thickness(polygon) / 2.0;
+    let start = polygon.iter().map(along).fold(f64::INFINITY, f64::min) + inset;
+    let end = polygon.iter().map(along).fold(f64::NEG_INFINITY, f64::max) - inset;
+    let (start, end) = if start < end {
+        (start, end)
+    } else {
+        ((start + end) / 2.0, (start + end) / 2.0)
+    };
+    let at = |t: f64| Vector2 {
+        x: center.x + cos * t,
+        y: center.y + sin * t,
+    };
+    Some((at(start), at(end)))
+}
+
+/// The minimum and maximum corners of the axis aligned bounding box.
+pub fn bounds(points: &[Vector2]) -> Option<(Vector2, Vector2)> {
+    let first = *points.first()?;
+    Some(points.iter().fold((first, first), |(min, max), p| {
+        (
+            Vector2 {
+                x: min.x.min(p.x),
+                y: min.y.min(p.y),
+            },
+            Vector2 {
+                x: max.x.max(p.x),
+                y: max.y.max(p.y),
+            },
+        )
+    }))
+}
+
+/// Straightens the edges of a closed polygon that lie within `tolerance`
+/// degrees of horizontal or vertical, moving both ends to their mean.
+pub fn orthogonalize(polygon: &[Vector2], tolerance: f64) -> Vec<Vector2> {
+    let mut points = polygon.to_vec();
+    let count = points.len();
+    for i in 0..count {
+        let j = (i + 1) % count;
+        let (a, b) = (points[i], points[j]);
+        let angle = (b.y - a.y).atan2(b.x - a.x).to_degrees().abs();
+        // Folded into 0 to 90 degrees from horizontal
+        let angle = angle.min(180.0 - angle);
+        if angle <= tolerance {
+            let y = (a.y + b.y) / 2.0;
+            points[i].y = y;
+            points[j].y = y;
+        } else if 90.0 - angle <= tolerance {
+            let x = (a.x + b.x) / 2.0;
+            points[i].x = x;
+            points[j].x = x;
+        }
+    }
+    points
+}
+
+/// Whether a closed polygon is drawn freehand, such as a cave or a coastline,
+/// rather than with straight walls: many points, and less than half its
+/// length along edges within `tolerance` degrees of horizontal or vertical.
+pub fn is_organic(polygon: &[Vector2], tolerance: f64) -> bool {
+    if polygon.len() < 8 {
+        return false;
+    }
+    let straight: f64 = polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .filter(|(a, b)| {
+            let angle = (b.y - a.y).atan2(b.x - a.x).to_degrees().abs();
+            let angle = angle.min(180.0 - angle);
+            angle <= tolerance || 90.0 - angle <= tolerance
+        })
+        .map(|(a, b)| distance(*a, *b))
+        .sum();
+    straight < perimeter(polygon) / 2.0
+}
+
+/// A closed polygon as a smooth curve, a Catmull-Rom spline through points
+/// about `spacing` apart along it, sampled at those points and halfway
+/// between them.
+pub fn smooth_outline(polygon: &[Vector2], spacing: f64) -> Vec<Vector2> {
+    let control = merge_short_edges(polygon, spacing, true);
+    let count = control.len();
+    if count < 4 {
+        return control;
+    }
+    let mut points = Vec::with_capacity(2 * count);
+    for i in 0..count {
+        let [p0, p1, p2, p3] = [count - 1, 0, 1, 2].map(|k| control[(i + k) % count]);
+        points.push(p1);
+        points.push(Vector2 {
+            x: (-p0.x + 9.0 * p1.x + 9.0 * p2.x - p3.x) / 16.0,
+            y: (-p0.y + 9.0 * p1.y + 9.0 * p2.y - p3.y) / 16.0,
+        });
+    }
+    points
+}
+
+/// An open line with its corners cut `iterations` times, each time replacing
+/// every inner point with points a quarter of the way along the edges either
+/// side of it, Chaikin's way, so staircases traced from pixels round off into
+/// curves. The ends stay where they are.
+pub fn chaikin(points: &[Vector2], iterations: usize) -> Vec<Vector2> {
+    let along = |a: Vector2, b: Vector2, t: f64| Vector2 {
+        x: a.x + (b.x - a.x) * t,
+        y: a.y + (b.y - a.y) * t,
+    };
+    let mut line = points.to_vec();
+    for _ in 0..iterations {
+        let count = line.len();
+        if count < 3 {
+            break;
+        }
+        let mut cut = Vec::with_capacity(2 * count);
+        cut.push(line[0]);
+        for (i, pair) in line.windows(2).enumerate() {
+            if i > 0 {
+                cut.push(along(pair[0], pair[1], 0.25));
+            }
+            if i < count - 2 {
+                cut.push(along(pair[0], pair[1], 0.75));
+            }
+        }
+        cut.push(line[count - 1]);
+        line = cut;
+    }
+    line
+}
+
+/// Merges runs of points closer together than `min_length` into their mean,
+/// taking out the short edges between them. A closed polygon keeps at least
+/// three points and an open one its two ends.
+pub fn merge_short_edges(points: &[Vector2], min_length: f64, closed: bool) -> Vec<Vector2> {
+    let mean = |run: &[Vector2]| Vector2 {
+        x: run.iter().map(|p| p.x).sum::<f64>() / run.len() as f64,
+        y: run.iter().map(|p| p.y).sum::<f64>() / run.len() as f64,
+    };
+    let mut runs: Vec<Vec<Vector2>> = Vec::new();
+    for &p in points {
+        match runs.last_mut() {
+            Some(run) if distance(run[0], p) < min_length => run.push(p),
+            _ => runs.push(vec![p]),
+        }
+    }
+    if closed && runs.len() > 1 && distance(runs[runs.len() - 1][0], runs[0][0]) < min_length {
+        let last = runs.pop().unwrap_or_default();
+        runs[0].extend(last);
+    }
+    let merged: Vec<Vector2> = if closed {
+        runs.iter().map(|run| mean(run)).collect()
+    } else {
+        // The ends of open walls stay where they are
+        let count = runs.len();
+        runs.iter()
+            .enumerate()
+            .map(|(i, run)| match i {
+                0 => run[0],
+                i if i == count - 1 => run[run.len() - 1],
+                _ => mean(run),
+            })
+            .collect()
+    };
+    let min_points = if closed { 3 } else { 2 };
+    if merged.len() < min_points && points.len() >= min_points {
+        return points.to_vec();
+    }
+    merged
+}
+
+/// Closes an outline traced around a boundary with a gap in it, such as the
+/// walls of a room with a small break, by bridging the nearest two of its
+/// points no more than `max_gap` apart across which `is_open`.
+///
+/// The two points must be at least four gaps apart along the outline either
+/// way, so its corners are not taken for gaps. Of the two outlines either side
+/// of the bridge, the larger is kept, if larger than the outline was.
+pub fn close_gap(
+    outline: &[Vector2],
+    max_gap: f64,
+    is_open: impl Fn(Vector2, Vector2) -> bool,
+) -> Option<Vec<Vector2>> {
+    let count = outline.len();
+    if count < 4 || max_gap <= 0.0 {
+        return None;
+    }
+    // How far along the outline each point is
+    let mut along = Vec::with_capacity(count);
+    let mut length = 0.0;
+    for i in 0..count {
+        along.push(length);
+        length += distance(outline[i], outline[(i + 1) % count]);
+    }
+    let mut bridges = Vec::new();
+    for i in 0..count {
+        for j in i + 1..count {
+            let gap = distance(outline[i], outline[j]);
+            let apart = along[j] - along[i];
+            if gap <= max_gap && apart.min(length - apart) >= 4.0 * max_gap {
+                bridges.push((gap, i, j));
+            }
+        }
+    }
+    bridges.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let &(_, i, j) = bridges
+        .iter()
+        .find(|(_, i, j)| is_open(outline[*i], outline[*j]))?;
+    let inside = outline[i..=j].to_vec();
+    let outside: Vec<Vector2> = outline[j..].iter().chain(&outline[..=i]).copied().collect();
+    let closed = if polygon_area(&inside) >= polygon_area(&outside) {
+        inside
+    } else {
+        outside
+    };
+    (polygon_area(&closed) > polygon_area(outline)).then_some(closed)
+}
+
+/// Where the segments from `a` to `b` and from `c` to `d` meet, if they do.
+/// Overlapping collinear segments meet where the overlap starts.
+pub fn segment_intersection(a: Vector2, b: Vector2, c: Vector2, d: Vector2) -> Option<Vector2> {
+    let cross = |ux: f64, uy: f64, vx: f64, vy: f64| ux * vy - uy * vx;
+    let (rx, ry) = (b.x - a.x, b.y - a.y);
+    let (sx, sy) = (d.x - c.x, d.y - c.y);
+    let (qx, qy) = (c.x - a.x, c.y - a.y);
+    let at = |t: f64| Vector2 {
+        x: a.x + t * rx,
+        y: a.y + t * ry,
+    };
+    let denominator = cross(rx, ry, sx, sy);
+    if denominator.abs() <= f64::EPSILON {
+        let length_squared = rx * rx + ry * ry;
+        if cross(qx, qy, rx, ry).abs() > f64::EPSILON || length_squared == 0.0 {
+            return None;
+        }
+        // Where either end of the second segment falls along the first
+        let t0 = (qx * rx + qy * ry) / length_squared;
+        let t1 = ((d.x - a.x) * rx + (d.y - a.y) * ry) / length_squared;
+        let (start, end) = (t0.min(t1), t0.max(t1));
+        return (start <= 1.0 && end >= 0.0).then(|| at(start.max(0.0)));
+    }
+    let t = cross(qx, qy, sx, sy) / denominator;
+    let u = cross(qx, qy, rx, ry) / denominator;
+    ((0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u)).then(|| at(t))
+}
+
+/// Where the edges of a closed polygon first cross or touch each other, other
+/// than neighboring edges meeting at their shared corner.
+pub fn self_intersection(polygon: &[Vector2]) -> Option<Vector2> {
+    let count = polygon.len();
+    if count < 4 {
+        return None;
+    }
+    let edge = |i: usize| (polygon[i], polygon[(i + 1) % count]);
+    for i in 0..count {
+        // The last edge neighbors the first
+        let last = if i == 0 { count - 1 } else { count };
+        for j in i + 2..last {
+            let ((a, b), (c, d)) = (edge(i), edge(j));
+            if let Some(p) = segment_intersection(a, b, c, d) {
+                return Some(p);
+            }
+        }
+    }
+    None
+}
+
+/// Splits a polygon crossing itself into the simple polygons it encloses,
+/// such as the two halves of a figure eight.
+pub fn repair(polygon: &[Vector2]) -> Vec<Vec<Vector2>> {
+    // Orienting a polygon crossing itself would reverse one of its halves
+    let polygon = geo::MultiPolygon::new(vec![to_geo_unoriented(polygon)]);
+    from_multi_polygon(polygon.union(&geo::MultiPolygon::new(Vec::new())))
+        .into_iter()
+        .map(|region| region.outline)
+        .collect()
+}
+
+fn to_geo_unoriented(polygon: &[Vector2]) -> geo::Polygon {
+    let ring: Vec<geo::Coord> = polygon
+        .iter()
+        .map(|p| geo::Coord { x: p.x, y: p.y })
+        .collect();
+    geo::Polygon::new(geo::LineString::new(ring), Vec::new())
+}
+
+fn to_geo(polygon: &[Vector2]) -> geo::Polygon {
+    // Traced outlines wind either way, which would otherwise cancel out
+    to_geo_unoriented(polygon).orient(Direction::Default)
+}
+
+/// The area covered by any of the polygons.
+fn to_multi_polygon(polygons: &[Vec<Vector2>]) -> geo::MultiPolygon {
+    let polygons: Vec<geo::Polygon> = polygons.iter().map(|polygon| to_geo(polygon)).collect();
+    geo::unary_union(&polygons)
+}
+
+/// The points of a ring, without repeating the first at its end.
+fn from_geo_ring(ring: &geo::LineString) -> Vec<Vector2> {
+    let mut points: Vec<Vector2> = ring.coords().map(|c| Vector2 { x: c.x, y: c.y }).collect();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    points
+}
+
+fn from_multi_polygon(polygons: geo::MultiPolygon) -> Vec<Region> {
+    polygons
+        .into_iter()
+        .map(|polygon| Region {
+            outline: from_geo_ring(polygon.exterior()),
+            holes: polygon.interiors().iter().map(from_geo_ring).collect(),
+        })
+        .collect()
+}
+
+/// Merges overlapping polygons into single regions, leaving holes where they
+/// surround an area none of them cover.
+pub fn union(polygons: &[Vec<Vector2>]) -> Vec<Region> {
+    from_multi_polygon(to_multi_polygon(polygons))
+}
+
+/// What is left of a polygon with others cut out of it.
+pub fn difference(polygon: &[Vector2], others: &[Vec<Vector2>]) -> Vec<Region> {
+    from_multi_polygon(
+        geo::MultiPolygon::new(vec![to_geo(polygon)]).difference(&to_multi_polygon(others)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: f64, y: f64) -> Vector2 {
+        Vector2 { x, y }
+    }
+
+    #[test]
+    fn test_polygon_area() {
+        let square = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)];
+        assert_eq!(polygon_area(&square), 4.0);
+        let reversed: Vec<_> = square.iter().rev().copied().collect();
+        assert_eq!(polygon_area(&reversed), 4.0);
+    }
+
+    #[test]
+    fn test_winding() {
+        let square = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)];
+        assert_eq!(signed_area(&square), 4.0);
+        assert_eq!(wind(&square, true), square.to_vec());
+        let reversed = wind(&square, false);
+        assert_eq!(
+            reversed,
+            vec![v(0.0, 0.0), v(0.0, 2.0), v(2.0, 2.0), v(2.0, 0.0)]
+        );
+        assert_eq!(signed_area(&reversed), -4.0);
+        // Closed and wound the wrong way
+        assert_eq!(wind(&close_ring(&reversed), true), square.to_vec());
+        let closed = close_ring(&square);
+        assert_eq!(closed.len(), 5);
+        assert_eq!(close_ring(&closed), closed);
+        assert!(close_ring(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_contains_point() {
+        let square = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)];
+        assert!(contains_point(&square, v(1.0, 1.0)));
+        assert!(!contains_point(&square, v(3.0, 1.0)));
+        assert!(!contains_point(&[], v(0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_boundary_distance() {
+        let square = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)];
+        assert_eq!(boundary_distance(&square, v(1.0, 1.0)), 1.0);
+        assert_eq!(boundary_distance(&square, v(1.0, -3.0)), 3.0);
+        assert_eq!(boundary_distance(&square, v(5.0, 6.0)), 5.0);
+    }
+
+    #[test]
+    fn test_nearest_edge() {
+        let square = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)];
+        assert_eq!(nearest_edge(&square, v(1.0, -1.0)), Some(0));
+        assert_eq!(nearest_edge(&square, v(-0.5, 1.5)), Some(3));
+        assert_eq!(nearest_edge(&[], v(0.0, 0.0)), None);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let points = [v(1.0, 5.0), v(-2.0, 3.0), v(4.0, -1.0)];
+        assert_eq!(bounds(&points), Some((v(-2.0, -1.0), v(4.0, 5.0))));
+        assert_eq!(bounds(&[]), None);
+    }
+
+    #[test]
+    fn test_centroid() {
+        let square = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(0.0, 2.0)];
+        assert_eq!(centroid(&square), Some(v(1.0, 1.0)));
+        let line = [v(0.0, 0.0), v(1.0, 1.0), v(2.0, 2.0)];
+        assert_eq!(centroid(&line), Some(v(1.0, 1.0)));
+        assert_eq!(centroid(&[]), None);
+    }
+
+    #[test]
+    fn test_centerline() {
+        // A wall a cell long and a tenth of a cell thick, drawn upright
+        let wall = [v(0.0, 0.0), v(10.0, 0.0), v(10.0, 100.0), v(0.0, 100.0)];
+        assert!((thickness(&wall) - 100.0 / 11.0).abs() < 1e-9);
+        let (a, b) = centerline(&wall).unwrap();
+        let inset = 50.0 / 11.0;
+        assert!(distance(a, v(5.0, inset)) < 1e-9 || distance(b, v(5.0, inset)) < 1e-9);
+        assert!((distance(a, b) - (100.0 - 2.0 * inset)).abs() < 1e-9);
+        assert_eq!(centerline(&[]), None);
+    }
+
+    #[test]
+    fn test_smooth_outline() {
+        // A circle of radius 10 traced with 64 points
+        let circle: Vec<Vector2> = (0..64)
+            .map(|i| {
+                let (sin, cos) = (i as f64 * std::f64::consts::TAU / 64.0).sin_cos();
+                v(10.0 * cos, 10.0 * sin)
+            })
+            .collect();
+        assert!(is_organic(&circle, 10.0));
+        let smooth = smooth_outline(&circle, 5.0);
+        assert!(smooth.len() < circle.len());
+        for p in &smooth {
+            assert!((distance(*p, v(0.0, 0.0)) - 10.0).abs() < 0.5);
+        }
+        let mut room = vec![v(0.0, 0.0), v(10.0, 0.0), v(10.0, 10.0), v(0.0, 10.0)];
+        room.extend((1..5).map(|i| v(0.0, 10.0 - 2.0 * i as f64)));
+        assert!(!is_organic(&room, 10.0));
+    }
+
+    #[test]
+    fn test_degenerate_polygon_area() {
+        assert_eq!(polygon_area(&[]), 0.0);
+        assert_eq!(polygon_area(&[v(0.0, 0.0), v(1.0, 1.0), v(2.0, 2.0)]), 0.0);
+    }
+
+    fn square(x: f64, y: f64, side: f64) -> Vec<Vector2> {
+        vec![
+            v(x, y),
+            v(x + side, y),
+            v(x + side, y + side),
+            v(x, y + side),
+        ]
+    }
+
+    #[test]
+    fn test_union() {
+        let reversed: Vec<_> = square(1.0, 1.0, 2.0).into_iter().rev().collect();
+        let merged = union(&[square(0.0, 0.0, 2.0), reversed]);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(polygon_area(&merged[0].outline), 7.0);
+        assert!(merged[0].holes.is_empty());
+        assert_eq!(
+            union(&[square(0.0, 0.0, 1.0), square(5.0, 0.0, 1.0)]).len(),
+            2
+        );
+        // Four walls of rooms around a courtyard
+        let ring = union(&[
+            vec![v(0.0, 0.0), v(3.0, 0.0), v(3.0, 1.0), v(0.0, 1.0)],
+            vec![v(2.0, 0.0), v(3.0, 0.0), v(3.0, 3.0), v(2.0, 3.0)],
+            vec![v(0.0, 2.0), v(3.0, 2.0), v(3.0, 3.0), v(0.0, 3.0)],
+            vec![v(0.0, 0.0), v(1.0, 0.0), v(1.0, 3.0), v(0.0, 3.0)],
+        ]);
+        assert_eq!(ring.len(), 1);
+        assert_eq!(ring[0].holes.len(), 1);
+        assert_eq!(polygon_area(&ring[0].holes[0]), 1.0);
+    }
+
+    #[test]
+    fn test_difference() {
+        let clipped = difference(&square(0.0, 0.0, 2.0), &[square(1.0, 1.0, 2.0)]);
+        assert_eq!(clipped.len(), 1);
+        assert_eq!(polygon_area(&clipped[0].outline), 3.0);
+        // A corridor cutting a room in two
+        let halves = difference(
+            &square(0.0, 0.0, 3.0),
+            &[vec![v(1.0, -1.0), v(2.0, -1.0), v(2.0, 4.0), v(1.0, 4.0)]],
+        );
+        assert_eq!(halves.len(), 2);
+        assert!(difference(&square(1.0, 1.0, 1.0), &[square(0.0, 0.0, 3.0)]).is_empty());
+    }
+
+    #[test]
+    fn test_merge_short_edges() {
+        // Stair steps down the side of a room
+        let stairs = [
+            v(0.0, 0.0),
+            v(10.0, 0.0),
+            v(10.0, 1.0),
+            v(11.0, 1.0),
+            v(11.0, 2.0),
+            v(11.0, 10.0),
+            v(0.0, 10.0),
+        ];
+        assert_eq!(
+            merge_short_edges(&stairs, 3.0, true),
+            vec![v(0.0, 0.0), v(10.5, 1.0), v(11.0, 10.0), v(0.0, 10.0)]
+        );
+        assert_eq!(merge_short_edges(&stairs, 0.0, true), stairs.to_vec());
+        // The closing edge is short too
+        let closing = [v(0.0, 0.0), v(10.0, 0.0), v(10.0, 10.0), v(0.0, 1.0)];
+        assert_eq!(merge_short_edges(&closing, 2.0, true).len(), 3);
+        let wall = [v(0.0, 0.0), v(0.5, 0.0), v(5.0, 0.0), v(5.5, 0.0)];
+        assert_eq!(
+            merge_short_edges(&wall, 2.0, false),
+            vec![v(0.0, 0.0), v(5.5, 0.0)]
+        );
+        // Too small to keep its shape
+        let speck = [v(0.0, 0.0), v(1.0, 0.0), v(1.0, 1.0)];
+        assert_eq!(merge_short_edges(&speck, 2.0, true), speck.to_vec());
+    }
+
+    #[test]
+    fn test_chaikin() {
+        let corner = [v(0.0, 0.0), v(8.0, 0.0), v(8.0, 8.0)];
+        assert_eq!(
+            chaikin(&corner, 1),
+            vec![v(0.0, 0.0), v(6.0, 0.0), v(8.0, 2.0), v(8.0, 8.0)]
+        );
+        let twice = chaikin(&corner, 2);
+        assert_eq!(twice.len(), 6);
+        assert_eq!((twice[0], twice[5]), (corner[0], corner[2]));
+        assert_eq!(chaikin(&corner, 0), corner.to_vec());
+        let straight = [v(0.0, 0.0), v(8.0, 0.0)];
+        assert_eq!(chaikin(&straight, 3), straight.to_vec());
+    }
+
+    #[test]
+    fn test_close_gap() {
+        // The outline of walls 10 wide around a room, broken from 40 to 60 along the top
+        let outline = [
+            v(40.0, 0.0),
+            v(0.0, 0.0),
+            v(0.0, 100.0),
+            v(100.0, 100.0),
+            v(100.0, 0.0),
+            v(60.0, 0.0),
+            v(60.0, 10.0),
+            v(90.0, 10.0),
+            v(90.0, 90.0),
+            v(10.0, 90.0),
+            v(10.0, 10.0),
+            v(40.0, 10.0),
+        ];
+        let inner = [v(10.0, 10.0), v(90.0, 10.0), v(90.0, 90.0), v(10.0, 90.0)];
+        let wall = |p: Vector2| {
+            let gap = p.y <= 10.0 && p.x > 40.0 && p.x < 60.0;
+            !gap && !contains_point(&inner, p)
+        };
+        let is_open = |a: Vector2, b: Vector2| {
+            !wall(Vector2 {
+                x: (a.x + b.x) / 2.0,
+                y: (a.y + b.y) / 2.0,
+            })
+        };
+        let closed = close_gap(&outline, 25.0, is_open).unwrap();
+        assert_eq!(closed, outline[..6].to_vec());
+        assert_eq!(polygon_area(&closed), 10000.0);
+        // Wider than allowed
+        assert_eq!(close_gap(&outline, 15.0, is_open), None);
+        assert_eq!(close_gap(&outline, 25.0, |_, _| false), None);
+    }
+
+    #[test]
+    fn test_segment_intersection() {
+        let crossing = segment_intersection(v(0.0, 0.0), v(2.0, 2.0), v(0.0, 2.0), v(2.0, 0.0));
+        assert_eq!(crossing, Some(v(1.0, 1.0)));
+        assert_eq!(
+            segment_intersection(v(0.0, 0.0), v(1.0, 0.0), v(0.0, 1.0), v(1.0, 1.0)),
+            None
+        );
+        assert_eq!(
+            segment_intersection(v(0.0, 0.0), v(2.0, 0.0), v(3.0, 0.0), v(1.0, 0.0)),
+            Some(v(1.0, 0.0))
+        );
+        assert_eq!(
+            segment_intersection(v(0.0, 0.0), v(1.0, 0.0), v(2.0, 0.0), v(3.0, 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_self_intersection() {
+        assert_eq!(self_intersection(&square(0.0, 0.0, 2.0)), None);
+        let bowtie = [v(0.0, 0.0), v(2.0, 2.0), v(2.0, 0.0), v(0.0, 2.0)];
+        assert_eq!(self_intersection(&bowtie), Some(v(1.0, 1.0)));
+        // Doubling back along an edge
+        let spike = [v(0.0, 0.0), v(2.0, 0.0), v(2.0, 2.0), v(2.0, -1.0)];
+        assert!(self_intersection(&spike).is_some());
+        assert_eq!(self_intersection(&[v(0.0, 0.0), v(1.0, 0.0)]), None);
+
+        let halves = repair(&bowtie);
+        assert_eq!(halves.len(), 2);
+        assert!(halves
+            .iter()
+            .all(|half| polygon_area(half) == 1.0 && self_intersection(half).is_none()));
+    }
+
+    #[test]
+    fn test_orthogonalize() {
+        let skewed = [v(0.0, 0.0), v(10.0, 0.4), v(10.2, 10.0), v(0.0, 10.0)];
+        assert_eq!(
+            orthogonalize(&skewed, 10.0),
+            vec![v(0.0, 0.2), v(10.1, 0.2), v(10.1, 10.0), v(0.0, 10.0)]
+        );
+        let diamond = [v(0.0, 5.0), v(5.0, 0.0), v(10.0, 5.0), v(5.0, 10.0)];
+        assert_eq!(orthogonalize(&diamond, 10.0), diamond.to_vec());
+    }
+}
@@ -0,0 +1,318 @@
+use std::path::{Path, PathBuf};
+
+use eframe::egui;
+use opencv::core::Mat;
+use opencv::imgproc;
+use opencv::prelude::*;
+use serde_json::Value;
+use tracing::{error, info};
+
+#[cfg(feature = "onnx")]
+use crate::annotations::Annotations;
+#[cfg(feature = "onnx")]
+use crate::assist::{Prompt, PromptableModel, PromptedImage};
+use crate::coordinates::CoordinateMapper;
+use crate::dungeon::{generate_dungeon, DungeonOptions};
+use crate::dungeondraft_v1::{node_points, node_position, nodes, write_map, Vector2};
+use crate::images::read_map_image;
+
+const WALL_COLOR: egui::Color32 = egui::Color32::from_rgb(0, 220, 0);
+const PORTAL_COLOR: egui::Color32 = egui::Color32::from_rgb(220, 120, 0);
+const LIGHT_COLOR: egui::Color32 = egui::Color32::from_rgb(250, 220, 60);
+#[cfg(feature = "onnx")]
+const INSIDE_COLOR: egui::Color32 = egui::Color32::from_rgb(0, 160, 255);
+#[cfg(feature = "onnx")]
+const OUTSIDE_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 40, 40);
+
+/// Rooms picked out of the image by a promptable model at points clicked on
+/// it, for rooms detection misses.
+#[cfg(feature = "onnx")]
+struct Assist {
+    model: PromptableModel,
+    /// The image embedded by the model, once a room is first picked out
+    embedded: Option<PromptedImage>,
+    prompts: Vec<Prompt>,
+}
+
+/// Previews what will be detected in an image and writes the map once it looks right.
+struct Preview {
+    image: Mat,
+    texture: egui::TextureHandle,
+    options: DungeonOptions,
+    mapper: CoordinateMapper,
+    output: String,
+    map: Option<Value>,
+    status: String,
+    #[cfg(feature = "onnx")]
+    assist: Option<Assist>,
+}
+
+impl Preview {
+    fn new(
+        ctx: &egui::Context,
+        image: Mat,
+        output: &Path,
+        #[cfg(feature = "onnx")] assist: Option<PromptableModel>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut rgb = Mat::default();
+        imgproc::cvt_color(&image, &mut rgb, imgproc::COLOR_BGR2RGB, 0)?;
+        let size = [image.cols() as usize, image.rows() as usize];
+        let pixels = egui::ColorImage::from_rgb(size, rgb.data_bytes()?);
+        let texture = ctx.load_texture("source", pixels, egui::TextureOptions::LINEAR);
+        let options = DungeonOptions::default();
+        let mut preview = Self {
+            image,
+            texture,
+            mapper: CoordinateMapper::new(options.pixels_per_cell),
+            options,
+            output: output.display().to_string(),
+            map: None,
+            status: String::new(),
+            #[cfg(feature = "onnx")]
+            assist: assist.map(|model| Assist {
+                model,
+                embedded: None,
+                prompts: Vec::new(),
+            }),
+        };
+        preview.detect();
+        Ok(preview)
+    }
+
+    fn detect(&mut self) {
+        let generated = self
+            .options
+            .mapper(&self.image)
+            .and_then(|mapper| Ok((mapper, generate_dungeon(&self.image, &self.options)?)));
+        match generated {
+            Ok((mapper, map)) => {
+                self.mapper = mapper;
+                let level = &map["world"]["levels"]["0"];
+                self.status = format!(
+                    "{} walls and {} lights",
+                    nodes(level, "walls").count(),
+                    nodes(level, "lights").count()
+                );
+                self.map = Some(map);
+            }
+            Err(e) => {
+                self.status = format!("Detection failed: {}", e);
+                self.map = None;
+            }
+        }
+    }
+
+    /// Picks out the room at the clicked points and adds it to the map.
+    #[cfg(feature = "onnx")]
+    fn extract(&mut self) {
+        let Some(assist) = &mut self.assist else {
+            return;
+        };
+        let picked = match &assist.embedded {
+            Some(embedded) => Ok(embedded),
+            None => assist
+                .model
+                .embed(&self.image)
+                .map(|embedded| &*assist.embedded.insert(embedded)),
+        }
+        .and_then(|embedded| embedded.region(&assist.prompts, &self.mapper));
+        match picked {
+            Ok(Some(room)) => {
+                assist.prompts.clear();
+                self.options
+                    .annotations
+                    .get_or_insert_with(Annotations::default)
+                    .rooms
+                    .push(room);
+                // The picked rooms are added to the detected ones
+                self.options.merge_detection = true;
+                self.detect();
+            }
+            Ok(None) => self.status = "No room found at the points".to_string(),
+            Err(e) => self.status = format!("Picking out the room failed: {}", e),
+        }
+    }
+
+    #[cfg(feature = "onnx")]
+    fn assist_controls(&mut self, ui: &mut egui::Ui) {
+        let Some(assist) = &mut self.assist else {
+            return;
+        };
+        ui.separator();
+        ui.heading("Assist");
+        ui.label("Click inside a missed room, and right click outside it, then pick it out");
+        let prompted = !assist.prompts.is_empty();
+        let clear = ui.add_enabled(prompted, egui::Button::new("Clear points"));
+        if clear.clicked() {
+            assist.prompts.clear();
+        }
+        if ui
+            .add_enabled(prompted, egui::Button::new("Pick out room"))
+            .clicked()
+        {
+            self.extract();
+        }
+    }
+
+    fn write(&mut self) {
+        let Some(map) = &self.map else {
+            return;
+        };
+        let output = PathBuf::from(&self.output);
+        let written = if output.exists() {
+            crate::create_backup(&output).map(|_| ())
+        } else {
+            Ok(())
+        }
+        .and_then(|_| write_map(&output, map));
+        self.status = match written {
+            Ok(()) => {
+                info!("Wrote {}", output.display());
+                format!("Wrote {}", output.display())
+            }
+            Err(e) => {
+                error!("failed to write {}: {}", output.display(), e);
+                format!("Failed to write {}: {}", output.display(), e)
+            }
+        };
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Detection");
+        let scale = ui.add(
+            egui::Slider::new(&mut self.options.pixels_per_cell, 16.0..=256.0)
+                .text("pixels per cell"),
+        );
+        let speckles = ui.add(
+            egui::Slider::new(&mut self.options.min_speckle_area, 0..=400).text("speckle area"),
+        );
+        let confidence = ui.add(
+            egui::Slider::new(&mut self.options.min_confidence, 0.0..=1.0)
+                .text("minimum confidence"),
+        );
+        // Detecting is slow, so wait for sliders to be let go
+        let settled = |r: &egui::Response| r.drag_stopped() || (r.changed() && !r.dragged());
+        if settled(&scale) || settled(&speckles) || settled(&confidence) {
+            self.detect();
+        }
+        ui.separator();
+        ui.label("Map file");
+        ui.text_edit_singleline(&mut self.output);
+        if ui
+            .add_enabled(self.map.is_some(), egui::Button::new("Write map"))
+            .clicked()
+        {
+            self.write();
+        }
+        #[cfg(feature = "onnx")]
+        self.assist_controls(ui);
+        ui.separator();
+        ui.label(&self.status);
+    }
+
+    /// Draws the image with the walls, doors, and lights of the map over it.
+    fn overlay(&mut self, ui: &mut egui::Ui) {
+        let image_size = self.texture.size_vec2();
+        let scale = (ui.available_width() / image_size.x).min(1.0);
+        let response = ui.add(
+            egui::Image::new(&self.texture)
+                .fit_to_exact_size(image_size * scale)
+                .sense(egui::Sense::click()),
+        );
+        let origin = response.rect.min;
+        #[cfg(feature = "onnx")]
+        if let Some(assist) = &mut self.assist {
+            let clicked = response
+                .interact_pointer_pos()
+                .filter(|_| response.clicked() || response.secondary_clicked());
+            if let Some(pos) = clicked {
+                let pixel = (pos - origin) / scale;
+                assist.prompts.push(Prompt {
+                    position: Vector2 {
+                        x: pixel.x as f64,
+                        y: pixel.y as f64,
+                    },
+                    inside: !response.secondary_clicked(),
+                });
+            }
+            let painter = ui.painter_at(response.rect);
+            for prompt in &assist.prompts {
+                let at =
+                    origin + egui::vec2(prompt.position.x as f32, prompt.position.y as f32) * scale;
+                let color = if prompt.inside {
+                    INSIDE_COLOR
+                } else {
+                    OUTSIDE_COLOR
+                };
+                painter.circle_filled(at, 5.0, color);
+            }
+        }
+        let Some(map) = &self.map else {
+            return;
+        };
+        let to_screen = |p: Vector2| {
+            let p = self.mapper.units_to_pixels(p);
+            origin + egui::vec2(p.x as f32, p.y as f32) * scale
+        };
+        let painter = ui.painter_at(response.rect);
+        let level = &map["world"]["levels"]["0"];
+        for wall in nodes(level, "walls") {
+            if let Some(points) = node_points(wall) {
+                let points = points.into_iter().map(to_screen).collect();
+                painter.add(egui::Shape::closed_line(
+                    points,
+                    egui::Stroke::new(2.0, WALL_COLOR),
+                ));
+            }
+            for portal in nodes(wall, "portals").filter_map(node_position) {
+                painter.circle_filled(to_screen(portal), 4.0, PORTAL_COLOR);
+            }
+        }
+        for light in nodes(level, "lights").filter_map(node_position) {
+            painter.circle_stroke(to_screen(light), 6.0, egui::Stroke::new(2.0, LIGHT_COLOR));
+        }
+    }
+}
+
+impl eframe::App for Preview {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        egui::Panel::left("controls").show(ui, |ui| self.controls(ui));
+        egui::CentralPanel::default_margins().show(ui, |ui| {
+            egui::ScrollArea::both().show(ui, |ui| self.overlay(ui));
+        });
+    }
+}
+
+/// Opens a window previewing the dungeon detected in the image.
+pub fn run_preview(
+    image_path: &Path,
+    output: &Path,
+    #[cfg(feature = "onnx")] assist: Option<PromptableModel>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let image = read_map_image(image_path)?;
+    if image.empty() {
+        return Err(format!("failed to read image {}", image_path.display()).into());
+    }
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_title(format!("dungeondraft-generator - {}", image_path.display())),
+        ..Default::default()
+    };
+    let output = output.to_path_buf();
+    eframe::run_native(
+        "dungeondraft-generator",
+        options,
+        Box::new(move |cc| {
+            let preview = Preview::new(
+                &cc.egui_ctx,
+                image,
+                &output,
+                #[cfg(feature = "onnx")]
+                assist,
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(Box::new(preview))
+        }),
+    )?;
+    Ok(())
+}
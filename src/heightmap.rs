@@ -0,0 +1,129 @@
+use std::path::Path;
+
+use opencv::core::{Mat, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::debug;
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+use crate::images::{read_image, trace_outlines};
+
+/// A range of elevations starting at `min` that share a terrain texture.
+pub struct ElevationBand {
+    pub name: &'static str,
+    pub min: u8,
+    pub texture: &'static str,
+}
+
+/// One band per terrain texture slot, from lowest to highest.
+pub const ELEVATION_BANDS: &[ElevationBand] = &[
+    ElevationBand {
+        name: "shore",
+        min: 0,
+        texture: "res://textures/terrain/terrain_sand.png",
+    },
+    ElevationBand {
+        name: "lowland",
+        min: 64,
+        texture: "res://textures/terrain/terrain_grass.png",
+    },
+    ElevationBand {
+        name: "highland",
+        min: 128,
+        texture: "res://textures/terrain/terrain_gravel.png",
+    },
+    ElevationBand {
+        name: "peak",
+        min: 192,
+        texture: "res://textures/terrain/terrain_snow.png",
+    },
+];
+
+/// Picks the highest band the elevation reaches.
+pub fn elevation_band(elevation: u8, bands: &[ElevationBand]) -> usize {
+    bands
+        .iter()
+        .rposition(|band| elevation >= band.min)
+        .unwrap_or(0)
+}
+
+/// Reads a grayscale heightmap, stretched to the size of the map image.
+pub fn read_heightmap(path: &Path, size: Size) -> Result<Mat, Box<dyn std::error::Error>> {
+    debug!("Reading heightmap {}", path.display());
+    let heightmap = read_image(path, opencv::imgcodecs::IMREAD_GRAYSCALE)?;
+    if heightmap.empty() {
+        return Err(format!("failed to read heightmap {}", path.display()).into());
+    }
+    let mut resized = Mat::default();
+    imgproc::resize(
+        &heightmap,
+        &mut resized,
+        size,
+        0.0,
+        0.0,
+        imgproc::INTER_LINEAR,
+    )?;
+    Ok(resized)
+}
+
+/// Samples the elevation band of every terrain splat pixel.
+pub fn elevation_slots(
+    heightmap: &Mat,
+    size: Size,
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut splat = Mat::default();
+    imgproc::resize(heightmap, &mut splat, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+    let mut slots = Vec::with_capacity((size.width * size.height) as usize);
+    for row in 0..size.height {
+        for col in 0..size.width {
+            slots.push(elevation_band(
+                *splat.at_2d::<u8>(row, col)?,
+                ELEVATION_BANDS,
+            ));
+        }
+    }
+    Ok(slots)
+}
+
+/// Traces the edge of every band above the lowest as a closed cliff outline in map units.
+pub fn cliffs(
+    heightmap: &Mat,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Vec<Vector2>>, Box<dyn std::error::Error>> {
+    let mut outlines = Vec::new();
+    for band in &ELEVATION_BANDS[1..] {
+        let mut plateau = Mat::default();
+        imgproc::threshold(
+            heightmap,
+            &mut plateau,
+            band.min as f64 - 1.0,
+            255.0,
+            imgproc::THRESH_BINARY,
+        )?;
+        // Listing every contour keeps the edges of valleys inside a plateau
+        outlines.extend(trace_outlines(
+            &plateau,
+            imgproc::RETR_LIST,
+            mapper.pixels_per_cell * mapper.pixels_per_cell,
+            0.005,
+            mapper,
+        )?);
+        debug!("Traced cliffs up to the {} band", band.name);
+    }
+    Ok(outlines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elevation_band() {
+        assert_eq!(elevation_band(0, ELEVATION_BANDS), 0);
+        assert_eq!(elevation_band(63, ELEVATION_BANDS), 0);
+        assert_eq!(elevation_band(64, ELEVATION_BANDS), 1);
+        assert_eq!(elevation_band(200, ELEVATION_BANDS), 3);
+        assert_eq!(elevation_band(255, ELEVATION_BANDS), 3);
+    }
+}
@@ -1,11 +1,163 @@
+use std::collections::HashMap;
+use std::fs;
 use std::path::{Path, PathBuf};
 
-use log::{debug, info};
+use image::metadata::Orientation;
 use opencv::core::{self, Scalar};
 use opencv::imgcodecs::{imread, imwrite};
 use opencv::imgproc;
 use opencv::prelude::*;
-use opencv::types::VectorOfMat;
+use opencv::types::{VectorOfMat, VectorOfPoint, VectorOfVectorOfPoint};
+use serde::Deserialize;
+use tracing::{debug, info, instrument};
+
+use crate::confidence::{
+    class_score, closure_score, edge_score, fit_score, Confidence, MIN_CONFIDENCE,
+};
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{Color, Vector2};
+use crate::fills::{segment_fills, FillColors};
+use crate::geometry::{close_gap, distance};
+use crate::preprocess::INK_THRESHOLD;
+
+/// Formats read with Rust codecs, as OpenCV is often built without them.
+const RUST_DECODED_FORMATS: &[&str] = &["webp", "avif", "heic", "heif"];
+/// Formats that can hold several pages, or frames of an animation.
+const MULTI_PAGE_FORMATS: &[&str] = &["tif", "tiff", "gif", "pdf"];
+/// PDF pages are rendered at this resolution, in dots per inch.
+const PDF_DPI: f64 = 150.0;
+/// Formats whose EXIF orientation is applied, as phones save photos unrotated.
+const EXIF_ORIENTED_FORMATS: &[&str] = &["jpg", "jpeg"];
+/// The resolutions software stores images at when nothing says otherwise,
+/// which say nothing about their printed size.
+const PLACEHOLDER_DPIS: &[f64] = &[72.0, 96.0];
+/// Gaps in a boundary up to this many pixels wide are closed, a quarter of a
+/// cell of the usual size.
+pub const MAX_GAP: f64 = 16.0;
+/// Pixels less opaque than this are outside the map and ignored.
+const ALPHA_THRESHOLD: f64 = 128.0;
+/// A contour nested in a shape running along it within this share of the
+/// shape's shorter side is the inner edge of its walls.
+const INNER_EDGE_SHARE: f64 = 0.15;
+
+/// The thresholds shapes are detected with, and the rules they must pass to
+/// be kept, from the `detection` config.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DetectionOptions {
+    /// Gradients weaker than this are never edges
+    pub canny_low: f64,
+    /// Gradients stronger than this are always edges
+    pub canny_high: f64,
+    /// How far outlines are simplified into vertices, as a share of their length
+    pub epsilon: f64,
+    /// Shapes smaller than this many square pixels are skipped
+    pub min_area: f64,
+    /// Shapes larger than this many square pixels are skipped
+    pub max_area: Option<f64>,
+    /// Shapes less elongated than this, the length of the rectangle around
+    /// them over its width, are skipped
+    pub min_aspect: f64,
+    /// Shapes more elongated than this are skipped
+    pub max_aspect: Option<f64>,
+    /// Shapes with outlines shorter than this many pixels are skipped
+    pub min_perimeter: f64,
+    /// Shapes with outlines longer than this many pixels are skipped
+    pub max_perimeter: Option<f64>,
+    /// Shapes filling less than this share of their convex hull are skipped
+    pub min_convexity: f64,
+}
+
+impl Default for DetectionOptions {
+    fn default() -> Self {
+        Self {
+            canny_low: 50.0,
+            canny_high: 150.0,
+            epsilon: 0.04,
+            min_area: 100.0,
+            max_area: None,
+            min_aspect: 1.0,
+            max_aspect: None,
+            min_perimeter: 0.0,
+            max_perimeter: None,
+            min_convexity: 0.0,
+        }
+    }
+}
+
+/// What a contour is measured by to decide whether it is kept as a shape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Measures {
+    /// In square pixels
+    area: f64,
+    /// In pixels
+    perimeter: f64,
+    /// The length of the smallest rectangle around the contour over its width
+    aspect: f64,
+    /// The share of its convex hull the contour fills
+    convexity: f64,
+}
+
+impl DetectionOptions {
+    /// Whether a contour passes every rule of what shapes are kept.
+    fn keeps(&self, measures: &Measures) -> bool {
+        let within = |value: f64, min: f64, max: Option<f64>| {
+            value >= min && max.is_none_or(|max| value <= max)
+        };
+        measures.area > self.min_area
+            && within(measures.area, self.min_area, self.max_area)
+            && within(measures.aspect, self.min_aspect, self.max_aspect)
+            && within(measures.perimeter, self.min_perimeter, self.max_perimeter)
+            && measures.convexity >= self.min_convexity
+    }
+
+    /// Checks the thresholds are in range, whether configured or given as flags.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=self.canny_high).contains(&self.canny_low) {
+            return Err(format!(
+                "invalid canny thresholds {} and {}, expected 0 <= low <= high",
+                self.canny_low, self.canny_high
+            ));
+        }
+        for (name, value) in [
+            ("epsilon", self.epsilon),
+            ("min_area", self.min_area),
+            ("min_perimeter", self.min_perimeter),
+        ] {
+            if !(0.0..).contains(&value) {
+                return Err(format!("invalid {} {}, expected 0 or more", name, value));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Measures a contour of at least one pixel.
+fn measure(contour: &Mat) -> Result<Measures, Box<dyn std::error::Error>> {
+    let area = imgproc::contour_area(contour, false)?;
+    let size = imgproc::min_area_rect(contour)?.size();
+    let (long, short) = (
+        size.width.max(size.height) as f64,
+        size.width.min(size.height) as f64,
+    );
+    let mut hull = VectorOfPoint::new();
+    imgproc::convex_hull(contour, &mut hull, false, true)?;
+    let hull_area = imgproc::contour_area(&hull, false)?;
+    Ok(Measures {
+        area,
+        perimeter: imgproc::arc_length(contour, true)?,
+        aspect: if short > 0.0 {
+            long / short
+        } else {
+            f64::INFINITY
+        },
+        convexity: if hull_area > 0.0 {
+            area / hull_area
+        } else {
+            1.0
+        },
+    })
+}
 
 #[derive(Debug)]
 pub struct Point {
@@ -19,67 +171,970 @@ impl std::fmt::Display for Point {
     }
 }
 
+/// Radius in pixels of the vertex markers drawn by [`trace_shapes`].
+const VERTEX_RADIUS: i32 = 4;
+const LABEL_SCALE: f64 = 0.4;
+
+/// What a shape looks like, judged by how many vertices its outline has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShapeClass {
+    Triangle,
+    Rectangle,
+    Polygon,
+    Circle,
+}
+
+impl ShapeClass {
+    const ALL: [ShapeClass; 4] = [
+        ShapeClass::Triangle,
+        ShapeClass::Rectangle,
+        ShapeClass::Polygon,
+        ShapeClass::Circle,
+    ];
+
+    pub fn of(vertex_count: usize) -> Self {
+        match vertex_count {
+            3 => ShapeClass::Triangle,
+            4 => ShapeClass::Rectangle,
+            // Round outlines are simplified to many short edges
+            n if n >= 8 => ShapeClass::Circle,
+            _ => ShapeClass::Polygon,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ShapeClass::Triangle => "triangle",
+            ShapeClass::Rectangle => "rectangle",
+            ShapeClass::Polygon => "polygon",
+            ShapeClass::Circle => "circle",
+        }
+    }
+}
+
+/// The colors shapes are traced with, configured by class name under `trace_colors`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStyle {
+    /// One color per class, in the order of [`ShapeClass::ALL`]
+    colors: [Color; 4],
+    /// Shapes scoring below `min_confidence` are drawn in this color instead
+    review: Color,
+    pub min_confidence: f64,
+}
+
+impl Default for TraceStyle {
+    fn default() -> Self {
+        let rgb = |r, g, b| Color { a: 0xff, r, g, b };
+        Self {
+            colors: [
+                rgb(255, 0, 0),
+                rgb(0, 255, 0),
+                rgb(0, 128, 255),
+                rgb(255, 0, 255),
+            ],
+            review: rgb(255, 128, 0),
+            min_confidence: MIN_CONFIDENCE,
+        }
+    }
+}
+
+impl TraceStyle {
+    /// The default style with the configured `class = "#rrggbb"` colors in its
+    /// place, where `review` is the color of low confidence shapes.
+    pub fn with_colors(colors: &HashMap<String, String>) -> Result<Self, String> {
+        let mut style = Self::default();
+        for (name, color) in colors {
+            if name == "review" {
+                style.review = color.parse()?;
+                continue;
+            }
+            let index = ShapeClass::ALL
+                .iter()
+                .position(|class| class.name() == name)
+                .ok_or_else(|| format!("unknown shape class '{}'", name))?;
+            style.colors[index] = color.parse()?;
+        }
+        Ok(style)
+    }
+
+    /// The color a shape is traced in.
+    pub fn shape_color(&self, shape: &Shape) -> Color {
+        if shape.confidence.score() < self.min_confidence {
+            self.review
+        } else {
+            self.color(shape.class())
+        }
+    }
+
+    pub fn color(&self, class: ShapeClass) -> Color {
+        let index = ShapeClass::ALL.iter().position(|c| *c == class).unwrap();
+        self.colors[index]
+    }
+}
+
 #[derive(Debug)]
 pub struct Shape {
     vertice_count: u32,
     coordinates: Point,
     contour: Mat,
+    vertices: Vec<Point>,
+    confidence: Confidence,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl Shape {
+    pub fn class(&self) -> ShapeClass {
+        ShapeClass::of(self.vertices.len())
+    }
+
+    /// The approximated vertices of the shape in image pixels.
+    pub fn pixel_vertices(&self) -> Vec<Vector2> {
+        self.vertices
+            .iter()
+            .map(|p| Vector2 {
+                x: p.x as f64,
+                y: p.y as f64,
+            })
+            .collect()
+    }
+
+    pub fn confidence(&self) -> &Confidence {
+        &self.confidence
+    }
+
+    /// The index of the shape this one lies within, such as the room around a
+    /// pillar, or `None` for an outermost shape.
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    /// The indices of the shapes lying directly within this one, such as the
+    /// pillars of a room.
+    pub fn children(&self) -> &[usize] {
+        &self.children
+    }
+
+    /// The approximated vertices of the shape in map units.
+    pub fn outline(&self, mapper: &CoordinateMapper) -> Vec<Vector2> {
+        self.vertices
+            .iter()
+            .map(|p| mapper.pixels_to_units(p.x as f64, p.y as f64))
+            .collect()
+    }
 }
 
 impl std::fmt::Display for Shape {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{} vertices @ {} : {:?}",
-            self.vertice_count, self.coordinates, self.contour
+            "{} vertices @ {} with confidence {:.2} : {:?}",
+            self.vertice_count,
+            self.coordinates,
+            self.confidence.score(),
+            self.contour
+        )?;
+        if let Some(parent) = self.parent {
+            write!(f, " within #{}", parent)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_rust_decoded(path: &Path) -> bool {
+    has_extension(path, RUST_DECODED_FORMATS)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extensions.contains(&extension.to_lowercase().as_str()))
+}
+
+/// The first segment of a JPEG with a marker whose contents start with a
+/// prefix, after the prefix.
+fn jpeg_segment<'a>(jpeg: &'a [u8], wanted: u8, prefix: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = jpeg.strip_prefix(&[0xff, 0xd8])?;
+    // Every segment up to the image data starts with a marker and its length
+    while let [0xff, marker, high, low, ..] = *rest {
+        if marker == 0xda {
+            break;
+        }
+        let length = u16::from_be_bytes([high, low]) as usize;
+        let segment = rest.get(4..2 + length)?;
+        if marker == wanted {
+            if let Some(contents) = segment.strip_prefix(prefix) {
+                return Some(contents);
+            }
+        }
+        rest = &rest[2 + length..];
+    }
+    None
+}
+
+/// The EXIF data of a JPEG, a TIFF header in its APP1 segment.
+fn jpeg_exif(jpeg: &[u8]) -> Option<&[u8]> {
+    jpeg_segment(jpeg, 0xe1, b"Exif\0\0")
+}
+
+/// The EXIF orientation of a JPEG.
+pub fn exif_orientation(jpeg: &[u8]) -> Option<Orientation> {
+    Orientation::from_exif_chunk(jpeg_exif(jpeg)?)
+}
+
+/// The horizontal resolution of a TIFF header, in dots per inch.
+fn tiff_dpi(tiff: &[u8]) -> Option<f64> {
+    let big_endian = match tiff.get(..4)? {
+        [0x49, 0x49, 42, 0] => false,
+        [0x4d, 0x4d, 0, 42] => true,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let ifd = u32_at(4)? as usize;
+    let mut resolution = None;
+    // Inches unless the resolution unit says otherwise
+    let mut unit = 2;
+    for entry in 0..u16_at(ifd)? as usize {
+        let at = ifd + 2 + entry * 12;
+        match u16_at(at)? {
+            // XResolution, a rational stored elsewhere
+            0x011a => {
+                let value = u32_at(at + 8)? as usize;
+                resolution = Some(u32_at(value)? as f64 / u32_at(value + 4)?.max(1) as f64);
+            }
+            // ResolutionUnit
+            0x0128 => unit = u16_at(at + 8)?,
+            _ => {}
+        }
+    }
+    match unit {
+        2 => resolution,
+        3 => resolution.map(|per_centimeter| per_centimeter * 2.54),
+        _ => None,
+    }
+}
+
+/// The horizontal resolution an image is stored at, in dots per inch, from
+/// the `pHYs` chunk of a PNG, the JFIF or EXIF header of a JPEG, or the tags
+/// of a TIFF.
+pub fn image_dpi(bytes: &[u8]) -> Option<f64> {
+    if let Some(mut chunks) = bytes.strip_prefix(b"\x89PNG\r\n\x1a\n") {
+        while let [a, b, c, d, ..] = *chunks {
+            let length = u32::from_be_bytes([a, b, c, d]) as usize;
+            let kind = chunks.get(4..8)?;
+            let data = chunks.get(8..8 + length)?;
+            if kind == b"pHYs" {
+                // Pixels per unit along x and y, then whether the unit is the meter
+                let per_meter = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+                return (data.get(8) == Some(&1)).then_some(per_meter as f64 * 0.0254);
+            }
+            if kind == b"IDAT" {
+                break;
+            }
+            // After the data comes its checksum
+            chunks = chunks.get(12 + length..)?;
+        }
+        return None;
+    }
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        // Version, units, then the horizontal and vertical densities
+        let jfif = jpeg_segment(bytes, 0xe0, b"JFIF\0").and_then(|jfif| match *jfif {
+            [_, _, 1, high, low, ..] => Some(u16::from_be_bytes([high, low]) as f64),
+            [_, _, 2, high, low, ..] => Some(u16::from_be_bytes([high, low]) as f64 * 2.54),
+            _ => None,
+        });
+        return jfif.or_else(|| tiff_dpi(jpeg_exif(bytes)?));
+    }
+    tiff_dpi(bytes)
+}
+
+/// The resolution an image file is stored at, in dots per inch, unless it is
+/// one software stores every image at.
+pub fn read_dpi(path: &Path) -> Option<f64> {
+    if has_extension(path, &["pdf"]) {
+        return Some(PDF_DPI);
+    }
+    let dpi = image_dpi(&fs::read(path).ok()?)?;
+    (dpi > 0.0 && !PLACEHOLDER_DPIS.contains(&dpi)).then_some(dpi)
+}
+
+/// Rotates and flips an image upright from how it is oriented.
+fn orient(image: Mat, orientation: Orientation) -> Result<Mat, Box<dyn std::error::Error>> {
+    let rotate = |image: &Mat, code: i32| -> opencv::Result<Mat> {
+        let mut rotated = Mat::default();
+        core::rotate(image, &mut rotated, code)?;
+        Ok(rotated)
+    };
+    // Horizontally about the y axis is 1 and vertically about the x axis 0
+    let flip = |image: &Mat, code: i32| -> opencv::Result<Mat> {
+        let mut flipped = Mat::default();
+        core::flip(image, &mut flipped, code)?;
+        Ok(flipped)
+    };
+    Ok(match orientation {
+        Orientation::NoTransforms => image,
+        Orientation::Rotate90 => rotate(&image, core::ROTATE_90_CLOCKWISE)?,
+        Orientation::Rotate180 => rotate(&image, core::ROTATE_180)?,
+        Orientation::Rotate270 => rotate(&image, core::ROTATE_90_COUNTERCLOCKWISE)?,
+        Orientation::FlipHorizontal => flip(&image, 1)?,
+        Orientation::FlipVertical => flip(&image, 0)?,
+        Orientation::Rotate90FlipH => flip(&rotate(&image, core::ROTATE_90_CLOCKWISE)?, 1)?,
+        Orientation::Rotate270FlipH => flip(&rotate(&image, core::ROTATE_90_COUNTERCLOCKWISE)?, 1)?,
+    })
+}
+
+/// Reads an image like `imread`, with the same `flags`.
+///
+/// WebP is decoded in Rust, and HEIC, HEIF, and AVIF too when built with the
+/// `heif` feature. A JPEG is turned upright by its EXIF orientation whatever the
+/// `flags`. An empty `Mat` is returned when OpenCV can't read the image.
+pub fn read_image(path: &Path, flags: i32) -> Result<Mat, Box<dyn std::error::Error>> {
+    let path_str = path.to_str().ok_or("invalid image path")?;
+    if has_extension(path, EXIF_ORIENTED_FORMATS) {
+        let image = imread(
+            path_str,
+            flags | opencv::imgcodecs::IMREAD_IGNORE_ORIENTATION,
+        )?;
+        let orientation = fs::read(path)
+            .ok()
+            .and_then(|jpeg| exif_orientation(&jpeg))
+            .unwrap_or(Orientation::NoTransforms);
+        if image.empty() || orientation == Orientation::NoTransforms {
+            return Ok(image);
+        }
+        debug!("Orienting {} by {:?}", path.display(), orientation);
+        return orient(image, orientation);
+    }
+    if !is_rust_decoded(path) {
+        return Ok(imread(path_str, flags)?);
+    }
+    #[cfg(feature = "heif")]
+    libheif_rs::integration::image::register_all_decoding_hooks();
+    debug!("Decoding {}", path.display());
+    let decoded = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?;
+    let rows = decoded.height() as i32;
+    let (bytes, channels, conversion) = match flags {
+        opencv::imgcodecs::IMREAD_GRAYSCALE => (decoded.to_luma8().into_raw(), 1, None),
+        opencv::imgcodecs::IMREAD_UNCHANGED if decoded.color().has_alpha() => (
+            decoded.to_rgba8().into_raw(),
+            4,
+            Some(imgproc::COLOR_RGBA2BGRA),
+        ),
+        _ => (
+            decoded.to_rgb8().into_raw(),
+            3,
+            Some(imgproc::COLOR_RGB2BGR),
+        ),
+    };
+    let rgb = Mat::from_slice(&bytes)?.reshape(channels, rows)?;
+    let Some(conversion) = conversion else {
+        return Ok(rgb.try_clone()?);
+    };
+    let mut bgr = Mat::default();
+    imgproc::cvt_color(&rgb, &mut bgr, conversion, 0)?;
+    Ok(bgr)
+}
+
+/// Reads a map image in color, with its transparent pixels blanked out as
+/// white paper so nothing is detected in them.
+///
+/// Many map-making tools export an isolated dungeon on a transparent
+/// background, its alpha channel masking everything around it.
+pub fn read_map_image(path: &Path) -> Result<Mat, Box<dyn std::error::Error>> {
+    to_map_image(read_image(path, opencv::imgcodecs::IMREAD_UNCHANGED)?, path)
+}
+
+/// Whether an image's format can hold several pages or frames.
+pub fn is_multi_page(path: &Path) -> bool {
+    has_extension(path, MULTI_PAGE_FORMATS)
+}
+
+/// The page of `pages` numbered `page` counting from 1, or all of them.
+fn pick_page(
+    mut pages: Vec<Mat>,
+    page: Option<usize>,
+    path: &Path,
+) -> Result<Vec<Mat>, Box<dyn std::error::Error>> {
+    let Some(page) = page else {
+        return Ok(pages);
+    };
+    if page == 0 || page > pages.len() {
+        return Err(format!(
+            "{} has no page {}, only pages 1 to {}",
+            path.display(),
+            page,
+            pages.len()
         )
+        .into());
     }
+    Ok(vec![pages.swap_remove(page - 1)])
 }
 
-pub fn try_find_shapes(image_path: &Path) -> Result<Vec<Shape>, Box<dyn std::error::Error>> {
-    debug!(
-        "Finding contours and tracing shapes in {}",
-        image_path.display()
-    );
-    let image = imread(
-        image_path.as_os_str().to_str().unwrap(),
-        opencv::imgcodecs::ImreadModes::IMREAD_COLOR as i32,
+/// Renders the pages of a PDF with `pdftoppm`, from poppler, at `PDF_DPI`,
+/// only `page` when given.
+fn render_pdf(path: &Path, page: Option<usize>) -> Result<Vec<Mat>, Box<dyn std::error::Error>> {
+    let dir =
+        std::env::temp_dir().join(format!("dungeondraft-generator-pdf-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let mut command = std::process::Command::new("pdftoppm");
+    command.args(["-png", "-r", &PDF_DPI.to_string()]);
+    if let Some(page) = page {
+        command.args(["-f", &page.to_string(), "-l", &page.to_string()]);
+    }
+    debug!("Rendering {} at {} DPI", path.display(), PDF_DPI);
+    let status = command.arg(path).arg(dir.join("page")).status();
+    let rendered = match status {
+        Ok(status) if status.success() => {
+            let mut files: Vec<PathBuf> = fs::read_dir(&dir)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect();
+            // Pages are numbered to the same number of digits
+            files.sort();
+            files
+                .iter()
+                .map(|file| read_image(file, opencv::imgcodecs::IMREAD_UNCHANGED))
+                .collect::<Result<Vec<Mat>, _>>()
+        }
+        Ok(status) => {
+            Err(format!("pdftoppm failed to render {}: {}", path.display(), status).into())
+        }
+        Err(e) => Err(format!(
+            "reading {} needs pdftoppm, from poppler, to render it: {}",
+            path.display(),
+            e
+        )
+        .into()),
+    };
+    fs::remove_dir_all(&dir)?;
+    rendered
+}
+
+/// Reads the pages of a multi-page TIFF or PDF, or the frames of an animated
+/// GIF, as `read_map_image` reads a single image, only the page numbered
+/// `page` counting from 1 when given. Any other image is a single page.
+pub fn read_map_pages(
+    path: &Path,
+    page: Option<usize>,
+) -> Result<Vec<Mat>, Box<dyn std::error::Error>> {
+    let pages = if has_extension(path, &["pdf"]) {
+        render_pdf(path, page)?
+    } else if is_multi_page(path) {
+        let mut pages = VectorOfMat::new();
+        let path_str = path.to_str().ok_or("invalid image path")?;
+        opencv::imgcodecs::imreadmulti(path_str, &mut pages, opencv::imgcodecs::IMREAD_UNCHANGED)?;
+        pick_page(pages.to_vec(), page, path)?
+    } else {
+        let image = read_image(path, opencv::imgcodecs::IMREAD_UNCHANGED)?;
+        pick_page(vec![image], page, path)?
+    };
+    if pages.is_empty() || pages.iter().any(|page| page.empty()) {
+        return Err(format!("failed to read image {}", path.display()).into());
+    }
+    debug!("Read {} pages of {}", pages.len(), path.display());
+    pages
+        .into_iter()
+        .map(|page| to_map_image(page, path))
+        .collect()
+}
+
+/// Converts an image as read to 8 bit color, with its transparent pixels
+/// blanked out.
+fn to_map_image(mut image: Mat, path: &Path) -> Result<Mat, Box<dyn std::error::Error>> {
+    if image.empty() {
+        return Ok(image);
+    }
+    if image.depth() != core::CV_8U {
+        // 16 bit images are scaled down to 8 bits
+        let mut scaled = Mat::default();
+        image.convert_to(&mut scaled, core::CV_8U, 1.0 / 257.0, 0.0)?;
+        image = scaled;
+    }
+    let mut bgr = Mat::default();
+    match image.channels() {
+        1 => imgproc::cvt_color(&image, &mut bgr, imgproc::COLOR_GRAY2BGR, 0)?,
+        4 => {
+            let mut alpha = Mat::default();
+            core::extract_channel(&image, &mut alpha, 3)?;
+            let mut transparent = Mat::default();
+            imgproc::threshold(
+                &alpha,
+                &mut transparent,
+                ALPHA_THRESHOLD - 1.0,
+                255.0,
+                imgproc::THRESH_BINARY_INV,
+            )?;
+            debug!(
+                "Masking {} transparent pixels of {}",
+                core::count_non_zero(&transparent)?,
+                path.display()
+            );
+            imgproc::cvt_color(&image, &mut bgr, imgproc::COLOR_BGRA2BGR, 0)?;
+            bgr.set_to(&Scalar::all(255.0), &transparent)?;
+        }
+        _ => bgr = image,
+    }
+    Ok(bgr)
+}
+
+/// Traces the white regions of a binary mask as polygons in map units.
+///
+/// Regions under `min_area` pixels are skipped, and outlines are simplified to
+/// within `epsilon` times their perimeter.
+pub fn trace_outlines(
+    mask: &Mat,
+    mode: i32,
+    min_area: f64,
+    epsilon: f64,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Vec<Vector2>>, Box<dyn std::error::Error>> {
+    let mut contours = VectorOfVectorOfPoint::new();
+    imgproc::find_contours(
+        mask,
+        &mut contours,
+        mode,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        core::Point::new(0, 0),
+    )?;
+    let mut outlines = Vec::new();
+    for contour in contours.iter() {
+        if imgproc::contour_area(&contour, false)? < min_area {
+            continue;
+        }
+        let mut approx = VectorOfPoint::new();
+        let tolerance = epsilon * imgproc::arc_length(&contour, true)?;
+        imgproc::approx_poly_dp(&contour, &mut approx, tolerance, true)?;
+        outlines.push(
+            approx
+                .iter()
+                .map(|p| mapper.pixels_to_units(p.x as f64, p.y as f64))
+                .collect(),
+        );
+    }
+    Ok(outlines)
+}
+
+/// The Sobel gradient magnitude of every pixel of a grayscale image.
+fn gradient_magnitude(gray: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut dx = Mat::default();
+    let mut dy = Mat::default();
+    imgproc::sobel(
+        gray,
+        &mut dx,
+        core::CV_32F,
+        1,
+        0,
+        3,
+        1.0,
+        0.0,
+        core::BORDER_DEFAULT,
+    )?;
+    imgproc::sobel(
+        gray,
+        &mut dy,
+        core::CV_32F,
+        0,
+        1,
+        3,
+        1.0,
+        0.0,
+        core::BORDER_DEFAULT,
     )?;
-    find_shapes(&image)
+    let mut magnitude = Mat::default();
+    core::magnitude(&dx, &dy, &mut magnitude)?;
+    Ok(magnitude)
+}
+
+/// The colors walls are drawn in, as OpenCV's HSV with hues from 0 to 179.
+/// A range whose low hue is above its high hue wraps around through red.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HsvRange {
+    pub low: [u8; 3],
+    pub high: [u8; 3],
+}
+
+/// Parses an HSV range, `H,S,V:H,S,V` from its low to its high end.
+pub fn parse_hsv_range(s: &str) -> Result<HsvRange, String> {
+    let invalid = || format!("invalid HSV range '{}', expected H,S,V:H,S,V", s);
+    let color = |part: &str| -> Result<[u8; 3], String> {
+        let channels: Vec<u8> = part
+            .split(',')
+            .map(|channel| channel.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        match channels[..] {
+            [h, s, v] if h < 180 => Ok([h, s, v]),
+            _ => Err(invalid()),
+        }
+    };
+    let (low, high) = s.split_once(':').ok_or_else(invalid)?;
+    let (low, high) = (color(low)?, color(high)?);
+    if low[1] > high[1] || low[2] > high[2] {
+        return Err(invalid());
+    }
+    Ok(HsvRange { low, high })
+}
+
+/// The colors of a fill in CIELAB, as OpenCV stores it in a byte a channel,
+/// lightness from 0 to 255 and a and b offset by 128.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LabRange {
+    pub low: [u8; 3],
+    pub high: [u8; 3],
+}
+
+/// Parses a CIELAB range, `L,A,B:L,A,B` from its low to its high end, with
+/// lightness from 0 to 100 and a and b from -128 to 127.
+pub fn parse_lab_range(s: &str) -> Result<LabRange, String> {
+    let invalid = || format!("invalid LAB range '{}', expected L,A,B:L,A,B", s);
+    let color = |part: &str| -> Result<[u8; 3], String> {
+        let channels: Vec<f64> = part
+            .split(',')
+            .map(|channel| channel.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        match channels[..] {
+            [l, a, b]
+                if (0.0..=100.0).contains(&l)
+                    && (-128.0..=127.0).contains(&a)
+                    && (-128.0..=127.0).contains(&b) =>
+            {
+                Ok([
+                    (l * 255.0 / 100.0).round() as u8,
+                    (a + 128.0).round() as u8,
+                    (b + 128.0).round() as u8,
+                ])
+            }
+            _ => Err(invalid()),
+        }
+    };
+    let (low, high) = s.split_once(':').ok_or_else(invalid)?;
+    let (low, high) = (color(low)?, color(high)?);
+    if (0..3).any(|channel| low[channel] > high[channel]) {
+        return Err(invalid());
+    }
+    Ok(LabRange { low, high })
+}
+
+/// A range of colors in HSV, for telling hues apart, or in CIELAB, for
+/// telling apart colors as different as they look.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorRange {
+    Hsv(HsvRange),
+    Lab(LabRange),
+}
+
+/// Parses a color range, `lab:L,A,B:L,A,B` in CIELAB, or `H,S,V:H,S,V`,
+/// optionally prefixed with `hsv:`, in HSV.
+pub fn parse_color_range(s: &str) -> Result<ColorRange, String> {
+    match s.strip_prefix("lab:") {
+        Some(lab) => parse_lab_range(lab).map(ColorRange::Lab),
+        None => parse_hsv_range(s.strip_prefix("hsv:").unwrap_or(s)).map(ColorRange::Hsv),
+    }
+}
+
+impl ColorRange {
+    /// A mask of the pixels of an image within the range.
+    pub fn mask(&self, image: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+        match self {
+            ColorRange::Hsv(range) => hsv_mask(image, range),
+            ColorRange::Lab(range) => {
+                let mut lab = Mat::default();
+                imgproc::cvt_color(image, &mut lab, imgproc::COLOR_BGR2Lab, 0)?;
+                let bound = |[l, a, b]: [u8; 3]| Scalar::new(l as f64, a as f64, b as f64, 0.0);
+                let mut mask = Mat::default();
+                core::in_range(&lab, &bound(range.low), &bound(range.high), &mut mask)?;
+                Ok(mask)
+            }
+        }
+    }
+}
+
+/// Parses a texture and the HSV range of the regions drawn with it, such as
+/// `--material-color` takes, `TEXTURE=H,S,V:H,S,V`.
+pub fn parse_texture_color(s: &str) -> Result<(String, HsvRange), String> {
+    let (texture, range) = s
+        .rsplit_once('=')
+        .filter(|(texture, _)| !texture.trim().is_empty())
+        .ok_or(format!(
+            "invalid texture color '{}', expected TEXTURE=H,S,V:H,S,V",
+            s
+        ))?;
+    Ok((texture.trim().to_string(), parse_hsv_range(range)?))
+}
+
+/// How the walls drawn in an image are told apart from its floors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detector {
+    /// The edges of the linework in grayscale
+    Canny,
+    /// Pixels of the colors walls are drawn in
+    Hsv(HsvRange),
+    /// Regions filled with the colors of floors and walls, for maps drawn
+    /// with fill colors, each traced its own way
+    Fills(FillColors),
+    /// Straight lines along the linework, for clean, CAD-like maps, traced as
+    /// walls rather than the outlines of shapes
+    Lines,
+}
+
+/// A mask of the pixels of an image within an HSV range.
+pub fn hsv_mask(image: &Mat, range: &HsvRange) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut hsv = Mat::default();
+    imgproc::cvt_color(image, &mut hsv, imgproc::COLOR_BGR2HSV, 0)?;
+    let bound = |[h, s, v]: [u8; 3]| Scalar::new(h as f64, s as f64, v as f64, 0.0);
+    let [low_hue, low_saturation, low_value] = range.low;
+    let [high_hue, high_saturation, high_value] = range.high;
+    let mut mask = Mat::default();
+    if low_hue <= high_hue {
+        core::in_range(&hsv, &bound(range.low), &bound(range.high), &mut mask)?;
+    } else {
+        // Through red, from the low hue up to the last and from the first up
+        // to the high hue
+        let mut upper = Mat::default();
+        let mut lower = Mat::default();
+        core::in_range(
+            &hsv,
+            &bound(range.low),
+            &bound([179, high_saturation, high_value]),
+            &mut upper,
+        )?;
+        core::in_range(
+            &hsv,
+            &bound([0, low_saturation, low_value]),
+            &bound(range.high),
+            &mut lower,
+        )?;
+        core::bitwise_or(&upper, &lower, &mut mask, &core::no_array())?;
+    }
+    Ok(mask)
 }
 
-pub fn find_shapes(image: &Mat) -> Result<Vec<Shape>, Box<dyn std::error::Error>> {
+/// Traces the regions of an image drawn within an HSV range as polygons in
+/// map units, skipping those under `min_area` pixels.
+pub fn trace_hsv_regions(
+    image: &Mat,
+    range: &HsvRange,
+    min_area: f64,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Vec<Vector2>>, Box<dyn std::error::Error>> {
+    trace_color_regions(image, &ColorRange::Hsv(*range), min_area, mapper)
+}
+
+/// Traces the regions of an image drawn within a range of colors as polygons
+/// in map units, skipping those under `min_area` pixels.
+pub fn trace_color_regions(
+    image: &Mat,
+    range: &ColorRange,
+    min_area: f64,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Vec<Vector2>>, Box<dyn std::error::Error>> {
+    trace_outlines(
+        &range.mask(image)?,
+        imgproc::RETR_EXTERNAL,
+        min_area,
+        0.005,
+        mapper,
+    )
+}
+
+/// Whether no ink is drawn between two points of a mask, away from either end.
+fn is_blank_between(ink: &Mat, a: Vector2, b: Vector2) -> bool {
+    [0.25, 0.5, 0.75].iter().all(|t| {
+        let x = (a.x + (b.x - a.x) * t).round() as i32;
+        let y = (a.y + (b.y - a.y) * t).round() as i32;
+        ink.at_2d::<u8>(y, x).is_ok_and(|&value| value == 0)
+    })
+}
+
+/// The indices of the contours a contour lies within, innermost first, from
+/// the `[next, previous, first child, parent]` links OpenCV finds them with.
+fn ancestors(hierarchy: &[[i32; 4]], index: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(Some(index), |&i| usize::try_from(hierarchy[i][3]).ok()).skip(1)
+}
+
+/// How deeply each contour is nested, outermost contours being 0 deep.
+fn contour_depths(hierarchy: &[[i32; 4]]) -> Vec<usize> {
+    (0..hierarchy.len())
+        .map(|index| ancestors(hierarchy, index).count())
+        .collect()
+}
+
+/// Whether a contour nested in another runs along it within `margin` pixels
+/// on every side, as the inner edge of a wall does its outer edge.
+fn is_inner_edge(outer: &core::Rect, inner: &core::Rect, margin: f64) -> bool {
+    let insets = [
+        inner.x - outer.x,
+        inner.y - outer.y,
+        (outer.x + outer.width) - (inner.x + inner.width),
+        (outer.y + outer.height) - (inner.y + inner.height),
+    ];
+    insets.iter().all(|&inset| inset as f64 <= margin)
+}
+
+/// Finds the shapes outlined in an image, closing gaps of up to `max_gap`
+/// pixels in their boundaries. Shapes within others, such as pillars and
+/// rooms inside outer walls, are found too, after the shapes around them.
+#[instrument(skip_all)]
+pub fn find_shapes(
+    image: &Mat,
+    detector: &Detector,
+    max_gap: f64,
+    detection: &DetectionOptions,
+) -> Result<Vec<Shape>, Box<dyn std::error::Error>> {
     // Convert the image to grayscale
     let mut gray_image = Mat::default();
     imgproc::cvt_color(&image, &mut gray_image, imgproc::COLOR_BGR2GRAY, 0)?;
 
-    // Apply edge detection (e.g. using the Canny algorithm)
-    let mut edges = Mat::default();
-    imgproc::canny(&gray_image, &mut edges, 50.0, 150.0, 3, false)?;
+    let (mut edges, ink) = match detector {
+        // Shapes of line traced maps are reviewed and previewed by their edges
+        Detector::Canny | Detector::Lines => {
+            // Apply edge detection (e.g. using the Canny algorithm)
+            let mut edges = Mat::default();
+            imgproc::canny(
+                &gray_image,
+                &mut edges,
+                detection.canny_low,
+                detection.canny_high,
+                3,
+                false,
+            )?;
+            let mut ink = Mat::default();
+            imgproc::threshold(
+                &gray_image,
+                &mut ink,
+                INK_THRESHOLD,
+                255.0,
+                imgproc::THRESH_BINARY_INV,
+            )?;
+            (edges, ink)
+        }
+        Detector::Hsv(range) => {
+            let mask = hsv_mask(image, range)?;
+            (mask.try_clone()?, mask)
+        }
+        Detector::Fills(colors) => {
+            // Whatever isn't floor bounds the rooms, so gaps are closed across it
+            let floor = segment_fills(image, colors, None)?.floor;
+            let mut bounds = Mat::default();
+            core::bitwise_not(&floor, &mut bounds, &core::no_array())?;
+            (floor, bounds)
+        }
+    };
 
-    // Find contours in the edge-detected image
+    // Find contours in the edge-detected image and how they nest, keeping
+    // every point so confidence can be judged along the whole outline
     let mut contours = VectorOfMat::new();
     let mut hierarchy = Mat::default();
     imgproc::find_contours_with_hierarchy(
         &mut edges,
         &mut contours,
         &mut hierarchy,
-        imgproc::RETR_EXTERNAL,
-        imgproc::CHAIN_APPROX_SIMPLE,
+        imgproc::RETR_TREE,
+        imgproc::CHAIN_APPROX_NONE,
         core::Point::new(0, 0),
     )?;
+    let gradient = gradient_magnitude(&gray_image)?;
+    let hierarchy: Vec<[i32; 4]> = if contours.is_empty() {
+        vec![]
+    } else {
+        hierarchy
+            .data_typed::<core::Vec4i>()?
+            .iter()
+            .map(|link| link.0)
+            .collect()
+    };
+    let depths = contour_depths(&hierarchy);
+    // Outer contours come before those nested within them, so parents are
+    // known by the time their children are reached
+    let mut order: Vec<usize> = (0..contours.len()).collect();
+    order.sort_by_key(|&index| depths[index]);
 
     // Iterate over detected contours and print their coords and dimensions
     info!("Detected {} contours", contours.len());
     let mut shapes = Vec::new();
-    for contour in contours.iter() {
-        let area = imgproc::contour_area(&contour, false)?;
-        if area > 100.0 {
-            let mut approx = Mat::default();
-            let epsilon = 0.04 * imgproc::arc_length(&contour, true)?;
-            imgproc::approx_poly_dp(&contour, &mut approx, epsilon, true)?;
-            let num_vertices = approx.total() as u32;
+    let mut bounds = Vec::new();
+    let mut shape_of = HashMap::new();
+    for index in order {
+        // Odd depths are the holes inside the contours around them
+        if depths[index] % 2 == 1 {
+            continue;
+        }
+        let mut contour = contours.get(index)?;
+        let points: Vec<Vector2> = contour
+            .data_typed::<core::Point>()?
+            .iter()
+            .map(|p| Vector2 {
+                x: p.x as f64,
+                y: p.y as f64,
+            })
+            .collect();
+        if let Some(closed) = close_gap(&points, max_gap, |a, b| is_blank_between(&ink, a, b)) {
+            let (first, last) = (closed[0], closed[closed.len() - 1]);
+            info!(
+                "Closed a gap of {:.0} pixels in a boundary between ({}, {}) and ({}, {})",
+                distance(first, last),
+                first.x,
+                first.y,
+                last.x,
+                last.y
+            );
+            contour = Mat::from_exact_iter(
+                closed
+                    .iter()
+                    .map(|p| core::Point::new(p.x as i32, p.y as i32)),
+            )?;
+        }
+        let measures = measure(&contour)?;
+        if detection.keeps(&measures) {
             let bounding_rect = imgproc::bounding_rect(&contour)?;
+            let parent = ancestors(&hierarchy, index).find_map(|a| shape_of.get(&a).copied());
+            if let Some(parent) = parent {
+                let outer: &core::Rect = &bounds[parent];
+                let margin = INNER_EDGE_SHARE * outer.width.min(outer.height) as f64;
+                if is_inner_edge(outer, &bounding_rect, margin) {
+                    // The inside of the walls around the parent, not a shape of its own
+                    shape_of.insert(index, parent);
+                    continue;
+                }
+            }
+            let mut approx = VectorOfPoint::new();
+            let epsilon = detection.epsilon * measures.perimeter;
+            imgproc::approx_poly_dp(&contour, &mut approx, epsilon, true)?;
+            let num_vertices = approx.len() as u32;
+            let outline = contour.data_typed::<core::Point>()?;
+            let gradients = outline
+                .iter()
+                .map(|p| gradient.at_2d::<f32>(p.y, p.x).map(|g| *g as f64))
+                .collect::<Result<Vec<_>, _>>()?;
+            let to_vector = |p: &core::Point| Vector2 {
+                x: p.x as f64,
+                y: p.y as f64,
+            };
+            let outline: Vec<Vector2> = outline.iter().map(to_vector).collect();
+            let vertices: Vec<Vector2> = approx.iter().map(|p| to_vector(&p)).collect();
+            let confidence = Confidence {
+                closure: closure_score(&outline),
+                edge: edge_score(&gradients),
+                fit: fit_score(&outline, &vertices, epsilon),
+                class: class_score(&vertices),
+            };
             let shape = Shape {
                 vertice_count: num_vertices,
                 coordinates: Point {
@@ -87,25 +1142,35 @@ pub fn find_shapes(image: &Mat) -> Result<Vec<Shape>, Box<dyn std::error::Error>
                     y: bounding_rect.y,
                 },
                 contour,
+                vertices: approx.iter().map(|p| Point { x: p.x, y: p.y }).collect(),
+                confidence,
+                parent,
+                children: Vec::new(),
             };
             info!("{}", shape);
+            if let Some(parent) = parent {
+                shapes[parent].children.push(shapes.len());
+            }
+            shape_of.insert(index, shapes.len());
+            bounds.push(bounding_rect);
             shapes.push(shape);
         }
     }
     Ok(shapes)
 }
 
-pub fn try_trace_shapes(image_path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+pub fn try_trace_shapes(
+    image_path: &Path,
+    style: &TraceStyle,
+    detection: &DetectionOptions,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
     debug!(
         "Finding contours and tracing shapes in {}",
         image_path.display()
     );
-    let image = imread(
-        image_path.as_os_str().to_str().unwrap(),
-        opencv::imgcodecs::ImreadModes::IMREAD_COLOR as i32,
-    )?;
+    let image = read_map_image(image_path)?;
 
-    let traced_image = trace_shapes(&image)?;
+    let traced_image = trace_shapes(&image, style, detection)?;
 
     let mut contour_image_path = image_path.to_path_buf();
     contour_image_path.set_extension("shapes.png");
@@ -119,66 +1184,382 @@ pub fn try_trace_shapes(image_path: &Path) -> Result<PathBuf, Box<dyn std::error
     Ok(contour_image_path)
 }
 
-pub fn trace_shapes(image: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
-    // Convert the image to grayscale
-    let mut gray_image = Mat::default();
-    imgproc::cvt_color(&image, &mut gray_image, imgproc::COLOR_BGR2GRAY, 0)?;
-
-    // Apply edge detection (e.g. using the Canny algorithm)
-    let mut edges = Mat::default();
-    imgproc::canny(&gray_image, &mut edges, 50.0, 150.0, 3, false)?;
-
-    // Find contours in the edge-detected image
-    let mut contours = VectorOfMat::new();
-    let mut hierarchy = Mat::default();
-    imgproc::find_contours_with_hierarchy(
-        &mut edges,
-        &mut contours,
-        &mut hierarchy,
-        imgproc::RETR_EXTERNAL,
-        imgproc::CHAIN_APPROX_SIMPLE,
-        core::Point::new(0, 0),
-    )?;
-
-    // Create a new image to draw contours on
-    let mut traced_image = Mat::default();
-    image.copy_to(&mut traced_image)?;
+fn scalar(color: Color) -> Scalar {
+    Scalar::new(color.b as f64, color.g as f64, color.r as f64, 0.0)
+}
 
-    // Iterate over detected contours and print their coords and dimensions
-    info!("Detected {} contours", contours.len());
-    let mut contour_count = 0;
-    for contour in contours.iter() {
-        let area = imgproc::contour_area(&contour, false)?;
-        if area > 100.0 {
-            let mut approx = Mat::default();
-            let epsilon = 0.04 * imgproc::arc_length(&contour, true)?;
-            imgproc::approx_poly_dp(&contour, &mut approx, epsilon, true)?;
-            let bounding_rect = imgproc::bounding_rect(&contour)?;
-            contour_count = contour_count + 1;
-            debug!(
-                "[{} / {}] Shape detected at ({}, {}) with width: {} and height {}",
-                contour_count,
-                contours.len(),
-                bounding_rect.x,
-                bounding_rect.y,
-                bounding_rect.width,
-                bounding_rect.height,
-            );
+fn label(image: &mut Mat, text: &str, at: core::Point, color: Scalar) -> opencv::Result<()> {
+    imgproc::put_text(
+        image,
+        text,
+        at,
+        imgproc::FONT_HERSHEY_SIMPLEX,
+        LABEL_SCALE,
+        color,
+        1,
+        imgproc::LINE_AA,
+        false,
+    )
+}
 
-            // Draw contours on the image
-            let color = Scalar::new(0.0, 255.0, 0.0, 0.0);
-            imgproc::draw_contours(
+/// Draws every shape found in an image over it, colored by its class, with
+/// its id and confidence at its center and the index of each of its vertices
+/// beside it. Shapes in need of review are colored alike.
+///
+/// Ids are the order of [`find_shapes`], so they match the walls generated
+/// from the image.
+pub fn trace_shapes(
+    image: &Mat,
+    style: &TraceStyle,
+    detection: &DetectionOptions,
+) -> Result<Mat, Box<dyn std::error::Error>> {
+    let shapes = find_shapes(image, &Detector::Canny, MAX_GAP, detection)?;
+    let mut traced_image = image.try_clone()?;
+    for (id, shape) in shapes.iter().enumerate() {
+        let color = scalar(style.shape_color(shape));
+        let mut contour = VectorOfMat::new();
+        contour.push(shape.contour.try_clone()?);
+        imgproc::draw_contours(
+            &mut traced_image,
+            &contour,
+            0,
+            color,
+            2,
+            imgproc::LINE_8,
+            &core::no_array(),
+            0,
+            core::Point::new(0, 0),
+        )?;
+        for (index, vertex) in shape.vertices.iter().enumerate() {
+            let center = core::Point::new(vertex.x, vertex.y);
+            imgproc::circle(
+                &mut traced_image,
+                center,
+                VERTEX_RADIUS,
+                color,
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )?;
+            let offset = core::Point::new(VERTEX_RADIUS + 2, -VERTEX_RADIUS - 2);
+            label(
                 &mut traced_image,
-                &contours,
-                -1,
+                &index.to_string(),
+                center + offset,
                 color,
-                2,
-                opencv::core::LINE_8,
-                &hierarchy,
-                1,
-                core::Point::new(0, 0),
             )?;
         }
+        let bounds = imgproc::bounding_rect(&shape.contour)?;
+        let center = core::Point::new(bounds.x + bounds.width / 2, bounds.y + bounds.height / 2);
+        let text = format!("#{} {:.2}", id, shape.confidence.score());
+        label(&mut traced_image, &text, center, color)?;
     }
     Ok(traced_image)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_page() {
+        let pages = || vec![Mat::default(), Mat::default(), Mat::default()];
+        let path = Path::new("crypt.tiff");
+        assert_eq!(pick_page(pages(), None, path).unwrap().len(), 3);
+        assert_eq!(pick_page(pages(), Some(3), path).unwrap().len(), 1);
+        assert!(pick_page(pages(), Some(0), path).is_err());
+        assert!(pick_page(pages(), Some(4), path).is_err());
+        assert!(is_multi_page(Path::new("keep.PDF")));
+        assert!(!is_multi_page(Path::new("keep.png")));
+    }
+
+    #[test]
+    fn test_is_rust_decoded() {
+        assert!(is_rust_decoded(Path::new("map.webp")));
+        assert!(is_rust_decoded(Path::new("/photos/MAP.HEIC")));
+        assert!(!is_rust_decoded(Path::new("map.png")));
+        assert!(!is_rust_decoded(Path::new("webp")));
+    }
+
+    /// A JPEG of the EXIF data in a TIFF header, after an empty APP0 segment.
+    fn exif_jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(tiff);
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0, 0xff, 0xe1];
+        jpeg.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        jpeg.extend_from_slice(&app1);
+        jpeg.extend_from_slice(&[0xff, 0xda, 0, 2]);
+        jpeg
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        let jpeg = exif_jpeg;
+        // A little endian IFD of a single orientation entry, rotated 90 degrees
+        let tiff = [
+            0x49, 0x49, 42, 0, 8, 0, 0, 0, 1, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0,
+            0,
+        ];
+        assert_eq!(exif_orientation(&jpeg(&tiff)), Some(Orientation::Rotate90));
+        // Big endian, flipped horizontally
+        let tiff = [
+            0x4d, 0x4d, 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 2, 0, 0, 0, 0, 0,
+            0,
+        ];
+        assert_eq!(
+            exif_orientation(&jpeg(&tiff)),
+            Some(Orientation::FlipHorizontal)
+        );
+        assert_eq!(exif_orientation(&[0xff, 0xd8, 0xff, 0xda, 0, 2]), None);
+        assert_eq!(exif_orientation(&jpeg(&tiff)[..12]), None);
+        assert_eq!(exif_orientation(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn test_image_dpi() {
+        // 5906 pixels per meter is 150 DPI
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x09pHYs".to_vec();
+        png.extend_from_slice(&[0, 0, 0x17, 0x12, 0, 0, 0x17, 0x12, 1, 0, 0, 0, 0]);
+        assert_eq!(image_dpi(&png).map(f64::round), Some(150.0));
+        // Without a unit only the aspect ratio of the pixels is known
+        png[24] = 0;
+        assert_eq!(image_dpi(&png), None);
+
+        let jfif = [
+            0xff, 0xd8, 0xff, 0xe0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 1, 0, 200, 0, 200, 0,
+            0, 0xff, 0xda, 0, 2,
+        ];
+        assert_eq!(image_dpi(&jfif), Some(200.0));
+
+        // 300 inches over 1, in an IFD of the resolution and its unit
+        let tiff = [
+            0x49, 0x49, 42, 0, 8, 0, 0, 0, 2, 0, 0x1a, 0x01, 5, 0, 1, 0, 0, 0, 38, 0, 0, 0, 0x28,
+            0x01, 3, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0x2c, 0x01, 0, 0, 1, 0, 0, 0,
+        ];
+        assert_eq!(image_dpi(&tiff), Some(300.0));
+        assert_eq!(image_dpi(&exif_jpeg(&tiff)), Some(300.0));
+        assert_eq!(image_dpi(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn test_detection_rules() {
+        let corridor = Measures {
+            area: 2000.0,
+            perimeter: 420.0,
+            aspect: 10.0,
+            convexity: 1.0,
+        };
+        let flourish = Measures {
+            area: 400.0,
+            perimeter: 300.0,
+            aspect: 1.5,
+            convexity: 0.4,
+        };
+        let defaults = DetectionOptions::default();
+        assert!(defaults.keeps(&corridor) && defaults.keeps(&flourish));
+
+        let rules = DetectionOptions {
+            min_convexity: 0.6,
+            ..defaults
+        };
+        assert!(rules.keeps(&corridor) && !rules.keeps(&flourish));
+        let rules = DetectionOptions {
+            max_aspect: Some(4.0),
+            ..defaults
+        };
+        assert!(!rules.keeps(&corridor) && rules.keeps(&flourish));
+        let rules = DetectionOptions {
+            min_area: 500.0,
+            max_perimeter: Some(400.0),
+            ..defaults
+        };
+        assert!(!rules.keeps(&corridor) && !rules.keeps(&flourish));
+        let rules = DetectionOptions {
+            max_area: Some(1000.0),
+            min_aspect: 1.2,
+            ..defaults
+        };
+        assert!(!rules.keeps(&corridor) && rules.keeps(&flourish));
+    }
+
+    #[test]
+    fn test_validate_detection() {
+        let defaults = DetectionOptions::default();
+        assert_eq!(defaults.validate(), Ok(()));
+        let invalid = [
+            DetectionOptions {
+                canny_low: 200.0,
+                ..defaults
+            },
+            DetectionOptions {
+                canny_low: -1.0,
+                ..defaults
+            },
+            DetectionOptions {
+                epsilon: -0.01,
+                ..defaults
+            },
+            DetectionOptions {
+                min_area: -100.0,
+                ..defaults
+            },
+            DetectionOptions {
+                min_perimeter: f64::NAN,
+                ..defaults
+            },
+        ];
+        for options in invalid {
+            assert!(options.validate().is_err(), "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn test_contour_nesting() {
+        // A building, the hole inside its outline, a pillar within, and a
+        // second building beside it
+        let hierarchy = [
+            [3, -1, 1, -1],
+            [-1, -1, 2, 0],
+            [-1, -1, -1, 1],
+            [-1, 0, -1, -1],
+        ];
+        assert_eq!(contour_depths(&hierarchy), vec![0, 1, 2, 0]);
+        assert_eq!(ancestors(&hierarchy, 2).collect::<Vec<_>>(), vec![1, 0]);
+        assert_eq!(ancestors(&hierarchy, 3).count(), 0);
+
+        let outer = core::Rect::new(10, 10, 100, 80);
+        assert!(is_inner_edge(&outer, &core::Rect::new(14, 14, 92, 72), 8.0));
+        assert!(!is_inner_edge(
+            &outer,
+            &core::Rect::new(40, 30, 20, 20),
+            8.0
+        ));
+    }
+
+    #[test]
+    fn test_find_nested_shapes() {
+        // A room walled all around with a pillar standing in its middle
+        let mut image =
+            Mat::new_rows_cols_with_default(200, 200, core::CV_8UC3, Scalar::all(255.0)).unwrap();
+        let mut draw = |rect: core::Rect, thickness: i32| {
+            imgproc::rectangle(
+                &mut image,
+                rect,
+                Scalar::all(0.0),
+                thickness,
+                imgproc::LINE_8,
+                0,
+            )
+            .unwrap();
+        };
+        draw(core::Rect::new(10, 10, 180, 180), 4);
+        draw(core::Rect::new(80, 80, 40, 40), imgproc::FILLED);
+
+        let shapes = find_shapes(
+            &image,
+            &Detector::Canny,
+            MAX_GAP,
+            &DetectionOptions::default(),
+        )
+        .unwrap();
+        // The inner edge of the walls is not a shape of its own
+        assert_eq!(shapes.len(), 2);
+        assert_eq!(shapes[0].parent(), None);
+        assert_eq!(shapes[0].children(), [1]);
+        assert_eq!(shapes[1].parent(), Some(0));
+        assert!(shapes[1].children().is_empty());
+        assert_eq!(shapes[1].class(), ShapeClass::Rectangle);
+    }
+
+    #[test]
+    fn test_parse_hsv_range() {
+        assert_eq!(
+            parse_hsv_range("100,80,40:130, 255, 255"),
+            Ok(HsvRange {
+                low: [100, 80, 40],
+                high: [130, 255, 255]
+            })
+        );
+        // Wrapping around through red
+        assert!(parse_hsv_range("170,100,100:10,255,255").is_ok());
+        assert!(parse_hsv_range("180,0,0:10,255,255").is_err());
+        assert!(parse_hsv_range("0,200,0:10,100,255").is_err());
+        assert!(parse_hsv_range("0,0,0").is_err());
+        assert!(parse_hsv_range("0,0:10,255,255").is_err());
+    }
+
+    #[test]
+    fn test_parse_color_range() {
+        let blue = HsvRange {
+            low: [100, 80, 40],
+            high: [130, 255, 255],
+        };
+        assert_eq!(
+            parse_color_range("100,80,40:130,255,255"),
+            Ok(ColorRange::Hsv(blue))
+        );
+        assert_eq!(
+            parse_color_range("hsv:100,80,40:130,255,255"),
+            Ok(ColorRange::Hsv(blue))
+        );
+        assert_eq!(
+            parse_color_range("lab:90,-10,-10:100,10,10"),
+            Ok(ColorRange::Lab(LabRange {
+                low: [230, 118, 118],
+                high: [255, 138, 138]
+            }))
+        );
+        assert!(parse_color_range("lab:90,-10:100,10,10").is_err());
+        assert!(parse_color_range("lab:90,-10,-10:120,10,10").is_err());
+        assert!(parse_color_range("lab:90,10,-10:100,-10,10").is_err());
+    }
+
+    #[test]
+    fn test_parse_texture_color() {
+        assert_eq!(
+            parse_texture_color("res://textures/materials/dirt.png=10,50,50:30,255,200"),
+            Ok((
+                "res://textures/materials/dirt.png".to_string(),
+                HsvRange {
+                    low: [10, 50, 50],
+                    high: [30, 255, 200]
+                }
+            ))
+        );
+        assert!(parse_texture_color("10,50,50:30,255,200").is_err());
+        assert!(parse_texture_color("=10,50,50:30,255,200").is_err());
+        assert!(parse_texture_color("dirt=10,50,50").is_err());
+    }
+
+    #[test]
+    fn test_shape_class() {
+        assert_eq!(ShapeClass::of(3), ShapeClass::Triangle);
+        assert_eq!(ShapeClass::of(4), ShapeClass::Rectangle);
+        assert_eq!(ShapeClass::of(6), ShapeClass::Polygon);
+        assert_eq!(ShapeClass::of(12), ShapeClass::Circle);
+    }
+
+    #[test]
+    fn test_trace_style() {
+        let colors = HashMap::from([("rectangle".to_string(), "#ffff00".to_string())]);
+        let style = TraceStyle::with_colors(&colors).unwrap();
+        assert_eq!(
+            style.color(ShapeClass::Rectangle),
+            Color {
+                a: 0xff,
+                r: 255,
+                g: 255,
+                b: 0
+            }
+        );
+        assert_eq!(
+            style.color(ShapeClass::Circle),
+            TraceStyle::default().color(ShapeClass::Circle)
+        );
+
+        let colors = HashMap::from([("hexagon".to_string(), "#ffff00".to_string())]);
+        assert!(TraceStyle::with_colors(&colors).is_err());
+        let colors = HashMap::from([("circle".to_string(), "yellow".to_string())]);
+        assert!(TraceStyle::with_colors(&colors).is_err());
+    }
+}
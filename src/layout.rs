@@ -0,0 +1,176 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use opencv::core::{self, Mat, Rect, Scalar};
+use opencv::prelude::*;
+use serde::Deserialize;
+use tracing::{debug, instrument};
+
+use crate::coordinates::Rotation;
+use crate::dungeondraft_v1::Vector2;
+use crate::images::read_map_image;
+use crate::preprocess::rotate_image;
+
+/// An image of a layout and where it goes on the composite.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Placement {
+    /// Relative to the layout file
+    pub image: PathBuf,
+    /// Where the top left of the turned image goes, in pixels
+    #[serde(default)]
+    pub offset: [f64; 2],
+    /// How far the image is turned counter-clockwise about its center, in degrees
+    #[serde(default)]
+    pub rotation: f64,
+}
+
+/// Several images placed together as one map, such as a dungeon printed
+/// across the pages of a PDF.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Layout {
+    pub images: Vec<Placement>,
+}
+
+/// Where the top left of each of a layout's images lands on a composite just
+/// large enough to hold them, given their sizes once turned, and the size of
+/// the composite.
+pub fn arrange(offsets: &[Vector2], sizes: &[Vector2]) -> (Vec<Vector2>, Vector2) {
+    let min = offsets.iter().fold(
+        Vector2 {
+            x: f64::INFINITY,
+            y: f64::INFINITY,
+        },
+        |min, p| Vector2 {
+            x: min.x.min(p.x),
+            y: min.y.min(p.y),
+        },
+    );
+    let corners: Vec<Vector2> = offsets
+        .iter()
+        .map(|p| Vector2 {
+            x: (p.x - min.x).round(),
+            y: (p.y - min.y).round(),
+        })
+        .collect();
+    let size = corners
+        .iter()
+        .zip(sizes)
+        .fold(Vector2::default(), |size, (corner, image)| Vector2 {
+            x: size.x.max(corner.x + image.x.ceil()),
+            y: size.y.max(corner.y + image.y.ceil()),
+        });
+    (corners, size)
+}
+
+impl Layout {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut layout: Layout = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if layout.images.is_empty() {
+            return Err(format!("{} places no images", path.display()).into());
+        }
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for placement in &mut layout.images {
+            placement.image = dir.join(&placement.image);
+        }
+        Ok(layout)
+    }
+
+    /// The images drawn together on a white composite, overlaps keeping the
+    /// ink of both so geometry runs on across their seams.
+    #[instrument(skip_all)]
+    pub fn compose(&self) -> Result<Mat, Box<dyn std::error::Error>> {
+        let mut images = Vec::new();
+        for placement in &self.images {
+            let image = read_map_image(&placement.image)?;
+            if image.empty() {
+                return Err(format!("failed to read image {}", placement.image.display()).into());
+            }
+            images.push(if placement.rotation == 0.0 {
+                image
+            } else {
+                let rotation = Rotation::new(placement.rotation, image.cols(), image.rows());
+                rotate_image(&image, &rotation)?
+            });
+        }
+        let offsets: Vec<Vector2> = self
+            .images
+            .iter()
+            .map(|placement| Vector2 {
+                x: placement.offset[0],
+                y: placement.offset[1],
+            })
+            .collect();
+        let sizes: Vec<Vector2> = images
+            .iter()
+            .map(|image| Vector2 {
+                x: image.cols() as f64,
+                y: image.rows() as f64,
+            })
+            .collect();
+        let (corners, size) = arrange(&offsets, &sizes);
+        debug!(
+            "Composing {} images into a {}x{} image",
+            images.len(),
+            size.x,
+            size.y
+        );
+        let composite = Mat::new_rows_cols_with_default(
+            size.y as i32,
+            size.x as i32,
+            core::CV_8UC3,
+            Scalar::all(255.0),
+        )?;
+        for (image, corner) in images.iter().zip(&corners) {
+            let area = Rect::new(corner.x as i32, corner.y as i32, image.cols(), image.rows());
+            // The region shares the composite's pixels
+            let mut region = Mat::roi(&composite, area)?;
+            let mut darkest = Mat::default();
+            core::min(&region, image, &mut darkest)?;
+            darkest.copy_to(&mut region)?;
+        }
+        Ok(composite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arrange() {
+        let offsets = [Vector2 { x: 100.0, y: -50.0 }, Vector2 { x: 900.0, y: 0.0 }];
+        let sizes = [
+            Vector2 { x: 800.0, y: 600.0 },
+            Vector2 { x: 640.5, y: 480.0 },
+        ];
+        let (corners, size) = arrange(&offsets, &sizes);
+        assert_eq!(corners[0], Vector2 { x: 0.0, y: 0.0 });
+        assert_eq!(corners[1], Vector2 { x: 800.0, y: 50.0 });
+        assert_eq!(
+            size,
+            Vector2 {
+                x: 1441.0,
+                y: 600.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_layout() {
+        let path = std::env::temp_dir().join(format!("ddgen-layout-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{ "images": [ { "image": "page1.png" }, { "image": "page2.png", "offset": [1200, 0], "rotation": 90 } ] }"#,
+        )
+        .unwrap();
+        let layout = Layout::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            layout.images[0].image,
+            std::env::temp_dir().join("page1.png")
+        );
+        assert_eq!(layout.images[0].offset, [0.0, 0.0]);
+        assert_eq!(layout.images[1].rotation, 90.0);
+    }
+}
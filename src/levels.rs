@@ -0,0 +1,161 @@
+use serde_json::Value;
+
+use crate::dungeondraft_v1::{
+    add_level, node_position, nodes, translate_level, Vector2, UNITS_PER_CELL,
+};
+use crate::geometry::distance;
+
+/// Stairs on two levels this close once aligned are the same stairwell.
+const STAIR_TOLERANCE: f64 = UNITS_PER_CELL / 2.0;
+
+/// How a level is lined up with the level below it by their stairs.
+#[derive(Debug, Default, PartialEq)]
+pub struct Alignment {
+    /// How far the upper level is moved, in map units
+    pub offset: Vector2,
+    /// The indices of the stairs of the lower and upper levels matched up
+    pub matched: Vec<(usize, usize)>,
+    pub unmatched_lower: Vec<Vector2>,
+    pub unmatched_upper: Vec<Vector2>,
+}
+
+/// Where the stairs of a level are, objects with a stairs texture.
+pub fn stairs(level: &Value) -> Vec<Vector2> {
+    nodes(level, "objects")
+        .filter(|object| {
+            object["texture"]
+                .as_str()
+                .is_some_and(|texture| texture.contains("stairs"))
+        })
+        .filter_map(node_position)
+        .collect()
+}
+
+/// The stairs of `upper` moved by `offset` that are within `STAIR_TOLERANCE` of
+/// stairs of `lower`, each matched at most once.
+fn matches(lower: &[Vector2], upper: &[Vector2], offset: Vector2) -> Vec<(usize, usize)> {
+    let mut matched: Vec<(usize, usize)> = Vec::new();
+    for (j, p) in upper.iter().enumerate() {
+        let moved = Vector2 {
+            x: p.x + offset.x,
+            y: p.y + offset.y,
+        };
+        let nearest = lower
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| matched.iter().all(|&(m, _)| m != *i))
+            .map(|(i, q)| (i, distance(moved, *q)))
+            .filter(|&(_, d)| d <= STAIR_TOLERANCE)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, _)) = nearest {
+            matched.push((i, j));
+        }
+    }
+    matched
+}
+
+/// The offset lining up the most stairs of the upper level with stairs of the
+/// lower, trying every pairing of a stair below with one above, the smallest
+/// such move when several line up as many.
+pub fn align_stairs(lower: &[Vector2], upper: &[Vector2]) -> Alignment {
+    let mut best: Option<(Vector2, Vec<(usize, usize)>)> = None;
+    for q in lower {
+        for p in upper {
+            let offset = Vector2 {
+                x: q.x - p.x,
+                y: q.y - p.y,
+            };
+            let found = matches(lower, upper, offset);
+            let better = best.as_ref().is_none_or(|(best_offset, best_found)| {
+                found.len() > best_found.len()
+                    || (found.len() == best_found.len()
+                        && distance(offset, Vector2::default())
+                            < distance(*best_offset, Vector2::default()))
+            });
+            if better {
+                best = Some((offset, found));
+            }
+        }
+    }
+    let (offset, matched) = best.unwrap_or_default();
+    // Averaged over every stairwell matched, rather than one pair's
+    let offset = if matched.is_empty() {
+        offset
+    } else {
+        let n = matched.len() as f64;
+        Vector2 {
+            x: matched
+                .iter()
+                .map(|&(i, j)| lower[i].x - upper[j].x)
+                .sum::<f64>()
+                / n,
+            y: matched
+                .iter()
+                .map(|&(i, j)| lower[i].y - upper[j].y)
+                .sum::<f64>()
+                / n,
+        }
+    };
+    Alignment {
+        offset,
+        unmatched_lower: (0..lower.len())
+            .filter(|i| matched.iter().all(|(m, _)| m != i))
+            .map(|i| lower[i])
+            .collect(),
+        unmatched_upper: (0..upper.len())
+            .filter(|j| matched.iter().all(|(_, m)| m != j))
+            .map(|j| upper[j])
+            .collect(),
+        matched,
+    }
+}
+
+/// Adds a generated map as a level above the top level of a map, moved so
+/// their stairwells line up, returning how it was aligned.
+pub fn stack_level(
+    map: &mut Value,
+    mut upper: Value,
+    label: &str,
+) -> Result<Alignment, Box<dyn std::error::Error>> {
+    let top = map
+        .pointer("/world/levels")
+        .and_then(Value::as_object)
+        .and_then(|levels| {
+            levels
+                .iter()
+                .filter_map(|(key, level)| Some((key.parse::<i64>().ok()?, level)))
+                .max_by_key(|(key, _)| *key)
+        })
+        .map(|(_, level)| stairs(level))
+        .ok_or("map has no levels")?;
+    let alignment = align_stairs(&top, &stairs(&upper["world"]["levels"]["0"]));
+    translate_level(&mut upper["world"]["levels"]["0"], alignment.offset);
+    add_level(map, &upper, label)?;
+    Ok(alignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(xy: &[(f64, f64)]) -> Vec<Vector2> {
+        xy.iter().map(|&(x, y)| Vector2 { x, y }).collect()
+    }
+
+    #[test]
+    fn test_align_stairs() {
+        let lower = points(&[(512.0, 512.0), (2048.0, 1024.0), (4096.0, 256.0)]);
+        // The first two stairwells, a cell to the left and up, and stairs
+        // going nowhere below
+        let upper = points(&[(256.0, 260.0), (1792.0, 768.0), (768.0, 3000.0)]);
+        let alignment = align_stairs(&lower, &upper);
+        assert_eq!(alignment.matched, vec![(0, 0), (1, 1)]);
+        assert_eq!(alignment.offset, Vector2 { x: 256.0, y: 254.0 });
+        assert_eq!(alignment.unmatched_lower, points(&[(4096.0, 256.0)]));
+        assert_eq!(alignment.unmatched_upper, points(&[(768.0, 3000.0)]));
+
+        let alone = align_stairs(&lower, &[]);
+        assert_eq!(alone.offset, Vector2::default());
+        assert_eq!(alone.unmatched_lower.len(), 3);
+    }
+}
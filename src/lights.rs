@@ -0,0 +1,198 @@
+use opencv::core::{self, Mat, Scalar};
+use opencv::imgproc;
+use opencv::types::VectorOfVectorOfPoint;
+use serde_json::Value;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{light_node, Vector2, DEFAULT_LIGHT_RANGE};
+use crate::geometry::{boundary_distance, centroid};
+
+const DAYLIGHT_COLOR: &str = "ffdcecff";
+const SCONCE_COLOR: &str = "ffffb25c";
+const ROOM_LIGHT_COLOR: &str = "fffff0d8";
+const DIRECTIONAL_LIGHT_TEXTURE: &str = "res://textures/lights/spot.png";
+/// HSV ranges of the pale blue glass drawn in windows.
+const WINDOW_LOWER: Scalar = Scalar::new(85.0, 60.0, 150.0, 0.0);
+const WINDOW_UPPER: Scalar = Scalar::new(110.0, 255.0, 255.0, 0.0);
+/// HSV ranges of the warm flames drawn for sconces.
+const SCONCE_LOWER: Scalar = Scalar::new(10.0, 120.0, 150.0, 0.0);
+const SCONCE_UPPER: Scalar = Scalar::new(30.0, 255.0, 255.0, 0.0);
+/// Glyphs smaller than this many pixels are noise.
+const MIN_GLYPH_AREA: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixtureKind {
+    Window,
+    Sconce,
+}
+
+/// A window or sconce glyph found in the source image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixture {
+    pub kind: FixtureKind,
+    pub position: Vector2,
+}
+
+impl FixtureKind {
+    fn color(self) -> &'static str {
+        match self {
+            FixtureKind::Window => DAYLIGHT_COLOR,
+            FixtureKind::Sconce => SCONCE_COLOR,
+        }
+    }
+
+    /// Range in cells.
+    fn range(self) -> f64 {
+        match self {
+            FixtureKind::Window => 6.0,
+            FixtureKind::Sconce => 3.0,
+        }
+    }
+}
+
+fn find_glyphs(
+    hsv: &Mat,
+    lower: Scalar,
+    upper: Scalar,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Vector2>, Box<dyn std::error::Error>> {
+    let pixels_per_cell = mapper.pixels_per_cell;
+    let mut mask = Mat::default();
+    core::in_range(hsv, &lower, &upper, &mut mask)?;
+    let mut contours = VectorOfVectorOfPoint::new();
+    imgproc::find_contours(
+        &mask,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        core::Point::new(0, 0),
+    )?;
+    let mut centers = Vec::new();
+    for contour in contours.iter() {
+        let area = imgproc::contour_area(&contour, false)?;
+        // Anything bigger than a cell is a colored floor rather than a glyph
+        if area < MIN_GLYPH_AREA || area > pixels_per_cell * pixels_per_cell {
+            continue;
+        }
+        let rect = imgproc::bounding_rect(&contour)?;
+        centers.push(mapper.pixels_to_units(
+            rect.x as f64 + rect.width as f64 / 2.0,
+            rect.y as f64 + rect.height as f64 / 2.0,
+        ));
+    }
+    Ok(centers)
+}
+
+/// Finds window glyphs by their pale blue glass and sconces by their warm flames.
+#[instrument(skip_all)]
+pub fn detect_fixtures(
+    image: &Mat,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Fixture>, Box<dyn std::error::Error>> {
+    let mut hsv = Mat::default();
+    imgproc::cvt_color(image, &mut hsv, imgproc::COLOR_BGR2HSV, 0)?;
+    let mut fixtures = Vec::new();
+    for (kind, lower, upper) in [
+        (FixtureKind::Window, WINDOW_LOWER, WINDOW_UPPER),
+        (FixtureKind::Sconce, SCONCE_LOWER, SCONCE_UPPER),
+    ] {
+        let found = find_glyphs(&hsv, lower, upper, mapper)?;
+        debug!("Detected {} {:?} glyphs", found.len(), kind);
+        fixtures.extend(found.into_iter().map(|position| Fixture { kind, position }));
+    }
+    Ok(fixtures)
+}
+
+/// Plans the lights of a level.
+///
+/// Every fixture lights the room whose walls it sits on, shining towards the
+/// middle of that room. Rooms without fixtures get a light in their middle.
+#[instrument(skip_all)]
+pub fn plan_lights(rooms: &[Vec<Vector2>], fixtures: &[Fixture]) -> Vec<Value> {
+    let mut lit = vec![false; rooms.len()];
+    let mut lights = Vec::new();
+    for fixture in fixtures {
+        let mut light = light_node(
+            fixture.position,
+            fixture.kind.color(),
+            fixture.kind.range(),
+            1.0,
+        );
+        let nearest = (0..rooms.len()).min_by(|&a, &b| {
+            boundary_distance(&rooms[a], fixture.position)
+                .total_cmp(&boundary_distance(&rooms[b], fixture.position))
+        });
+        if let Some(room) = nearest {
+            lit[room] = true;
+            if let Some(target) = centroid(&rooms[room]) {
+                let rotation = (target.y - fixture.position.y).atan2(target.x - fixture.position.x);
+                light["rotation"] = rotation.into();
+                light["texture"] = DIRECTIONAL_LIGHT_TEXTURE.into();
+            }
+        }
+        lights.push(light);
+    }
+    for (room, _) in rooms.iter().zip(&lit).filter(|(_, lit)| !**lit) {
+        if let Some(center) = centroid(room) {
+            lights.push(light_node(
+                center,
+                ROOM_LIGHT_COLOR,
+                DEFAULT_LIGHT_RANGE,
+                1.0,
+            ));
+        }
+    }
+    lights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(x: f64) -> Vec<Vector2> {
+        vec![
+            Vector2 { x, y: 0.0 },
+            Vector2 {
+                x: x + 512.0,
+                y: 0.0,
+            },
+            Vector2 {
+                x: x + 512.0,
+                y: 512.0,
+            },
+            Vector2 { x, y: 512.0 },
+        ]
+    }
+
+    #[test]
+    fn test_plan_fixture_lights() {
+        let rooms = vec![room(0.0), room(1024.0)];
+        let fixtures = vec![Fixture {
+            kind: FixtureKind::Window,
+            position: Vector2 { x: 0.0, y: 256.0 },
+        }];
+        let lights = plan_lights(&rooms, &fixtures);
+        assert_eq!(lights.len(), 2);
+        // The window on the west wall shines east into the room
+        assert_eq!(lights[0]["rotation"], 0.0);
+        assert_eq!(lights[0]["color"], DAYLIGHT_COLOR);
+        assert_eq!(lights[0]["texture"], DIRECTIONAL_LIGHT_TEXTURE);
+        // The second room has no fixtures so is lit from its middle
+        assert_eq!(lights[1]["position"], "Vector2( 1280, 256 )");
+        assert_eq!(lights[1]["color"], ROOM_LIGHT_COLOR);
+    }
+
+    #[test]
+    fn test_plan_sconce_direction() {
+        let fixtures = vec![Fixture {
+            kind: FixtureKind::Sconce,
+            position: Vector2 { x: 256.0, y: 0.0 },
+        }];
+        let lights = plan_lights(&[room(0.0)], &fixtures);
+        assert_eq!(lights.len(), 1);
+        let rotation = lights[0]["rotation"].as_f64().unwrap();
+        assert!((rotation - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+        assert_eq!(lights[0]["color"], SCONCE_COLOR);
+    }
+}
@@ -0,0 +1,292 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::coordinates::cells_to_units;
+use crate::dungeondraft_v1::{is_loop, node_points, node_position, nodes, Vector2, UNITS_PER_CELL};
+use crate::geometry::{contains_point, distance, self_intersection};
+
+/// Wall endpoints closer than this are considered an accidentally open loop.
+const MAX_WALL_GAP: f64 = UNITS_PER_CELL / 2.0;
+/// Door radius assumed when a portal does not store one.
+const DEFAULT_PORTAL_RADIUS: f64 = UNITS_PER_CELL / 2.0;
+
+/// The level being linted along with the world's canvas size.
+pub struct LevelContext<'a> {
+    level: &'a Value,
+    width: f64,
+    height: f64,
+}
+
+pub struct Rule {
+    pub id: &'static str,
+    pub description: &'static str,
+    check: fn(&LevelContext) -> Vec<String>,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        id: "unclosed-wall-loop",
+        description: "walls whose endpoints meet but are not marked as a loop",
+        check: unclosed_wall_loops,
+    },
+    Rule {
+        id: "self-intersecting-wall",
+        description: "wall loops crossing themselves, which DungeonDraft draws incorrectly",
+        check: self_intersecting_walls,
+    },
+    Rule {
+        id: "overlapping-doors",
+        description: "portals overlapping another portal",
+        check: overlapping_doors,
+    },
+    Rule {
+        id: "light-outside-room",
+        description: "lights not inside any closed wall loop",
+        check: lights_outside_rooms,
+    },
+    Rule {
+        id: "object-off-canvas",
+        description: "objects positioned outside the map bounds",
+        check: objects_off_canvas,
+    },
+];
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub level: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] level {}: {}", self.rule, self.level, self.message)
+    }
+}
+
+fn node_id(node: &Value) -> String {
+    crate::dungeondraft_v1::node_id(node).unwrap_or_else(|| "?".to_string())
+}
+
+fn unclosed_wall_loops(context: &LevelContext) -> Vec<String> {
+    nodes(context.level, "walls")
+        .filter(|wall| !is_loop(wall))
+        .filter_map(|wall| {
+            let points = node_points(wall)?;
+            let (first, last) = (*points.first()?, *points.last()?);
+            let gap = distance(first, last);
+            (points.len() > 2 && gap <= MAX_WALL_GAP).then(|| {
+                format!(
+                    "wall {} ends {:.0} units from its start but is not a loop",
+                    node_id(wall),
+                    gap
+                )
+            })
+        })
+        .collect()
+}
+
+fn self_intersecting_walls(context: &LevelContext) -> Vec<String> {
+    nodes(context.level, "walls")
+        .filter(|wall| is_loop(wall))
+        .filter_map(|wall| {
+            let p = self_intersection(&node_points(wall)?)?;
+            Some(format!(
+                "wall {} crosses itself at ({}, {})",
+                node_id(wall),
+                p.x,
+                p.y
+            ))
+        })
+        .collect()
+}
+
+fn overlapping_doors(context: &LevelContext) -> Vec<String> {
+    let portals: Vec<(&Value, Vector2, f64)> = nodes(context.level, "portals")
+        .chain(nodes(context.level, "walls").flat_map(|wall| nodes(wall, "portals")))
+        .filter_map(|portal| {
+            let radius = portal
+                .get("radius")
+                .and_then(Value::as_f64)
+                .unwrap_or(DEFAULT_PORTAL_RADIUS);
+            Some((portal, node_position(portal)?, radius))
+        })
+        .collect();
+
+    let mut messages = Vec::new();
+    for (i, (a, a_position, a_radius)) in portals.iter().enumerate() {
+        for (b, b_position, b_radius) in &portals[i + 1..] {
+            if distance(*a_position, *b_position) < a_radius + b_radius {
+                messages.push(format!(
+                    "portal {} overlaps portal {}",
+                    node_id(a),
+                    node_id(b)
+                ));
+            }
+        }
+    }
+    messages
+}
+
+fn lights_outside_rooms(context: &LevelContext) -> Vec<String> {
+    let rooms: Vec<Vec<Vector2>> = nodes(context.level, "walls")
+        .filter(|wall| is_loop(wall))
+        .filter_map(node_points)
+        .filter(|points| points.len() > 2)
+        .collect();
+    // Outdoor levels have no rooms to be inside of
+    if rooms.is_empty() {
+        return Vec::new();
+    }
+
+    nodes(context.level, "lights")
+        .filter_map(|light| Some((light, node_position(light)?)))
+        .filter(|(_, p)| !rooms.iter().any(|room| contains_point(room, *p)))
+        .map(|(light, p)| {
+            format!(
+                "light {} at ({}, {}) is outside every room",
+                node_id(light),
+                p.x,
+                p.y
+            )
+        })
+        .collect()
+}
+
+fn objects_off_canvas(context: &LevelContext) -> Vec<String> {
+    nodes(context.level, "objects")
+        .filter_map(|object| Some((object, node_position(object)?)))
+        .filter(|(_, p)| p.x < 0.0 || p.y < 0.0 || p.x > context.width || p.y > context.height)
+        .map(|(object, p)| {
+            format!(
+                "object {} at ({}, {}) is outside the {}x{} map",
+                node_id(object),
+                p.x,
+                p.y,
+                context.width,
+                context.height
+            )
+        })
+        .collect()
+}
+
+/// Runs every rule not listed in `allowed` against each level of the map.
+pub fn lint(map: &Value, allowed: &[String]) -> Vec<Finding> {
+    let world = &map["world"];
+    let width = cells_to_units(world["width"].as_f64().unwrap_or(0.0));
+    let height = cells_to_units(world["height"].as_f64().unwrap_or(0.0));
+
+    let mut findings = Vec::new();
+    let Some(levels) = world["levels"].as_object() else {
+        return findings;
+    };
+    for (name, level) in levels {
+        let context = LevelContext {
+            level,
+            width,
+            height,
+        };
+        for rule in RULES.iter().filter(|r| !allowed.iter().any(|a| a == r.id)) {
+            findings.extend((rule.check)(&context).into_iter().map(|message| Finding {
+                rule: rule.id,
+                level: name.clone(),
+                message,
+            }));
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map() -> Value {
+        json!({
+            "world": {
+                "width": 4,
+                "height": 4,
+                "levels": {
+                    "0": {
+                        "walls": [
+                            {
+                                "node_id": "1",
+                                "loop": true,
+                                "points": "PoolVector2Array( 0, 0, 512, 0, 512, 512, 0, 512 )",
+                                "portals": [
+                                    { "node_id": "2", "position": "Vector2( 256, 0 )", "radius": 128 }
+                                ]
+                            },
+                            {
+                                "node_id": "3",
+                                "loop": false,
+                                "points": "PoolVector2Array( 600, 600, 900, 600, 900, 900, 610, 610 )"
+                            },
+                            {
+                                "node_id": "9",
+                                "loop": true,
+                                "points": "PoolVector2Array( 0, 600, 300, 900, 300, 600, 0, 900 )"
+                            }
+                        ],
+                        "portals": [
+                            { "node_id": "4", "position": "Vector2( 300, 0 )", "radius": 128 }
+                        ],
+                        "lights": [
+                            { "node_id": "5", "position": "Vector2( 256, 256 )" },
+                            { "node_id": "6", "position": "Vector2( 800, 800 )" }
+                        ],
+                        "objects": [
+                            { "node_id": "7", "position": "Vector2( 256, 256 )" },
+                            { "node_id": "8", "position": "Vector2( 2000, 256 )" }
+                        ]
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_lint_all_rules() {
+        let findings = lint(&map(), &[]);
+        let rules: Vec<_> = findings.iter().map(|f| f.rule).collect();
+        assert_eq!(
+            rules,
+            vec![
+                "unclosed-wall-loop",
+                "self-intersecting-wall",
+                "overlapping-doors",
+                "light-outside-room",
+                "object-off-canvas"
+            ]
+        );
+        assert_eq!(findings[1].message, "wall 9 crosses itself at (150, 750)");
+        assert!(findings[3].message.contains("light 6"));
+        assert!(findings[4].message.contains("object 8"));
+    }
+
+    #[test]
+    fn test_lint_allowed_rules() {
+        let allowed = vec![
+            "overlapping-doors".to_string(),
+            "object-off-canvas".to_string(),
+        ];
+        let findings = lint(&map(), &allowed);
+        assert_eq!(findings.len(), 3);
+        assert!(findings
+            .iter()
+            .all(|f| !allowed.iter().any(|a| a == f.rule)));
+    }
+
+    #[test]
+    fn test_lights_without_rooms() {
+        let map = json!({
+            "world": {
+                "width": 4,
+                "height": 4,
+                "levels": { "0": { "lights": [ { "position": "Vector2( 0, 0 )" } ] } }
+            }
+        });
+        assert!(lint(&map, &[]).is_empty());
+    }
+}
@@ -1,15 +1,130 @@
-use clap::{value_parser, Arg};
+use clap::builder::{PossibleValue, PossibleValuesParser};
+use clap::{value_parser, Arg, ArgAction, ArgGroup};
 use config::{Config, Environment, File};
 use directories::UserDirs;
-use log::{debug, error, info, trace, warn, LevelFilter};
+use opencv::core::Mat;
+use opencv::prelude::*;
 use serde::Deserialize;
-use std::io;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
 
-use crate::images::{try_find_shapes, try_trace_shapes};
+use crate::annotations::read_annotations;
+use crate::assets::{
+    asset_references, pack_id, pack_reference, parse_rename, read_renames, AssetMigration,
+    AssetResolver,
+};
+use crate::biome_map::{default_rules, BiomeRule};
+use crate::confidence::parse_confidence;
+use crate::coordinates::{
+    parse_cells_per_inch, parse_offset, parse_pixels_per_cell, parse_rounding, units_to_cells,
+    Rounding,
+};
+use crate::daemon::DaemonOptions;
+use crate::diff::diff_maps;
+use crate::doors::PortalStyles;
+use crate::dungeon::{
+    generate_dungeon, generate_dungeon_with_report, try_generate_dungeon, try_review_dungeon,
+    DungeonOptions, Overlapping, CELLS_PER_INCH,
+};
+use crate::dungeondraft_v1::{
+    add_door, generator_settings, insert_into_template, make_reproducible, merge_maps, metadata,
+    read_map, read_map_format, record_generated, regenerate, set_metadata, smooth_paths,
+    stamp_generator, write_map_format, Color, Light, MapBuilder, MapFile, Object, Text, Textures,
+    Vector2, WallStyles, WaterStyle, DEFAULT_LIGHT_RANGE, MERGE_KINDS, METADATA_FIELDS,
+    WALL_CLASSES, WALL_STYLE_FIELDS, WATER_STYLE_FIELDS,
+};
+use crate::edit::{
+    parse_assignment, parse_door, parse_light, parse_object, parse_positive, parse_text, Edit,
+    Selector,
+};
+use crate::fills::FillColors;
+use crate::images::{
+    is_multi_page, parse_color_range, parse_hsv_range, parse_texture_color, read_map_image,
+    read_map_pages, try_trace_shapes, ColorRange, DetectionOptions, Detector, HsvRange, TraceStyle,
+};
+use crate::layout::Layout;
+use crate::levels::stack_level;
+use crate::map_format::{CURRENT_FORMAT, OLDEST_FORMAT};
+use crate::markers::{detect_markers, GmNotes, Note};
+use crate::numbering::{number_rooms, RoomOrder};
+use crate::owlbear::try_export_owlbear;
+use crate::pack::asset_name;
+use crate::pipeline::Pipeline;
+use crate::plugins::{default_plugins_dir, discover_plugins};
+use crate::prefab::{parse_region, try_export_prefab, Selection, PREFAB_EXTENSION};
+use crate::preprocess::Morphology;
+use crate::print::{parse_length, parse_positive_length, try_export_print, Paper, PrintOptions};
+use crate::remote::{fetch_input, is_url};
+use crate::render::try_render_thumbnail;
+use crate::report::RunReport;
+use crate::review::Review;
+use crate::scatter::{parse_density, scatter, Scatter};
+use crate::scripting::Script;
+use crate::tiles::{try_export_tiles, TileOptions};
+use crate::town::try_generate_town;
+use crate::world::try_generate_world;
 
-// mod dungeondraft_v1;
+mod analytics;
+mod annotations;
+mod assets;
+#[cfg(all(feature = "gui", feature = "onnx"))]
+mod assist;
+mod biome_map;
+#[cfg(feature = "capture")]
+mod capture;
+mod confidence;
+mod coordinates;
+mod daemon;
+mod diff;
+mod doors;
+mod dungeon;
+mod dungeondraft_v1;
+mod edit;
+mod evaluate;
+mod fills;
+mod geometry;
+#[cfg(feature = "gui")]
+mod gui;
+mod heightmap;
 mod images;
+mod layout;
+mod levels;
+mod lights;
+mod lint;
+mod map_format;
+mod markers;
+mod models;
+mod numbering;
+mod ocr;
+mod owlbear;
+mod pack;
+mod pipeline;
+mod plugins;
+mod prefab;
+mod preprocess;
+mod print;
+mod remote;
+mod render;
+mod report;
+mod review;
+mod scatter;
+mod scripting;
+#[cfg(feature = "onnx")]
+mod segmentation;
+mod serve;
+mod stairs;
+#[cfg(feature = "onnx")]
+mod symbols;
+mod tiles;
+mod town;
+mod uvtt;
+mod validate;
+mod walls;
+mod world;
 
 const MAPFILE_BACKUP_EXT: &str = "dungeondraft_map.bak";
 
@@ -32,17 +147,197 @@ fn get_backup_path(origional_path: &Path) -> PathBuf {
     backup_path
 }
 
+/// Writes the map to `output`, or overwrites `mapfile` after backing it up,
+/// in the map `format` it was read in.
+fn save_map(
+    mapfile: &Path,
+    output: Option<&PathBuf>,
+    map: &serde_json::Value,
+    format: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match output {
+        Some(output) => write_map_format(output, map, format),
+        None => {
+            create_backup(mapfile)?;
+            write_map_format(mapfile, map, format)
+        }
+    }
+}
+
+/// Writes a generated map to `output` in the map `format`, backing up the map
+/// already there, or else creating the directory it goes in.
+fn write_generated(
+    output: &Path,
+    map: &serde_json::Value,
+    format: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if output.exists() {
+        create_backup(output)?;
+    } else if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_map_format(output, map, format)
+}
+
+/// The map file written for an input when none is given, next to the image
+/// read from `source`, or in the working directory when it was downloaded.
+fn default_mapfile(input: &Path, source: &Path) -> PathBuf {
+    match (is_url(input), source.file_name()) {
+        (true, Some(name)) => PathBuf::from(name),
+        _ => source.to_path_buf(),
+    }
+    .with_extension("dungeondraft_map")
+}
+
+/// Detects asset packs in the directories given with `--assets`, or else in
+/// those of the `assets` config, if any.
+fn asset_resolver(matches: &clap::ArgMatches, configured: &[PathBuf]) -> Option<AssetResolver> {
+    let mut dirs: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("assets")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    if dirs.is_empty() {
+        dirs = configured.to_vec();
+    }
+    (!dirs.is_empty()).then(|| AssetResolver::detect(&dirs))
+}
+
+/// The flags overriding the `detection` config, for a subcommand detecting
+/// shapes `context`, such as " in dungeon mode".
+fn detection_args(context: &str) -> [Arg; 10] {
+    let defaults = DetectionOptions::default();
+    [
+        Arg::new("canny-low")
+            .long("canny-low")
+            .value_name("GRADIENT")
+            .help(format!("The gradient below which nothing is an edge{} [default: {}]", context, defaults.canny_low))
+            .value_parser(value_parser!(f64)),
+        Arg::new("canny-high")
+            .long("canny-high")
+            .value_name("GRADIENT")
+            .help(format!("The gradient above which everything is an edge{} [default: {}]", context, defaults.canny_high))
+            .value_parser(value_parser!(f64)),
+        Arg::new("epsilon")
+            .long("epsilon")
+            .value_name("SHARE")
+            .help(format!("How far outlines are simplified into vertices, as a share of their length{} [default: {}]", context, defaults.epsilon))
+            .value_parser(value_parser!(f64)),
+        Arg::new("min-area")
+            .long("min-area")
+            .value_name("PIXELS")
+            .help(format!("Skip shapes smaller than this many square pixels{} [default: {}]", context, defaults.min_area))
+            .value_parser(value_parser!(f64)),
+        Arg::new("max-area")
+            .long("max-area")
+            .value_name("PIXELS")
+            .help(format!("Skip shapes larger than this many square pixels{}", context))
+            .value_parser(value_parser!(f64)),
+        Arg::new("min-aspect")
+            .long("min-aspect")
+            .value_name("RATIO")
+            .help(format!("Skip shapes less elongated than this, the length of the rectangle around them over its width{} [default: {}]", context, defaults.min_aspect))
+            .value_parser(value_parser!(f64)),
+        Arg::new("max-aspect")
+            .long("max-aspect")
+            .value_name("RATIO")
+            .help(format!("Skip shapes more elongated than this{}", context))
+            .value_parser(value_parser!(f64)),
+        Arg::new("min-perimeter")
+            .long("min-perimeter")
+            .value_name("PIXELS")
+            .help(format!("Skip shapes with outlines shorter than this{} [default: {}]", context, defaults.min_perimeter))
+            .value_parser(value_parser!(f64)),
+        Arg::new("max-perimeter")
+            .long("max-perimeter")
+            .value_name("PIXELS")
+            .help(format!("Skip shapes with outlines longer than this{}", context))
+            .value_parser(value_parser!(f64)),
+        Arg::new("min-convexity")
+            .long("min-convexity")
+            .value_name("SHARE")
+            .help(format!("Skip shapes filling less than this share of their convex hull, such as decorative flourishes{} [default: {}]", context, defaults.min_convexity))
+            .value_parser(value_parser!(f64)),
+    ]
+}
+
+/// The `detection` config with the thresholds given as flags in its place,
+/// checked to be in range.
+fn detection_options(
+    matches: &clap::ArgMatches,
+    configured: &DetectionOptions,
+) -> Result<DetectionOptions, String> {
+    let flag = |id: &str, value: f64| matches.get_one::<f64>(id).copied().unwrap_or(value);
+    let limit = |id: &str, value: Option<f64>| matches.get_one::<f64>(id).copied().or(value);
+    let options = DetectionOptions {
+        canny_low: flag("canny-low", configured.canny_low),
+        canny_high: flag("canny-high", configured.canny_high),
+        epsilon: flag("epsilon", configured.epsilon),
+        min_area: flag("min-area", configured.min_area),
+        max_area: limit("max-area", configured.max_area),
+        min_aspect: flag("min-aspect", configured.min_aspect),
+        max_aspect: limit("max-aspect", configured.max_aspect),
+        min_perimeter: flag("min-perimeter", configured.min_perimeter),
+        max_perimeter: limit("max-perimeter", configured.max_perimeter),
+        min_convexity: flag("min-convexity", configured.min_convexity),
+    };
+    options.validate()?;
+    Ok(options)
+}
+
+/// Parses a length or width of 0 or more.
+fn parse_non_negative(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|value: &f64| *value >= 0.0 && value.is_finite())
+        .ok_or_else(|| format!("invalid value '{}', expected 0 or more", s))
+}
+
 #[derive(Debug, Deserialize)]
 struct Settings {
     verbose: String,
+    log_format: String,
     config_path: PathBuf,
+    biomes: Vec<BiomeRule>,
+    trace_colors: HashMap<String, String>,
+    plugins_dir: Option<PathBuf>,
+    models: Vec<models::ModelSource>,
+    models_dir: PathBuf,
+    walls: WallStyles,
+    portals: PortalStyles,
+    textures: Textures,
+    water: WaterStyle,
+    /// Corner cuts smoothing the paths of roads and routes
+    path_smoothing: usize,
+    scatter: Scatter,
+    asset_dirs: Vec<PathBuf>,
+    detection: DetectionOptions,
+    #[cfg(feature = "onnx")]
+    symbols: Vec<symbols::SymbolClass>,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
             verbose: "info".to_string(),
+            log_format: "text".to_string(),
             config_path: default_config_path(),
+            biomes: Vec::new(),
+            trace_colors: HashMap::new(),
+            plugins_dir: None,
+            models: Vec::new(),
+            models_dir: models::default_models_dir(),
+            walls: WallStyles::default(),
+            portals: PortalStyles::default(),
+            textures: Textures::default(),
+            water: WaterStyle::default(),
+            path_smoothing: 2,
+            scatter: Scatter::default(),
+            asset_dirs: Vec::new(),
+            detection: DetectionOptions::default(),
+            #[cfg(feature = "onnx")]
+            symbols: symbols::default_symbols(),
         }
     }
 }
@@ -59,9 +354,55 @@ impl From<Config> for Settings {
         if let Ok(o) = value.get_string("verbose") {
             cfg.verbose = o;
         }
+        if let Ok(o) = value.get_string("log_format") {
+            cfg.log_format = o;
+        }
         if let Ok(o) = value.get_string("config") {
             cfg.config_path = PathBuf::new().join(o);
         }
+        if let Ok(o) = value.get::<Vec<BiomeRule>>("biomes") {
+            cfg.biomes = o;
+        }
+        if let Ok(o) = value.get::<HashMap<String, String>>("trace_colors") {
+            cfg.trace_colors = o;
+        }
+        if let Ok(o) = value.get_string("plugins") {
+            cfg.plugins_dir = Some(PathBuf::from(o));
+        }
+        if let Ok(o) = value.get::<Vec<models::ModelSource>>("models") {
+            cfg.models = o;
+        }
+        if let Ok(o) = value.get_string("models_dir") {
+            cfg.models_dir = PathBuf::from(o);
+        }
+        if let Ok(o) = value.get::<WallStyles>("walls") {
+            cfg.walls = o;
+        }
+        if let Ok(o) = value.get::<PortalStyles>("portals") {
+            cfg.portals = o;
+        }
+        if let Ok(o) = value.get::<Textures>("textures") {
+            cfg.textures = o;
+        }
+        if let Ok(o) = value.get::<WaterStyle>("water") {
+            cfg.water = o;
+        }
+        if let Ok(o) = value.get::<usize>("path_smoothing") {
+            cfg.path_smoothing = o;
+        }
+        if let Ok(o) = value.get::<Scatter>("scatter") {
+            cfg.scatter = o;
+        }
+        if let Ok(o) = value.get::<Vec<PathBuf>>("assets") {
+            cfg.asset_dirs = o;
+        }
+        if let Ok(o) = value.get::<DetectionOptions>("detection") {
+            cfg.detection = o;
+        }
+        #[cfg(feature = "onnx")]
+        if let Ok(o) = value.get::<Vec<symbols::SymbolClass>>("symbols") {
+            cfg.symbols = o;
+        }
         cfg
     }
 }
@@ -73,15 +414,23 @@ fn default_config_path() -> PathBuf {
     path
 }
 
-fn setup_logging(verbose: &str) {
-    env_logger::builder()
-        .filter(None, verbose.parse().unwrap_or(LevelFilter::Info))
-        .init();
+/// Logs at the `verbose` level, or by its `tracing` filter directives, as
+/// text or as a JSON object a line, to stderr so as not to mix with the
+/// output of subcommands such as `info` and `diff`.
+fn setup_logging(verbose: &str, format: &str) {
+    let filter = EnvFilter::try_new(verbose).unwrap_or_else(|_| EnvFilter::new("info"));
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        "json" => logger.json().init(),
+        _ => logger.init(),
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     const ABOUT: &str = "A program to generate DungeonDraft maps.";
-    let matches = clap::Command::new("fixme")
+    let command = clap::Command::new("fixme")
         .version("v0.1.0")
         .author("Erich Schroeter <erich.schroeter@gmail.com>")
         .about(ABOUT)
@@ -115,7 +464,17 @@ Argument values are processed in the following order, using the last processed v
                     "Sets the verbosity log level [default: {}]",
                     Settings::default().verbose
                 ))
-                .long_help("Choices: [error, warn, info, debug, trace]"),
+                .long_help("Choices: [error, warn, info, debug, trace], or tracing filter directives such as dungeondraft_generator=debug"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help(format!(
+                    "How log lines are written, json being an object a line with the spans of the stage logging it [default: {}]",
+                    Settings::default().log_format
+                ))
+                .value_parser(["text", "json"]),
         )
         .subcommand(
             clap::Command::new("info")
@@ -126,40 +485,1297 @@ Argument values are processed in the following order, using the last processed v
                         .value_name("FILE")
                         .help("A .dungeondraft_map file")
                         .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("assets")
+                        .long("assets")
+                        .value_name("DIR")
+                        .action(ArgAction::Append)
+                        .help("A directory of asset packs, packed or not, to name the map's assets with")
+                        .value_parser(value_parser!(PathBuf)),
                 ),
         )
         .subcommand(
-            clap::Command::new("generate")
-                .about("Generate a DungeonDraft map file from an image")
+            clap::Command::new("edit")
+                .about("Edit nodes or fields of a DungeonDraft map file")
+                .arg(
+                    Arg::new("mapfile")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("A .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("select")
+                        .long("select")
+                        .value_name("SELECTOR")
+                        .help("The nodes to edit, e.g. 'lights[color=#ffffff]' or 'walls[width<4]'"),
+                )
+                .arg(
+                    Arg::new("set")
+                        .long("set")
+                        .value_name("KEY=VALUE")
+                        .action(ArgAction::Append)
+                        .help("Sets a field on every selected node, or without --select a field of the map by its path, e.g. world.width=40"),
+                )
+                .arg(
+                    Arg::new("delete")
+                        .long("delete")
+                        .action(ArgAction::SetTrue)
+                        .requires("select")
+                        .help("Deletes every selected node"),
+                )
+                .arg(
+                    Arg::new("header")
+                        .long("header")
+                        .value_name("FIELD=VALUE")
+                        .action(ArgAction::Append)
+                        .help(format!(
+                            "Sets a header field of the map, one of {}",
+                            METADATA_FIELDS.join(", ")
+                        )),
+                )
+                .arg(
+                    Arg::new("add-door")
+                        .long("add-door")
+                        .value_name("X,Y[,DEGREES]")
+                        .action(ArgAction::Append)
+                        .help("Adds a door at a position in cells, set in the nearest wall within half a cell and turned along it unless DEGREES is given"),
+                )
+                .arg(
+                    Arg::new("add-light")
+                        .long("add-light")
+                        .value_name("X,Y[,RANGE]")
+                        .action(ArgAction::Append)
+                        .help("Adds a light at a position in cells, reaching RANGE cells [default: 4]"),
+                )
+                .arg(
+                    Arg::new("light-color")
+                        .long("light-color")
+                        .value_name("COLOR")
+                        .default_value("#ffffff")
+                        .help("The color of lights added with --add-light, as #rrggbb or aarrggbb"),
+                )
+                .arg(
+                    Arg::new("light-intensity")
+                        .long("light-intensity")
+                        .value_name("INTENSITY")
+                        .default_value("1")
+                        .help("The intensity of lights added with --add-light")
+                        .value_parser(parse_positive),
+                )
+                .arg(
+                    Arg::new("add-object")
+                        .long("add-object")
+                        .value_name("X,Y[,DEGREES]")
+                        .action(ArgAction::Append)
+                        .requires("object-texture")
+                        .help("Adds an object at a position in cells, turned clockwise by DEGREES"),
+                )
+                .arg(
+                    Arg::new("object-texture")
+                        .long("object-texture")
+                        .value_name("TEXTURE")
+                        .help("The image of objects added with --add-object, a res:// reference, or a path within the pack given with --object-pack"),
+                )
+                .arg(
+                    Arg::new("object-pack")
+                        .long("object-pack")
+                        .value_name("PACK_ID")
+                        .requires("object-texture")
+                        .help("The id of the asset pack the texture of objects added with --add-object is in"),
+                )
+                .arg(
+                    Arg::new("object-scale")
+                        .long("object-scale")
+                        .value_name("SCALE")
+                        .default_value("1")
+                        .help("The scale of objects added with --add-object")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("add-text")
+                        .long("add-text")
+                        .value_name("X,Y=TEXT")
+                        .action(ArgAction::Append)
+                        .help("Adds a text label centered on a position in cells, such as the name of a room"),
+                )
+                .arg(
+                    Arg::new("font-size")
+                        .long("font-size")
+                        .value_name("SIZE")
+                        .default_value("40")
+                        .help("The font size of labels added with --add-text")
+                        .value_parser(parse_positive),
+                )
+                .arg(
+                    Arg::new("level")
+                        .long("level")
+                        .value_name("INDEX")
+                        .default_value("0")
+                        .help("The level to add doors, lights, objects, and text to"),
+                )
+                .group(
+                    ArgGroup::new("action")
+                        .args([
+                            "set",
+                            "delete",
+                            "header",
+                            "add-door",
+                            "add-light",
+                            "add-object",
+                            "add-text",
+                        ])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the edited map [default: overwrite FILE, keeping a backup]")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("migrate-assets")
+                .about("Rewrite asset references after an asset pack is updated or replaced")
+                .arg(
+                    Arg::new("mapfile")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("A .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("map")
+                        .long("map")
+                        .value_name("OLD=NEW")
+                        .action(ArgAction::Append)
+                        .help("Renames an asset pack id"),
+                )
+                .arg(
+                    Arg::new("renames")
+                        .long("renames")
+                        .value_name("CSV")
+                        .help("A CSV file of old_path,new_path asset renames")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .group(
+                    ArgGroup::new("migration")
+                        .args(["map", "renames"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the migrated map [default: overwrite FILE, keeping a backup]")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("diff")
+                .about("Compare the header, walls, portals, objects, and other nodes of two maps")
+                .arg(
+                    Arg::new("before")
+                        .required(true)
+                        .value_name("A")
+                        .help("A .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("after")
+                        .required(true)
+                        .value_name("B")
+                        .help("The .dungeondraft_map file to compare it to")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("merge")
+                .about("Merge the walls, objects, and other nodes of one map into another")
+                .arg(
+                    Arg::new("mapfile")
+                        .required(true)
+                        .value_name("BASE")
+                        .help("The .dungeondraft_map file to merge into, keeping its header and world settings")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("overlay")
+                        .required(true)
+                        .value_name("OVERLAY")
+                        .help("The .dungeondraft_map file to merge from")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("from-base")
+                        .long("from-base")
+                        .value_name("KIND")
+                        .action(ArgAction::Append)
+                        .value_delimiter(',')
+                        .help("A kind of node to keep from BASE [default: all]")
+                        .value_parser(PossibleValuesParser::new(MERGE_KINDS)),
+                )
+                .arg(
+                    Arg::new("from-overlay")
+                        .long("from-overlay")
+                        .value_name("KIND")
+                        .action(ArgAction::Append)
+                        .value_delimiter(',')
+                        .help("A kind of node to take from OVERLAY [default: all]")
+                        .value_parser(PossibleValuesParser::new(MERGE_KINDS)),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the merged map [default: overwrite BASE, keeping a backup]")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("regenerate")
+                .about("Generate a map again from its image, keeping what was added or changed in DungeonDraft since")
+                .arg(
+                    Arg::new("mapfile")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("A .dungeondraft_map file written by generate")
+                        .value_parser(value_parser!(PathBuf)),
+                )
                 .arg(
                     Arg::new("image")
                         .short('i')
                         .long("image")
                         .required(true)
                         .value_name("IMAGE")
-                        .help("An image file supported by OpenCV")
+                        .help("An image file or http(s) URL, or GeoJSON streets in town mode")
                         .value_parser(value_parser!(PathBuf)),
                 )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .value_name("MODE")
+                        .help("The kind of map the image shows [default: the mode the map was generated in, or dungeon]")
+                        .value_parser(["dungeon", "world", "town"]),
+                )
+                .arg(
+                    Arg::new("level")
+                        .long("level")
+                        .value_name("INDEX")
+                        .help("The level the map was generated into [default: the level it was generated into, or 0]")
+                        .value_parser(value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the regenerated map [default: overwrite FILE, keeping a backup]")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("validate")
+                .about("Find orphaned references and missing assets in a DungeonDraft map file")
                 .arg(
                     Arg::new("mapfile")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("A .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("fix")
+                        .long("fix")
+                        .action(ArgAction::SetTrue)
+                        .help("Prunes the orphaned references from the map"),
+                )
+                .arg(
+                    Arg::new("assets")
+                        .long("assets")
+                        .value_name("DIR")
+                        .action(ArgAction::Append)
+                        .help("A directory of asset packs, packed or not, to check the map's asset references against")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("output")
                         .short('o')
                         .long("output")
                         .value_name("FILE")
+                        .requires("fix")
+                        .help("Where to write the fixed map [default: overwrite FILE, keeping a backup]")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("lint")
+                .about("Check a DungeonDraft map file for map quality issues")
+                .arg(
+                    Arg::new("mapfile")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("A .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("allow")
+                        .short('A')
+                        .long("allow")
+                        .value_name("RULE")
+                        .action(ArgAction::Append)
+                        .help("Suppresses a lint rule")
+                        .value_parser(PossibleValuesParser::new(
+                            lint::RULES
+                                .iter()
+                                .map(|rule| PossibleValue::new(rule.id).help(rule.description)),
+                        )),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .default_value("text")
+                        .help("The report format")
+                        .value_parser(["text", "json"]),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("analyze")
+                .about("Report room areas, corridor lengths, doors, and connectivity of a map")
+                .arg(
+                    Arg::new("mapfile")
+                        .required(true)
+                        .value_name("FILE")
                         .help("A .dungeondraft_map file")
                         .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .default_value("json")
+                        .help("The report format, dot being a Graphviz connectivity graph")
+                        .value_parser(["json", "dot"]),
                 ),
         )
+        .subcommand(
+            clap::Command::new("evaluate")
+                .about("Score dungeons generated from a corpus of images against their reference maps")
+                .arg(
+                    Arg::new("corpus")
+                        .required(true)
+                        .value_name("DIR")
+                        .help("A directory of images, each with a .dungeondraft_map reference map of the same name")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("min-confidence")
+                        .long("min-confidence")
+                        .value_name("SCORE")
+                        .help("Leave out shapes detected with less confidence than this, from 0 to 1 [default: 0.5]")
+                        .value_parser(parse_confidence),
+                )
+                .arg(
+                    Arg::new("cells-per-inch")
+                        .long("cells-per-inch")
+                        .value_name("CELLS")
+                        .help(format!("How many cells an inch of the images is printed at, to tell their scale from the resolution they are stored at [default: {}]", CELLS_PER_INCH))
+                        .value_parser(parse_cells_per_inch),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("export")
+                .about("Export a DungeonDraft map file to raster images")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("print")
+                        .about("Render the map at physical scale for printing")
+                        .arg(
+                            Arg::new("mapfile")
+                                .required(true)
+                                .value_name("FILE")
+                                .help("A .dungeondraft_map file")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("DIR")
+                                .default_value(".")
+                                .help("The directory to write the images to")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("dpi")
+                                .long("dpi")
+                                .value_name("DPI")
+                                .default_value("300")
+                                .help("The printer resolution in dots per inch")
+                                .value_parser(value_parser!(u32).range(1..)),
+                        )
+                        .arg(
+                            Arg::new("cell-size")
+                                .long("cell-size")
+                                .value_name("LENGTH")
+                                .default_value("1in")
+                                .help("The printed size of one grid cell, e.g. 1in or 25mm")
+                                .value_parser(parse_positive_length),
+                        )
+                        .arg(
+                            Arg::new("grid")
+                                .long("grid")
+                                .action(ArgAction::SetTrue)
+                                .help("Prints grid lines over the map"),
+                        )
+                        .arg(
+                            Arg::new("paper")
+                                .long("paper")
+                                .value_name("PAPER")
+                                .help("Splits the map into pages with crop marks [possible values: a4, letter]")
+                                .value_parser(|s: &str| s.parse::<Paper>()),
+                        )
+                        .arg(
+                            Arg::new("margin")
+                                .long("margin")
+                                .value_name("LENGTH")
+                                .default_value("0.25in")
+                                .help("The unprintable border of each page")
+                                .value_parser(parse_length),
+                        )
+                        .arg(
+                            Arg::new("assets")
+                                .long("assets")
+                                .value_name("DIR")
+                                .action(ArgAction::Append)
+                                .help("A directory of unpacked asset packs to texture the map with")
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("tiles")
+                        .about("Split the rendered map into a tile pyramid for web viewers and VTTs")
+                        .arg(
+                            Arg::new("mapfile")
+                                .required(true)
+                                .value_name("FILE")
+                                .help("A .dungeondraft_map file")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("DIR")
+                                .default_value("tiles")
+                                .help("The directory to write the tiles and manifest.json to")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("tile-size")
+                                .long("tile-size")
+                                .value_name("PIXELS")
+                                .default_value("256")
+                                .help("The width and height of each tile")
+                                .value_parser(value_parser!(u32).range(1..)),
+                        )
+                        .arg(
+                            Arg::new("zoom-levels")
+                                .long("zoom-levels")
+                                .value_name("COUNT")
+                                .help("The number of zoom levels [default: until the map fits in one tile]")
+                                .value_parser(value_parser!(u32).range(1..)),
+                        )
+                        .arg(
+                            Arg::new("pixels-per-cell")
+                                .long("pixels-per-cell")
+                                .value_name("PIXELS")
+                                .default_value("256")
+                                .help("The resolution of the most detailed zoom level")
+                                .value_parser(value_parser!(u32).range(1..)),
+                        )
+                        .arg(
+                            Arg::new("assets")
+                                .long("assets")
+                                .value_name("DIR")
+                                .action(ArgAction::Append)
+                                .help("A directory of unpacked asset packs to texture the map with")
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("owlbear")
+                        .about("Write the rendered map and its walls and doors as an Owlbear Rodeo scene")
+                        .arg(
+                            Arg::new("mapfile")
+                                .required(true)
+                                .value_name("FILE")
+                                .help("A .dungeondraft_map file")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("DIR")
+                                .default_value("owlbear")
+                                .help("The directory to write map.png and scene.json to")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("pixels-per-cell")
+                                .long("pixels-per-cell")
+                                .value_name("PIXELS")
+                                .default_value("150")
+                                .help("The resolution of the scene image, set as the scene's grid DPI")
+                                .value_parser(value_parser!(u32).range(1..)),
+                        )
+                        .arg(
+                            Arg::new("assets")
+                                .long("assets")
+                                .value_name("DIR")
+                                .action(ArgAction::Append)
+                                .help("A directory of unpacked asset packs to texture the map with")
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("prefab")
+                        .about("Write a room or region of the map as a DungeonDraft prefab")
+                        .arg(
+                            Arg::new("mapfile")
+                                .required(true)
+                                .value_name("FILE")
+                                .help("A .dungeondraft_map file")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("FILE")
+                                .help("Where to write the prefab [default: FILE with a .dungeondraft_prefab extension]")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("level")
+                                .long("level")
+                                .value_name("LEVEL")
+                                .default_value("0")
+                                .help("The level to export from"),
+                        )
+                        .arg(
+                            Arg::new("room")
+                                .long("room")
+                                .value_name("WALL_ID")
+                                .help("Exports the room within the closed wall with this node id, as listed by analyze"),
+                        )
+                        .arg(
+                            Arg::new("region")
+                                .long("region")
+                                .value_name("X,Y,WIDTH,HEIGHT")
+                                .help("Exports the nodes within this rectangle, in cells")
+                                .value_parser(parse_region),
+                        )
+                        .group(
+                            ArgGroup::new("selection")
+                                .args(["room", "region"])
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("generate")
+                .about("Generate a DungeonDraft map file from an image")
+                .arg(
+                    Arg::new("image")
+                        .short('i')
+                        .long("image")
+                        .required_unless_present("layout")
+                        .value_name("IMAGE")
+                        .help("An image file or http(s) URL, or GeoJSON streets in town mode")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("upper-level")
+                        .long("upper-level")
+                        .value_name("IMAGE")
+                        .action(ArgAction::Append)
+                        .help("An image of the floor above, stacked as the next level of the map and moved so its stairs line up with the stairs below in dungeon mode, given once a floor")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("page")
+                        .long("page")
+                        .visible_alias("frame")
+                        .value_name("N")
+                        .help("The page of a multi-page TIFF or PDF, or the frame of an animated GIF, to generate from, counting from 1, in dungeon mode [default: 1]")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("all-pages")
+                        .long("all-pages")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("page")
+                        .help("Generate a level from every page or frame of the input, stacked as the floors above the first page's like --upper-level, in dungeon mode"),
+                )
+                .arg(
+                    Arg::new("layout")
+                        .long("layout")
+                        .value_name("FILE")
+                        .conflicts_with("image")
+                        .help("A JSON layout placing several images at offsets and rotations, composed into one dungeon with its geometry stitched across their seams")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("mapfile")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("A .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .value_name("MODE")
+                        .default_value("dungeon")
+                        .help("The kind of map the image shows")
+                        .value_parser(PossibleValuesParser::new([
+                            PossibleValue::new("dungeon")
+                                .help("Shapes become walled rooms lit by the windows and sconces drawn in them"),
+                            PossibleValue::new("world")
+                                .help("Regions become terrain, settlements objects, and routes paths"),
+                            PossibleValue::new("town")
+                                .help("Streets become paths and blocks roofed buildings"),
+                        ])),
+                )
+                .arg(
+                    Arg::new("heightmap")
+                        .long("heightmap")
+                        .value_name("IMAGE")
+                        .help("A grayscale heightmap whose elevation bands become terrain and cliffs in world mode")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("biome-map")
+                        .long("biome-map")
+                        .value_name("IMAGE")
+                        .conflicts_with("heightmap")
+                        .help("A painted biome map whose colors become terrain, water, and materials in world mode, as set by the biomes config")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("building")
+                        .long("building")
+                        .value_name("TEXTURE")
+                        .action(ArgAction::Append)
+                        .help("An object texture placed on town blocks, used in turn when given more than once"),
+                )
+                .arg(
+                    Arg::new("script")
+                        .long("script")
+                        .value_name("FILE")
+                        .help("A Rhai script whose shape and node hooks filter or change detected shapes and generated nodes")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("plugins")
+                        .long("plugins")
+                        .value_name("DIR")
+                        .help("A directory of WASM plugins run on detected shapes and the generated map [default: plugins beside the config file]")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("template")
+                        .long("template")
+                        .value_name("MAP")
+                        .help("A .dungeondraft_map whose world settings, terrain, lighting, and decorations the generated geometry is inserted into")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("level")
+                        .long("level")
+                        .value_name("INDEX")
+                        .default_value("0")
+                        .value_parser(value_parser!(u32))
+                        .help("The level the generated map goes into, 0 being the ground, with any floors stacked above it going into the levels above that"),
+                )
+                .arg(
+                    Arg::new("reproducible")
+                        .long("reproducible")
+                        .action(ArgAction::SetTrue)
+                        .help("Write a byte-identical map for the same inputs, dated by SOURCE_DATE_EPOCH or else the Unix epoch"),
+                )
+                .arg(
+                    Arg::new("map-format")
+                        .long("map-format")
+                        .value_name("VERSION")
+                        .default_value("3")
+                        .help("The map format version to write, 2 for DungeonDraft before 1.1")
+                        .value_parser(value_parser!(i64).range(OLDEST_FORMAT..=CURRENT_FORMAT)),
+                )
+                .arg(
+                    Arg::new("title")
+                        .long("title")
+                        .value_name("TITLE")
+                        .help("The title written in the map's header"),
+                )
+                .arg(
+                    Arg::new("creator")
+                        .long("creator")
+                        .value_name("NAME")
+                        .help("Who made the map, written in its header"),
+                )
+                .arg(
+                    Arg::new("notes")
+                        .long("notes")
+                        .value_name("TEXT")
+                        .help("Notes written in the map's header"),
+                )
+                .arg(
+                    Arg::new("gm-notes")
+                        .long("gm-notes")
+                        .value_name("FILE")
+                        .help("Write notes for the GM beside the map, as Markdown to a .md file or else JSON: the numbered rooms, the traps drawn as a T in a circle and the asterisks of the image, and the secret doors and other labels annotated on it, in dungeon mode")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("wall-style")
+                        .long("wall-style")
+                        .value_name("CLASS.FIELD=VALUE")
+                        .action(ArgAction::Append)
+                        .help(format!(
+                            "Sets a field of the walls config, one of {} of the {} walls, e.g. cave.shadow=false or boundary.outline=#202020",
+                            WALL_STYLE_FIELDS.join(", "),
+                            WALL_CLASSES.join(", ")
+                        )),
+                )
+                .arg(
+                    Arg::new("water-style")
+                        .long("water-style")
+                        .value_name("FIELD=VALUE")
+                        .action(ArgAction::Append)
+                        .help(format!(
+                            "Sets a field of the water config, one of {}, e.g. deep_color=#1a3c5a or wave_speed=0",
+                            WATER_STYLE_FIELDS.join(", ")
+                        )),
+                )
+                .arg(
+                    Arg::new("path-smoothing")
+                        .long("path-smoothing")
+                        .value_name("STRENGTH")
+                        .help("Cut the corners of roads and routes this many times so they curve rather than step, 0 to keep them as traced [default: the path_smoothing config's, or 2]")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("scatter")
+                        .long("scatter")
+                        .action(ArgAction::SetTrue)
+                        .help("Scatter the decorations of the scatter config, such as rubble, cracks, and grass tufts, over the rooms, corridors, and terrain"),
+                )
+                .arg(
+                    Arg::new("scatter-seed")
+                        .long("scatter-seed")
+                        .value_name("SEED")
+                        .requires("scatter")
+                        .help("The seed decorations are scattered from, the same seed scattering the same decorations [default: the scatter config's, or 0]")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("scatter-density")
+                        .long("scatter-density")
+                        .value_name("PER_CELL")
+                        .requires("scatter")
+                        .help("How many decorations a cell gets on average, up to 16, in place of the scatter config's density of each class")
+                        .value_parser(parse_density),
+                )
+                .arg(
+                    Arg::new("number-rooms")
+                        .long("number-rooms")
+                        .value_name("ORDER")
+                        .help("Label the rooms with numbers in dungeon mode, as the key of a published adventure")
+                        .value_parser(PossibleValuesParser::new([
+                            PossibleValue::new("position")
+                                .help("Row by row from the top, and left to right along each row"),
+                            PossibleValue::new("size").help("The largest room first"),
+                        ])),
+                )
+                .arg(
+                    Arg::new("room-key")
+                        .long("room-key")
+                        .value_name("FILE")
+                        .requires("number-rooms")
+                        .help("Write the number, position, and area of every numbered room to a JSON key file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .value_name("FILE")
+                        .help("Write a JSON report of how long each stage took, the shapes in and out of it, the options applied, and the warnings")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("pipeline")
+                        .long("pipeline")
+                        .value_name("FILE")
+                        .conflicts_with_all(["emit-review", "apply-review"])
+                        .help("A YAML pipeline of stages that trace the rooms in place of shape detection in dungeon mode")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("emit-review")
+                        .long("emit-review")
+                        .value_name("FILE")
+                        .conflicts_with("apply-review")
+                        .help("Write every shape detected in dungeon mode to a review file to accept, reject, or reclassify, instead of the map")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("apply-review")
+                        .long("apply-review")
+                        .value_name("FILE")
+                        .help("Use the shapes of an edited review file in place of the detected ones in dungeon mode")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("annotations")
+                        .long("annotations")
+                        .value_name("FILE")
+                        .help("A LabelMe or COCO annotation file whose rooms, walls, doors, and lights are used in place of detection in dungeon mode")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("merge-detection")
+                        .long("merge-detection")
+                        .requires("annotations")
+                        .action(ArgAction::SetTrue)
+                        .help("Detect the image as well, adding the annotations to what is found"),
+                )
+                .arg(
+                    Arg::new("annotation-labels")
+                        .long("annotation-labels")
+                        .requires("annotations")
+                        .action(ArgAction::SetTrue)
+                        .help("Write the other labels annotated on the image, such as the names of rooms, into the map as text as well as the GM notes"),
+                )
+                .arg(
+                    Arg::new("min-confidence")
+                        .long("min-confidence")
+                        .value_name("SCORE")
+                        .help("Leave out shapes detected with less confidence than this, from 0 to 1, in dungeon mode [default: 0.5]")
+                        .value_parser(parse_confidence),
+                )
+                .arg(
+                    Arg::new("nested-shapes")
+                        .long("nested-shapes")
+                        .action(ArgAction::SetTrue)
+                        .help("Wall the shapes found within other shapes too, such as pillars and rooms inside outer walls, in dungeon mode"),
+                )
+                .arg(
+                    Arg::new("stairs")
+                        .long("stairs")
+                        .action(ArgAction::SetTrue)
+                        .help("Place a stairs object on each flight of short parallel treads drawn on the image, in dungeon mode"),
+                )
+                .arg(
+                    Arg::new("ocr")
+                        .long("ocr")
+                        .action(ArgAction::SetTrue)
+                        .help("Read the room numbers and labels on the image with tesseract, painting them over before tracing the walls and writing them into the map as text, in dungeon mode"),
+                )
+                .arg(
+                    Arg::new("min-wall-length")
+                        .long("min-wall-length")
+                        .value_name("CELLS")
+                        .help("Merge away wall edges shorter than this share of a cell, the stair steps of traced pixels, in dungeon mode, 0 to keep them all [default: 0.25]")
+                        .value_parser(parse_non_negative),
+                )
+                .arg(
+                    Arg::new("max-gap")
+                        .long("max-gap")
+                        .value_name("PIXELS")
+                        .help("Close gaps up to this wide in the walls around a room in dungeon mode, 0 to leave them open [default: 16]")
+                        .value_parser(parse_non_negative),
+                )
+                .arg(
+                    Arg::new("morphology")
+                        .long("morphology")
+                        .value_name("OP:KERNEL")
+                        .help("Erode, dilate, open, or close the linework with a square kernel this many pixels wide before finding shapes in dungeon mode, e.g. close:5 to connect broken wall lines, repeatable and applied in order")
+                        .action(ArgAction::Append)
+                        .value_parser(|s: &str| s.parse::<Morphology>()),
+                )
+                .args(detection_args(" in dungeon mode"))
+                .arg(
+                    Arg::new("detector")
+                        .long("detector")
+                        .value_name("DETECTOR")
+                        .help("How walls are found in dungeon mode [default: edges]")
+                        .conflicts_with_all(["wall-hsv", "floor-color"])
+                        .value_parser(PossibleValuesParser::new([
+                            PossibleValue::new("edges")
+                                .help("Rooms are the shapes outlined by edges in grayscale"),
+                            PossibleValue::new("lines").help(
+                                "Walls are the straight lines of clean, CAD-like maps, merged along each wall",
+                            ),
+                        ])),
+                )
+                .arg(
+                    Arg::new("wall-hsv")
+                        .long("wall-hsv")
+                        .value_name("H,S,V:H,S,V")
+                        .help("Detect walls as the pixels within this range of OpenCV HSV colors, hues from 0 to 179, rather than by their edges in grayscale, in dungeon mode")
+                        .value_parser(parse_hsv_range),
+                )
+                .arg(
+                    Arg::new("floor-color")
+                        .long("floor-color")
+                        .value_name("COLORS")
+                        .help("Detect rooms as the regions filled with this range of colors, H,S,V:H,S,V in OpenCV HSV or lab:L,A,B:L,A,B in CIELAB, rather than by the edges of their walls, in dungeon mode")
+                        .conflicts_with("wall-hsv")
+                        .value_parser(parse_color_range),
+                )
+                .arg(
+                    Arg::new("wall-color")
+                        .long("wall-color")
+                        .value_name("COLORS")
+                        .help("The range of colors walls are filled with on maps split by --floor-color, the thin ones standing on their own placed as walls, H,S,V:H,S,V or lab:L,A,B:L,A,B")
+                        .requires("floor-color")
+                        .value_parser(parse_color_range),
+                )
+                .arg(
+                    Arg::new("water-color")
+                        .long("water-color")
+                        .value_name("COLORS")
+                        .help("Trace the regions drawn within this range of colors, H,S,V:H,S,V in OpenCV HSV or lab:L,A,B:L,A,B in CIELAB, as water in dungeon mode")
+                        .value_parser(parse_color_range),
+                )
+                .arg(
+                    Arg::new("material-color")
+                        .long("material-color")
+                        .value_name("TEXTURE=H,S,V:H,S,V")
+                        .help("Paint the regions drawn within a range of OpenCV HSV colors with a material texture in dungeon mode, repeatable")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_texture_color),
+                )
+                .arg(
+                    Arg::new("path-color")
+                        .long("path-color")
+                        .value_name("TEXTURE=H,S,V:H,S,V")
+                        .help("Draw the strokes within a range of OpenCV HSV colors, such as roads and rivers, as paths of a texture in dungeon mode, repeatable")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_texture_color),
+                )
+                .arg(
+                    Arg::new("pattern-color")
+                        .long("pattern-color")
+                        .value_name("TEXTURE=H,S,V:H,S,V")
+                        .help("Fill the shapes within a range of OpenCV HSV colors, such as rugs and decorative borders, with a pattern texture in dungeon mode, repeatable")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_texture_color),
+                )
+                .arg(
+                    Arg::new("grid-size")
+                        .long("grid-size")
+                        .value_name("PIXELS")
+                        .help("The size of the cells of the grid drawn in the image in dungeon mode, in pixels [default: detected from the grid, a scale bar, or the image's DPI]")
+                        .value_parser(parse_pixels_per_cell),
+                )
+                .arg(
+                    Arg::new("grid-offset")
+                        .long("grid-offset")
+                        .value_name("X,Y")
+                        .help("Where the grid drawn in the image starts in dungeon mode, in pixels, so walls on grid lines land on the map's grid [default: detected]")
+                        .value_parser(parse_offset),
+                )
+                .arg(
+                    Arg::new("grid-rotation")
+                        .long("grid-rotation")
+                        .value_name("DEGREES")
+                        .help("How far to turn the image counter-clockwise to straighten a grid drawn at an angle in dungeon mode, 0 to keep it as it is [default: detected]")
+                        .allow_negative_numbers(true)
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("cells-per-inch")
+                        .long("cells-per-inch")
+                        .value_name("CELLS")
+                        .help(format!("How many cells an inch of the image is printed at, to tell its scale from the resolution it is stored at in dungeon mode [default: {}]", CELLS_PER_INCH))
+                        .value_parser(parse_cells_per_inch),
+                )
+                .arg(
+                    Arg::new("isometric")
+                        .long("isometric")
+                        .help("Read an isometric image, drawn on a diamond grid, into a top-down map in dungeon mode")
+                        .conflicts_with_all(["grid-offset", "grid-rotation"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("straight-walls")
+                        .long("straight-walls")
+                        .help("Wall freehand rooms such as caves along the points traced around them rather than with smooth curves in dungeon mode")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("terrain")
+                        .long("terrain")
+                        .help("Paint the terrain from the colors of the image, grass, sand, rock, or snow, as in world mode, for outdoor maps in dungeon mode")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("overlapping")
+                        .long("overlapping")
+                        .value_name("POLICY")
+                        .help("What is done with rooms that overlap in dungeon mode [default: keep]")
+                        .value_parser(PossibleValuesParser::new([
+                            PossibleValue::new("keep").help("Each room is walled as it is"),
+                            PossibleValue::new("union")
+                                .help("Overlapping rooms are merged into a single floor"),
+                            PossibleValue::new("clip")
+                                .help("Smaller rooms, such as corridors, are cut out of the larger rooms they overlap"),
+                        ])),
+                )
+                .arg(
+                    Arg::new("rounding")
+                        .long("rounding")
+                        .value_name("POLICY")
+                        .help("Round positions in dungeon mode to whole units, to steps of a cell such as 4 for quarter cells, or none [default: none]")
+                        .value_parser(parse_rounding),
+                )
+        )
         .subcommand(
             clap::Command::new("preview")
-                .about("Find what shapes will be detected in an image")
+                .about("Find what shapes will be detected in an image, or render a map thumbnail")
                 .arg(
                     Arg::new("image")
                         .value_name("IMAGE")
-                        .help("An image file supported by OpenCV")
+                        .help("An image file supported by OpenCV, or a .dungeondraft_map file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the map thumbnail [default: FILE with a .png extension]")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("width")
+                        .long("width")
+                        .value_name("PIXELS")
+                        .default_value("512")
+                        .help("The width of the map thumbnail")
+                        .value_parser(value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("assets")
+                        .long("assets")
+                        .value_name("DIR")
+                        .action(ArgAction::Append)
+                        .help("A directory of unpacked asset packs to texture the map thumbnail with")
                         .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("min-confidence")
+                        .long("min-confidence")
+                        .value_name("SCORE")
+                        .help("Color shapes detected with less confidence than this, from 0 to 1, for review [default: 0.5]")
+                        .value_parser(parse_confidence),
+                )
+                .args(detection_args(""))
+        )
+        .subcommand(
+            clap::Command::new("daemon")
+                .about("Generate maps for every job dropped into an inbox directory")
+                .arg(
+                    Arg::new("inbox")
+                        .value_name("INBOX")
+                        .required(true)
+                        .help("The directory watched for images and .job.json files")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("outbox")
+                        .value_name("OUTBOX")
+                        .required(true)
+                        .help("The directory maps and job status files are written to")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_name("COUNT")
+                        .default_value("1")
+                        .help("How many jobs to work on at once")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .default_value("2")
+                        .help("How often to check the inbox for new jobs")
+                        .value_parser(value_parser!(u64).range(1..)),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            clap::Command::new("serve")
+                .about("Generate maps over HTTP from uploaded images")
+                .arg(
+                    Arg::new("port")
+                        .short('p')
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("8080")
+                        .help("The port to listen on")
+                        .value_parser(value_parser!(u16)),
+                )
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDRESS")
+                        .default_value("127.0.0.1")
+                        .help("The address to listen on, 0.0.0.0 to take requests from other machines")
+                        .value_parser(value_parser!(std::net::IpAddr)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("pack")
+                .about("Look through the installed asset packs")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("search")
+                        .about("List the assets of every pack with a tag, or with it in their name")
+                        .arg(
+                            Arg::new("tag")
+                                .long("tag")
+                                .value_name("TAG")
+                                .required(true)
+                                .help("The tag to look for, ignoring case"),
+                        )
+                        .arg(
+                            Arg::new("assets")
+                                .long("assets")
+                                .value_name("DIR")
+                                .action(ArgAction::Append)
+                                .help("A directory of unpacked asset packs, in place of those of the assets config")
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("models")
+                .about("Manage the ML models of the models config in the cache")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("list")
+                        .about("List the configured and cached models"),
+                )
+                .subcommand(
+                    clap::Command::new("download")
+                        .about("Download a configured model, verifying its checksum")
+                        .arg(
+                            Arg::new("name")
+                                .value_name("NAME")
+                                .required(true)
+                                .help("The name of the model in the models config"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("remove")
+                        .about("Remove a model from the cache")
+                        .arg(
+                            Arg::new("name")
+                                .value_name("NAME")
+                                .required(true)
+                                .help("The name of the cached model"),
+                        ),
+                ),
+        );
+    #[cfg(feature = "gui")]
+    let command = command.subcommand(
+        clap::Command::new("gui")
+            .about("Previews detection in a window before writing the map")
+            .arg(
+                Arg::new("image")
+                    .value_name("IMAGE")
+                    .required(true)
+                    .help("An image file or http(s) URL")
+                    .value_parser(value_parser!(PathBuf)),
+            )
+            .arg(
+                Arg::new("mapfile")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE")
+                    .help("The map file to write [default: the image with a .dungeondraft_map extension]")
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+    );
+    #[cfg(feature = "capture")]
+    let command = command.mut_subcommand("generate", |generate| {
+        generate
+            .arg(
+                Arg::new("capture")
+                    .long("capture")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all(["image", "layout"])
+                    .help("Capture the screen and drag over the map on it, such as one shown in a PDF reader or browser, to generate from, saving the region as capture-DATE.png"),
+            )
+            .mut_arg("image", |image| {
+                image.required_unless_present_any(["layout", "capture"])
+            })
+    });
+    #[cfg(all(feature = "gui", feature = "onnx"))]
+    let command = command.mut_subcommand("gui", |gui| {
+        gui.arg(
+            Arg::new("sam-encoder")
+                .long("sam-encoder")
+                .value_name("FILE")
+                .help("The ONNX image encoder of a SAM style model, to pick out missed rooms at points clicked on the preview")
+                .requires("sam-decoder")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("sam-decoder")
+                .long("sam-decoder")
+                .value_name("FILE")
+                .help("The ONNX prompt decoder of a SAM style model")
+                .requires("sam-encoder")
+                .value_parser(value_parser!(PathBuf)),
+        )
+    });
+    #[cfg(feature = "onnx")]
+    let command = command.mut_subcommand("generate", |generate| {
+        generate.arg(
+            Arg::new("segmentation-model")
+                .long("segmentation-model")
+                .value_name("FILE")
+                .help("An ONNX model classifying every pixel as wall, floor, door, or water, to detect the dungeon with in place of edge detection in dungeon mode, or the name of a downloaded model")
+                .conflicts_with_all(["pipeline", "wall-hsv"])
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("symbol-model")
+                .long("symbol-model")
+                .value_name("FILE")
+                .help("A YOLO style ONNX model detecting the symbols of the symbols config, placed as objects or doors in dungeon mode, or the name of a downloaded model")
+                .value_parser(value_parser!(PathBuf)),
+        )
+    });
+    let matches = command.get_matches();
 
     let settings = Config::builder()
         .add_source(
@@ -175,39 +1791,1019 @@ Argument values are processed in the following order, using the last processed v
         settings.verbose = o.to_owned();
     }
 
+    if let Some(o) = matches.get_one::<String>("log-format") {
+        settings.log_format = o.to_owned();
+    }
+
     if let Some(o) = matches.get_one::<PathBuf>("config") {
         settings.config_path = o.to_owned();
     }
 
-    setup_logging(&settings.verbose);
+    setup_logging(&settings.verbose, &settings.log_format);
 
-    error!("testing");
-    warn!("testing");
-    info!("{}", settings);
-    debug!("testing");
-    trace!("testing");
+    debug!("{}", settings);
 
     match matches.subcommand() {
         Some(("preview", sub_matches)) => {
             if let Some(o) = sub_matches.get_one::<PathBuf>("image") {
-                // let _ = find_shapes(&o);
-                let _ = try_trace_shapes(&o);
+                if o.extension().is_some_and(|ext| ext == "dungeondraft_map") {
+                    let resolver = asset_resolver(sub_matches, &settings.asset_dirs);
+                    let thumbnail = try_render_thumbnail(
+                        o,
+                        sub_matches.get_one::<PathBuf>("output"),
+                        *sub_matches.get_one::<u32>("width").unwrap(),
+                        resolver.as_ref(),
+                    )?;
+                    info!("Rendered {}", thumbnail.display());
+                } else {
+                    // let _ = find_shapes(&o);
+                    let mut style = TraceStyle::with_colors(&settings.trace_colors)?;
+                    if let Some(confidence) = sub_matches.get_one::<f64>("min-confidence") {
+                        style.min_confidence = *confidence;
+                    }
+                    let detection = detection_options(sub_matches, &settings.detection)?;
+                    let _ = try_trace_shapes(o, &style, &detection);
+                }
             }
         }
         Some(("info", sub_matches)) => {
             if let Some(o) = sub_matches.get_one::<PathBuf>("mapfile") {
                 debug!("Reading {}", o.display());
-                let file = std::fs::File::open(o)?;
-                let reader = io::BufReader::new(file);
-                let data: serde_json::Value = serde_json::from_reader(reader)?;
+                let (data, format) = read_map_format(o)?;
                 debug!("{:?}", data);
+                println!("format: {}", format);
+                for (field, value) in metadata(&data) {
+                    println!("{}: {}", field, value);
+                }
+                if let Some(resolver) = asset_resolver(sub_matches, &settings.asset_dirs) {
+                    let mut packs: Vec<(String, Vec<(String, usize)>)> = Vec::new();
+                    for (reference, uses) in asset_references(&data) {
+                        let pack = match pack_id(&reference) {
+                            Some(id) => resolver.pack_label(id).map_or_else(
+                                || id.to_string(),
+                                |label| format!("{} ({})", label, id),
+                            ),
+                            None => "default".to_string(),
+                        };
+                        let found = resolver.exists(&reference);
+                        let asset = format!(
+                            "{}{}",
+                            asset_name(&reference),
+                            if found { "" } else { ", missing" }
+                        );
+                        match packs.iter_mut().find(|(name, _)| *name == pack) {
+                            Some((_, assets)) => assets.push((asset, uses)),
+                            None => packs.push((pack, vec![(asset, uses)])),
+                        }
+                    }
+                    for (pack, assets) in packs {
+                        println!("assets of {}:", pack);
+                        for (asset, uses) in assets {
+                            println!("  {} x{}", asset, uses);
+                        }
+                    }
+                }
+                let map = MapFile::from_value(data)?;
+                println!("size: {}x{} cells", map.world.width, map.world.height);
+                if let Some(grid_size) = map.world.grid_size {
+                    println!("grid size: {} units", grid_size);
+                }
+                for (index, level) in &map.world.levels {
+                    println!(
+                        "level {} ({}): {} walls, {} portals, {} lights, {} objects, {} paths, {} texts, {} bodies of water, {} material regions",
+                        index,
+                        level.label,
+                        level.walls.len(),
+                        level.all_portals().count(),
+                        level.lights.len(),
+                        level.objects.len(),
+                        level.paths.len(),
+                        level.texts.len(),
+                        level.water_bodies().len(),
+                        level.material_count()
+                    );
+                    for portal in level.all_portals() {
+                        println!(
+                            "  portal {} at ({}, {}), {}°, {}, {}",
+                            portal.node_id.as_deref().unwrap_or("-"),
+                            units_to_cells(portal.position.x),
+                            units_to_cells(portal.position.y),
+                            portal.rotation.to_degrees().round(),
+                            match portal.wall_id.as_deref() {
+                                Some(wall) if wall != "-1" => format!("in wall {}", wall),
+                                _ => "freestanding".to_string(),
+                            },
+                            if portal.closed { "closed" } else { "open" }
+                        );
+                    }
+                }
+            }
+        }
+        Some(("edit", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let (mut map, format) = read_map_format(mapfile)?;
+            if let Some(selector) = sub_matches.get_one::<String>("select") {
+                let selector: Selector = selector.parse()?;
+                let edit = if sub_matches.get_flag("delete") {
+                    Edit::Delete
+                } else {
+                    Edit::Set(
+                        sub_matches
+                            .get_many::<String>("set")
+                            .unwrap_or_default()
+                            .map(|s| parse_assignment(s))
+                            .collect::<Result<_, _>>()?,
+                    )
+                };
+                let count = edit::apply(&mut map, &selector, &edit);
+                info!("{} nodes matched", count);
+            } else if let Some(assignments) = sub_matches.get_many::<String>("set") {
+                let mut typed = MapFile::from_value(map)?;
+                for assignment in assignments {
+                    let (field, value) = parse_assignment(assignment)?;
+                    typed.set(&field, value)?;
+                }
+                map = typed.to_value()?;
+            }
+            let level = sub_matches.get_one::<String>("level").unwrap();
+            for door in sub_matches
+                .get_many::<String>("add-door")
+                .unwrap_or_default()
+            {
+                let (position, rotation) = parse_door(door)?;
+                let id = add_door(&mut map, level, position, rotation)?;
+                info!("Added door {}", id);
+            }
+            if let Some(lights) = sub_matches.get_many::<String>("add-light") {
+                let color: Color = sub_matches
+                    .get_one::<String>("light-color")
+                    .unwrap()
+                    .parse()?;
+                let intensity = *sub_matches.get_one::<f64>("light-intensity").unwrap();
+                let mut typed = MapFile::from_value(map)?;
+                for light in lights {
+                    let (position, range) = parse_light(light)?;
+                    let light = Light::new(
+                        position,
+                        color,
+                        range.unwrap_or(DEFAULT_LIGHT_RANGE),
+                        intensity,
+                    );
+                    let id = typed.add_light(level, light)?;
+                    info!("Added light {}", id);
+                }
+                map = typed.to_value()?;
+            }
+            if let Some(objects) = sub_matches.get_many::<String>("add-object") {
+                let texture = sub_matches.get_one::<String>("object-texture").unwrap();
+                let texture = match sub_matches.get_one::<String>("object-pack") {
+                    Some(pack) => pack_reference(pack, texture),
+                    None => texture.clone(),
+                };
+                let scale = *sub_matches.get_one::<f64>("object-scale").unwrap();
+                let mut typed = MapFile::from_value(map)?;
+                for object in objects {
+                    let (position, rotation) = parse_object(object)?;
+                    let mut object = Object::new(position, &texture);
+                    object.rotation = rotation;
+                    object.scale = Vector2 { x: scale, y: scale };
+                    let id = typed.add_object(level, object)?;
+                    info!("Added object {}", id);
+                }
+                map = typed.to_value()?;
+            }
+            if let Some(texts) = sub_matches.get_many::<String>("add-text") {
+                let font_size = *sub_matches.get_one::<f64>("font-size").unwrap();
+                let mut typed = MapFile::from_value(map)?;
+                for text in texts {
+                    let (position, text) = parse_text(text)?;
+                    let id = typed.add_text(level, Text::new(position, &text, font_size))?;
+                    info!("Added text {}", id);
+                }
+                map = typed.to_value()?;
+            }
+            for assignment in sub_matches.get_many::<String>("header").unwrap_or_default() {
+                let (field, value) = assignment
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected field=value but found '{}'", assignment))?;
+                set_metadata(&mut map, field.trim(), value)?;
+            }
+
+            save_map(
+                mapfile,
+                sub_matches.get_one::<PathBuf>("output"),
+                &map,
+                format,
+            )?;
+        }
+        Some(("migrate-assets", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let mut migration = AssetMigration::new();
+            for rename in sub_matches.get_many::<String>("map").unwrap_or_default() {
+                let (old, new) = parse_rename(rename)?;
+                migration = migration.pack(&old, &new);
+            }
+            if let Some(o) = sub_matches.get_one::<PathBuf>("renames") {
+                for (old, new) in read_renames(o)? {
+                    migration = migration.path(&old, &new);
+                }
+            }
+
+            let (mut map, format) = read_map_format(mapfile)?;
+            let count = assets::migrate(&mut map, &migration);
+            info!("{} asset references rewritten", count);
+
+            save_map(
+                mapfile,
+                sub_matches.get_one::<PathBuf>("output"),
+                &map,
+                format,
+            )?;
+        }
+        Some(("diff", sub_matches)) => {
+            let before = read_map(sub_matches.get_one::<PathBuf>("before").unwrap())?;
+            let after = read_map(sub_matches.get_one::<PathBuf>("after").unwrap())?;
+            let diff = diff_maps(&before, &after);
+            if diff.is_empty() {
+                println!("no differences");
+            } else {
+                println!("{}", diff);
             }
         }
+        Some(("merge", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let kinds = |id: &str| -> Vec<&str> {
+                match sub_matches.get_many::<String>(id) {
+                    Some(kinds) => kinds.map(String::as_str).collect(),
+                    None => MERGE_KINDS.to_vec(),
+                }
+            };
+            let (mut map, format) = read_map_format(mapfile)?;
+            let overlay = read_map(sub_matches.get_one::<PathBuf>("overlay").unwrap())?;
+            merge_maps(
+                &mut map,
+                &overlay,
+                &kinds("from-base"),
+                &kinds("from-overlay"),
+            )?;
+            save_map(
+                mapfile,
+                sub_matches.get_one::<PathBuf>("output"),
+                &map,
+                format,
+            )?;
+        }
+        Some(("regenerate", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let input = sub_matches.get_one::<PathBuf>("image").unwrap();
+            let o = &fetch_input(input)?;
+            let (mut map, format) = read_map_format(mapfile)?;
+            // Generated again as it was, unless told otherwise
+            let recorded = generator_settings(&map).clone();
+            let mode = sub_matches
+                .get_one::<String>("mode")
+                .map(String::as_str)
+                .or(recorded["mode"].as_str())
+                .unwrap_or("dungeon");
+            let level = sub_matches
+                .get_one::<u32>("level")
+                .copied()
+                .or_else(|| {
+                    recorded["level"]
+                        .as_u64()
+                        .and_then(|level| level.try_into().ok())
+                })
+                .unwrap_or(0);
+            let mut generated = match mode {
+                "world" => {
+                    let rules = if settings.biomes.is_empty() {
+                        default_rules()
+                    } else {
+                        settings.biomes.clone()
+                    };
+                    try_generate_world(o, None, None, &rules, &settings.textures)?
+                }
+                "town" => try_generate_town(o, &[], &settings.textures, &settings.walls)?,
+                _ => {
+                    let options = DungeonOptions {
+                        wall_styles: settings.walls.clone(),
+                        portal_styles: settings.portals.clone(),
+                        textures: settings.textures.clone(),
+                        detection: settings.detection,
+                        ..Default::default()
+                    }
+                    .with_recorded(&recorded["parameters"]);
+                    try_generate_dungeon(o, &options)?
+                }
+            };
+            smooth_paths(&mut generated, settings.path_smoothing);
+            settings.water.apply(&mut generated);
+
+            let regeneration = regenerate(&mut map, &generated, level)?;
+            info!(
+                "Replaced {} generated nodes with {}, keeping {} added or changed since",
+                regeneration.replaced, regeneration.added, regeneration.kept
+            );
+            save_map(
+                mapfile,
+                sub_matches.get_one::<PathBuf>("output"),
+                &map,
+                format,
+            )?;
+        }
+        Some(("validate", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let fix = sub_matches.get_flag("fix");
+
+            let (mut map, format) = read_map_format(mapfile)?;
+            let issues = validate::find_orphans(&mut map, fix);
+            for issue in &issues {
+                warn!("{}", issue);
+            }
+            let missing = asset_resolver(sub_matches, &settings.asset_dirs)
+                .map(|resolver| validate::find_missing_assets(&map, &resolver))
+                .unwrap_or_default();
+            for issue in &missing {
+                warn!("{}", issue);
+            }
+
+            if !issues.is_empty() {
+                if !fix {
+                    return Err(format!("{} issues found", issues.len()).into());
+                }
+                save_map(
+                    mapfile,
+                    sub_matches.get_one::<PathBuf>("output"),
+                    &map,
+                    format,
+                )?;
+                info!("{} issues fixed", issues.len());
+            }
+            // Missing assets can't be fixed in the map, only reported
+            if !missing.is_empty() {
+                return Err(format!("{} assets missing", missing.len()).into());
+            }
+            if issues.is_empty() {
+                info!("no issues found");
+            }
+        }
+        Some(("lint", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let allowed: Vec<String> = sub_matches
+                .get_many::<String>("allow")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+
+            let map = read_map(mapfile)?;
+            let findings = lint::lint(&map, &allowed);
+            match sub_matches.get_one::<String>("format").unwrap().as_str() {
+                "json" => println!("{}", serde_json::to_string_pretty(&findings)?),
+                _ => {
+                    for finding in &findings {
+                        warn!("{}", finding);
+                    }
+                }
+            }
+
+            if !findings.is_empty() {
+                return Err(format!("{} lint findings", findings.len()).into());
+            }
+        }
+        Some(("analyze", sub_matches)) => {
+            let mapfile = sub_matches.get_one::<PathBuf>("mapfile").unwrap();
+            let map = read_map(mapfile)?;
+            let reports = analytics::analyze(&map);
+            match sub_matches.get_one::<String>("format").unwrap().as_str() {
+                "dot" => print!("{}", analytics::to_dot(&reports)),
+                _ => println!("{}", serde_json::to_string_pretty(&reports)?),
+            }
+        }
+        Some(("evaluate", sub_matches)) => {
+            let corpus = sub_matches.get_one::<PathBuf>("corpus").unwrap();
+            let mut options = DungeonOptions {
+                detection: settings.detection,
+                ..Default::default()
+            };
+            if let Some(confidence) = sub_matches.get_one::<f64>("min-confidence") {
+                options.min_confidence = *confidence;
+            }
+            let cells_per_inch = sub_matches
+                .get_one::<f64>("cells-per-inch")
+                .copied()
+                .unwrap_or(CELLS_PER_INCH);
+            let report = evaluate::evaluate_corpus(corpus, &options, cells_per_inch)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Some(("export", sub_matches)) => match sub_matches.subcommand() {
+            Some(("print", print_matches)) => {
+                let mapfile = print_matches.get_one::<PathBuf>("mapfile").unwrap();
+                let options = PrintOptions {
+                    dpi: *print_matches.get_one::<u32>("dpi").unwrap() as f64,
+                    cell_size: *print_matches.get_one::<f64>("cell-size").unwrap(),
+                    grid: print_matches.get_flag("grid"),
+                    paper: print_matches.get_one::<Paper>("paper").copied(),
+                    margin: *print_matches.get_one::<f64>("margin").unwrap(),
+                };
+                let resolver = asset_resolver(print_matches, &settings.asset_dirs);
+                let output = print_matches.get_one::<PathBuf>("output").unwrap();
+                for path in try_export_print(mapfile, output, &options, resolver.as_ref())? {
+                    info!("Wrote {}", path.display());
+                }
+            }
+            Some(("tiles", tiles_matches)) => {
+                let mapfile = tiles_matches.get_one::<PathBuf>("mapfile").unwrap();
+                let options = TileOptions {
+                    tile_size: *tiles_matches.get_one::<u32>("tile-size").unwrap(),
+                    pixels_per_cell: *tiles_matches.get_one::<u32>("pixels-per-cell").unwrap()
+                        as f64,
+                    zoom_levels: tiles_matches.get_one::<u32>("zoom-levels").copied(),
+                };
+                let resolver = asset_resolver(tiles_matches, &settings.asset_dirs);
+                let output = tiles_matches.get_one::<PathBuf>("output").unwrap();
+                let manifest = try_export_tiles(mapfile, output, &options, resolver.as_ref())?;
+                info!("Wrote {}", manifest.display());
+            }
+            Some(("owlbear", owlbear_matches)) => {
+                let mapfile = owlbear_matches.get_one::<PathBuf>("mapfile").unwrap();
+                let pixels_per_cell =
+                    *owlbear_matches.get_one::<u32>("pixels-per-cell").unwrap() as f64;
+                let resolver = asset_resolver(owlbear_matches, &settings.asset_dirs);
+                let output = owlbear_matches.get_one::<PathBuf>("output").unwrap();
+                let scene =
+                    try_export_owlbear(mapfile, output, pixels_per_cell, resolver.as_ref())?;
+                info!("Wrote {}", scene.display());
+            }
+            Some(("prefab", prefab_matches)) => {
+                let mapfile = prefab_matches.get_one::<PathBuf>("mapfile").unwrap();
+                let output = prefab_matches
+                    .get_one::<PathBuf>("output")
+                    .cloned()
+                    .unwrap_or_else(|| mapfile.with_extension(PREFAB_EXTENSION));
+                let level = prefab_matches.get_one::<String>("level").unwrap();
+                let selection = match prefab_matches.get_one::<String>("room") {
+                    Some(id) => Selection::Room(id.clone()),
+                    None => prefab_matches
+                        .get_one::<Selection>("region")
+                        .cloned()
+                        .unwrap(),
+                };
+                let count = try_export_prefab(mapfile, &output, level, &selection)?;
+                info!("Wrote {} nodes to {}", count, output.display());
+            }
+            _ => unreachable!("Exhausted list of export subcommands"),
+        },
         Some(("generate", sub_matches)) => {
-            if let Some(o) = sub_matches.get_one::<PathBuf>("image") {
-                create_backup(o).unwrap();
-                let _shapes = try_find_shapes(&o);
+            #[cfg(feature = "capture")]
+            let captured = if sub_matches.get_flag("capture") {
+                Some(capture::capture_region()?)
+            } else {
+                None
+            };
+            #[cfg(not(feature = "capture"))]
+            let captured: Option<PathBuf> = None;
+            if let Some(input) = captured
+                .as_ref()
+                .or_else(|| sub_matches.get_one::<PathBuf>("image"))
+                .or_else(|| sub_matches.get_one::<PathBuf>("layout"))
+            {
+                let mode = sub_matches.get_one::<String>("mode").unwrap();
+                let heightmap = sub_matches.get_one::<PathBuf>("heightmap");
+                let biome_map = sub_matches.get_one::<PathBuf>("biome-map");
+                if (heightmap.is_some() || biome_map.is_some()) && mode != "world" {
+                    return Err(
+                        "--heightmap and --biome-map are only supported in world mode".into(),
+                    );
+                }
+                let dungeon_only = [
+                    "annotations",
+                    "emit-review",
+                    "apply-review",
+                    "pipeline",
+                    "rounding",
+                    "grid-size",
+                    "grid-offset",
+                    "grid-rotation",
+                    "overlapping",
+                    "min-wall-length",
+                    "max-gap",
+                    "morphology",
+                    "canny-low",
+                    "canny-high",
+                    "epsilon",
+                    "min-area",
+                    "max-area",
+                    "min-aspect",
+                    "max-aspect",
+                    "min-perimeter",
+                    "max-perimeter",
+                    "min-convexity",
+                    "detector",
+                    "wall-hsv",
+                    "floor-color",
+                    "wall-color",
+                    "cells-per-inch",
+                    "layout",
+                    "upper-level",
+                    "page",
+                    "gm-notes",
+                    "number-rooms",
+                ];
+                if let Some(arg) = dungeon_only.iter().find(|arg| sub_matches.contains_id(arg)) {
+                    if mode != "dungeon" {
+                        return Err(format!("--{} is only supported in dungeon mode", arg).into());
+                    }
+                }
+                for flag in ["isometric", "nested-shapes", "stairs", "ocr"] {
+                    if sub_matches.get_flag(flag) && mode != "dungeon" {
+                        return Err(format!("--{} is only supported in dungeon mode", flag).into());
+                    }
+                }
+                if sub_matches.get_flag("all-pages") && mode != "dungeon" {
+                    return Err("--all-pages is only supported in dungeon mode".into());
+                }
+                #[cfg(feature = "onnx")]
+                if let Some(arg) = ["segmentation-model", "symbol-model"]
+                    .iter()
+                    .find(|arg| sub_matches.contains_id(arg))
+                {
+                    if mode != "dungeon" {
+                        return Err(format!("--{} is only supported in dungeon mode", arg).into());
+                    }
+                }
+                for assignment in sub_matches
+                    .get_many::<String>("wall-style")
+                    .unwrap_or_default()
+                {
+                    let (key, value) = parse_assignment(assignment)?;
+                    settings.walls.set(&key, &value)?;
+                }
+                for assignment in sub_matches
+                    .get_many::<String>("water-style")
+                    .unwrap_or_default()
+                {
+                    let (key, value) = parse_assignment(assignment)?;
+                    settings.water.set(&key, &value)?;
+                }
+                let script = sub_matches
+                    .get_one::<PathBuf>("script")
+                    .map(|path| Script::load(path).map(Arc::new))
+                    .transpose()?;
+                let plugins_dir = sub_matches
+                    .get_one::<PathBuf>("plugins")
+                    .cloned()
+                    .or_else(|| settings.plugins_dir.clone())
+                    .unwrap_or_else(|| default_plugins_dir(&settings.config_path));
+                let plugins: Vec<_> = discover_plugins(&plugins_dir)?
+                    .into_iter()
+                    .map(Arc::new)
+                    .collect();
+                let o = &fetch_input(input)?;
+                let heightmap = heightmap.map(|path| fetch_input(path)).transpose()?;
+                let biome_map = biome_map.map(|path| fetch_input(path)).transpose()?;
+                let _span =
+                    info_span!("generate", input = %input.display(), mode = %mode).entered();
+                let mut report = RunReport::new(&input.display().to_string(), mode);
+                // Written once the map is in its template, so they name its final node ids
+                let mut room_key = None;
+                let mut gm_notes = None;
+                let started = Instant::now();
+                let mut map = match mode.as_str() {
+                    "world" => {
+                        let rules = if settings.biomes.is_empty() {
+                            default_rules()
+                        } else {
+                            settings.biomes.clone()
+                        };
+                        let map = try_generate_world(
+                            o,
+                            heightmap.as_deref(),
+                            biome_map.as_deref(),
+                            &rules,
+                            &settings.textures,
+                        )?;
+                        report.stage("generate", started, None, None);
+                        map
+                    }
+                    "town" => {
+                        let buildings: Vec<String> = sub_matches
+                            .get_many::<String>("building")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect();
+                        let map =
+                            try_generate_town(o, &buildings, &settings.textures, &settings.walls)?;
+                        report.stage("generate", started, None, None);
+                        map
+                    }
+                    _ => {
+                        let mut options = DungeonOptions {
+                            script: script.clone(),
+                            plugins: plugins.clone(),
+                            wall_styles: settings.walls.clone(),
+                            portal_styles: settings.portals.clone(),
+                            textures: settings.textures.clone(),
+                            ..Default::default()
+                        };
+                        let cells_per_inch = sub_matches
+                            .get_one::<f64>("cells-per-inch")
+                            .copied()
+                            .unwrap_or(CELLS_PER_INCH);
+                        let page = sub_matches.get_one::<usize>("page").copied();
+                        let mut other_pages = Vec::new();
+                        let image = match sub_matches.get_one::<PathBuf>("layout") {
+                            Some(path) => Layout::read(path)?.compose()?,
+                            None if page.is_some() || is_multi_page(o) => {
+                                let mut pages = read_map_pages(o, page)?.into_iter();
+                                let first = pages.next().unwrap_or_default();
+                                other_pages = pages.collect();
+                                if !other_pages.is_empty() && !sub_matches.get_flag("all-pages") {
+                                    report.warn(format!(
+                                        "Generating the first of the {} pages of {}, pass --page or --all-pages for the others",
+                                        other_pages.len() + 1,
+                                        o.display()
+                                    ));
+                                    other_pages.clear();
+                                }
+                                first
+                            }
+                            None => read_map_image(o)?,
+                        };
+                        if image.empty() {
+                            return Err(format!("failed to read image {}", o.display()).into());
+                        }
+                        options.grid_size = sub_matches.get_one::<f64>("grid-size").copied();
+                        options.isometric = sub_matches.get_flag("isometric");
+                        options.read_scale(o, &image, cells_per_inch)?;
+                        report.stage("read image", started, None, None);
+                        if let Some(confidence) = sub_matches.get_one::<f64>("min-confidence") {
+                            options.min_confidence = *confidence;
+                        }
+                        if sub_matches
+                            .get_one::<String>("detector")
+                            .is_some_and(|detector| detector == "lines")
+                        {
+                            options.detector = Detector::Lines;
+                        }
+                        if let Some(range) = sub_matches.get_one::<HsvRange>("wall-hsv") {
+                            options.detector = Detector::Hsv(*range);
+                        }
+                        if let Some(floor) = sub_matches.get_one::<ColorRange>("floor-color") {
+                            options.detector = Detector::Fills(FillColors {
+                                floor: *floor,
+                                wall: sub_matches.get_one::<ColorRange>("wall-color").copied(),
+                            });
+                        }
+                        options.water_color =
+                            sub_matches.get_one::<ColorRange>("water-color").copied();
+                        options.material_colors = sub_matches
+                            .get_many::<(String, HsvRange)>("material-color")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect();
+                        options.path_colors = sub_matches
+                            .get_many::<(String, HsvRange)>("path-color")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect();
+                        options.pattern_colors = sub_matches
+                            .get_many::<(String, HsvRange)>("pattern-color")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect();
+                        if let Some(gap) = sub_matches.get_one::<f64>("max-gap") {
+                            options.max_gap = *gap;
+                        }
+                        options.detection = detection_options(sub_matches, &settings.detection)?;
+                        options.nested_shapes = sub_matches.get_flag("nested-shapes");
+                        options.stairs = sub_matches.get_flag("stairs");
+                        options.ocr = sub_matches.get_flag("ocr");
+                        options.morphology = sub_matches
+                            .get_many::<Morphology>("morphology")
+                            .unwrap_or_default()
+                            .copied()
+                            .collect();
+                        if let Some(length) = sub_matches.get_one::<f64>("min-wall-length") {
+                            options.min_wall_length = *length;
+                        }
+                        if let Some(rounding) = sub_matches.get_one::<Rounding>("rounding") {
+                            options.rounding = *rounding;
+                        }
+                        options.grid_offset =
+                            sub_matches.get_one::<Vector2>("grid-offset").copied();
+                        options.grid_rotation =
+                            sub_matches.get_one::<f64>("grid-rotation").copied();
+                        options.curved_walls = !sub_matches.get_flag("straight-walls");
+                        options.terrain = sub_matches.get_flag("terrain");
+                        if let Some(overlapping) = sub_matches
+                            .get_one::<String>("overlapping")
+                            .and_then(|policy| Overlapping::parse(policy))
+                        {
+                            options.overlapping = overlapping;
+                        }
+                        if let Some(path) = sub_matches.get_one::<PathBuf>("pipeline") {
+                            options.pipeline = Some(Pipeline::read(path)?);
+                        }
+                        #[cfg(feature = "onnx")]
+                        if let Some(path) = sub_matches.get_one::<PathBuf>("segmentation-model") {
+                            let path = models::resolve_model(&settings.models_dir, path);
+                            options.segmentation =
+                                Some(Arc::new(segmentation::SegmentationModel::load(&path)?));
+                        }
+                        #[cfg(feature = "onnx")]
+                        if let Some(path) = sub_matches.get_one::<PathBuf>("symbol-model") {
+                            let path = models::resolve_model(&settings.models_dir, path);
+                            options.symbols = Some(Arc::new(symbols::SymbolDetector::load(
+                                &path,
+                                settings.symbols.clone(),
+                            )?));
+                        }
+                        if let Some(path) = sub_matches.get_one::<PathBuf>("annotations") {
+                            let mapper = options.mapper(&image)?;
+                            // Annotations and detection share the same grid
+                            options.grid_offset = Some(mapper.offset);
+                            options.grid_rotation =
+                                Some(mapper.rotation.map_or(0.0, |rotation| rotation.degrees));
+                            options.annotations = Some(read_annotations(path, o, &mapper)?);
+                            options.merge_detection = sub_matches.get_flag("merge-detection");
+                            options.annotation_labels = sub_matches.get_flag("annotation-labels");
+                        }
+                        if let Some(path) = sub_matches.get_one::<PathBuf>("emit-review") {
+                            try_review_dungeon(o, &options)?.write(path)?;
+                            info!("Wrote {}", path.display());
+                            return Ok(());
+                        }
+                        if let Some(path) = sub_matches.get_one::<PathBuf>("apply-review") {
+                            options.review = Some(Review::read(path)?);
+                        }
+                        let mut map = generate_dungeon_with_report(&image, &options, &mut report)?;
+                        if let Some(order) = sub_matches
+                            .get_one::<String>("number-rooms")
+                            .and_then(|order| RoomOrder::parse(order))
+                        {
+                            let started = Instant::now();
+                            let key = number_rooms(&mut map, order, &input.display().to_string())?;
+                            report.stage("number rooms", started, None, Some(key.rooms.len()));
+                            room_key = Some(key);
+                        }
+                        if sub_matches.contains_id("gm-notes") {
+                            let started = Instant::now();
+                            let notes = GmNotes {
+                                image: input.display().to_string(),
+                                rooms: Vec::new(),
+                                markers: detect_markers(&image, &options.mapper(&image)?)?,
+                                secret_doors: options
+                                    .annotations
+                                    .iter()
+                                    .flat_map(|annotations| &annotations.doors)
+                                    .filter(|door| door.secret)
+                                    .map(|door| Note::at("Secret door", door.position()))
+                                    .collect(),
+                                labels: options
+                                    .annotations
+                                    .iter()
+                                    .flat_map(|annotations| &annotations.labels)
+                                    .map(|(text, position)| Note::at(text, *position))
+                                    .collect(),
+                            };
+                            report.stage("gm notes", started, None, Some(notes.markers.len()));
+                            gm_notes = Some(notes);
+                        }
+                        // The other pages of the input are the first floors above it
+                        let mut upper_levels: Vec<(PathBuf, Mat)> = other_pages
+                            .into_iter()
+                            .map(|image| (o.clone(), image))
+                            .collect();
+                        for path in sub_matches
+                            .get_many::<PathBuf>("upper-level")
+                            .unwrap_or_default()
+                        {
+                            let path = fetch_input(path)?;
+                            let image = read_map_image(&path)?;
+                            if image.empty() {
+                                return Err(
+                                    format!("failed to read image {}", path.display()).into()
+                                );
+                            }
+                            upper_levels.push((path, image));
+                        }
+                        for (floor, (path, image)) in upper_levels.into_iter().enumerate() {
+                            let started = Instant::now();
+                            let mut level_options = DungeonOptions {
+                                annotations: None,
+                                review: None,
+                                ..options.clone()
+                            };
+                            level_options.read_scale(&path, &image, cells_per_inch)?;
+                            let level = generate_dungeon(&image, &level_options)?;
+                            let label = format!("Floor {}", floor + 1);
+                            let alignment = stack_level(&mut map, level, &label)?;
+                            info!(
+                                "Moved {} by {} to line up {} stairwells",
+                                label,
+                                alignment.offset,
+                                alignment.matched.len()
+                            );
+                            for stairs in &alignment.unmatched_lower {
+                                report.warn(format!(
+                                    "Stairs at {} below {} lead to no stairs above",
+                                    stairs, label
+                                ));
+                            }
+                            for stairs in &alignment.unmatched_upper {
+                                report.warn(format!(
+                                    "Stairs at {} on {} lead to no stairs below",
+                                    stairs, label
+                                ));
+                            }
+                            report.stage("stack level", started, None, None);
+                        }
+                        map
+                    }
+                };
+                let smoothing = sub_matches
+                    .get_one::<usize>("path-smoothing")
+                    .copied()
+                    .unwrap_or(settings.path_smoothing);
+                smooth_paths(&mut map, smoothing);
+                settings.water.apply(&mut map);
+                if sub_matches.get_flag("scatter") {
+                    let started = Instant::now();
+                    let mut scattering = settings.scatter.clone();
+                    if let Some(seed) = sub_matches.get_one::<u64>("scatter-seed") {
+                        scattering.seed = *seed;
+                    }
+                    if let Some(density) = sub_matches.get_one::<f64>("scatter-density") {
+                        scattering = scattering.with_density(*density);
+                    }
+                    let count = scatter(&mut map, &scattering)?;
+                    info!("Scattered {} decorations", count);
+                    report.stage("scatter", started, None, Some(count));
+                }
+                if let Some(script) = &script {
+                    let started = Instant::now();
+                    script.nodes(&mut map)?;
+                    report.stage("script", started, None, None);
+                }
+                for plugin in &plugins {
+                    let started = Instant::now();
+                    plugin.map(&mut map)?;
+                    report.stage("plugin", started, None, None);
+                }
+                record_generated(&mut map);
+                let level = *sub_matches.get_one::<u32>("level").unwrap();
+                let mut offset = 0;
+                if let Some(path) = sub_matches.get_one::<PathBuf>("template") {
+                    let started = Instant::now();
+                    let mut template = read_map(path)?;
+                    offset = insert_into_template(&mut template, &map, level)?;
+                    map = template;
+                    report.stage("template", started, None, None);
+                } else if level > 0 {
+                    // Levels below the one generated are left empty
+                    let mut levels = MapBuilder::new(1, 1)?.build();
+                    offset = insert_into_template(&mut levels, &map, level)?;
+                    map = levels;
+                }
+                if let Some(key) = &mut room_key {
+                    key.offset_walls(offset);
+                    if let Some(path) = sub_matches.get_one::<PathBuf>("room-key") {
+                        key.write(path)?;
+                        info!(
+                            "Wrote the key of {} rooms to {}",
+                            key.rooms.len(),
+                            path.display()
+                        );
+                    }
+                }
+                if let (Some(notes), Some(path)) =
+                    (&mut gm_notes, sub_matches.get_one::<PathBuf>("gm-notes"))
+                {
+                    notes.rooms = room_key.map(|key| key.rooms).unwrap_or_default();
+                    notes.write(path)?;
+                    info!(
+                        "Wrote {} GM markers, {} secret doors, and {} labels to {}",
+                        notes.markers.len(),
+                        notes.secret_doors.len(),
+                        notes.labels.len(),
+                        path.display()
+                    );
+                }
+                for field in ["title", "creator", "notes"] {
+                    if let Some(value) = sub_matches.get_one::<String>(field) {
+                        set_metadata(&mut map, field, value)?;
+                    }
+                }
+                stamp_generator(
+                    &mut map,
+                    &serde_json::json!({
+                        "mode": mode,
+                        "level": level,
+                        "parameters": report.parameters,
+                    }),
+                )?;
+                if sub_matches.get_flag("reproducible") {
+                    make_reproducible(&mut map)?;
+                }
+                let output = sub_matches
+                    .get_one::<PathBuf>("mapfile")
+                    .cloned()
+                    .unwrap_or_else(|| default_mapfile(input, o));
+                let format = *sub_matches.get_one::<i64>("map-format").unwrap();
+                write_generated(&output, &map, format)?;
+                info!("Wrote {}", output.display());
+                if let Some(path) = sub_matches.get_one::<PathBuf>("report") {
+                    report.write(path)?;
+                    info!("Wrote {}", path.display());
+                }
+            }
+        }
+        Some(("daemon", sub_matches)) => {
+            let options = DaemonOptions {
+                jobs: *sub_matches.get_one::<usize>("jobs").unwrap(),
+                interval: Duration::from_secs(*sub_matches.get_one::<u64>("interval").unwrap()),
+            };
+            let rules = if settings.biomes.is_empty() {
+                default_rules()
+            } else {
+                settings.biomes.clone()
+            };
+            daemon::run_daemon(
+                sub_matches.get_one::<PathBuf>("inbox").unwrap(),
+                sub_matches.get_one::<PathBuf>("outbox").unwrap(),
+                &options,
+                &rules,
+            )?;
+        }
+        Some(("serve", sub_matches)) => {
+            let port = *sub_matches.get_one::<u16>("port").unwrap();
+            let bind = *sub_matches.get_one::<std::net::IpAddr>("bind").unwrap();
+            let rules = if settings.biomes.is_empty() {
+                default_rules()
+            } else {
+                settings.biomes.clone()
+            };
+            serve::serve(bind, port, &rules)?;
+        }
+        Some(("pack", sub_matches)) => match sub_matches.subcommand() {
+            Some(("search", search_matches)) => {
+                let tag = search_matches.get_one::<String>("tag").unwrap();
+                let resolver = asset_resolver(search_matches, &settings.asset_dirs)
+                    .ok_or("no asset directories given with --assets or the assets config")?;
+                for asset in resolver.search(tag) {
+                    println!("{}", asset);
+                }
+            }
+            _ => unreachable!("Exhausted list of pack subcommands"),
+        },
+        Some(("models", sub_matches)) => match sub_matches.subcommand() {
+            Some(("list", _)) => {
+                for line in models::list(&settings.models_dir, &settings.models)? {
+                    println!("{}", line);
+                }
+            }
+            Some(("download", download_matches)) => {
+                let name = download_matches.get_one::<String>("name").unwrap();
+                let source = settings
+                    .models
+                    .iter()
+                    .find(|source| source.name == *name)
+                    .ok_or_else(|| format!("{} is not in the models config", name))?;
+                models::download(&settings.models_dir, source)?;
+            }
+            Some(("remove", remove_matches)) => {
+                let name = remove_matches.get_one::<String>("name").unwrap();
+                if !models::remove(&settings.models_dir, name)? {
+                    return Err(format!("{} is not downloaded", name).into());
+                }
             }
+            _ => unreachable!("Exhausted list of models subcommands"),
+        },
+        #[cfg(feature = "gui")]
+        Some(("gui", sub_matches)) => {
+            let input = sub_matches.get_one::<PathBuf>("image").unwrap();
+            let image = fetch_input(input)?;
+            let output = sub_matches
+                .get_one::<PathBuf>("mapfile")
+                .cloned()
+                .unwrap_or_else(|| default_mapfile(input, &image));
+            #[cfg(feature = "onnx")]
+            let assist = match (
+                sub_matches.get_one::<PathBuf>("sam-encoder"),
+                sub_matches.get_one::<PathBuf>("sam-decoder"),
+            ) {
+                (Some(encoder), Some(decoder)) => Some(assist::PromptableModel::load(
+                    &models::resolve_model(&settings.models_dir, encoder),
+                    &models::resolve_model(&settings.models_dir, decoder),
+                )?),
+                _ => None,
+            };
+            gui::run_preview(
+                &image,
+                &output,
+                #[cfg(feature = "onnx")]
+                assist,
+            )?;
         }
         _ => {}
     }
@@ -220,3 +2816,85 @@ Argument values are processed in the following order, using the last processed v
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detection_options() {
+        let command = clap::Command::new("generate").args(detection_args(""));
+        let configured = DetectionOptions {
+            canny_low: 30.0,
+            canny_high: 90.0,
+            min_area: 400.0,
+            max_area: Some(9000.0),
+            ..Default::default()
+        };
+        let matches = command
+            .clone()
+            .try_get_matches_from(["generate", "--canny-high", "120", "--max-area", "5000"])
+            .unwrap();
+        let options = detection_options(&matches, &configured).unwrap();
+        assert_eq!(options.canny_low, 30.0);
+        assert_eq!(options.canny_high, 120.0);
+        assert_eq!(options.min_area, 400.0);
+        assert_eq!(options.max_area, Some(5000.0));
+
+        // Flags are checked against the config they are mixed with
+        let matches = command
+            .clone()
+            .try_get_matches_from(["generate", "--canny-low", "100"])
+            .unwrap();
+        assert!(detection_options(&matches, &configured).is_err());
+        let matches = command
+            .try_get_matches_from(["generate", "--epsilon=-0.1"])
+            .unwrap();
+        assert!(detection_options(&matches, &configured).is_err());
+    }
+
+    #[test]
+    fn test_write_generated() {
+        let mut image = Mat::new_rows_cols_with_default(
+            256,
+            256,
+            opencv::core::CV_8UC3,
+            opencv::core::Scalar::all(255.0),
+        )
+        .unwrap();
+        opencv::imgproc::rectangle(
+            &mut image,
+            opencv::core::Rect::new(64, 64, 128, 128),
+            opencv::core::Scalar::all(0.0),
+            4,
+            opencv::imgproc::LINE_8,
+            0,
+        )
+        .unwrap();
+        let map = generate_dungeon(&image, &DungeonOptions::default()).unwrap();
+        let dir = std::env::temp_dir().join(format!("ddgen-generated-{}", std::process::id()));
+        let output = dir.join("maps").join("crypt.dungeondraft_map");
+
+        // The directory is created and the map loads back as it was generated
+        write_generated(&output, &map, CURRENT_FORMAT).unwrap();
+        let written = read_map(&output).unwrap();
+        let walls = &written["world"]["levels"]["0"]["walls"];
+        assert!(!walls.as_array().unwrap().is_empty());
+        assert_eq!(walls, &map["world"]["levels"]["0"]["walls"]);
+        assert_eq!(written["world"]["width"], map["world"]["width"]);
+
+        // Writing it again backs up the map already there
+        write_generated(&output, &map, CURRENT_FORMAT).unwrap();
+        assert_eq!(read_map(&get_backup_path(&output)).unwrap(), written);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_non_negative() {
+        assert_eq!(parse_non_negative("0"), Ok(0.0));
+        assert_eq!(parse_non_negative("0.25"), Ok(0.25));
+        assert!(parse_non_negative("-0.5").is_err());
+        assert!(parse_non_negative("inf").is_err());
+        assert!(parse_non_negative("NaN").is_err());
+    }
+}
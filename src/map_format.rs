@@ -0,0 +1,167 @@
+use serde_json::Value;
+use tracing::debug;
+
+use crate::dungeondraft_v1::MAPFILE;
+
+/// The map format this generator works with, that of DungeonDraft 1.1.
+pub const CURRENT_FORMAT: i64 = 3;
+/// The oldest map format that can be read and written.
+pub const OLDEST_FORMAT: i64 = 2;
+/// The DungeonDraft release that moved to the current format.
+const CURRENT_FORMAT_BUILD: (u32, u32) = (1, 1);
+/// World fields added by the current format.
+const FORMAT_3_WORLD_FIELDS: &[&str] = &["next_prefab_id"];
+
+/// Reads and writes maps of one version of the DungeonDraft map format, so
+/// the rest of the generator only ever works on the current one.
+pub trait MapFormat {
+    /// The `world.format` number of maps in this format.
+    fn version(&self) -> i64;
+
+    /// Brings a map read in this format up to the current format.
+    fn read(&self, map: &mut Value);
+
+    /// Brings a map in the current format down to this format for writing.
+    fn write(&self, map: &mut Value);
+}
+
+/// Maps of DungeonDraft 1.1 and later.
+struct Format3;
+
+impl MapFormat for Format3 {
+    fn version(&self) -> i64 {
+        3
+    }
+
+    fn read(&self, _map: &mut Value) {}
+
+    fn write(&self, _map: &mut Value) {}
+}
+
+/// Maps of DungeonDraft before 1.1, lacking some fields of the current format.
+struct Format2;
+
+impl MapFormat for Format2 {
+    fn version(&self) -> i64 {
+        2
+    }
+
+    /// Fills in the world and level fields the map lacks from the default map.
+    fn read(&self, map: &mut Value) {
+        let defaults: Value = serde_json::from_str(MAPFILE).expect("default map is valid JSON");
+        let default_world = &defaults["world"];
+        let default_level = &default_world["levels"]["0"];
+        fill_missing(&mut map["world"], default_world);
+        if let Some(levels) = map["world"]["levels"].as_object_mut() {
+            for level in levels.values_mut() {
+                fill_missing(level, default_level);
+            }
+        }
+        map["world"]["format"] = CURRENT_FORMAT.into();
+    }
+
+    fn write(&self, map: &mut Value) {
+        if let Some(world) = map["world"].as_object_mut() {
+            for field in FORMAT_3_WORLD_FIELDS {
+                world.remove(*field);
+            }
+        }
+        map["world"]["format"] = self.version().into();
+    }
+}
+
+/// Adds the fields of `defaults` missing from `target`, leaving those it has as they are.
+fn fill_missing(target: &mut Value, defaults: &Value) {
+    let (Some(target), Some(defaults)) = (target.as_object_mut(), defaults.as_object()) else {
+        return;
+    };
+    for (key, value) in defaults {
+        if !target.contains_key(key) {
+            target.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// The reader and writer of a map format version.
+pub fn map_format(version: i64) -> Result<Box<dyn MapFormat>, String> {
+    match version {
+        3 => Ok(Box::new(Format3)),
+        2 => Ok(Box::new(Format2)),
+        _ => Err(format!(
+            "unsupported map format {}, expected {} to {}",
+            version, OLDEST_FORMAT, CURRENT_FORMAT
+        )),
+    }
+}
+
+/// The `major.minor` version of a DungeonDraft build such as `"1.1.0.3 fluffy phoenix"`.
+fn build_version(build: &str) -> Option<(u32, u32)> {
+    let mut numbers = build.split_whitespace().next()?.split('.');
+    Some((numbers.next()?.parse().ok()?, numbers.next()?.parse().ok()?))
+}
+
+/// Detects the format of a map from its `world.format`, or else from the
+/// DungeonDraft build in its header that saved it.
+pub fn detect_format(map: &Value) -> Result<i64, String> {
+    if let Some(version) = map["world"]["format"].as_i64() {
+        return Ok(version);
+    }
+    let build = map["header"]["creation_build"]
+        .as_str()
+        .ok_or("map has neither a world format nor a creation build")?;
+    match build_version(build) {
+        Some(version) if version >= CURRENT_FORMAT_BUILD => Ok(CURRENT_FORMAT),
+        Some(_) => Ok(OLDEST_FORMAT),
+        None => Err(format!("unrecognized creation build '{}'", build)),
+    }
+}
+
+/// Detects the format of a map and brings it up to the current format,
+/// returning the version it was in.
+pub fn upgrade(map: &mut Value) -> Result<i64, String> {
+    let version = detect_format(map)?;
+    if version != CURRENT_FORMAT {
+        debug!("Reading format {} map", version);
+    }
+    map_format(version)?.read(map);
+    Ok(version)
+}
+
+/// Brings a map in the current format down to `version`.
+pub fn downgrade(map: &mut Value, version: i64) -> Result<(), String> {
+    map_format(version)?.write(map);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        let mut map: Value = serde_json::from_str(MAPFILE).unwrap();
+        assert_eq!(detect_format(&map), Ok(3));
+        map["world"].as_object_mut().unwrap().remove("format");
+        assert_eq!(detect_format(&map), Ok(3));
+        map["header"]["creation_build"] = "1.0.3.2 hearty hydra".into();
+        assert_eq!(detect_format(&map), Ok(2));
+        map["header"]["creation_build"] = "nightly".into();
+        assert!(detect_format(&map).is_err());
+        assert!(map_format(1).is_err());
+    }
+
+    #[test]
+    fn test_format_2() {
+        let current: Value = serde_json::from_str(MAPFILE).unwrap();
+        let mut map = current.clone();
+        downgrade(&mut map, 2).unwrap();
+        assert_eq!(map["world"]["format"], 2);
+        assert!(map["world"].get("next_prefab_id").is_none());
+        map["world"]["levels"]["0"]
+            .as_object_mut()
+            .unwrap()
+            .remove("texts");
+        assert_eq!(upgrade(&mut map), Ok(2));
+        assert_eq!(map, current);
+    }
+}
@@ -0,0 +1,319 @@
+use std::f64::consts::TAU;
+use std::path::Path;
+
+use opencv::core::{self, Mat};
+use opencv::imgproc;
+use opencv::prelude::*;
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::coordinates::{units_to_cells, CoordinateMapper};
+use crate::dungeondraft_v1::Vector2;
+use crate::numbering::KeyedRoom;
+use crate::preprocess::{ink_mask, is_symbol};
+
+/// Markers are at least this share of a cell wide, smaller ink being specks
+/// and letters.
+const MIN_MARKER_SIZE: f64 = 0.15;
+/// Markers are about as wide as they are high, within this ratio.
+const MAX_MARKER_ASPECT: f64 = 1.5;
+/// A ring inked at this share of the angles around it is a circle.
+const MIN_CIRCLE_COVERAGE: f64 = 0.85;
+/// How many angles around a marker are sampled.
+const MARKER_SAMPLES: usize = 72;
+/// An asterisk has at least this many strokes out from its middle.
+const MIN_ASTERISK_SPOKES: usize = 5;
+
+/// A marker only the GM should see.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkerSymbol {
+    /// A T in a circle
+    Trap,
+    /// An asterisk, the usual sign of something hidden or of note
+    Asterisk,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Marker {
+    pub symbol: MarkerSymbol,
+    /// In map units
+    pub position: [f64; 2],
+    /// The cell the marker is in, counting from 0
+    pub cell: [i64; 2],
+}
+
+/// Text for the GM at a place on the map, such as a label annotated on it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Note {
+    pub text: String,
+    /// In map units
+    pub position: [f64; 2],
+    /// The cell the note is in, counting from 0
+    pub cell: [i64; 2],
+}
+
+impl Note {
+    pub fn at(text: &str, position: Vector2) -> Self {
+        Self {
+            text: text.to_string(),
+            position: [position.x, position.y],
+            cell: [
+                units_to_cells(position.x).floor() as i64,
+                units_to_cells(position.y).floor() as i64,
+            ],
+        }
+    }
+}
+
+/// What only the GM should know about a map, written beside it rather than
+/// into it, to prepare a session from.
+#[derive(Debug, Default, Serialize)]
+pub struct GmNotes {
+    pub image: String,
+    /// The numbered rooms, when the rooms are numbered
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<KeyedRoom>,
+    pub markers: Vec<Marker>,
+    pub secret_doors: Vec<Note>,
+    /// Labels annotated on the map other than those of what it is made from
+    pub labels: Vec<Note>,
+}
+
+impl GmNotes {
+    /// Writes the notes as Markdown to a `.md` file, or as JSON otherwise.
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let markdown = path
+            .extension()
+            .is_some_and(|ext| ext == "md" || ext == "markdown");
+        let contents = if markdown {
+            self.to_markdown()
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// The notes as a Markdown document, with a heading for each numbered room
+    /// to write its description under.
+    pub fn to_markdown(&self) -> String {
+        let cell = |cell: [i64; 2]| format!("column {}, row {}", cell[0], cell[1]);
+        let mut document = format!("# {}\n", self.image);
+        if !self.rooms.is_empty() {
+            document.push_str("\n## Rooms\n");
+            for room in &self.rooms {
+                document.push_str(&format!(
+                    "\n### {}\n\nAt {}, {} cells.\n",
+                    room.number,
+                    cell(room.cell),
+                    room.area.round()
+                ));
+            }
+        }
+        let sections = [
+            (
+                "Markers",
+                self.markers
+                    .iter()
+                    .map(|marker| format!("{:?} at {}", marker.symbol, cell(marker.cell)))
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "Secret doors",
+                self.secret_doors
+                    .iter()
+                    .map(|door| format!("{} at {}", door.text, cell(door.cell)))
+                    .collect(),
+            ),
+            (
+                "Labels",
+                self.labels
+                    .iter()
+                    .map(|label| format!("\"{}\" at {}", label.text, cell(label.cell)))
+                    .collect(),
+            ),
+        ];
+        for (heading, items) in sections.iter().filter(|(_, items)| !items.is_empty()) {
+            document.push_str(&format!("\n## {}\n\n", heading));
+            for item in items {
+                document.push_str(&format!("- {}\n", item));
+            }
+        }
+        document
+    }
+}
+
+/// The strokes crossing a circle around a marker, counting the runs of ink
+/// running all the way around as one.
+pub fn spokes(samples: &[bool]) -> usize {
+    let starts = (0..samples.len())
+        .filter(|&i| samples[i] && !samples[(i + samples.len() - 1) % samples.len()])
+        .count();
+    if starts == 0 && samples.iter().any(|&ink| ink) {
+        1
+    } else {
+        starts
+    }
+}
+
+/// What a marker is from its ink around its edge, `rim`, and around its
+/// middle, `inner`, both sampled at even angles.
+pub fn marker_symbol(rim: &[bool], inner: &[bool]) -> Option<MarkerSymbol> {
+    let coverage = rim.iter().filter(|&&ink| ink).count() as f64 / rim.len().max(1) as f64;
+    let strokes = spokes(inner);
+    if coverage >= MIN_CIRCLE_COVERAGE {
+        // The arms and stem of the T, and not a filled circle such as a pillar
+        (2..=4).contains(&strokes).then_some(MarkerSymbol::Trap)
+    } else {
+        (strokes >= MIN_ASTERISK_SPOKES).then_some(MarkerSymbol::Asterisk)
+    }
+}
+
+/// Finds traps drawn as a T in a circle and asterisks in a dungeon image.
+#[instrument(skip_all)]
+pub fn detect_markers(
+    image: &Mat,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Marker>, Box<dyn std::error::Error>> {
+    let ink = ink_mask(image)?;
+    let mut labels = Mat::default();
+    let mut stats = Mat::default();
+    let mut centroids = Mat::default();
+    let count = imgproc::connected_components_with_stats(
+        &ink,
+        &mut labels,
+        &mut stats,
+        &mut centroids,
+        8,
+        core::CV_32S,
+    )?;
+    let pixels_per_cell = mapper.pixels_per_cell;
+    let inked = |x: f64, y: f64| -> Result<bool, Box<dyn std::error::Error>> {
+        let (x, y) = (x.round() as i32, y.round() as i32);
+        Ok(x >= 0 && y >= 0 && x < ink.cols() && y < ink.rows() && *ink.at_2d::<u8>(y, x)? > 0)
+    };
+    let mut markers = Vec::new();
+    let mut circles: Vec<(i32, i32, i32, i32)> = Vec::new();
+    // Label 0 is the background
+    for label in 1..count {
+        let stat = |stat: i32| stats.at_2d::<i32>(label, stat).copied();
+        let (left, top) = (stat(imgproc::CC_STAT_LEFT)?, stat(imgproc::CC_STAT_TOP)?);
+        let (width, height) = (
+            stat(imgproc::CC_STAT_WIDTH)?,
+            stat(imgproc::CC_STAT_HEIGHT)?,
+        );
+        let (long, short) = (width.max(height) as f64, width.min(height) as f64);
+        if !is_symbol(width, height, pixels_per_cell)
+            || short < MIN_MARKER_SIZE * pixels_per_cell
+            || long > MAX_MARKER_ASPECT * short
+        {
+            continue;
+        }
+        // The T of a trap may be apart from its circle
+        if circles.iter().any(|&(l, t, w, h)| {
+            left >= l && top >= t && left + width <= l + w && top + height <= t + h
+        }) {
+            continue;
+        }
+        let center = (
+            left as f64 + width as f64 / 2.0,
+            top as f64 + height as f64 / 2.0,
+        );
+        let radius = short / 2.0;
+        let around = |share: f64| -> Result<Vec<bool>, Box<dyn std::error::Error>> {
+            (0..MARKER_SAMPLES)
+                .map(|i| {
+                    let (sin, cos) = (TAU * i as f64 / MARKER_SAMPLES as f64).sin_cos();
+                    inked(
+                        center.0 + cos * radius * share,
+                        center.1 + sin * radius * share,
+                    )
+                })
+                .collect()
+        };
+        // A circle's stroke is anywhere near its edge
+        let rim: Vec<bool> = around(0.95)?
+            .iter()
+            .zip(around(0.85)?)
+            .zip(around(0.75)?)
+            .map(|((&a, b), c)| a || b || c)
+            .collect();
+        let Some(symbol) = marker_symbol(&rim, &around(0.45)?) else {
+            continue;
+        };
+        if symbol == MarkerSymbol::Trap {
+            circles.push((left, top, width, height));
+        }
+        let position = mapper.pixels_to_units(center.0, center.1);
+        debug!("Found a {:?} marker at {}", symbol, position);
+        markers.push(Marker {
+            symbol,
+            position: [position.x, position.y],
+            cell: [
+                units_to_cells(position.x).floor() as i64,
+                units_to_cells(position.y).floor() as i64,
+            ],
+        });
+    }
+    Ok(markers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples inked within the given ranges of angles, in sample indices.
+    fn samples(inked: &[(usize, usize)]) -> Vec<bool> {
+        (0..MARKER_SAMPLES)
+            .map(|i| inked.iter().any(|&(from, to)| (from..to).contains(&i)))
+            .collect()
+    }
+
+    #[test]
+    fn test_spokes() {
+        assert_eq!(spokes(&samples(&[])), 0);
+        assert_eq!(spokes(&samples(&[(0, MARKER_SAMPLES)])), 1);
+        // A run wrapping around the first sample is one stroke
+        assert_eq!(spokes(&samples(&[(0, 3), (70, 72), (30, 33)])), 2);
+    }
+
+    #[test]
+    fn test_marker_symbol() {
+        let circle = samples(&[(0, MARKER_SAMPLES)]);
+        // The left and right arms of the T and its stem going down
+        let t = samples(&[(0, 3), (17, 20), (35, 38)]);
+        assert_eq!(marker_symbol(&circle, &t), Some(MarkerSymbol::Trap));
+        assert_eq!(marker_symbol(&circle, &circle), None);
+        let asterisk = samples(&[(0, 2), (12, 14), (24, 26), (36, 38), (48, 50), (60, 62)]);
+        assert_eq!(
+            marker_symbol(&asterisk, &asterisk),
+            Some(MarkerSymbol::Asterisk)
+        );
+        assert_eq!(marker_symbol(&samples(&[(0, 5)]), &t), None);
+    }
+
+    #[test]
+    fn test_gm_notes_markdown() {
+        let notes = GmNotes {
+            image: "crypt.png".to_string(),
+            rooms: vec![KeyedRoom {
+                number: 1,
+                wall: "0".to_string(),
+                position: [320.0, 320.0],
+                cell: [1, 1],
+                area: 9.0,
+            }],
+            secret_doors: vec![Note::at("Secret door", Vector2 { x: 640.0, y: 384.0 })],
+            labels: vec![Note::at("altar", Vector2 { x: 300.0, y: 300.0 })],
+            ..Default::default()
+        };
+        assert_eq!(notes.secret_doors[0].cell, [2, 1]);
+        assert_eq!(
+            notes.to_markdown(),
+            "# crypt.png\n\n## Rooms\n\n### 1\n\nAt column 1, row 1, 9 cells.\n\
+             \n## Secret doors\n\n- Secret door at column 2, row 1\n\
+             \n## Labels\n\n- \"altar\" at column 1, row 1\n"
+        );
+    }
+}
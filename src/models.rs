@@ -0,0 +1,208 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+/// The extension models are cached with.
+const MODEL_EXTENSION: &str = "onnx";
+
+/// A model that can be downloaded into the cache, from the `models` config.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ModelSource {
+    /// What the model is cached as and given to `--*-model` options by
+    pub name: String,
+    pub url: String,
+    /// The SHA-256 of the model file, in hex
+    pub sha256: String,
+}
+
+/// The directory downloaded models are cached in.
+pub fn default_models_dir() -> PathBuf {
+    ProjectDirs::from("", "", "dungeondraft-generator")
+        .map(|dirs| dirs.cache_dir().join("models"))
+        .unwrap_or_else(|| {
+            std::env::temp_dir()
+                .join("dungeondraft-generator")
+                .join("models")
+        })
+}
+
+/// Where a model of a name is cached.
+pub fn model_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name).with_extension(MODEL_EXTENSION)
+}
+
+/// The model file an option names: the file when one is there, or else the
+/// cached model of that name.
+#[cfg(feature = "onnx")]
+pub fn resolve_model(dir: &Path, model: &Path) -> PathBuf {
+    if model.is_file() {
+        return model.to_path_buf();
+    }
+    match model.to_str().map(|name| model_path(dir, name)) {
+        Some(cached) if cached.is_file() => {
+            tracing::debug!("Using the cached model {}", cached.display());
+            cached
+        }
+        _ => model.to_path_buf(),
+    }
+}
+
+/// The SHA-256 of everything read, in hex.
+pub fn sha256_hex(mut reader: impl Read, mut copy: impl Write) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        copy.write_all(&buffer[..read])?;
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Whether a cached model file is the one its source names.
+pub fn verify(path: &Path, source: &ModelSource) -> Result<bool, Box<dyn std::error::Error>> {
+    let digest = sha256_hex(fs::File::open(path)?, io::sink())?;
+    Ok(digest.eq_ignore_ascii_case(&source.sha256))
+}
+
+/// Downloads a model into the cache, checking its checksum before keeping it.
+pub fn download(dir: &Path, source: &ModelSource) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = model_path(dir, &source.name);
+    if path.is_file() && verify(&path, source)? {
+        info!("{} is already downloaded", source.name);
+        return Ok(path);
+    }
+    fs::create_dir_all(dir)?;
+    info!("Downloading {} from {}", source.name, source.url);
+    let mut response = ureq::get(&source.url).call()?;
+    // Models are kept only once whole and verified
+    let partial = path.with_extension("part");
+    let digest = sha256_hex(
+        response.body_mut().with_config().limit(u64::MAX).reader(),
+        fs::File::create(&partial)?,
+    )?;
+    if !digest.eq_ignore_ascii_case(&source.sha256) {
+        fs::remove_file(&partial)?;
+        return Err(format!(
+            "{} has checksum {}, expected {}",
+            source.url, digest, source.sha256
+        )
+        .into());
+    }
+    fs::rename(&partial, &path)?;
+    info!("Downloaded {} to {}", source.name, path.display());
+    Ok(path)
+}
+
+/// Removes a model from the cache, returning whether it was there.
+pub fn remove(dir: &Path, name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let path = model_path(dir, name);
+    if !path.is_file() {
+        return Ok(false);
+    }
+    fs::remove_file(&path)?;
+    info!("Removed {}", path.display());
+    Ok(true)
+}
+
+/// A line for each model of the config and each other model in the cache,
+/// with whether it is downloaded.
+pub fn list(
+    dir: &Path,
+    sources: &[ModelSource],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut lines = Vec::new();
+    for source in sources {
+        let path = model_path(dir, &source.name);
+        let status = if !path.is_file() {
+            "not downloaded"
+        } else if verify(&path, source)? {
+            "downloaded"
+        } else {
+            "checksum mismatch"
+        };
+        lines.push(format!("{}\t{}\t{}", source.name, status, source.url));
+    }
+    if dir.is_dir() {
+        let mut cached: Vec<String> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == MODEL_EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str().map(str::to_string))
+            .filter(|name| sources.iter().all(|source| source.name != *name))
+            .collect();
+        cached.sort();
+        lines.extend(cached.iter().map(|name| format!("{}\tdownloaded", name)));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_hex() {
+        let mut copy = Vec::new();
+        assert_eq!(
+            sha256_hex(&b"abc"[..], &mut copy).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(copy, b"abc");
+    }
+
+    #[test]
+    fn test_cached_models() {
+        let dir = std::env::temp_dir().join(format!("ddgen-models-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = ModelSource {
+            name: "walls".to_string(),
+            url: "https://example.com/walls.onnx".to_string(),
+            sha256: "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_string(),
+        };
+        assert_eq!(
+            list(&dir, std::slice::from_ref(&source)).unwrap(),
+            vec!["walls\tnot downloaded\thttps://example.com/walls.onnx"]
+        );
+
+        fs::write(model_path(&dir, "walls"), b"abc").unwrap();
+        fs::write(model_path(&dir, "symbols"), b"").unwrap();
+        assert!(verify(&model_path(&dir, "walls"), &source).unwrap());
+        assert_eq!(
+            list(&dir, &[source]).unwrap(),
+            vec![
+                "walls\tdownloaded\thttps://example.com/walls.onnx",
+                "symbols\tdownloaded"
+            ]
+        );
+        assert!(remove(&dir, "walls").unwrap());
+        assert!(!remove(&dir, "walls").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "onnx")]
+    #[test]
+    fn test_resolve_model() {
+        let dir = std::env::temp_dir().join(format!("ddgen-resolve-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(resolve_model(&dir, Path::new("walls")), Path::new("walls"));
+        fs::write(model_path(&dir, "walls"), b"abc").unwrap();
+        assert_eq!(
+            resolve_model(&dir, Path::new("walls")),
+            model_path(&dir, "walls")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
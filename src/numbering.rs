@@ -0,0 +1,256 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::coordinates::{square_units_to_cells, units_to_cells};
+use crate::dungeondraft_v1::{
+    append_node, is_loop, node_id, node_points, nodes, text_node, Vector2, UNITS_PER_CELL,
+};
+use crate::geometry::{bounds, centroid, contains_point, distance, polygon_area, signed_area};
+
+/// The font size room numbers are labelled with.
+const ROOM_NUMBER_FONT_SIZE: f64 = 40.0;
+
+/// The order rooms are numbered in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomOrder {
+    /// Row by row of cells from the top, and left to right along each row,
+    /// the way the page is read
+    Position,
+    /// The largest room first
+    Size,
+}
+
+impl RoomOrder {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "position" => Some(Self::Position),
+            "size" => Some(Self::Size),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyedRoom {
+    pub number: usize,
+    /// The node id of the wall around the room
+    pub wall: String,
+    /// Where the label is, in map units
+    pub position: [f64; 2],
+    /// The cell the label is in, counting from 0
+    pub cell: [i64; 2],
+    /// Floor area in cells
+    pub area: f64,
+}
+
+/// The numbered rooms of a map, written beside it as the key of a published
+/// adventure is, to fill in their descriptions.
+#[derive(Debug, Serialize)]
+pub struct RoomKey {
+    pub image: String,
+    pub rooms: Vec<KeyedRoom>,
+}
+
+impl RoomKey {
+    /// Moves the wall ids of the rooms by `offset`, as inserting the map into
+    /// a template moves its node ids.
+    pub fn offset_walls(&mut self, offset: u64) {
+        for room in &mut self.rooms {
+            if let Ok(id) = u64::from_str_radix(&room.wall, 16) {
+                room.wall = format!("{:x}", id + offset);
+            }
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Where a room's label goes, at its center of mass, or at the point of a
+/// quarter cell grid inside it nearest that when it falls outside, as in the
+/// bend of an L.
+fn label_position(room: &[Vector2]) -> Option<Vector2> {
+    let center = centroid(room)?;
+    if contains_point(room, center) {
+        return Some(center);
+    }
+    let (min, max) = bounds(room)?;
+    let step = UNITS_PER_CELL / 4.0;
+    let columns = ((max.x - min.x) / step).ceil() as usize;
+    let rows = ((max.y - min.y) / step).ceil() as usize;
+    (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (row, column)))
+        .map(|(row, column)| Vector2 {
+            x: min.x + (column as f64 + 0.5) * step,
+            y: min.y + (row as f64 + 0.5) * step,
+        })
+        .filter(|&p| contains_point(room, p))
+        .min_by(|&a, &b| distance(a, center).total_cmp(&distance(b, center)))
+        .or(Some(center))
+}
+
+/// The rooms of the walls looping around a floor in a level, in `order`,
+/// leaving out the walls around holes in floors, which run the other way.
+fn ordered_rooms(level: &Value, order: RoomOrder) -> Vec<(String, Vec<Vector2>, Vector2)> {
+    let mut rooms: Vec<(String, Vec<Vector2>, Vector2)> = nodes(level, "walls")
+        .filter(|wall| is_loop(wall))
+        .filter_map(|wall| {
+            let points = node_points(wall).filter(|points| points.len() > 2)?;
+            if signed_area(&points) <= 0.0 {
+                return None;
+            }
+            let position = label_position(&points)?;
+            Some((node_id(wall)?, points, position))
+        })
+        .collect();
+    match order {
+        RoomOrder::Position => rooms.sort_by(|(_, _, a), (_, _, b)| {
+            let row = |p: &Vector2| units_to_cells(p.y).floor();
+            row(a).total_cmp(&row(b)).then(a.x.total_cmp(&b.x))
+        }),
+        RoomOrder::Size => {
+            rooms.sort_by(|(_, a, _), (_, b, _)| polygon_area(b).total_cmp(&polygon_area(a)))
+        }
+    }
+    rooms
+}
+
+/// Numbers the rooms of the ground level of a map from 1 in `order`, labelling
+/// each with its number, and returns the key of the numbered rooms.
+#[instrument(skip_all)]
+pub fn number_rooms(
+    map: &mut Value,
+    order: RoomOrder,
+    image: &str,
+) -> Result<RoomKey, Box<dyn std::error::Error>> {
+    let rooms = ordered_rooms(&map["world"]["levels"]["0"], order);
+    let mut keyed = Vec::new();
+    for (index, (wall, points, position)) in rooms.into_iter().enumerate() {
+        let number = index + 1;
+        append_node(
+            map,
+            "0",
+            "texts",
+            text_node(position, &number.to_string(), ROOM_NUMBER_FONT_SIZE),
+        )?;
+        keyed.push(KeyedRoom {
+            number,
+            wall,
+            position: [position.x, position.y],
+            cell: [
+                units_to_cells(position.x).floor() as i64,
+                units_to_cells(position.y).floor() as i64,
+            ],
+            area: square_units_to_cells(polygon_area(&points)),
+        });
+    }
+    Ok(RoomKey {
+        image: image.to_string(),
+        rooms: keyed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dungeondraft_v1::{hole_wall_node, insert_into_template, wall_node, MapBuilder};
+
+    fn square(x: f64, y: f64, size: f64) -> Vec<Vector2> {
+        let (x, y, size) = (
+            x * UNITS_PER_CELL,
+            y * UNITS_PER_CELL,
+            size * UNITS_PER_CELL,
+        );
+        vec![
+            Vector2 { x, y },
+            Vector2 { x: x + size, y },
+            Vector2 {
+                x: x + size,
+                y: y + size,
+            },
+            Vector2 { x, y: y + size },
+        ]
+    }
+
+    fn map() -> Value {
+        let mut builder = MapBuilder::new(20, 20).unwrap();
+        builder.add_node("walls", wall_node(&square(10.0, 0.0, 2.0), true));
+        builder.add_node("walls", wall_node(&square(0.0, 0.0, 3.0), true));
+        builder.add_node("walls", wall_node(&square(0.0, 10.0, 5.0), true));
+        builder.add_node("walls", hole_wall_node(&square(1.0, 11.0, 1.0)));
+        builder.build()
+    }
+
+    #[test]
+    fn test_number_by_position() {
+        let mut map = map();
+        let key = number_rooms(&mut map, RoomOrder::Position, "crypt.png").unwrap();
+        let walls: Vec<&str> = key.rooms.iter().map(|room| room.wall.as_str()).collect();
+        assert_eq!(walls, ["1", "0", "2"]);
+        assert_eq!(key.rooms[0].cell, [1, 1]);
+        assert_eq!(key.rooms[2].area, 25.0);
+        let texts = map["world"]["levels"]["0"]["texts"].as_array().unwrap();
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[0]["text"], "1");
+        assert_eq!(texts[0]["node_id"], "4");
+        assert_eq!(map["world"]["next_node_id"], "7");
+    }
+
+    #[test]
+    fn test_number_by_size() {
+        let mut map = map();
+        let key = number_rooms(&mut map, RoomOrder::Size, "crypt.png").unwrap();
+        let walls: Vec<&str> = key.rooms.iter().map(|room| room.wall.as_str()).collect();
+        assert_eq!(walls, ["2", "1", "0"]);
+    }
+
+    #[test]
+    fn test_offset_walls() {
+        let mut map = map();
+        let mut key = number_rooms(&mut map, RoomOrder::Size, "crypt.png").unwrap();
+        let mut template = MapBuilder::new(20, 20).unwrap();
+        template.add_node("walls", wall_node(&square(15.0, 15.0, 1.0), true));
+        let mut template = template.build();
+        let offset = insert_into_template(&mut template, &map, 0).unwrap();
+        key.offset_walls(offset);
+        let walls: Vec<&str> = key.rooms.iter().map(|room| room.wall.as_str()).collect();
+        assert_eq!(walls, ["3", "2", "1"]);
+        // The largest room is still keyed to its own wall
+        let level = &template["world"]["levels"]["0"];
+        let largest = nodes(level, "walls")
+            .find(|wall| node_id(wall).as_deref() == Some(key.rooms[0].wall.as_str()))
+            .and_then(node_points)
+            .unwrap();
+        assert_eq!(square_units_to_cells(polygon_area(&largest)), 25.0);
+    }
+
+    #[test]
+    fn test_label_position() {
+        let l = [
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 2560.0, y: 0.0 },
+            Vector2 {
+                x: 2560.0,
+                y: 256.0,
+            },
+            Vector2 { x: 256.0, y: 256.0 },
+            Vector2 {
+                x: 256.0,
+                y: 2560.0,
+            },
+            Vector2 { x: 0.0, y: 2560.0 },
+        ];
+        assert!(!contains_point(&l, centroid(&l).unwrap()));
+        assert!(contains_point(&l, label_position(&l).unwrap()));
+        let square = square(0.0, 0.0, 2.0);
+        assert_eq!(
+            label_position(&square),
+            Some(Vector2 { x: 256.0, y: 256.0 })
+        );
+    }
+}
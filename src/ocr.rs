@@ -0,0 +1,188 @@
+use std::fs;
+
+use opencv::core::{self, Mat, Scalar};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+use crate::preprocess::{ink_mask, paint_over};
+
+/// Words read with less confidence than this, out of 100, are left out as
+/// walls and hatching mistaken for letters.
+const MIN_WORD_CONFIDENCE: f64 = 60.0;
+/// The level of the rows of tesseract's TSV output holding single words.
+const WORD_LEVEL: &str = "5";
+
+/// A line of text read from an image, such as a room number or label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub text: String,
+    /// The box around the text in image pixels, from its minimum to maximum corner
+    pub min: Vector2,
+    pub max: Vector2,
+}
+
+impl Label {
+    /// The middle of the text in image pixels.
+    pub fn center(&self) -> Vector2 {
+        Vector2 {
+            x: (self.min.x + self.max.x) / 2.0,
+            y: (self.min.y + self.max.y) / 2.0,
+        }
+    }
+}
+
+/// The lines of text in tesseract's TSV output, each joined from the words
+/// read confidently enough on the same line.
+fn parse_tsv(tsv: &str) -> Vec<Label> {
+    let mut labels: Vec<((String, String, String), Label)> = Vec::new();
+    for row in tsv.lines().skip(1) {
+        let columns: Vec<&str> = row.split('\t').collect();
+        let [level, _, block, paragraph, line, _, left, top, width, height, confidence, text] =
+            columns[..]
+        else {
+            continue;
+        };
+        let number = |column: &str| column.trim().parse::<f64>().ok();
+        let (Some(left), Some(top), Some(width), Some(height), Some(confidence)) = (
+            number(left),
+            number(top),
+            number(width),
+            number(height),
+            number(confidence),
+        ) else {
+            continue;
+        };
+        let text = text.trim();
+        if level != WORD_LEVEL || text.is_empty() || confidence < MIN_WORD_CONFIDENCE {
+            continue;
+        }
+        let key = (block.to_string(), paragraph.to_string(), line.to_string());
+        let (min, max) = (
+            Vector2 { x: left, y: top },
+            Vector2 {
+                x: left + width,
+                y: top + height,
+            },
+        );
+        match labels.iter_mut().find(|(other, _)| *other == key) {
+            Some((_, label)) => {
+                label.text = format!("{} {}", label.text, text);
+                label.min = Vector2 {
+                    x: label.min.x.min(min.x),
+                    y: label.min.y.min(min.y),
+                };
+                label.max = Vector2 {
+                    x: label.max.x.max(max.x),
+                    y: label.max.y.max(max.y),
+                };
+            }
+            None => labels.push((
+                key,
+                Label {
+                    text: text.to_string(),
+                    min,
+                    max,
+                },
+            )),
+        }
+    }
+    labels.into_iter().map(|(_, label)| label).collect()
+}
+
+/// Reads the text drawn on an image with `tesseract`, which must be
+/// installed, looking for sparse text scattered over the map.
+#[instrument(skip_all)]
+pub fn read_labels(image: &Mat) -> Result<Vec<Label>, Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!(
+        "dungeondraft-generator-ocr-{}.png",
+        std::process::id()
+    ));
+    let path_str = path.to_str().ok_or("invalid temporary path")?;
+    imwrite(path_str, image, &core::Vector::new())?;
+    let output = std::process::Command::new("tesseract")
+        .arg(&path)
+        .args(["stdout", "--psm", "11", "tsv"])
+        .output();
+    fs::remove_file(&path)?;
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            return Err(format!(
+                "tesseract failed to read text: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into())
+        }
+        Err(e) => return Err(format!("reading text needs tesseract: {}", e).into()),
+    };
+    let labels = parse_tsv(&String::from_utf8_lossy(&output.stdout));
+    debug!("Read {} labels", labels.len());
+    Ok(labels)
+}
+
+/// Paints over the ink of labels with the floor around them, so they are not
+/// traced as walls, leaving any wall their boxes overlap.
+pub fn strip_labels(image: &Mat, labels: &[Label]) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut boxes = Mat::new_rows_cols_with_default(
+        image.rows(),
+        image.cols(),
+        core::CV_8UC1,
+        Scalar::all(0.0),
+    )?;
+    for label in labels {
+        let rect = core::Rect::new(
+            label.min.x as i32,
+            label.min.y as i32,
+            (label.max.x - label.min.x) as i32,
+            (label.max.y - label.min.y) as i32,
+        );
+        imgproc::rectangle(
+            &mut boxes,
+            rect,
+            Scalar::all(255.0),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )?;
+    }
+    let mut text = Mat::default();
+    core::bitwise_and(&ink_mask(image)?, &boxes, &mut text, &core::no_array())?;
+    paint_over(image, &text)
+}
+
+/// The labels read from an image as text at the middle of each, in map units.
+pub fn placed_labels(labels: &[Label], mapper: &CoordinateMapper) -> Vec<(String, Vector2)> {
+    labels
+        .iter()
+        .map(|label| (label.text.clone(), mapper.point(label.center())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tsv() {
+        let tsv = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext
+1\t1\t0\t0\t0\t0\t0\t0\t640\t480\t-1\t
+4\t1\t1\t1\t1\t0\t100\t50\t90\t20\t-1\t
+5\t1\t1\t1\t1\t1\t100\t50\t40\t20\t91.5\tGuard
+5\t1\t1\t1\t1\t2\t150\t52\t40\t20\t88.0\tRoom
+5\t1\t2\t1\t1\t1\t300\t200\t12\t18\t95.2\t12
+5\t1\t3\t1\t1\t1\t400\t10\t30\t30\t12.0\t|||
+";
+        let labels = parse_tsv(tsv);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].text, "Guard Room");
+        assert_eq!(labels[0].min, Vector2 { x: 100.0, y: 50.0 });
+        assert_eq!(labels[0].max, Vector2 { x: 190.0, y: 72.0 });
+        assert_eq!(labels[1].text, "12");
+        assert_eq!(labels[1].center(), Vector2 { x: 306.0, y: 209.0 });
+        assert!(parse_tsv("").is_empty());
+    }
+}
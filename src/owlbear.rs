@@ -0,0 +1,201 @@
+use std::path::{Path, PathBuf};
+
+use opencv::core;
+use opencv::imgcodecs::imwrite;
+use opencv::prelude::*;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::assets::AssetResolver;
+use crate::coordinates::units_to_cells;
+use crate::dungeondraft_v1::{is_loop, node_id, node_points, nodes, read_map, Vector2};
+use crate::render::render_map;
+use crate::uvtt::portal_span;
+
+/// The background image of an exported scene, beside its scene file.
+const SCENE_IMAGE: &str = "map.png";
+const SCENE_FILE: &str = "scene.json";
+/// The metadata Owlbear Rodeo's Dynamic Fog extension marks walls and doors with.
+const WALL_METADATA: &str = "rodeo.owlbear.dynamic-fog/wall";
+const DOOR_METADATA: &str = "rodeo.owlbear.dynamic-fog/door";
+const WALL_STROKE_COLOR: &str = "#000000";
+const DOOR_STROKE_COLOR: &str = "#ff7f00";
+
+/// A curve item on the fog layer through `points` in map units, drawn on
+/// the scene image at `pixels_per_cell`.
+fn fog_curve(
+    id: String,
+    name: &str,
+    points: &[Vector2],
+    closed: bool,
+    color: &str,
+    pixels_per_cell: f64,
+) -> Value {
+    let points: Vec<Value> = points
+        .iter()
+        .map(|p| {
+            json!({
+                "x": units_to_cells(p.x) * pixels_per_cell,
+                "y": units_to_cells(p.y) * pixels_per_cell,
+            })
+        })
+        .collect();
+    json!({
+        "id": id,
+        "type": "CURVE",
+        "name": name,
+        "layer": "FOG",
+        "position": { "x": 0, "y": 0 },
+        "rotation": 0,
+        "scale": { "x": 1, "y": 1 },
+        "visible": true,
+        "locked": true,
+        "points": points,
+        "style": {
+            "strokeColor": color,
+            "strokeOpacity": 1,
+            "strokeWidth": 4,
+            "strokeDash": [],
+            "fillColor": color,
+            "fillOpacity": 0,
+            "tension": 0,
+            "closed": closed,
+        },
+        "metadata": {},
+    })
+}
+
+/// Converts the first level of a map to an Owlbear Rodeo scene over its
+/// image rendered at `pixels_per_cell`, its walls and doors as curves on the
+/// fog layer, marked for the Dynamic Fog extension to block sight with.
+pub fn to_owlbear_scene(map: &Value, name: &str, pixels_per_cell: f64) -> Value {
+    let world = &map["world"];
+    let level = &world["levels"]["0"];
+    let mut items = Vec::new();
+    for (index, wall) in nodes(level, "walls").enumerate() {
+        let Some(points) = node_points(wall) else {
+            continue;
+        };
+        let id = node_id(wall).unwrap_or_else(|| index.to_string());
+        let mut curve = fog_curve(
+            format!("wall-{}", id),
+            "Wall",
+            &points,
+            is_loop(wall),
+            WALL_STROKE_COLOR,
+            pixels_per_cell,
+        );
+        curve["metadata"][WALL_METADATA] = json!({});
+        items.push(curve);
+    }
+    let portals = nodes(level, "portals")
+        .chain(nodes(level, "walls").flat_map(|wall| nodes(wall, "portals")));
+    for (index, portal) in portals.enumerate() {
+        let Some((from, to)) = portal_span(portal) else {
+            continue;
+        };
+        let id = node_id(portal).unwrap_or_else(|| index.to_string());
+        let closed = portal
+            .get("closed")
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        let mut curve = fog_curve(
+            format!("door-{}", id),
+            "Door",
+            &[from, to],
+            false,
+            DOOR_STROKE_COLOR,
+            pixels_per_cell,
+        );
+        curve["metadata"][DOOR_METADATA] = json!({ "open": !closed });
+        items.push(curve);
+    }
+    let cells = |dimension: &str| world[dimension].as_f64().unwrap_or_default();
+    json!({
+        "name": name,
+        "grid": { "dpi": pixels_per_cell, "type": "SQUARE", "scale": "5ft" },
+        "background": {
+            "image": SCENE_IMAGE,
+            "width": cells("width") * pixels_per_cell,
+            "height": cells("height") * pixels_per_cell,
+        },
+        "items": items,
+    })
+}
+
+/// Renders a map and writes it as an Owlbear Rodeo scene in `output_dir`,
+/// the rendered image beside the scene's walls and doors.
+///
+/// Returns the path of the scene file.
+pub fn try_export_owlbear(
+    map_path: &Path,
+    output_dir: &Path,
+    pixels_per_cell: f64,
+    resolver: Option<&AssetResolver>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let map = read_map(map_path)?;
+    let image = render_map(&map, pixels_per_cell, resolver)?;
+    std::fs::create_dir_all(output_dir)?;
+    let image_path = output_dir.join(SCENE_IMAGE);
+    debug!(
+        "Writing {}x{} {}",
+        image.cols(),
+        image.rows(),
+        image_path.display()
+    );
+    imwrite(
+        image_path.to_str().ok_or("invalid output path")?,
+        &image,
+        &core::Vector::new(),
+    )?;
+    let name = map_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let scene_path = output_dir.join(SCENE_FILE);
+    let scene = to_owlbear_scene(&map, &name, pixels_per_cell);
+    std::fs::write(&scene_path, serde_json::to_string_pretty(&scene)?)?;
+    Ok(scene_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_owlbear_scene() {
+        let map = json!({
+            "world": {
+                "width": 4,
+                "height": 2,
+                "levels": {
+                    "0": {
+                        "walls": [
+                            {
+                                "node_id": "0",
+                                "loop": true,
+                                "points": "PoolVector2Array( 0, 0, 512, 0, 512, 512 )",
+                                "portals": [
+                                    { "node_id": "1", "wall_id": "0", "position": "Vector2( 256, 0 )", "rotation": 0, "radius": 128, "closed": false }
+                                ]
+                            }
+                        ]
+                    }
+                }
+            }
+        });
+        let scene = to_owlbear_scene(&map, "crypt", 100.0);
+        assert_eq!(scene["background"]["width"], 400.0);
+        let items = scene["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["id"], "wall-0");
+        assert_eq!(items[0]["style"]["closed"], true);
+        assert_eq!(items[0]["points"][1], json!({ "x": 200.0, "y": 0.0 }));
+        assert!(items[0]["metadata"].get(WALL_METADATA).is_some());
+        assert_eq!(
+            items[1]["points"],
+            json!([{ "x": 50.0, "y": 0.0 }, { "x": 150.0, "y": 0.0 }])
+        );
+        assert_eq!(items[1]["metadata"][DOOR_METADATA]["open"], true);
+    }
+}
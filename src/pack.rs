@@ -0,0 +1,248 @@
+use serde_json::Value;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// The file extension of packed Dungeondraft asset packs.
+pub const PACK_EXTENSION: &str = "dungeondraft_pack";
+/// The magic number Godot PCK files start with, `GDPC`.
+const PCK_MAGIC: u32 = 0x4350_4447;
+/// The PCK format version of Godot 3, which Dungeondraft runs on.
+const PCK_VERSION: u32 = 1;
+/// Unused words between the Godot version and the file count.
+const PCK_RESERVED: usize = 16;
+const PACKS_PREFIX: &str = "res://packs/";
+
+/// A file stored in a PCK archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackEntry {
+    /// Its `res://` reference
+    pub path: String,
+    offset: u64,
+    size: u64,
+}
+
+/// A Dungeondraft asset pack read from its `.dungeondraft_pack` file, a
+/// Godot PCK archive of the pack's textures and data.
+#[derive(Debug, Clone)]
+pub struct AssetPack {
+    pub id: String,
+    pub name: String,
+    pub author: String,
+    pub version: String,
+    pub path: PathBuf,
+    entries: Vec<PackEntry>,
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads the header and file index of a PCK archive.
+fn read_index(reader: &mut impl Read) -> Result<Vec<PackEntry>, Box<dyn std::error::Error>> {
+    if read_u32(reader)? != PCK_MAGIC {
+        return Err("not a Godot PCK file".into());
+    }
+    let version = read_u32(reader)?;
+    if version != PCK_VERSION {
+        return Err(format!("unsupported PCK version {}", version).into());
+    }
+    // The Godot version the archive was packed with, then reserved space
+    for _ in 0..3 + PCK_RESERVED {
+        read_u32(reader)?;
+    }
+    let count = read_u32(reader)?;
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut path = vec![0; read_u32(reader)? as usize];
+        reader.read_exact(&mut path)?;
+        // Paths are padded with zeros
+        let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+        let path = String::from_utf8_lossy(&path[..end]).into_owned();
+        let offset = read_u64(reader)?;
+        let size = read_u64(reader)?;
+        let mut md5 = [0; 16];
+        reader.read_exact(&mut md5)?;
+        entries.push(PackEntry { path, offset, size });
+    }
+    Ok(entries)
+}
+
+/// Whether a reference is the data file describing a pack, either
+/// `res://packs/ID.json` or a `pack.json` within the pack.
+fn is_pack_data(path: &str) -> bool {
+    match path.strip_prefix(PACKS_PREFIX) {
+        Some(rest) => {
+            (!rest.contains('/') && rest.ends_with(".json")) || rest.ends_with("/pack.json")
+        }
+        None => path == "res://pack.json",
+    }
+}
+
+impl AssetPack {
+    /// Reads the index and the id, name, author, and version of a pack.
+    pub fn read(path: &Path) -> Result<AssetPack, Box<dyn std::error::Error>> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let entries = read_index(&mut file)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let mut pack = AssetPack {
+            id: String::new(),
+            name: String::new(),
+            author: String::new(),
+            version: String::new(),
+            path: path.to_path_buf(),
+            entries,
+        };
+        let data = pack
+            .entries
+            .iter()
+            .find(|entry| is_pack_data(&entry.path))
+            .cloned()
+            .ok_or_else(|| format!("{} has no pack data", path.display()))?;
+        let data: Value = serde_json::from_slice(&pack.read_entry(&data)?)?;
+        let field = |name: &str| data[name].as_str().unwrap_or_default().to_string();
+        pack.id = field("id");
+        pack.name = field("name");
+        pack.author = field("author");
+        pack.version = field("version");
+        if pack.id.is_empty() {
+            return Err(format!("{} has no pack id", path.display()).into());
+        }
+        debug!(
+            "Read asset pack {} ({}) of {} files",
+            pack.name,
+            pack.id,
+            pack.entries.len()
+        );
+        Ok(pack)
+    }
+
+    fn read_entry(&self, entry: &PackEntry) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut file = std::fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut contents = vec![0; entry.size as usize];
+        file.read_exact(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// The references of every file in the pack.
+    pub fn references(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|entry| entry.path.as_str())
+    }
+
+    pub fn contains(&self, reference: &str) -> bool {
+        self.entries.iter().any(|entry| entry.path == reference)
+    }
+
+    /// Reads a file of the pack by its reference.
+    pub fn read_file(&self, reference: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.path == reference)
+            .ok_or_else(|| format!("{} is not in {}", reference, self.path.display()))?;
+        self.read_entry(entry)
+    }
+}
+
+/// A readable name for an asset from its file name, such as `Armchair Red`
+/// for `res://packs/TAVERN01/textures/objects/Armchair_Red.png`.
+pub fn asset_name(reference: &str) -> String {
+    let file = reference.rsplit('/').next().unwrap_or(reference);
+    let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+    stem.split(['_', '-'])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PCK archive of the files given.
+    fn pck(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let index_size: usize = files
+            .iter()
+            .map(|(path, _)| 4 + path.len().next_multiple_of(4) + 8 + 8 + 16)
+            .sum();
+        let mut offset = (4 * (6 + PCK_RESERVED) + index_size) as u64;
+        let mut contents = Vec::new();
+        for (path, data) in files {
+            let mut padded = path.as_bytes().to_vec();
+            padded.resize(path.len().next_multiple_of(4), 0);
+            index.extend((padded.len() as u32).to_le_bytes());
+            index.extend(padded);
+            index.extend(offset.to_le_bytes());
+            index.extend((data.len() as u64).to_le_bytes());
+            index.extend([0; 16]);
+            offset += data.len() as u64;
+            contents.extend_from_slice(data);
+        }
+        let mut bytes = Vec::new();
+        for word in [PCK_MAGIC, PCK_VERSION, 3, 4, 2] {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend([0; 4 * PCK_RESERVED]);
+        bytes.extend((files.len() as u32).to_le_bytes());
+        bytes.extend(index);
+        bytes.extend(contents);
+        bytes
+    }
+
+    #[test]
+    fn test_read_pack() {
+        let path = std::env::temp_dir().join(format!(
+            "ddgen-pack-{}.{}",
+            std::process::id(),
+            PACK_EXTENSION
+        ));
+        let data = br#"{ "name": "Tavern", "id": "TAVERN01", "author": "Ink", "version": "1.2" }"#;
+        std::fs::write(
+            &path,
+            pck(&[
+                ("res://packs/TAVERN01.json", data),
+                (
+                    "res://packs/TAVERN01/textures/objects/Armchair_Red.png",
+                    b"png",
+                ),
+            ]),
+        )
+        .unwrap();
+
+        let pack = AssetPack::read(&path).unwrap();
+        assert_eq!(pack.id, "TAVERN01");
+        assert_eq!(pack.name, "Tavern");
+        assert_eq!(pack.author, "Ink");
+        assert_eq!(pack.references().count(), 2);
+        let chair = "res://packs/TAVERN01/textures/objects/Armchair_Red.png";
+        assert!(pack.contains(chair));
+        assert_eq!(pack.read_file(chair).unwrap(), b"png");
+        assert!(pack.read_file("res://packs/TAVERN01/missing.png").is_err());
+
+        std::fs::write(&path, b"not a pack").unwrap();
+        assert!(AssetPack::read(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_asset_name() {
+        assert_eq!(
+            asset_name("res://packs/TAVERN01/textures/objects/Armchair_Red.png"),
+            "Armchair Red"
+        );
+        assert_eq!(
+            asset_name("res://textures/walls/stone-wall.png"),
+            "stone wall"
+        );
+    }
+}
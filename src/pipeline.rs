@@ -0,0 +1,293 @@
+use std::fs;
+use std::path::Path;
+
+use opencv::core::{self, Mat, Size};
+use opencv::imgproc;
+use serde::Deserialize;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+use crate::geometry::orthogonalize;
+use crate::images::trace_outlines;
+
+/// A step of a pipeline, processing the image until `contours` traces it and
+/// the outlines after that.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Stage {
+    /// Smooths away noise with a Gaussian blur
+    Blur {
+        #[serde(default = "Stage::default_kernel")]
+        kernel: i32,
+    },
+    /// Keeps the pixels darker than `value`, or lighter when not `invert`ed
+    Threshold {
+        #[serde(default = "Stage::default_threshold")]
+        value: f64,
+        #[serde(default = "Stage::default_invert")]
+        invert: bool,
+    },
+    /// Finds edges between the `low` and `high` gradient thresholds
+    Canny {
+        #[serde(default = "Stage::default_low")]
+        low: f64,
+        #[serde(default = "Stage::default_high")]
+        high: f64,
+    },
+    /// Grows the white pixels, closing gaps in the linework
+    Dilate {
+        #[serde(default = "Stage::default_kernel")]
+        kernel: i32,
+        #[serde(default = "Stage::default_iterations")]
+        iterations: i32,
+    },
+    /// Traces outlines of at least `min_area` pixels, simplified to within
+    /// `epsilon` times their perimeter
+    Contours {
+        #[serde(default = "Stage::default_min_area")]
+        min_area: f64,
+        #[serde(default = "Stage::default_epsilon")]
+        epsilon: f64,
+    },
+    /// Straightens edges within `tolerance` degrees of horizontal or vertical
+    Orthogonalize {
+        #[serde(default = "Stage::default_tolerance")]
+        tolerance: f64,
+    },
+    /// Emits the outlines as walled rooms
+    Walls,
+}
+
+impl Stage {
+    fn default_kernel() -> i32 {
+        5
+    }
+
+    fn default_threshold() -> f64 {
+        128.0
+    }
+
+    fn default_invert() -> bool {
+        true
+    }
+
+    fn default_low() -> f64 {
+        50.0
+    }
+
+    fn default_high() -> f64 {
+        150.0
+    }
+
+    fn default_iterations() -> i32 {
+        1
+    }
+
+    fn default_min_area() -> f64 {
+        100.0
+    }
+
+    fn default_epsilon() -> f64 {
+        0.04
+    }
+
+    fn default_tolerance() -> f64 {
+        10.0
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Blur { .. } => "blur",
+            Stage::Threshold { .. } => "threshold",
+            Stage::Canny { .. } => "canny",
+            Stage::Dilate { .. } => "dilate",
+            Stage::Contours { .. } => "contours",
+            Stage::Orthogonalize { .. } => "orthogonalize",
+            Stage::Walls => "walls",
+        }
+    }
+
+    /// Whether the stage works on the image rather than traced outlines.
+    fn is_image_stage(&self) -> bool {
+        matches!(
+            self,
+            Stage::Blur { .. }
+                | Stage::Threshold { .. }
+                | Stage::Canny { .. }
+                | Stage::Dilate { .. }
+        )
+    }
+}
+
+/// The ordered stages that turn a dungeon image into walls, read from a YAML
+/// file in place of the built in shape detection.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Parses a pipeline, checking its stages are in an order that can run.
+    pub fn parse(yaml: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let pipeline: Pipeline = serde_yaml::from_str(yaml)?;
+        let traced = pipeline
+            .stages
+            .iter()
+            .position(|stage| matches!(stage, Stage::Contours { .. }))
+            .ok_or("pipeline has no contours stage")?;
+        if pipeline.stages.last() != Some(&Stage::Walls) {
+            return Err("pipeline does not end with a walls stage".into());
+        }
+        for (index, stage) in pipeline.stages.iter().enumerate() {
+            let misplaced = match stage {
+                Stage::Contours { .. } => index != traced,
+                Stage::Walls => index != pipeline.stages.len() - 1,
+                stage if stage.is_image_stage() => index > traced,
+                _ => index < traced,
+            };
+            if misplaced {
+                return Err(
+                    format!("stage {} ({}) is out of order", index + 1, stage.name()).into(),
+                );
+            }
+        }
+        Ok(pipeline)
+    }
+
+    /// Runs the stages on an image, returning the outlines of the rooms in map
+    /// units.
+    #[instrument(skip_all)]
+    pub fn run(
+        &self,
+        image: &Mat,
+        mapper: &CoordinateMapper,
+    ) -> Result<Vec<Vec<Vector2>>, Box<dyn std::error::Error>> {
+        let mut processed = Mat::default();
+        imgproc::cvt_color(image, &mut processed, imgproc::COLOR_BGR2GRAY, 0)?;
+        let mut outlines = Vec::new();
+        for stage in &self.stages {
+            debug!("Running pipeline stage {}", stage.name());
+            let mut output = Mat::default();
+            match *stage {
+                Stage::Blur { kernel } => {
+                    // Odd kernels keep the blur centered on each pixel
+                    let size = kernel.max(1) | 1;
+                    imgproc::gaussian_blur(
+                        &processed,
+                        &mut output,
+                        Size::new(size, size),
+                        0.0,
+                        0.0,
+                        core::BORDER_DEFAULT,
+                    )?;
+                }
+                Stage::Threshold { value, invert } => {
+                    let kind = if invert {
+                        imgproc::THRESH_BINARY_INV
+                    } else {
+                        imgproc::THRESH_BINARY
+                    };
+                    imgproc::threshold(&processed, &mut output, value, 255.0, kind)?;
+                }
+                Stage::Canny { low, high } => {
+                    imgproc::canny(&processed, &mut output, low, high, 3, false)?;
+                }
+                Stage::Dilate { kernel, iterations } => {
+                    let size = kernel.max(1);
+                    let kernel = imgproc::get_structuring_element(
+                        imgproc::MORPH_RECT,
+                        Size::new(size, size),
+                        core::Point::new(-1, -1),
+                    )?;
+                    imgproc::dilate(
+                        &processed,
+                        &mut output,
+                        &kernel,
+                        core::Point::new(-1, -1),
+                        iterations,
+                        core::BORDER_CONSTANT,
+                        imgproc::morphology_default_border_value()?,
+                    )?;
+                }
+                Stage::Contours { min_area, epsilon } => {
+                    outlines = trace_outlines(
+                        &processed,
+                        imgproc::RETR_EXTERNAL,
+                        min_area,
+                        epsilon,
+                        mapper,
+                    )?;
+                    continue;
+                }
+                Stage::Orthogonalize { tolerance } => {
+                    outlines = outlines
+                        .iter()
+                        .map(|outline| orthogonalize(outline, tolerance))
+                        .collect();
+                    continue;
+                }
+                Stage::Walls => break,
+            }
+            processed = output;
+        }
+        outlines.retain(|outline| outline.len() > 2);
+        Ok(outlines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pipeline() {
+        let pipeline = Pipeline::parse(
+            r#"
+stages:
+  - stage: blur
+    kernel: 3
+  - stage: canny
+    high: 200
+  - stage: contours
+  - stage: orthogonalize
+  - stage: walls
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            pipeline.stages,
+            vec![
+                Stage::Blur { kernel: 3 },
+                Stage::Canny {
+                    low: 50.0,
+                    high: 200.0
+                },
+                Stage::Contours {
+                    min_area: 100.0,
+                    epsilon: 0.04
+                },
+                Stage::Orthogonalize { tolerance: 10.0 },
+                Stage::Walls,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_pipeline() {
+        let parse = |stages: &str| Pipeline::parse(&format!("stages: [{}]", stages));
+        assert!(parse("{ stage: canny }, { stage: contours }, { stage: walls }").is_ok());
+        assert!(parse("{ stage: canny }, { stage: walls }").is_err());
+        assert!(parse("{ stage: contours }").is_err());
+        assert!(parse("{ stage: contours }, { stage: blur }, { stage: walls }").is_err());
+        assert!(parse("{ stage: orthogonalize }, { stage: contours }, { stage: walls }").is_err());
+        assert!(parse("{ stage: contours, kernel: 3 }, { stage: walls }").is_err());
+        assert!(parse("{ stage: sharpen }, { stage: contours }, { stage: walls }").is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use tracing::{debug, info};
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// The version of the host API plugins are built against, exported by every
+/// plugin as `dd_api_version`.
+pub const API_VERSION: i32 = 1;
+/// Instructions a plugin may run per call, so a runaway plugin cannot hang
+/// generation.
+const PLUGIN_FUEL: u64 = 1_000_000_000;
+/// Bytes of memory a plugin may grow to, so a runaway plugin cannot exhaust
+/// the host's.
+const PLUGIN_MEMORY: usize = 256 << 20;
+/// The export a plugin allocates `len` bytes of its memory with.
+const ALLOC_EXPORT: &str = "dd_alloc";
+/// The export given the detected shapes of a dungeon as a JSON array.
+const SHAPES_EXPORT: &str = "dd_shapes";
+/// The export given the generated map as JSON.
+const MAP_EXPORT: &str = "dd_map";
+
+/// A WebAssembly module of stages run on detected shapes and generated maps.
+///
+/// Plugins are given no imports, so they can reach nothing but the JSON passed
+/// to them, and each call is limited to `PLUGIN_FUEL` instructions and
+/// `PLUGIN_MEMORY` bytes of memory. Besides `memory`,
+/// `dd_api_version`, and `dd_alloc(len) -> ptr`, a plugin exports either or
+/// both of `dd_shapes` and `dd_map`, taking `(ptr, len)` of the UTF-8 JSON
+/// input and returning the JSON output as `ptr << 32 | len`.
+pub struct Plugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("name", &self.name).finish()
+    }
+}
+
+impl PartialEq for Plugin {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        Self::compile(name, &fs::read(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Compiles a plugin from WebAssembly binary or text, checking it is
+    /// built for this host API.
+    pub fn compile(name: &str, wasm: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+        let plugin = Self {
+            name: name.to_string(),
+            engine,
+            module,
+            fuel: PLUGIN_FUEL,
+        };
+        let mut store = plugin.store()?;
+        let instance =
+            Linker::new(&plugin.engine).instantiate_and_start(&mut store, &plugin.module)?;
+        let version = instance
+            .get_typed_func::<(), i32>(&store, "dd_api_version")?
+            .call(&mut store, ())?;
+        if version != API_VERSION {
+            return Err(format!(
+                "plugin {} is built for API version {}, expected {}",
+                name, version, API_VERSION
+            )
+            .into());
+        }
+        if !plugin.exports(SHAPES_EXPORT) && !plugin.exports(MAP_EXPORT) {
+            return Err(format!(
+                "plugin {} exports neither {} nor {}",
+                name, SHAPES_EXPORT, MAP_EXPORT
+            )
+            .into());
+        }
+        Ok(plugin)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn store(&self) -> Result<Store<StoreLimits>, Box<dyn std::error::Error>> {
+        let limits = StoreLimitsBuilder::new().memory_size(PLUGIN_MEMORY).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+        Ok(store)
+    }
+
+    fn exports(&self, name: &str) -> bool {
+        self.module.exports().any(|export| export.name() == name)
+    }
+
+    /// Calls an export with JSON, in a fresh instance so no state is kept
+    /// between calls.
+    fn call(&self, export: &str, input: &Value) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut store = self.store()?;
+        let instance = Linker::new(&self.engine).instantiate_and_start(&mut store, &self.module)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| format!("plugin {} exports no memory", self.name))?;
+        let input = serde_json::to_vec(input)?;
+        let len = i32::try_from(input.len())?;
+        let ptr = instance
+            .get_typed_func::<i32, i32>(&store, ALLOC_EXPORT)?
+            .call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, &input)?;
+        let packed = instance
+            .get_typed_func::<(i32, i32), i64>(&store, export)?
+            .call(&mut store, (ptr, len))?;
+        let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+        let end = ptr.checked_add(len);
+        if end.filter(|&end| end <= memory.data_size(&store)).is_none() {
+            return Err(format!("plugin {} returned output outside its memory", self.name).into());
+        }
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output)?;
+        serde_json::from_slice(&output)
+            .map_err(|e| format!("plugin {} returned invalid JSON: {}", self.name, e).into())
+    }
+
+    /// Runs the plugin on detected shapes, which it may filter, change,
+    /// reclassify, or add to.
+    pub fn shapes(&self, shapes: Vec<Value>) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        if !self.exports(SHAPES_EXPORT) {
+            return Ok(shapes);
+        }
+        debug!("Running plugin {} on {} shapes", self.name, shapes.len());
+        match self.call(SHAPES_EXPORT, &Value::Array(shapes))? {
+            Value::Array(shapes) => Ok(shapes),
+            _ => Err(format!("plugin {} returned shapes that are not an array", self.name).into()),
+        }
+    }
+
+    /// Runs the plugin on a generated map, returning the map that replaces it.
+    pub fn map(&self, map: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.exports(MAP_EXPORT) {
+            return Ok(());
+        }
+        debug!("Running plugin {} on the map", self.name);
+        match self.call(MAP_EXPORT, map)? {
+            processed @ Value::Object(_) => {
+                *map = processed;
+                Ok(())
+            }
+            _ => Err(format!("plugin {} returned a map that is not an object", self.name).into()),
+        }
+    }
+}
+
+/// The default directory plugins are discovered in, beside the config file.
+pub fn default_plugins_dir(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("plugins")
+}
+
+/// Loads every `.wasm` plugin of a directory, run in order of their names.
+/// A missing directory has no plugins.
+pub fn discover_plugins(dir: &Path) -> Result<Vec<Plugin>, Box<dyn std::error::Error>> {
+    if !dir.is_dir() {
+        debug!("No plugins directory at {}", dir.display());
+        return Ok(Vec::new());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+        .collect();
+    paths.sort();
+    let plugins = paths
+        .iter()
+        .map(|path| Plugin::load(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    for plugin in &plugins {
+        info!("Loaded plugin {}", plugin.name());
+    }
+    Ok(plugins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Echoes its input back, always written at the same place in its memory.
+    const ECHO: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "dd_api_version") (result i32) i32.const 1)
+            (func (export "dd_alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "dd_shapes") (param i32 i32) (result i64)
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32))
+                    (i64.extend_i32_u (local.get 1)))))
+    "#;
+
+    #[test]
+    fn test_shapes_plugin() {
+        let plugin = Plugin::compile("echo", ECHO.as_bytes()).unwrap();
+        let shapes = vec![json!({ "id": 0, "class": "rectangle" })];
+        assert_eq!(plugin.shapes(shapes.clone()).unwrap(), shapes);
+        // Without a map stage the map is left as it is
+        let mut map = json!({ "header": {} });
+        plugin.map(&mut map).unwrap();
+        assert_eq!(map, json!({ "header": {} }));
+    }
+
+    #[test]
+    fn test_map_plugin() {
+        let plugin = Plugin::compile(
+            "clear",
+            br#"
+            (module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{\"cleared\":true}")
+                (func (export "dd_api_version") (result i32) i32.const 1)
+                (func (export "dd_alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "dd_map") (param i32 i32) (result i64) i64.const 16))
+            "#,
+        )
+        .unwrap();
+        let mut map = json!({ "header": {} });
+        plugin.map(&mut map).unwrap();
+        assert_eq!(map, json!({ "cleared": true }));
+    }
+
+    #[test]
+    fn test_rejected_plugins() {
+        // Built for another version of the host API
+        assert!(Plugin::compile(
+            "old",
+            ECHO.replace("i32.const 1)", "i32.const 0)").as_bytes()
+        )
+        .is_err());
+        // Asking the host for anything is not allowed
+        let importing = ECHO.replace(
+            "(memory",
+            r#"(import "env" "open" (func (param i32))) (memory"#,
+        );
+        assert!(Plugin::compile("importing", importing.as_bytes()).is_err());
+        // Runs out of fuel rather than hanging
+        let looping = ECHO.replace("(i64.or", "(loop (br 0)) (i64.or");
+        let mut plugin = Plugin::compile("looping", looping.as_bytes()).unwrap();
+        plugin.fuel = 100_000;
+        assert!(plugin.shapes(vec![json!({})]).is_err());
+        // Growing past the memory allowed
+        let hungry = ECHO.replace(
+            r#"(memory (export "memory") 1)"#,
+            r#"(memory (export "memory") 8192)"#,
+        );
+        assert!(Plugin::compile("hungry", hungry.as_bytes()).is_err());
+        // Returning more output than its memory holds
+        let overflowing = ECHO.replace("(local.get 1)))", "(i32.const 0x7fffffff)))");
+        let plugin = Plugin::compile("overflowing", overflowing.as_bytes()).unwrap();
+        assert!(plugin.shapes(vec![json!({})]).is_err());
+    }
+
+    #[test]
+    fn test_discover_missing_plugins() {
+        assert!(discover_plugins(Path::new("no/such/plugins"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            default_plugins_dir(Path::new("config/fixme/default.json")),
+            Path::new("config/fixme/plugins")
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+use crate::coordinates::{cells_to_units, units_to_cells};
+use crate::dungeondraft_v1::{
+    is_loop, node_id, node_points, node_position, nodes, read_map, serialize_map, translate_level,
+    Vector2, UNITS_PER_CELL,
+};
+use crate::geometry::{boundary_distance, bounds, contains_point};
+
+/// The file extension of Dungeondraft prefabs.
+pub const PREFAB_EXTENSION: &str = "dungeondraft_prefab";
+/// The node collections of a level a prefab can hold, portals set in walls
+/// going along with their walls.
+const PREFAB_COLLECTIONS: &[&str] = &[
+    "walls", "portals", "patterns", "paths", "objects", "lights", "texts",
+];
+/// How far outside a room's walls a node may sit and still be part of it.
+const ROOM_TOLERANCE: f64 = UNITS_PER_CELL / 4.0;
+
+/// The part of a level to export as a prefab.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    /// The floor within a closed wall loop, by the wall's node id
+    Room(String),
+    /// The nodes within a rectangle, from its minimum to maximum corner in units
+    Region(Vector2, Vector2),
+}
+
+/// Parses an `x,y,width,height` region in cells.
+pub fn parse_region(s: &str) -> Result<Selection, String> {
+    let parts: Vec<f64> = s
+        .split(',')
+        .map(|part| part.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid region '{}', expected x,y,width,height", s))?;
+    match parts[..] {
+        [x, y, width, height] if width > 0.0 && height > 0.0 => Ok(Selection::Region(
+            Vector2 {
+                x: cells_to_units(x),
+                y: cells_to_units(y),
+            },
+            Vector2 {
+                x: cells_to_units(x + width),
+                y: cells_to_units(y + height),
+            },
+        )),
+        _ => Err(format!("invalid region '{}', expected x,y,width,height", s)),
+    }
+}
+
+/// Every point a node is placed at.
+fn placement(node: &Value) -> Vec<Vector2> {
+    node_points(node)
+        .or_else(|| node_position(node).map(|p| vec![p]))
+        .unwrap_or_default()
+}
+
+/// A selection resolved against a level: the points it holds, and the grid
+/// corner the prefab starts at.
+struct Area {
+    contains: Box<dyn Fn(Vector2) -> bool>,
+    origin: Vector2,
+    end: Vector2,
+}
+
+fn resolve(level: &Value, selection: &Selection) -> Result<Area, String> {
+    let snap = |p: Vector2, round: fn(f64) -> f64| Vector2 {
+        x: cells_to_units(round(units_to_cells(p.x))),
+        y: cells_to_units(round(units_to_cells(p.y))),
+    };
+    match selection {
+        Selection::Room(id) => {
+            let polygon = nodes(level, "walls")
+                .find(|wall| is_loop(wall) && node_id(wall).as_ref() == Some(id))
+                .and_then(node_points)
+                .filter(|points| points.len() > 2)
+                .ok_or(format!("no room with wall id {}", id))?;
+            let (min, max) = bounds(&polygon).unwrap_or_default();
+            Ok(Area {
+                origin: snap(min, f64::floor),
+                end: snap(max, f64::ceil),
+                contains: Box::new(move |p| {
+                    contains_point(&polygon, p) || boundary_distance(&polygon, p) <= ROOM_TOLERANCE
+                }),
+            })
+        }
+        Selection::Region(min, max) => {
+            let (min, max) = (*min, *max);
+            Ok(Area {
+                origin: min,
+                end: max,
+                contains: Box::new(move |p| {
+                    (min.x..=max.x).contains(&p.x) && (min.y..=max.y).contains(&p.y)
+                }),
+            })
+        }
+    }
+}
+
+/// Takes the walls, portals, objects, and other nodes of a level within a
+/// selection as a prefab named `name`, moved so the selection starts at the
+/// origin. A node is taken only if it lies wholly within the selection.
+pub fn extract_prefab(
+    map: &Value,
+    level: &str,
+    selection: &Selection,
+    name: &str,
+) -> Result<Value, String> {
+    let level = map["world"]["levels"]
+        .get(level)
+        .ok_or(format!("map has no level {}", level))?;
+    let area = resolve(level, selection)?;
+    let mut prefab = Map::new();
+    let mut count = 0;
+    for collection in PREFAB_COLLECTIONS {
+        let taken: Vec<Value> = nodes(level, collection)
+            .filter(|node| {
+                let points = placement(node);
+                !points.is_empty() && points.iter().all(|p| (area.contains)(*p))
+            })
+            .cloned()
+            .collect();
+        count += taken.len();
+        prefab.insert(collection.to_string(), taken.into());
+    }
+    if count == 0 {
+        return Err(format!("nothing to export in {:?}", selection));
+    }
+    let mut prefab = Value::Object(prefab);
+    translate_level(
+        &mut prefab,
+        Vector2 {
+            x: -area.origin.x,
+            y: -area.origin.y,
+        },
+    );
+    prefab["width"] = (units_to_cells(area.end.x - area.origin.x).ceil() as u64).into();
+    prefab["height"] = (units_to_cells(area.end.y - area.origin.y).ceil() as u64).into();
+    Ok(json!({
+        "header": {
+            "name": name,
+            "creation_build": map["header"]["creation_build"],
+        },
+        "prefab": prefab,
+    }))
+}
+
+/// Exports the selection of a level of a map as a prefab file at `output`,
+/// named after it, returning the number of nodes exported.
+pub fn try_export_prefab(
+    map_path: &Path,
+    output: &Path,
+    level: &str,
+    selection: &Selection,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let map = read_map(map_path)?;
+    let name = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let prefab = extract_prefab(&map, level, selection, &name)?;
+    let count = PREFAB_COLLECTIONS
+        .iter()
+        .map(|collection| nodes(&prefab["prefab"], collection).count())
+        .sum();
+    let file = std::fs::File::create(output)?;
+    serialize_map(std::io::BufWriter::new(file), &prefab)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> Value {
+        json!({
+            "header": { "creation_build": "1.1.0.3 fluffy phoenix" },
+            "world": { "levels": { "0": {
+                "walls": [
+                    { "node_id": "1", "loop": true, "points": "PoolVector2Array( 512, 512, 1024, 512, 1024, 1024, 512, 1024 )", "portals": [
+                        { "node_id": "3", "wall_id": "1", "position": "Vector2( 768, 512 )" }
+                    ] },
+                    { "node_id": "2", "loop": true, "points": "PoolVector2Array( 1024, 512, 1536, 512, 1536, 1024, 1024, 1024 )", "portals": [] }
+                ],
+                "objects": [
+                    { "node_id": "4", "position": "Vector2( 640, 640 )" },
+                    { "node_id": "5", "position": "Vector2( 1280, 640 )" }
+                ]
+            } } }
+        })
+    }
+
+    #[test]
+    fn test_extract_room() {
+        let prefab =
+            extract_prefab(&map(), "0", &Selection::Room("1".to_string()), "cell").unwrap();
+        assert_eq!(prefab["header"]["name"], "cell");
+        let prefab = &prefab["prefab"];
+        assert_eq!(
+            (prefab["width"].as_u64(), prefab["height"].as_u64()),
+            (Some(2), Some(2))
+        );
+        let walls = prefab["walls"].as_array().unwrap();
+        assert_eq!(walls.len(), 1);
+        assert_eq!(
+            walls[0]["points"],
+            "PoolVector2Array( 0, 0, 512, 0, 512, 512, 0, 512 )"
+        );
+        assert_eq!(walls[0]["portals"][0]["position"], "Vector2( 256, 0 )");
+        assert_eq!(
+            prefab["objects"],
+            json!([{ "node_id": "4", "position": "Vector2( 128, 128 )" }])
+        );
+
+        assert!(extract_prefab(&map(), "0", &Selection::Room("4".to_string()), "cell").is_err());
+        assert!(extract_prefab(&map(), "1", &Selection::Room("1".to_string()), "cell").is_err());
+    }
+
+    #[test]
+    fn test_extract_region() {
+        let region = parse_region("2,2,4,2").unwrap();
+        let prefab = extract_prefab(&map(), "0", &region, "cells").unwrap();
+        let prefab = &prefab["prefab"];
+        assert_eq!(prefab["walls"].as_array().unwrap().len(), 2);
+        assert_eq!(prefab["objects"].as_array().unwrap().len(), 2);
+        assert_eq!(prefab["width"].as_u64(), Some(4));
+
+        assert!(parse_region("2,2,0,2").is_err());
+        assert!(parse_region("2,2").is_err());
+        assert!(extract_prefab(&map(), "0", &parse_region("20,20,1,1").unwrap(), "empty").is_err());
+    }
+}
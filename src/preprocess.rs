@@ -0,0 +1,996 @@
+use std::f64::consts::TAU;
+use std::str::FromStr;
+
+use opencv::core::{self, Mat, Scalar, Size};
+use opencv::imgproc;
+use opencv::photo;
+use opencv::prelude::*;
+use opencv::types::VectorOfMat;
+use tracing::{debug, warn};
+
+use crate::coordinates::{Isometric, Rotation};
+use crate::dungeondraft_v1::Vector2;
+
+/// Ink darker than this is drawn linework rather than floor.
+pub const INK_THRESHOLD: f64 = 128.0;
+/// Room numbers, letters, and markers fit within this share of a cell.
+const MAX_SYMBOL_SIZE: f64 = 0.5;
+/// How far around removed pixels inpainting samples the surrounding floor.
+const INPAINT_RADIUS: f64 = 3.0;
+/// The paper's brightness is averaged over a window this many times the
+/// feature size wide.
+const ILLUMINATION_WINDOW: i32 = 4;
+/// Pixels with less than this share of paper around them are lit as the paper
+/// is on average.
+const MIN_PAPER_WEIGHT: f64 = 0.01;
+/// Rows or columns with ink across this share of the image are grid lines.
+const GRID_COVERAGE: f64 = 0.6;
+/// How far grid lines may stray from even spacing, relative to the spacing.
+const GRID_SPACING_TOLERANCE: f64 = 0.2;
+/// The finest angle, in degrees, grid lines are told apart by.
+const GRID_ANGLE_RESOLUTION: f64 = 0.25;
+/// Isometric grid lines cross this share of the image, less than a square
+/// grid's lines since they run corner to corner.
+const ISOMETRIC_COVERAGE: f64 = 0.3;
+/// The angle of isometric grid lines when none are found, each cell twice as
+/// wide as it is high.
+const ISOMETRIC_DEGREES: f64 = 26.565_051_177_077_99;
+/// Lines within this many degrees of horizontal or vertical are not part of
+/// an isometric grid.
+const ISOMETRIC_MIN_DEGREES: f64 = 15.0;
+/// Lines within this many degrees of the grid's angle are grid lines.
+const ISOMETRIC_TOLERANCE: f64 = 2.0;
+/// A scale bar is at least this many times as wide as it is high.
+const SCALE_BAR_ASPECT: i32 = 5;
+/// A scale bar is no higher than this share of the image.
+const SCALE_BAR_MAX_HEIGHT: f64 = 0.05;
+/// A scale bar is divided into at least this many graduations.
+const MIN_GRADUATIONS: usize = 3;
+/// How far the graduations of a scale bar may stray from their mean length,
+/// relative to it.
+const GRADUATION_TOLERANCE: f64 = 0.2;
+
+/// A mask of the dark linework of an image.
+pub fn ink_mask(image: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut gray = Mat::default();
+    imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    let mut ink = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut ink,
+        INK_THRESHOLD,
+        255.0,
+        imgproc::THRESH_BINARY_INV,
+    )?;
+    Ok(ink)
+}
+
+/// Evens out shading and gradients across a photographed page.
+///
+/// The paper's brightness is estimated from the paper alone: the dark fills
+/// and linework are masked out, and the light pixels left are averaged over a
+/// window a few times wider than `feature_size`. Every pixel is divided by
+/// that estimate so the page becomes uniformly white, while walls and rock
+/// filled solid black stay as dark as the paper around them is light.
+pub fn normalize_illumination(
+    image: &Mat,
+    feature_size: i32,
+) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut gray = Mat::default();
+    if image.channels() == 1 {
+        gray = image.try_clone()?;
+    } else {
+        imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    }
+    // Otsu's threshold splits the paper from the ink however the page is lit,
+    // and shrinking the paper leaves out the blurred edges of the ink
+    let mut paper = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut paper,
+        0.0,
+        255.0,
+        imgproc::THRESH_BINARY | imgproc::THRESH_OTSU,
+    )?;
+    let mut shrunk = Mat::default();
+    imgproc::erode(
+        &paper,
+        &mut shrunk,
+        &Mat::default(),
+        core::Point::new(-1, -1),
+        1,
+        core::BORDER_REPLICATE,
+        imgproc::morphology_default_border_value()?,
+    )?;
+    let paper = shrunk;
+
+    let mut pixels = Mat::default();
+    image.convert_to(&mut pixels, core::CV_32F, 1.0, 0.0)?;
+    let mut masked = Mat::new_rows_cols_with_default(
+        image.rows(),
+        image.cols(),
+        pixels.typ(),
+        Scalar::all(0.0),
+    )?;
+    pixels.copy_to_masked(&mut masked, &paper)?;
+    let mut weights = Mat::default();
+    paper.convert_to(&mut weights, core::CV_32F, 1.0 / 255.0, 0.0)?;
+
+    // Odd kernels keep the estimate centered on each pixel
+    let size = (ILLUMINATION_WINDOW * feature_size.max(1)) | 1;
+    let blur = |source: &Mat| -> Result<Mat, Box<dyn std::error::Error>> {
+        let mut blurred = Mat::default();
+        imgproc::blur(
+            source,
+            &mut blurred,
+            Size::new(size, size),
+            core::Point::new(-1, -1),
+            core::BORDER_REPLICATE,
+        )?;
+        Ok(blurred)
+    };
+    let (sums, weights) = (blur(&masked)?, blur(&weights)?);
+    let mut spread = VectorOfMat::new();
+    for _ in 0..image.channels() {
+        spread.push(weights.try_clone()?);
+    }
+    let mut weights_per_channel = Mat::default();
+    core::merge(&spread, &mut weights_per_channel)?;
+    let mut background = Mat::default();
+    core::divide2(&sums, &weights_per_channel, &mut background, 1.0, -1)?;
+    // Where no paper is near, as deep inside a large dark fill, the paper is
+    // taken to be as bright as it is on average
+    let mut unseen = Mat::default();
+    core::compare(
+        &weights,
+        &Scalar::all(MIN_PAPER_WEIGHT),
+        &mut unseen,
+        core::CMP_LT,
+    )?;
+    let average = core::mean(image, &paper)?;
+    background.set_to(&average, &unseen)?;
+
+    let mut normalized = Mat::default();
+    core::divide2(&pixels, &background, &mut normalized, 255.0, -1)?;
+    let mut result = Mat::default();
+    normalized.convert_to(&mut result, image.typ(), 1.0, 0.0)?;
+    Ok(result)
+}
+
+/// The share of every row (`dim` 1) or column (`dim` 0) of a mask that is set.
+fn coverage(mask: &Mat, dim: i32) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
+    let mut sums = Mat::default();
+    core::reduce(mask, &mut sums, dim, core::REDUCE_SUM, core::CV_32S)?;
+    let length = if dim == 0 { mask.rows() } else { mask.cols() }.max(1) as f64;
+    (0..sums.total() as i32)
+        .map(|i| Ok(*sums.at::<i32>(i)? as f64 / 255.0 / length))
+        .collect()
+}
+
+/// The runs of rows or columns, as `start..end`, crossed by evenly spaced grid lines.
+///
+/// Returns nothing unless there are at least three lines, since a couple of
+/// long walls can cover as much of the image as a grid line.
+pub fn grid_lines(coverage: &[f64]) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (i, share) in coverage.iter().chain([&0.0]).enumerate() {
+        match (start, *share >= GRID_COVERAGE) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                runs.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if runs.len() < 3 {
+        return Vec::new();
+    }
+    let spacing = grid_spacing(&runs).unwrap_or_default();
+    let centers: Vec<f64> = runs.iter().map(|(s, e)| (s + e) as f64 / 2.0).collect();
+    if centers
+        .windows(2)
+        .any(|w| (w[1] - w[0] - spacing).abs() > GRID_SPACING_TOLERANCE * spacing)
+    {
+        return Vec::new();
+    }
+    runs
+}
+
+/// The median distance between the centers of neighboring grid lines, the
+/// size of a cell in pixels.
+pub fn grid_spacing(runs: &[(usize, usize)]) -> Option<f64> {
+    let centers: Vec<f64> = runs.iter().map(|(s, e)| (s + e) as f64 / 2.0).collect();
+    let mut gaps: Vec<f64> = centers.windows(2).map(|w| w[1] - w[0]).collect();
+    gaps.sort_by(f64::total_cmp);
+    gaps.get(gaps.len() / 2).copied()
+}
+
+/// The size of the cells of a grid drawn over an image, in pixels, from the
+/// spacing of its lines either way. Returns nothing when no grid is found.
+pub fn detect_grid_size(image: &Mat) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    let ink = ink_mask(image)?;
+    let mut spacings = Vec::new();
+    for dim in [0, 1] {
+        spacings.extend(grid_spacing(&grid_lines(&coverage(&ink, dim)?)));
+    }
+    if spacings.is_empty() {
+        return Ok(None);
+    }
+    let size = spacings.iter().sum::<f64>() / spacings.len() as f64;
+    debug!("Detected grid cells {} pixels across", size);
+    Ok(Some(size))
+}
+
+/// Where evenly spaced grid lines cross the image relative to multiples of the
+/// cell size, from `-pixels_per_cell / 2` to `pixels_per_cell / 2`.
+///
+/// Taking the circular mean of the line centers lets lines either side of a
+/// multiple, a pixel early or late, average out.
+pub fn grid_phase(runs: &[(usize, usize)], pixels_per_cell: f64) -> Option<f64> {
+    // Runs end after their last pixel, so the center of a 1 pixel line is its start
+    let centers: Vec<f64> = runs
+        .iter()
+        .map(|(start, end)| (start + end - 1) as f64 / 2.0)
+        .collect();
+    circular_phase(&centers, pixels_per_cell)
+}
+
+/// The circular mean of positions repeating every `period`, from `-period / 2`
+/// to `period / 2`.
+fn circular_phase(positions: &[f64], period: f64) -> Option<f64> {
+    if positions.is_empty() || period <= 0.0 {
+        return None;
+    }
+    let (sin, cos) = positions.iter().fold((0.0, 0.0), |(sin, cos), position| {
+        let angle = position / period * TAU;
+        (sin + angle.sin(), cos + angle.cos())
+    });
+    Some(f64::atan2(sin, cos) / TAU * period)
+}
+
+/// Where the grid drawn over an image starts, in pixels, so geometry can be
+/// shifted for walls drawn on grid lines to land on the map's grid lines.
+/// Axes without a grid are not shifted.
+pub fn detect_grid_offset(
+    image: &Mat,
+    pixels_per_cell: f64,
+) -> Result<Vector2, Box<dyn std::error::Error>> {
+    let ink = ink_mask(image)?;
+    let phase = |dim| -> Result<f64, Box<dyn std::error::Error>> {
+        Ok(grid_phase(&grid_lines(&coverage(&ink, dim)?), pixels_per_cell).unwrap_or(0.0))
+    };
+    // Vertical grid lines fill columns and horizontal ones rows
+    let offset = Vector2 {
+        x: phase(0)?,
+        y: phase(1)?,
+    };
+    debug!("Detected grid offset {}", offset);
+    Ok(offset)
+}
+
+/// How far grid lines are turned from horizontal and vertical, in degrees
+/// from -45 to 45, given the angles of lines drawn along them.
+///
+/// Lines either way of a grid are a quarter turn apart, so the angles are
+/// averaged as four times themselves around the circle.
+pub fn grid_angle(degrees: &[f64]) -> Option<f64> {
+    if degrees.is_empty() {
+        return None;
+    }
+    let (sin, cos) = degrees.iter().fold((0.0, 0.0), |(sin, cos), degrees| {
+        let angle = (degrees * 4.0).to_radians();
+        (sin + angle.sin(), cos + angle.cos())
+    });
+    Some(f64::atan2(sin, cos).to_degrees() / 4.0)
+}
+
+/// How far counter-clockwise an image must be turned to straighten the grid
+/// drawn over it, in degrees, or 0 without a grid.
+///
+/// Only straight lines spanning most of the image are long enough to be grid
+/// lines rather than walls or furniture.
+pub fn detect_grid_rotation(image: &Mat) -> Result<f64, Box<dyn std::error::Error>> {
+    let ink = ink_mask(image)?;
+    let mut lines = core::Vector::<core::Vec2f>::new();
+    let votes = (ink.cols().min(ink.rows()) as f64 * GRID_COVERAGE) as i32;
+    imgproc::hough_lines(
+        &ink,
+        &mut lines,
+        1.0,
+        GRID_ANGLE_RESOLUTION.to_radians(),
+        votes.max(1),
+        0.0,
+        0.0,
+        0.0,
+        std::f64::consts::PI,
+    )?;
+    // Lines are found by the angle of their normal, a quarter turn from the
+    // line itself, which is the same angle of the grid
+    let angles: Vec<f64> = lines
+        .iter()
+        .map(|line| (line[1] as f64).to_degrees())
+        .collect();
+    let degrees = match grid_angle(&angles) {
+        Some(degrees) if degrees.abs() >= GRID_ANGLE_RESOLUTION => degrees,
+        _ => 0.0,
+    };
+    debug!("Detected grid rotation of {} degrees", degrees);
+    Ok(degrees)
+}
+
+/// The angle of an isometric grid's lines from horizontal, in degrees, given
+/// the angles of the normals of lines drawn in the image.
+///
+/// Lines one way of the grid have normals the grid's angle short of vertical
+/// and the other way the same angle past it.
+pub fn isometric_angle(normals: &[f64]) -> Option<f64> {
+    let angles: Vec<f64> = normals
+        .iter()
+        .map(|normal| (normal - 90.0).abs())
+        .filter(|angle| (ISOMETRIC_MIN_DEGREES..=90.0 - ISOMETRIC_MIN_DEGREES).contains(angle))
+        .collect();
+    if angles.is_empty() {
+        return None;
+    }
+    Some(angles.iter().sum::<f64>() / angles.len() as f64)
+}
+
+/// The diamond grid of an isometric image with diamonds `tile_width` pixels
+/// wide, shifted so its lines land on cell boundaries.
+pub fn detect_isometric_grid(
+    image: &Mat,
+    tile_width: f64,
+) -> Result<Isometric, Box<dyn std::error::Error>> {
+    let ink = ink_mask(image)?;
+    let mut lines = core::Vector::<core::Vec2f>::new();
+    let votes = (ink.cols().min(ink.rows()) as f64 * ISOMETRIC_COVERAGE) as i32;
+    imgproc::hough_lines(
+        &ink,
+        &mut lines,
+        1.0,
+        GRID_ANGLE_RESOLUTION.to_radians(),
+        votes.max(1),
+        0.0,
+        0.0,
+        0.0,
+        std::f64::consts::PI,
+    )?;
+    let lines: Vec<(f64, f64)> = lines
+        .iter()
+        .map(|line| (line[0] as f64, (line[1] as f64).to_degrees()))
+        .collect();
+    let normals: Vec<f64> = lines.iter().map(|(_, normal)| *normal).collect();
+    let degrees = isometric_angle(&normals).unwrap_or_else(|| {
+        warn!("No isometric grid found, assuming cells twice as wide as they are high");
+        ISOMETRIC_DEGREES
+    });
+    let isometric = Isometric::new(tile_width, degrees);
+    // How far along the grid lines of either way are, in cells
+    let phase = |normal: f64| {
+        let positions: Vec<f64> = lines
+            .iter()
+            .filter(|(_, angle)| (angle - normal).abs() <= ISOMETRIC_TOLERANCE)
+            .map(|(distance, _)| isometric.cells_across(*distance))
+            .collect();
+        circular_phase(&positions, 1.0).unwrap_or(0.0)
+    };
+    let phase = Vector2 {
+        x: phase(90.0 - degrees),
+        y: phase(90.0 + degrees),
+    };
+    debug!(
+        "Detected isometric grid at {} degrees, {} cells along",
+        degrees, phase
+    );
+    Ok(isometric.aligned(phase, image.cols(), image.rows()))
+}
+
+/// Turns an image onto a white canvas, as by the rotation.
+pub fn rotate_image(image: &Mat, rotation: &Rotation) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut matrix = imgproc::get_rotation_matrix_2d(
+        core::Point2f::new(rotation.from.x as f32, rotation.from.y as f32),
+        rotation.degrees,
+        1.0,
+    )?;
+    *matrix.at_2d_mut::<f64>(0, 2)? += rotation.to.x - rotation.from.x;
+    *matrix.at_2d_mut::<f64>(1, 2)? += rotation.to.y - rotation.from.y;
+    let (width, height) = rotation.size();
+    let mut rotated = Mat::default();
+    imgproc::warp_affine(
+        image,
+        &mut rotated,
+        &matrix,
+        Size::new(width.ceil() as i32, height.ceil() as i32),
+        imgproc::INTER_LINEAR,
+        core::BORDER_CONSTANT,
+        Scalar::all(255.0),
+    )?;
+    Ok(rotated)
+}
+
+/// The length of the graduations of a scale bar from a row across it, marked
+/// where it is inked: either alternating inked and blank segments of about the
+/// same length, or thin ticks evenly spaced.
+pub fn graduation_length(row: &[bool]) -> Option<f64> {
+    let mut runs: Vec<(bool, f64)> = Vec::new();
+    for &ink in row {
+        match runs.last_mut() {
+            Some((inked, length)) if *inked == ink => *length += 1.0,
+            _ => runs.push((ink, 1.0)),
+        }
+    }
+    // Only the runs from the first ink to the last count
+    if runs.first().is_some_and(|&(ink, _)| !ink) {
+        runs.remove(0);
+    }
+    if runs.last().is_some_and(|&(ink, _)| !ink) {
+        runs.pop();
+    }
+    let even = |lengths: &[f64]| -> Option<f64> {
+        if lengths.len() < MIN_GRADUATIONS {
+            return None;
+        }
+        let mean = lengths.iter().sum::<f64>() / lengths.len() as f64;
+        lengths
+            .iter()
+            .all(|length| (length - mean).abs() <= GRADUATION_TOLERANCE * mean)
+            .then_some(mean)
+    };
+    let lengths = |inked: bool| -> Vec<f64> {
+        runs.iter()
+            .filter(|&&(ink, _)| ink == inked)
+            .map(|&(_, length)| length)
+            .collect()
+    };
+    let (ticks, gaps) = (lengths(true), lengths(false));
+    let thickest = ticks.iter().copied().fold(0.0, f64::max);
+    if thickest * 4.0 <= gaps.iter().copied().fold(f64::INFINITY, f64::min) {
+        // From the middle of each tick to the next
+        let tick = ticks.iter().sum::<f64>() / ticks.len() as f64;
+        return even(&gaps).map(|gap| gap + tick);
+    }
+    // The outline around the segments is left out
+    let longest = runs.iter().map(|&(_, length)| length).fold(0.0, f64::max);
+    let segments: Vec<f64> = runs
+        .iter()
+        .map(|&(_, length)| length)
+        .filter(|&length| length * 4.0 > longest)
+        .collect();
+    even(&segments)
+}
+
+/// Finds a graduated scale bar printed on a map, returning the length of its
+/// graduations in pixels, a cell each.
+pub fn detect_scale_bar(image: &Mat) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    let mut labels = Mat::default();
+    let mut stats = Mat::default();
+    let mut centroids = Mat::default();
+    let count = imgproc::connected_components_with_stats(
+        &ink_mask(image)?,
+        &mut labels,
+        &mut stats,
+        &mut centroids,
+        8,
+        core::CV_32S,
+    )?;
+    let max_height = SCALE_BAR_MAX_HEIGHT * image.rows() as f64;
+    // Label 0 is the background
+    for label in 1..count {
+        let stat = |stat: i32| stats.at_2d::<i32>(label, stat).copied();
+        let (left, top) = (stat(imgproc::CC_STAT_LEFT)?, stat(imgproc::CC_STAT_TOP)?);
+        let (width, height) = (
+            stat(imgproc::CC_STAT_WIDTH)?,
+            stat(imgproc::CC_STAT_HEIGHT)?,
+        );
+        if width < SCALE_BAR_ASPECT * height || height as f64 > max_height {
+            continue;
+        }
+        // Across the middle of the bar, then nearer its edges for ticks
+        for share in [0.5, 0.25, 0.75] {
+            let y = top + (height as f64 * share) as i32;
+            let row: Vec<bool> = (left..left + width)
+                .map(|x| labels.at_2d::<i32>(y, x).map(|&at| at == label))
+                .collect::<Result<_, _>>()?;
+            if let Some(length) = graduation_length(&row) {
+                debug!(
+                    "Found a scale bar {}x{} at ({}, {}) of {:.1} pixel graduations",
+                    width, height, left, top, length
+                );
+                return Ok(Some(length));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Erases the grid lines drawn over an image, keeping the walls they cross.
+///
+/// Walls are told apart from grid lines by being thicker than the grid in
+/// both directions where the two overlap.
+pub fn remove_grid(image: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    let ink = ink_mask(image)?;
+    let rows = grid_lines(&coverage(&ink, 1)?);
+    let cols = grid_lines(&coverage(&ink, 0)?);
+    let mut cleaned = image.try_clone()?;
+    if rows.is_empty() && cols.is_empty() {
+        return Ok(cleaned);
+    }
+    debug!(
+        "Removing {} horizontal and {} vertical grid lines",
+        rows.len(),
+        cols.len()
+    );
+
+    let mut lines =
+        Mat::new_rows_cols_with_default(ink.rows(), ink.cols(), core::CV_8UC1, Scalar::all(0.0))?;
+    let mut thickness = 1;
+    for (start, end) in &rows {
+        let rect = core::Rect::new(0, *start as i32, ink.cols(), (end - start) as i32);
+        imgproc::rectangle(
+            &mut lines,
+            rect,
+            Scalar::all(255.0),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )?;
+        thickness = thickness.max(end - start);
+    }
+    for (start, end) in &cols {
+        let rect = core::Rect::new(*start as i32, 0, (end - start) as i32, ink.rows());
+        imgproc::rectangle(
+            &mut lines,
+            rect,
+            Scalar::all(255.0),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )?;
+        thickness = thickness.max(end - start);
+    }
+
+    let size = thickness as i32 + 2;
+    let kernel = imgproc::get_structuring_element(
+        imgproc::MORPH_RECT,
+        Size::new(size, size),
+        core::Point::new(-1, -1),
+    )?;
+    let mut walls = Mat::default();
+    imgproc::morphology_ex(
+        &ink,
+        &mut walls,
+        imgproc::MORPH_OPEN,
+        &kernel,
+        core::Point::new(-1, -1),
+        1,
+        core::BORDER_CONSTANT,
+        imgproc::morphology_default_border_value()?,
+    )?;
+    let mut grid = Mat::default();
+    core::bitwise_and(&ink, &lines, &mut grid, &core::no_array())?;
+    let mut not_walls = Mat::default();
+    core::bitwise_not(&walls, &mut not_walls, &core::no_array())?;
+    let mut erase = Mat::default();
+    core::bitwise_and(&grid, &not_walls, &mut erase, &core::no_array())?;
+    cleaned.set_to(&Scalar::all(255.0), &erase)?;
+    Ok(cleaned)
+}
+
+/// Whether a blob of ink is small enough to be text or a symbol rather than a wall.
+pub fn is_symbol(width: i32, height: i32, pixels_per_cell: f64) -> bool {
+    width.max(height) as f64 <= MAX_SYMBOL_SIZE * pixels_per_cell
+}
+
+/// Marks the connected components of a mask that the filter keeps.
+fn components_where(
+    mask: &Mat,
+    keep: impl Fn(&[i32]) -> bool,
+) -> Result<(Mat, usize), Box<dyn std::error::Error>> {
+    let mut labels = Mat::default();
+    let mut stats = Mat::default();
+    let mut centroids = Mat::default();
+    let count = imgproc::connected_components_with_stats(
+        mask,
+        &mut labels,
+        &mut stats,
+        &mut centroids,
+        8,
+        core::CV_32S,
+    )?;
+    // Label 0 is the background
+    let mut kept = vec![false; count.max(0) as usize];
+    for label in 1..count {
+        let row: Vec<i32> = (0..imgproc::CC_STAT_MAX)
+            .map(|stat| stats.at_2d::<i32>(label, stat).copied())
+            .collect::<Result<_, _>>()?;
+        kept[label as usize] = keep(&row);
+    }
+    let mut selected =
+        Mat::new_rows_cols_with_default(mask.rows(), mask.cols(), core::CV_8UC1, Scalar::all(0.0))?;
+    for row in 0..mask.rows() {
+        for col in 0..mask.cols() {
+            if kept[*labels.at_2d::<i32>(row, col)? as usize] {
+                *selected.at_2d_mut::<u8>(row, col)? = 255;
+            }
+        }
+    }
+    Ok((selected, kept.iter().filter(|kept| **kept).count()))
+}
+
+/// A morphological operation on the linework of an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MorphOp {
+    /// Thins the linework, removing specks thinner than the kernel
+    Erode,
+    /// Thickens the linework, joining strokes closer than the kernel
+    Dilate,
+    /// Erodes then dilates, removing specks without thinning the walls
+    Open,
+    /// Dilates then erodes, connecting broken lines without thickening them
+    Close,
+}
+
+/// A morphological step and the size of its square kernel in pixels, as
+/// given to `--morphology`, e.g. `close:5`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Morphology {
+    pub op: MorphOp,
+    pub kernel: i32,
+}
+
+impl FromStr for Morphology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid morphology '{}', expected erode, dilate, open, or close, then :KERNEL",
+                s
+            )
+        };
+        let (op, kernel) = s.split_once(':').ok_or_else(invalid)?;
+        let op = match op.trim() {
+            "erode" => MorphOp::Erode,
+            "dilate" => MorphOp::Dilate,
+            "open" => MorphOp::Open,
+            "close" => MorphOp::Close,
+            _ => return Err(invalid()),
+        };
+        match kernel.trim().parse() {
+            Ok(kernel) if kernel > 0 => Ok(Morphology { op, kernel }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Applies morphological steps in order to the dark linework of an image,
+/// leaving the floor around it as it was.
+pub fn apply_morphology(
+    image: &Mat,
+    steps: &[Morphology],
+) -> Result<Mat, Box<dyn std::error::Error>> {
+    if steps.is_empty() {
+        return Ok(image.try_clone()?);
+    }
+    // The operations grow and shrink white, so they work on the ink inverted
+    let mut ink = Mat::default();
+    core::bitwise_not(image, &mut ink, &core::no_array())?;
+    for step in steps {
+        debug!("Applying {:?} with a {} pixel kernel", step.op, step.kernel);
+        let kernel = imgproc::get_structuring_element(
+            imgproc::MORPH_RECT,
+            Size::new(step.kernel, step.kernel),
+            core::Point::new(-1, -1),
+        )?;
+        let op = match step.op {
+            MorphOp::Erode => imgproc::MORPH_ERODE,
+            MorphOp::Dilate => imgproc::MORPH_DILATE,
+            MorphOp::Open => imgproc::MORPH_OPEN,
+            MorphOp::Close => imgproc::MORPH_CLOSE,
+        };
+        let mut processed = Mat::default();
+        imgproc::morphology_ex(
+            &ink,
+            &mut processed,
+            op,
+            &kernel,
+            core::Point::new(-1, -1),
+            1,
+            core::BORDER_CONSTANT,
+            imgproc::morphology_default_border_value()?,
+        )?;
+        ink = processed;
+    }
+    let mut processed = Mat::default();
+    core::bitwise_not(&ink, &mut processed, &core::no_array())?;
+    Ok(processed)
+}
+
+/// Clears specks of ink smaller than `min_area` pixels from the floor, and fills
+/// pinholes as small in the walls, as left by dithering or scanning.
+pub fn remove_speckles(image: &Mat, min_area: i32) -> Result<Mat, Box<dyn std::error::Error>> {
+    let is_speck = |stats: &[i32]| stats[imgproc::CC_STAT_AREA as usize] < min_area;
+    let ink = ink_mask(image)?;
+    let (specks, speck_count) = components_where(&ink, is_speck)?;
+    let mut floor = Mat::default();
+    core::bitwise_not(&ink, &mut floor, &core::no_array())?;
+    let (holes, hole_count) = components_where(&floor, is_speck)?;
+    debug!(
+        "Removing {} specks and {} pinholes",
+        speck_count, hole_count
+    );
+    let mut cleaned = image.try_clone()?;
+    cleaned.set_to(&Scalar::all(255.0), &specks)?;
+    cleaned.set_to(&Scalar::all(0.0), &holes)?;
+    Ok(cleaned)
+}
+
+/// Paints over text and symbols with the floor around them, so room numbers
+/// and markers are not traced as tiny rooms.
+pub fn remove_symbols(
+    image: &Mat,
+    pixels_per_cell: f64,
+) -> Result<Mat, Box<dyn std::error::Error>> {
+    let (symbols, count) = components_where(&ink_mask(image)?, |stats| {
+        is_symbol(
+            stats[imgproc::CC_STAT_WIDTH as usize],
+            stats[imgproc::CC_STAT_HEIGHT as usize],
+            pixels_per_cell,
+        )
+    })?;
+    debug!("Removing {} symbols", count);
+    paint_over(image, &symbols)
+}
+
+/// Paints over the pixels of a mask with the floor around them.
+pub fn paint_over(image: &Mat, mask: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    // Growing the mask covers the anti-aliased edges of the glyphs
+    let mut grown = Mat::default();
+    let kernel = imgproc::get_structuring_element(
+        imgproc::MORPH_RECT,
+        Size::new(3, 3),
+        core::Point::new(-1, -1),
+    )?;
+    imgproc::dilate(
+        mask,
+        &mut grown,
+        &kernel,
+        core::Point::new(-1, -1),
+        1,
+        core::BORDER_CONSTANT,
+        imgproc::morphology_default_border_value()?,
+    )?;
+    let mut inpainted = Mat::default();
+    photo::inpaint(
+        image,
+        &grown,
+        &mut inpainted,
+        INPAINT_RADIUS,
+        photo::INPAINT_TELEA,
+    )?;
+    Ok(inpainted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page shaded from white on the left to gray on the right, with ink
+    /// drawn by `ink` where it is dark, as a share of the paper's brightness.
+    fn shaded_page(ink: impl Fn(i32, i32) -> Option<f64>) -> Mat {
+        let (rows, cols) = (200, 240);
+        let mut image =
+            Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC3, Scalar::all(0.0)).unwrap();
+        for y in 0..rows {
+            for x in 0..cols {
+                let paper = 255.0 - 100.0 * x as f64 / cols as f64;
+                let value = (paper * ink(x, y).unwrap_or(1.0)) as u8;
+                *image.at_2d_mut::<core::Vec3b>(y, x).unwrap() = core::Vec3b::all(value);
+            }
+        }
+        image
+    }
+
+    /// The darkest and lightest gray of a region of an image.
+    fn gray_range(image: &Mat, region: core::Rect) -> (f64, f64) {
+        let mut gray = Mat::default();
+        imgproc::cvt_color(
+            &Mat::roi(image, region).unwrap(),
+            &mut gray,
+            imgproc::COLOR_BGR2GRAY,
+            0,
+        )
+        .unwrap();
+        let (mut min, mut max) = (0.0, 0.0);
+        core::min_max_loc(
+            &gray,
+            Some(&mut min),
+            Some(&mut max),
+            None,
+            None,
+            &core::no_array(),
+        )
+        .unwrap();
+        (min, max)
+    }
+
+    #[test]
+    fn test_normalize_illumination() {
+        // A wall a cell of 64 pixels thick filled almost black, and a thin line
+        let image = shaded_page(|x, y| match (x, y) {
+            (100..=159, _) => Some(0.1),
+            (10..=89, 50..=51) => Some(0.2),
+            _ => None,
+        });
+        let normalized = normalize_illumination(&image, 32).unwrap();
+        assert_eq!(normalized.typ(), image.typ());
+
+        let (_, wall) = gray_range(&normalized, core::Rect::new(102, 0, 56, 200));
+        assert!(wall < INK_THRESHOLD, "the wall was lightened to {}", wall);
+        let (_, line) = gray_range(&normalized, core::Rect::new(20, 50, 60, 2));
+        assert!(line < INK_THRESHOLD, "the line was lightened to {}", line);
+        // The paper is as white in the shade as in the light
+        for x in [0, 170, 230] {
+            let (paper, _) = gray_range(&normalized, core::Rect::new(x, 100, 10, 100));
+            assert!(paper > 220.0, "the paper at {} is only {}", x, paper);
+        }
+    }
+
+    #[test]
+    fn test_remove_speckles() {
+        let mut image =
+            Mat::new_rows_cols_with_default(100, 100, core::CV_8UC3, Scalar::all(255.0)).unwrap();
+        let mut fill = |rect: core::Rect, value: f64| {
+            imgproc::rectangle(
+                &mut image,
+                rect,
+                Scalar::all(value),
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )
+            .unwrap();
+        };
+        // A speck on the floor, a wall with a pinhole, and a blob as large
+        // as the speck limit
+        fill(core::Rect::new(10, 10, 2, 2), 0.0);
+        fill(core::Rect::new(40, 10, 30, 30), 0.0);
+        fill(core::Rect::new(55, 25, 2, 2), 255.0);
+        fill(core::Rect::new(10, 60, 4, 4), 0.0);
+
+        let cleaned = remove_speckles(&image, 16).unwrap();
+        let ink = ink_mask(&cleaned).unwrap();
+        let is_ink = |x: i32, y: i32| *ink.at_2d::<u8>(y, x).unwrap() == 255;
+        assert!(!is_ink(10, 10) && !is_ink(11, 11));
+        assert!(is_ink(55, 25) && is_ink(56, 26));
+        assert!(is_ink(10, 60) && is_ink(13, 63));
+        assert_eq!(core::count_non_zero(&ink).unwrap(), 30 * 30 + 4 * 4);
+    }
+
+    #[test]
+    fn test_grid_lines() {
+        let mut coverage = vec![0.1; 108];
+        for line in [10, 11, 42, 43, 74, 75, 106, 107] {
+            coverage[line] = 0.9;
+        }
+        // The last line runs off the edge of the image
+        assert_eq!(
+            grid_lines(&coverage),
+            vec![(10, 12), (42, 44), (74, 76), (106, 108)]
+        );
+        // A couple of long walls are not a grid
+        assert_eq!(grid_lines(&coverage[..64]), vec![]);
+    }
+
+    #[test]
+    fn test_uneven_grid_lines() {
+        let mut coverage = vec![0.0; 100];
+        for line in [10, 30, 90] {
+            coverage[line] = 1.0;
+        }
+        assert_eq!(grid_lines(&coverage), vec![]);
+    }
+
+    #[test]
+    fn test_grid_spacing() {
+        assert_eq!(
+            grid_spacing(&[(10, 12), (42, 44), (74, 76), (107, 109)]),
+            Some(32.0)
+        );
+        assert_eq!(grid_spacing(&[(10, 12)]), None);
+    }
+
+    #[test]
+    fn test_parse_morphology() {
+        assert_eq!(
+            "close:5".parse(),
+            Ok(Morphology {
+                op: MorphOp::Close,
+                kernel: 5
+            })
+        );
+        assert_eq!(
+            "erode: 3".parse::<Morphology>().map(|step| step.op),
+            Ok(MorphOp::Erode)
+        );
+        assert!("close".parse::<Morphology>().is_err());
+        assert!("close:0".parse::<Morphology>().is_err());
+        assert!("smudge:3".parse::<Morphology>().is_err());
+    }
+
+    #[test]
+    fn test_grid_phase() {
+        let runs = [(10, 12), (42, 44), (74, 76)];
+        assert!((grid_phase(&runs, 32.0).unwrap() - 10.5).abs() < 1e-9);
+        // Lines just before a multiple of the cell size are a little early
+        let runs = [(31, 32), (63, 64), (95, 96)];
+        assert!((grid_phase(&runs, 32.0).unwrap() + 1.0).abs() < 1e-9);
+        assert_eq!(grid_phase(&[], 32.0), None);
+    }
+
+    #[test]
+    fn test_grid_angle() {
+        assert!(grid_angle(&[90.0, 0.0, 90.0]).unwrap().abs() < 1e-9);
+        // Slightly turned lines either way of the grid
+        assert!((grid_angle(&[2.0, 92.0, 1.5, 91.5]).unwrap() - 1.75).abs() < 1e-9);
+        assert!((grid_angle(&[179.0, 89.0]).unwrap() + 1.0).abs() < 1e-9);
+        assert!((grid_angle(&[45.0, 135.0]).unwrap().abs() - 45.0).abs() < 1e-9);
+        assert_eq!(grid_angle(&[]), None);
+    }
+
+    #[test]
+    fn test_isometric_angle() {
+        let angle = isometric_angle(&[63.5, 116.5, 0.0, 90.0, 180.0]).unwrap();
+        assert!((angle - 26.5).abs() < 1e-9);
+        assert_eq!(isometric_angle(&[0.0, 90.0, 88.0]), None);
+    }
+
+    #[test]
+    fn test_graduation_length() {
+        let row = |runs: &[(bool, usize)]| -> Vec<bool> {
+            runs.iter()
+                .flat_map(|&(ink, length)| std::iter::repeat_n(ink, length))
+                .collect()
+        };
+        // Alternating segments inside an outline
+        let segments = row(&[
+            (false, 3),
+            (true, 2),
+            (false, 40),
+            (true, 41),
+            (false, 39),
+            (true, 42),
+            (false, 5),
+        ]);
+        assert_eq!(graduation_length(&segments), Some(40.5));
+        let ticks = row(&[
+            (true, 2),
+            (false, 30),
+            (true, 2),
+            (false, 30),
+            (true, 2),
+            (false, 30),
+            (true, 2),
+        ]);
+        assert_eq!(graduation_length(&ticks), Some(32.0));
+        // A corridor between two walls
+        assert_eq!(
+            graduation_length(&row(&[(true, 8), (false, 100), (true, 8)])),
+            None
+        );
+        assert_eq!(
+            graduation_length(&row(&[(true, 20), (false, 40), (true, 60), (false, 40)])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_symbol() {
+        assert!(is_symbol(20, 30, 64.0));
+        assert!(is_symbol(32, 8, 64.0));
+        assert!(!is_symbol(33, 8, 64.0));
+        assert!(!is_symbol(4, 200, 64.0));
+    }
+}
@@ -0,0 +1,286 @@
+use std::path::{Path, PathBuf};
+
+use opencv::core::{self, Mat, Point, Rect, Scalar};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::{debug, info};
+
+use crate::assets::AssetResolver;
+use crate::dungeondraft_v1::read_map;
+use crate::render::{draw_grid, render_map};
+
+const MM_PER_INCH: f64 = 25.4;
+/// The longest length taken, in inches, well past any page or cell printed.
+const MAX_LENGTH: f64 = 1000.0;
+const PAGE_COLOR: Scalar = Scalar::new(255.0, 255.0, 255.0, 0.0);
+const MARK_COLOR: Scalar = Scalar::new(0.0, 0.0, 0.0, 0.0);
+
+/// Parses a physical length such as `1in`, `25mm`, or `2.5cm` into inches,
+/// up to `MAX_LENGTH`.
+pub fn parse_length(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let (number, units_per_inch) = if let Some(n) = s.strip_suffix("mm") {
+        (n, MM_PER_INCH)
+    } else if let Some(n) = s.strip_suffix("cm") {
+        (n, MM_PER_INCH / 10.0)
+    } else if let Some(n) = s.strip_suffix("in") {
+        (n, 1.0)
+    } else {
+        return Err(format!(
+            "expected a length in in, mm, or cm but found '{}'",
+            s
+        ));
+    };
+    match number.trim().parse::<f64>().map(|n| n / units_per_inch) {
+        Ok(inches) if (0.0..=MAX_LENGTH).contains(&inches) => Ok(inches),
+        _ => Err(format!(
+            "invalid length '{}', expected 0 to {}in",
+            s, MAX_LENGTH
+        )),
+    }
+}
+
+/// Parses a length like `parse_length`, but one of more than nothing, such
+/// as the size of a cell that pages are divided by.
+pub fn parse_positive_length(s: &str) -> Result<f64, String> {
+    match parse_length(s)? {
+        inches if inches > 0.0 => Ok(inches),
+        _ => Err(format!(
+            "invalid length '{}', expected more than 0",
+            s.trim()
+        )),
+    }
+}
+
+/// Paper sizes in inches, portrait.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Paper {
+    A4,
+    Letter,
+}
+
+impl Paper {
+    fn size(self) -> (f64, f64) {
+        match self {
+            Paper::A4 => (210.0 / MM_PER_INCH, 297.0 / MM_PER_INCH),
+            Paper::Letter => (8.5, 11.0),
+        }
+    }
+}
+
+impl std::str::FromStr for Paper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "a4" => Ok(Paper::A4),
+            "letter" => Ok(Paper::Letter),
+            _ => Err(format!("unknown paper size '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PrintOptions {
+    pub dpi: f64,
+    /// Printed size of one grid cell in inches
+    pub cell_size: f64,
+    pub grid: bool,
+    pub paper: Option<Paper>,
+    /// Unprintable page border in inches
+    pub margin: f64,
+}
+
+/// The part of the map image printed on one page.
+#[derive(Debug, PartialEq)]
+pub struct PageTile {
+    pub row: i32,
+    pub col: i32,
+    pub rect: Rect,
+}
+
+/// Splits an image into page sized pieces, left to right then top to bottom.
+pub fn layout_pages(width: i32, height: i32, page_width: i32, page_height: i32) -> Vec<PageTile> {
+    let mut pages = Vec::new();
+    if page_width <= 0 || page_height <= 0 {
+        return pages;
+    }
+    for (row, y) in (0..height).step_by(page_height as usize).enumerate() {
+        for (col, x) in (0..width).step_by(page_width as usize).enumerate() {
+            pages.push(PageTile {
+                row: row as i32,
+                col: col as i32,
+                rect: Rect::new(x, y, page_width.min(width - x), page_height.min(height - y)),
+            });
+        }
+    }
+    pages
+}
+
+/// Draws crop marks in the margin around each corner of the printed area.
+fn draw_crop_marks(
+    page: &mut Mat,
+    area: Rect,
+    length: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let gap = (length / 4).max(1);
+    let (left, top) = (area.x, area.y);
+    let (right, bottom) = (area.x + area.width, area.y + area.height);
+    for (x, dx) in [(left, -1), (right, 1)] {
+        for (y, dy) in [(top, -1), (bottom, 1)] {
+            let marks = [
+                (
+                    Point::new(x + dx * gap, y),
+                    Point::new(x + dx * (gap + length), y),
+                ),
+                (
+                    Point::new(x, y + dy * gap),
+                    Point::new(x, y + dy * (gap + length)),
+                ),
+            ];
+            for (from, to) in marks {
+                imgproc::line(page, from, to, MARK_COLOR, 1, imgproc::LINE_8, 0)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_png(path: &Path, image: &Mat) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Writing {}", path.display());
+    imwrite(
+        path.to_str().ok_or("invalid output path")?,
+        image,
+        &core::Vector::new(),
+    )?;
+    Ok(())
+}
+
+/// Renders a map at physical scale, either as one image or split into pages.
+///
+/// Returns the paths of every image written into `output_dir`.
+pub fn try_export_print(
+    map_path: &Path,
+    output_dir: &Path,
+    options: &PrintOptions,
+    resolver: Option<&AssetResolver>,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let map = read_map(map_path)?;
+    let pixels_per_cell = options.dpi * options.cell_size;
+    let mut image = render_map(&map, pixels_per_cell, resolver)?;
+    if options.grid {
+        let thickness = (options.dpi / 150.0).round().max(1.0) as i32;
+        draw_grid(&mut image, pixels_per_cell, MARK_COLOR, thickness)?;
+    }
+
+    std::fs::create_dir_all(output_dir)?;
+    let stem = map_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("map");
+
+    let Some(paper) = options.paper else {
+        let path = output_dir.join(format!("{}.print.png", stem));
+        write_png(&path, &image)?;
+        return Ok(vec![path]);
+    };
+
+    let (page_width, page_height) = paper.size();
+    let to_pixels = |inches: f64| {
+        let pixels = (inches * options.dpi).round();
+        match (0.0..=i32::MAX as f64).contains(&pixels) {
+            true => Ok(pixels as i32),
+            false => Err("page is too large to print at this DPI"),
+        }
+    };
+    let (page_cols, page_rows) = (to_pixels(page_width)?, to_pixels(page_height)?);
+    let margin = to_pixels(options.margin)?;
+    let printable = |size: i32| {
+        margin
+            .checked_mul(2)
+            .and_then(|border| size.checked_sub(border))
+            .filter(|&printable| printable > 0)
+            .ok_or("margin leaves no printable area on the page")
+    };
+    let (printable_width, printable_height) = (printable(page_cols)?, printable(page_rows)?);
+
+    let pages = layout_pages(
+        image.cols(),
+        image.rows(),
+        printable_width,
+        printable_height,
+    );
+    info!("Splitting map into {} pages", pages.len());
+    let mut paths = Vec::new();
+    for tile in pages {
+        let mut page =
+            Mat::new_rows_cols_with_default(page_rows, page_cols, core::CV_8UC3, PAGE_COLOR)?;
+        let area = Rect::new(margin, margin, tile.rect.width, tile.rect.height);
+        let source = Mat::roi(&image, tile.rect)?;
+        let mut target = Mat::roi(&page, area)?;
+        source.copy_to(&mut target)?;
+        draw_crop_marks(&mut page, area, margin / 2)?;
+
+        let path = output_dir.join(format!(
+            "{}.page-{}-{}.png",
+            stem,
+            tile.row + 1,
+            tile.col + 1
+        ));
+        write_png(&path, &page)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_length() {
+        assert_eq!(parse_length("1in").unwrap(), 1.0);
+        assert_eq!(parse_length("25.4mm").unwrap(), 1.0);
+        assert_eq!(parse_length("2.54 cm").unwrap(), 1.0);
+        assert!(parse_length("1").is_err());
+        assert!(parse_length("-1in").is_err());
+        assert!(parse_length("infin").is_err());
+        assert!(parse_length("NaNmm").is_err());
+        assert!(parse_length("1e9in").is_err());
+        assert_eq!(parse_length("1000in").unwrap(), 1000.0);
+        assert_eq!(parse_length("0in").unwrap(), 0.0);
+        assert_eq!(parse_positive_length("25.4mm").unwrap(), 1.0);
+        assert!(parse_positive_length("0in").is_err());
+        assert!(parse_positive_length("0.0mm").is_err());
+    }
+
+    #[test]
+    fn test_parse_paper() {
+        assert_eq!("A4".parse::<Paper>().unwrap(), Paper::A4);
+        assert_eq!("letter".parse::<Paper>().unwrap(), Paper::Letter);
+        assert!("legal".parse::<Paper>().is_err());
+    }
+
+    #[test]
+    fn test_layout_pages() {
+        let pages = layout_pages(250, 120, 100, 100);
+        assert_eq!(pages.len(), 6);
+        assert_eq!(
+            pages[2],
+            PageTile {
+                row: 0,
+                col: 2,
+                rect: Rect::new(200, 0, 50, 100)
+            }
+        );
+        assert_eq!(pages[5].rect, Rect::new(200, 100, 50, 20));
+    }
+
+    #[test]
+    fn test_layout_single_page() {
+        let pages = layout_pages(80, 60, 100, 100);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].rect, Rect::new(0, 0, 80, 60));
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, info};
+
+/// Downloads and uploads larger than this are refused.
+pub const MAX_DOWNLOAD_BYTES: u64 = 64 * 1024 * 1024;
+/// Content types accepted for download and the extension they are saved with.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/webp", "webp"),
+    ("image/bmp", "bmp"),
+    ("image/tiff", "tiff"),
+    ("image/avif", "avif"),
+    ("image/heic", "heic"),
+    ("image/heif", "heif"),
+    ("application/geo+json", "geojson"),
+    ("application/json", "json"),
+];
+
+/// The extension files of an accepted content type are saved with.
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    CONTENT_TYPES
+        .iter()
+        .find(|(accepted, _)| *accepted == content_type)
+        .map(|(_, extension)| *extension)
+}
+
+/// Whether the input names an `http` or `https` URL rather than a file.
+pub fn is_url(input: &Path) -> bool {
+    input
+        .to_str()
+        .is_some_and(|s| s.starts_with("https://") || s.starts_with("http://"))
+}
+
+/// The file name to save a download as, from the last segment of the URL,
+/// with the extension of its content type.
+pub fn download_name(url: &str, extension: &str) -> String {
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .split_once("://")
+        .map_or("", |(_, rest)| rest);
+    let stem = path
+        .split_once('/')
+        .and_then(|(_, path)| path.rsplit('/').find(|segment| !segment.is_empty()))
+        .map(|segment| Path::new(segment).with_extension(""))
+        .and_then(|stem| stem.to_str().map(str::to_string))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "download".to_string());
+    format!("{}.{}", stem, extension)
+}
+
+/// Downloads the input to a temporary file when it is a URL, or returns it as is.
+///
+/// Only image and GeoJSON content types are accepted, and downloads over
+/// [`MAX_DOWNLOAD_BYTES`] are refused.
+pub fn fetch_input(input: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if !is_url(input) {
+        return Ok(input.to_path_buf());
+    }
+    let url = input.to_str().ok_or("invalid URL")?;
+    info!("Downloading {}", url);
+    let mut response = ureq::get(url).call()?;
+    let content_type = response.body().mime_type().unwrap_or_default().to_string();
+    let extension = extension_for(&content_type)
+        .ok_or_else(|| format!("{} has unsupported content type '{}'", url, content_type))?;
+    if let Some(length) = response.body().content_length() {
+        if length > MAX_DOWNLOAD_BYTES {
+            return Err(format!(
+                "{} is {} bytes, over the {} byte limit",
+                url, length, MAX_DOWNLOAD_BYTES
+            )
+            .into());
+        }
+    }
+    // The limit also guards against servers sending more than they announced
+    let bytes = response
+        .body_mut()
+        .with_config()
+        .limit(MAX_DOWNLOAD_BYTES)
+        .read_to_vec()?;
+
+    let directory = std::env::temp_dir().join("dungeondraft-generator");
+    fs::create_dir_all(&directory)?;
+    let path = directory.join(download_name(url, extension));
+    fs::write(&path, bytes)?;
+    debug!("Downloaded {} to {}", url, path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_url() {
+        assert!(is_url(Path::new("https://example.com/map.png")));
+        assert!(is_url(Path::new("http://example.com/map.png")));
+        assert!(!is_url(Path::new("/maps/map.png")));
+        assert!(!is_url(Path::new("ftp://example.com/map.png")));
+    }
+
+    #[test]
+    fn test_download_name() {
+        assert_eq!(
+            download_name("https://cdn.example.com/a/b/tavern.webp?ex=1&is=2", "webp"),
+            "tavern.webp"
+        );
+        // The content type wins over the extension in the URL
+        assert_eq!(
+            download_name("https://example.com/maps/crypt.php", "png"),
+            "crypt.png"
+        );
+        assert_eq!(download_name("https://example.com/", "jpg"), "download.jpg");
+        assert_eq!(download_name("https://example.com", "jpg"), "download.jpg");
+    }
+}
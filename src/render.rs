@@ -0,0 +1,492 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use opencv::core::{self, Mat, Point, Rect, Scalar, Size};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::{VectorOfMat, VectorOfPoint, VectorOfVectorOfPoint};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::assets::AssetResolver;
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{
+    is_loop, node_points, node_position, nodes, parse_int_array, read_map, Color, Vector2,
+    DEFAULT_WALL_COLOR, UNITS_PER_CELL,
+};
+use crate::images::read_image;
+
+const BACKGROUND_COLOR: Scalar = Scalar::new(40.0, 40.0, 40.0, 0.0);
+const FLOOR_COLOR: Scalar = Scalar::new(200.0, 200.0, 200.0, 0.0);
+const PORTAL_COLOR: Scalar = Scalar::new(40.0, 90.0, 140.0, 0.0);
+const OBJECT_COLOR: Scalar = Scalar::new(60.0, 140.0, 60.0, 0.0);
+
+fn to_scalar(color: Color) -> Scalar {
+    Scalar::new(color.b as f64, color.g as f64, color.r as f64, 0.0)
+}
+
+fn node_color(node: &Value, default: &str) -> Scalar {
+    let color = node.get("color").and_then(Value::as_str).unwrap_or(default);
+    to_scalar(color.parse().unwrap_or_else(|_| default.parse().unwrap()))
+}
+
+fn node_vector2(node: &Value, field: &str) -> Option<Vector2> {
+    node.get(field)?.as_str()?.parse().ok()
+}
+
+/// Draws map nodes onto an image, loading textures through the resolver when one is given.
+struct Renderer<'a> {
+    mapper: CoordinateMapper,
+    resolver: Option<&'a AssetResolver>,
+    textures: HashMap<String, Option<Mat>>,
+}
+
+impl<'a> Renderer<'a> {
+    fn to_pixel(&self, v: Vector2) -> Point {
+        let p = self.mapper.units_to_pixels(v);
+        Point::new(p.x.round() as i32, p.y.round() as i32)
+    }
+
+    /// Output pixels per map unit.
+    fn scale(&self) -> f64 {
+        self.mapper.pixels_per_cell / UNITS_PER_CELL
+    }
+
+    /// Loads a texture scaled to the output resolution, keeping any alpha channel.
+    fn texture(&mut self, reference: &str) -> Option<&Mat> {
+        if !self.textures.contains_key(reference) {
+            let texture = self
+                .resolver
+                .and_then(|resolver| resolver.resolve(reference))
+                .and_then(|path| self.load_texture(&path));
+            if texture.is_none() {
+                debug!("no texture found for {}", reference);
+            }
+            self.textures.insert(reference.to_string(), texture);
+        }
+        self.textures.get(reference)?.as_ref()
+    }
+
+    fn load_texture(&self, path: &Path) -> Option<Mat> {
+        let image = read_image(path, opencv::imgcodecs::IMREAD_UNCHANGED).ok()?;
+        if image.empty() {
+            warn!("failed to read texture {}", path.display());
+            return None;
+        }
+        let mut scaled = Mat::default();
+        imgproc::resize(
+            &image,
+            &mut scaled,
+            Size::default(),
+            self.scale(),
+            self.scale(),
+            imgproc::INTER_AREA,
+        )
+        .ok()?;
+        (!scaled.empty()).then_some(scaled)
+    }
+
+    /// Fills the masked area with a tiled texture, or a flat color when it cannot be loaded.
+    fn fill(
+        &mut self,
+        canvas: &mut Mat,
+        mask: &Mat,
+        texture: Option<&str>,
+        color: Scalar,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (rows, cols) = (canvas.rows(), canvas.cols());
+        if let Some(texture) = texture.and_then(|t| self.texture(t)) {
+            let bgr = to_bgr(texture)?;
+            let tiled = core::repeat(&bgr, rows / bgr.rows() + 1, cols / bgr.cols() + 1)?;
+            let tiled = Mat::roi(&tiled, Rect::new(0, 0, cols, rows))?;
+            tiled.copy_to_masked(canvas, mask)?;
+        } else {
+            let filled = Mat::new_rows_cols_with_default(rows, cols, canvas.typ(), color)?;
+            filled.copy_to_masked(canvas, mask)?;
+        }
+        Ok(())
+    }
+
+    fn draw_tiles(
+        &mut self,
+        canvas: &mut Mat,
+        level: &Value,
+        width: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(cells) = level
+            .pointer("/tiles/cells")
+            .and_then(Value::as_str)
+            .and_then(|cells| parse_int_array(cells).ok())
+        else {
+            return Ok(());
+        };
+        let cell_size = self.mapper.pixels_per_cell;
+
+        let mut masks: HashMap<i64, Mat> = HashMap::new();
+        for (i, &tile) in cells.iter().enumerate().filter(|(_, &tile)| tile >= 0) {
+            let (x, y) = ((i as i32 % width) as f64, (i as i32 / width) as f64);
+            let mask = match masks.entry(tile) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(Mat::zeros(canvas.rows(), canvas.cols(), core::CV_8UC1)?.to_mat()?)
+                }
+            };
+            imgproc::rectangle_points(
+                mask,
+                Point::new((x * cell_size) as i32, (y * cell_size) as i32),
+                Point::new(
+                    ((x + 1.0) * cell_size) as i32,
+                    ((y + 1.0) * cell_size) as i32,
+                ),
+                Scalar::all(255.0),
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )?;
+        }
+
+        for (tile, mask) in masks {
+            let texture = level
+                .pointer(&format!("/tiles/lookup/{}", tile))
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            self.fill(canvas, &mask, texture.as_deref(), FLOOR_COLOR)?;
+        }
+        Ok(())
+    }
+
+    fn draw_patterns(
+        &mut self,
+        canvas: &mut Mat,
+        level: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for pattern in nodes(level, "patterns") {
+            let Some(points) = node_points(pattern) else {
+                continue;
+            };
+            let offset = node_position(pattern).unwrap_or_default();
+            let polygon: VectorOfPoint = points
+                .iter()
+                .map(|p| {
+                    self.to_pixel(Vector2 {
+                        x: p.x + offset.x,
+                        y: p.y + offset.y,
+                    })
+                })
+                .collect();
+            let mut mask = Mat::zeros(canvas.rows(), canvas.cols(), core::CV_8UC1)?.to_mat()?;
+            imgproc::fill_poly(
+                &mut mask,
+                &VectorOfVectorOfPoint::from_iter([polygon]),
+                Scalar::all(255.0),
+                imgproc::LINE_8,
+                0,
+                Point::new(0, 0),
+            )?;
+            let texture = pattern.get("texture").and_then(Value::as_str);
+            let color = node_color(pattern, "ffffffff");
+            self.fill(canvas, &mask, texture, color)?;
+        }
+        Ok(())
+    }
+
+    fn draw_walls(
+        &self,
+        canvas: &mut Mat,
+        level: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let thickness = ((self.mapper.pixels_per_cell * 0.12).round() as i32).max(1);
+        for wall in nodes(level, "walls") {
+            let Some(points) = node_points(wall) else {
+                continue;
+            };
+            let polyline: VectorOfPoint = points.iter().map(|p| self.to_pixel(*p)).collect();
+            imgproc::polylines(
+                canvas,
+                &VectorOfVectorOfPoint::from_iter([polyline]),
+                is_loop(wall),
+                node_color(wall, DEFAULT_WALL_COLOR),
+                thickness,
+                imgproc::LINE_AA,
+                0,
+            )?;
+        }
+
+        let portals = nodes(level, "portals")
+            .chain(nodes(level, "walls").flat_map(|wall| nodes(wall, "portals")));
+        for portal in portals {
+            let Some(position) = node_position(portal) else {
+                continue;
+            };
+            let rotation = portal
+                .get("rotation")
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0);
+            let radius = portal
+                .get("radius")
+                .and_then(Value::as_f64)
+                .unwrap_or(UNITS_PER_CELL / 2.0);
+            let (dx, dy) = (rotation.cos() * radius, rotation.sin() * radius);
+            imgproc::line(
+                canvas,
+                self.to_pixel(Vector2 {
+                    x: position.x - dx,
+                    y: position.y - dy,
+                }),
+                self.to_pixel(Vector2 {
+                    x: position.x + dx,
+                    y: position.y + dy,
+                }),
+                PORTAL_COLOR,
+                thickness,
+                imgproc::LINE_AA,
+                0,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn draw_objects(
+        &mut self,
+        canvas: &mut Mat,
+        level: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let marker_radius = ((self.mapper.pixels_per_cell * 0.2).round() as i32).max(1);
+        for object in nodes(level, "objects") {
+            let Some(position) = node_position(object) else {
+                continue;
+            };
+            let center = self.to_pixel(position);
+            let texture = object
+                .get("texture")
+                .and_then(Value::as_str)
+                .and_then(|t| self.texture(t))
+                .map(|t| t.try_clone())
+                .transpose()?;
+            match texture {
+                Some(texture) => {
+                    let scale = node_vector2(object, "scale").unwrap_or(Vector2 { x: 1.0, y: 1.0 });
+                    let rotation = object
+                        .get("rotation")
+                        .and_then(Value::as_f64)
+                        .unwrap_or(0.0);
+                    let mirror = object
+                        .get("mirror")
+                        .and_then(Value::as_bool)
+                        .unwrap_or(false);
+                    let sprite = transform_sprite(&texture, scale, rotation, mirror)?;
+                    overlay(canvas, &sprite, center)?;
+                }
+                None => {
+                    imgproc::circle(
+                        canvas,
+                        center,
+                        marker_radius,
+                        OBJECT_COLOR,
+                        imgproc::FILLED,
+                        imgproc::LINE_AA,
+                        0,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_bgr(image: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    let code = match image.channels() {
+        1 => imgproc::COLOR_GRAY2BGR,
+        4 => imgproc::COLOR_BGRA2BGR,
+        _ => return Ok(image.try_clone()?),
+    };
+    let mut bgr = Mat::default();
+    imgproc::cvt_color(image, &mut bgr, code, 0)?;
+    Ok(bgr)
+}
+
+/// Scales, mirrors, and rotates an object texture around its center.
+fn transform_sprite(
+    texture: &Mat,
+    scale: Vector2,
+    rotation: f64,
+    mirror: bool,
+) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut scaled = Mat::default();
+    imgproc::resize(
+        texture,
+        &mut scaled,
+        Size::default(),
+        scale.x.abs().max(f64::EPSILON),
+        scale.y.abs().max(f64::EPSILON),
+        imgproc::INTER_AREA,
+    )?;
+    if mirror {
+        let mut flipped = Mat::default();
+        core::flip(&scaled, &mut flipped, 1)?;
+        scaled = flipped;
+    }
+    if rotation == 0.0 {
+        return Ok(scaled);
+    }
+
+    // Rotate into a square large enough to hold every orientation of the sprite
+    let (w, h) = (scaled.cols() as f64, scaled.rows() as f64);
+    let side = w.hypot(h).ceil();
+    let mut matrix = imgproc::get_rotation_matrix_2d(
+        core::Point2f::new((w / 2.0) as f32, (h / 2.0) as f32),
+        // Godot rotates clockwise in radians, OpenCV counter-clockwise in degrees
+        -rotation.to_degrees(),
+        1.0,
+    )?;
+    *matrix.at_2d_mut::<f64>(0, 2)? += (side - w) / 2.0;
+    *matrix.at_2d_mut::<f64>(1, 2)? += (side - h) / 2.0;
+    let mut rotated = Mat::default();
+    imgproc::warp_affine(
+        &scaled,
+        &mut rotated,
+        &matrix,
+        Size::new(side as i32, side as i32),
+        imgproc::INTER_LINEAR,
+        core::BORDER_CONSTANT,
+        Scalar::all(0.0),
+    )?;
+    Ok(rotated)
+}
+
+/// Draws a sprite centered on a point, using its alpha channel as a mask.
+fn overlay(
+    canvas: &mut Mat,
+    sprite: &Mat,
+    center: Point,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let left = center.x - sprite.cols() / 2;
+    let top = center.y - sprite.rows() / 2;
+    let x0 = left.max(0);
+    let y0 = top.max(0);
+    let x1 = (left + sprite.cols()).min(canvas.cols());
+    let y1 = (top + sprite.rows()).min(canvas.rows());
+    if x1 <= x0 || y1 <= y0 {
+        return Ok(());
+    }
+
+    let visible = Rect::new(x0 - left, y0 - top, x1 - x0, y1 - y0);
+    let sprite = Mat::roi(sprite, visible)?;
+    let mask = if sprite.channels() == 4 {
+        let mut channels = VectorOfMat::new();
+        core::split(&sprite, &mut channels)?;
+        channels.get(3)?
+    } else {
+        Mat::new_rows_cols_with_default(
+            sprite.rows(),
+            sprite.cols(),
+            core::CV_8UC1,
+            Scalar::all(255.0),
+        )?
+    };
+    let bgr = to_bgr(&sprite)?;
+    let mut target = Mat::roi(canvas, Rect::new(x0, y0, x1 - x0, y1 - y0))?;
+    bgr.copy_to_masked(&mut target, &mask)?;
+    Ok(())
+}
+
+/// Draws a line along every grid cell boundary of the image.
+pub fn draw_grid(
+    canvas: &mut Mat,
+    pixels_per_cell: f64,
+    color: Scalar,
+    thickness: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (rows, cols) = (canvas.rows(), canvas.cols());
+    let columns = (cols as f64 / pixels_per_cell).ceil() as i32;
+    for i in 0..=columns {
+        let x = (i as f64 * pixels_per_cell).round() as i32;
+        imgproc::line(
+            canvas,
+            Point::new(x, 0),
+            Point::new(x, rows),
+            color,
+            thickness,
+            imgproc::LINE_8,
+            0,
+        )?;
+    }
+    let lines = (rows as f64 / pixels_per_cell).ceil() as i32;
+    for i in 0..=lines {
+        let y = (i as f64 * pixels_per_cell).round() as i32;
+        imgproc::line(
+            canvas,
+            Point::new(0, y),
+            Point::new(cols, y),
+            color,
+            thickness,
+            imgproc::LINE_8,
+            0,
+        )?;
+    }
+    Ok(())
+}
+
+/// Renders the first level of a map at the given number of pixels per grid cell.
+pub fn render_map(
+    map: &Value,
+    pixels_per_cell: f64,
+    resolver: Option<&AssetResolver>,
+) -> Result<Mat, Box<dyn std::error::Error>> {
+    let world = map.get("world").ok_or("map has no world")?;
+    let width = world["width"].as_i64().ok_or("map has no width")? as i32;
+    let height = world["height"].as_i64().ok_or("map has no height")? as i32;
+    let level = world["levels"]
+        .as_object()
+        .and_then(|levels| levels.values().next())
+        .ok_or("map has no levels")?;
+
+    let mut canvas = Mat::new_rows_cols_with_default(
+        (height as f64 * pixels_per_cell).round() as i32,
+        (width as f64 * pixels_per_cell).round() as i32,
+        core::CV_8UC3,
+        BACKGROUND_COLOR,
+    )?;
+    let mut renderer = Renderer {
+        mapper: CoordinateMapper::new(pixels_per_cell),
+        resolver,
+        textures: HashMap::new(),
+    };
+    renderer.draw_tiles(&mut canvas, level, width)?;
+    renderer.draw_patterns(&mut canvas, level)?;
+    renderer.draw_walls(&mut canvas, level)?;
+    renderer.draw_objects(&mut canvas, level)?;
+    Ok(canvas)
+}
+
+/// Renders a map file to a PNG `width` pixels wide, next to the map unless `output` is given.
+pub fn try_render_thumbnail(
+    map_path: &Path,
+    output: Option<&PathBuf>,
+    width: u32,
+    resolver: Option<&AssetResolver>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    debug!("Rendering thumbnail of {}", map_path.display());
+    let map = read_map(map_path)?;
+    let cells = map
+        .pointer("/world/width")
+        .and_then(Value::as_f64)
+        .filter(|w| *w > 0.0)
+        .ok_or("map has no width")?;
+    let image = render_map(&map, width as f64 / cells, resolver)?;
+
+    let output = output.cloned().unwrap_or_else(|| {
+        let mut path = map_path.to_path_buf();
+        path.set_extension("png");
+        path
+    });
+    debug!("Writing thumbnail {}", output.display());
+    imwrite(
+        output.to_str().ok_or("invalid output path")?,
+        &image,
+        &core::Vector::new(),
+    )?;
+    Ok(output)
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::time::Instant;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+
+/// How long a stage of generation took, and how many shapes went in and came
+/// out of it where it works on shapes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageReport {
+    pub name: String,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shapes_in: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shapes_out: Option<usize>,
+}
+
+/// What happened while generating a map, to tell why it came out as it did.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub input: String,
+    pub mode: String,
+    /// The options generation ran with, detected ones included
+    pub parameters: Value,
+    pub stages: Vec<StageReport>,
+    pub warnings: Vec<String>,
+    pub duration_ms: f64,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self::new("", "")
+    }
+}
+
+fn millis_since(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+impl RunReport {
+    pub fn new(input: &str, mode: &str) -> Self {
+        Self {
+            input: input.to_string(),
+            mode: mode.to_string(),
+            parameters: Value::Null,
+            stages: Vec::new(),
+            warnings: Vec::new(),
+            duration_ms: 0.0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records a stage started at `started` that was given `shapes_in` shapes
+    /// and left `shapes_out`.
+    pub fn stage(
+        &mut self,
+        name: &str,
+        started: Instant,
+        shapes_in: Option<usize>,
+        shapes_out: Option<usize>,
+    ) {
+        self.stages.push(StageReport {
+            name: name.to_string(),
+            duration_ms: millis_since(started),
+            shapes_in,
+            shapes_out,
+        });
+    }
+
+    /// Logs a warning and keeps it for the report.
+    pub fn warn(&mut self, warning: String) {
+        warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+
+    /// Writes the report as JSON, timed up to now.
+    pub fn write(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.duration_ms = millis_since(self.started);
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_report() {
+        let mut report = RunReport::new("crypt.png", "dungeon");
+        report.stage("detect shapes", Instant::now(), Some(5), Some(3));
+        report.stage("clean walls", Instant::now(), None, None);
+        report.warn("Left out shape #2".to_string());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["stages"][0]["shapes_in"], 5);
+        assert_eq!(json["stages"][0]["shapes_out"], 3);
+        assert!(json["stages"][1].get("shapes_in").is_none());
+        assert_eq!(json["warnings"][0], "Left out shape #2");
+        assert!(json.get("started").is_none());
+    }
+}
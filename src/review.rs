@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::annotations::{is_known_label, Annotations};
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+
+/// What is done with a candidate shape when the review is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Accept,
+    Reject,
+}
+
+/// A candidate shape, as detected, with the decision made about it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReviewShape {
+    /// The id the shape is labeled with in the `preview` shapes image
+    pub id: usize,
+    pub class: String,
+    pub confidence: f64,
+    pub decision: Decision,
+    /// What an accepted shape becomes, `room` unless reclassified as a `door`,
+    /// `window`, or `light`
+    pub label: String,
+    /// Vertices in image pixels
+    pub vertices: Vec<[f64; 2]>,
+}
+
+impl ReviewShape {
+    /// A room, accepted when detected with enough confidence and rejected otherwise.
+    pub fn new(
+        id: usize,
+        class: &str,
+        confidence: f64,
+        vertices: &[Vector2],
+        min_confidence: f64,
+    ) -> Self {
+        Self {
+            id,
+            class: class.to_string(),
+            // Two decimals are plenty to judge by and keep the file readable
+            confidence: (confidence * 100.0).round() / 100.0,
+            decision: if confidence >= min_confidence {
+                Decision::Accept
+            } else {
+                Decision::Reject
+            },
+            label: "room".to_string(),
+            vertices: vertices.iter().map(|p| [p.x, p.y]).collect(),
+        }
+    }
+}
+
+/// Every candidate shape detected in an image, written by `--emit-review`,
+/// edited by hand, and read back by `--apply-review`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Review {
+    pub image: String,
+    pub shapes: Vec<ReviewShape>,
+}
+
+impl Review {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let review: Review = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if let Some(shape) = review
+            .shapes
+            .iter()
+            .find(|shape| !is_known_label(&shape.label))
+        {
+            return Err(format!(
+                "shape #{} of {} has unknown label '{}'",
+                shape.id,
+                path.display(),
+                shape.label
+            )
+            .into());
+        }
+        Ok(review)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The accepted shapes, as what they are labeled.
+    pub fn annotations(&self, mapper: &CoordinateMapper) -> Annotations {
+        let mut annotations = Annotations::default();
+        for shape in self
+            .shapes
+            .iter()
+            .filter(|shape| shape.decision == Decision::Accept)
+        {
+            let points = shape
+                .vertices
+                .iter()
+                .map(|&[x, y]| Vector2 { x, y })
+                .collect();
+            annotations.add_polygon(&shape.label, points, mapper);
+        }
+        annotations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: f64) -> Vec<Vector2> {
+        [(x, 0.0), (x + 64.0, 0.0), (x + 64.0, 64.0), (x, 64.0)]
+            .iter()
+            .map(|&(x, y)| Vector2 { x, y })
+            .collect()
+    }
+
+    #[test]
+    fn test_review_shape() {
+        let shape = ReviewShape::new(3, "rectangle", 0.4567, &square(0.0), 0.5);
+        assert_eq!(shape.confidence, 0.46);
+        assert_eq!(shape.decision, Decision::Reject);
+        assert_eq!(shape.label, "room");
+        assert_eq!(shape.vertices[2], [64.0, 64.0]);
+        let shape = ReviewShape::new(3, "rectangle", 0.9, &square(0.0), 0.5);
+        assert_eq!(shape.decision, Decision::Accept);
+    }
+
+    #[test]
+    fn test_review_annotations() {
+        let mut review = Review {
+            image: "crypt.png".to_string(),
+            shapes: (0..4)
+                .map(|id| ReviewShape::new(id, "rectangle", 0.9, &square(id as f64 * 64.0), 0.5))
+                .collect(),
+        };
+        review.shapes[1].decision = Decision::Reject;
+        review.shapes[2].label = "door".to_string();
+        review.shapes[3].label = "light".to_string();
+
+        let annotations = review.annotations(&CoordinateMapper::new(64.0));
+        assert_eq!(annotations.rooms.len(), 1);
+        assert_eq!(annotations.doors.len(), 1);
+        assert_eq!(annotations.fixtures.len(), 1);
+        assert_eq!(
+            annotations.fixtures[0].position,
+            Vector2 { x: 896.0, y: 128.0 }
+        );
+
+        let edited: Review = serde_json::from_str(
+            r#"{ "image": "crypt.png", "shapes": [
+                { "id": 0, "class": "triangle", "confidence": 0.2, "decision": "accept",
+                  "label": "room", "vertices": [[0, 0], [64, 0], [0, 64]] }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            edited.annotations(&CoordinateMapper::new(64.0)).rooms.len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::f64::consts::TAU;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, instrument};
+
+use crate::analytics::{room_kind, RoomKind};
+use crate::coordinates::{cells_to_units, square_units_to_cells};
+use crate::dungeondraft_v1::{
+    append_node, is_loop, node_points, nodes, object_node, Vector2, UNITS_PER_CELL,
+};
+use crate::geometry::{boundary_distance, bounds, contains_point, polygon_area, signed_area};
+
+/// Decorations are kept at least this far from walls, in map units.
+const WALL_MARGIN: f64 = UNITS_PER_CELL / 4.0;
+/// How many times a decoration's position is drawn before giving up on it,
+/// for floors with little room away from their walls.
+const MAX_ATTEMPTS: usize = 32;
+/// The most decorations a cell gets on average, past which floors are buried
+/// and scattering them takes ages.
+const MAX_DENSITY: f64 = 16.0;
+
+/// Parses how many decorations a cell gets on average, from 0 to `MAX_DENSITY`.
+pub fn parse_density(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|density: &f64| (0.0..=MAX_DENSITY).contains(density))
+        .ok_or_else(|| {
+            format!(
+                "invalid density '{}', expected 0 to {} a cell",
+                s, MAX_DENSITY
+            )
+        })
+}
+
+/// The decorations scattered over one class of floor.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ScatterClass {
+    /// The textures of the decorations, each picked at random
+    pub assets: Vec<String>,
+    /// How many decorations a cell gets on average
+    pub density: f64,
+}
+
+/// The decorations scattered over rooms, corridors, and the terrain outside
+/// them, from the `scatter` config.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Scatter {
+    /// The same seed scatters the same decorations
+    pub seed: u64,
+    pub room: ScatterClass,
+    pub corridor: ScatterClass,
+    pub terrain: ScatterClass,
+}
+
+impl Default for Scatter {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            room: ScatterClass {
+                assets: vec![
+                    "res://textures/objects/dungeon/rubble_01.png".to_string(),
+                    "res://textures/objects/dungeon/rubble_02.png".to_string(),
+                ],
+                density: 0.1,
+            },
+            corridor: ScatterClass {
+                assets: vec!["res://textures/objects/dungeon/crack_01.png".to_string()],
+                density: 0.05,
+            },
+            terrain: ScatterClass {
+                assets: vec![
+                    "res://textures/objects/nature/grass_tuft_01.png".to_string(),
+                    "res://textures/objects/nature/grass_tuft_02.png".to_string(),
+                ],
+                density: 0.25,
+            },
+        }
+    }
+}
+
+impl Scatter {
+    /// The scatter with every class given `density` decorations a cell.
+    pub fn with_density(mut self, density: f64) -> Self {
+        for class in [&mut self.room, &mut self.corridor, &mut self.terrain] {
+            class.density = density;
+        }
+        self
+    }
+
+    /// Checks the density of every class is in range, whether configured or
+    /// given as a flag.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, class) in [
+            ("room", &self.room),
+            ("corridor", &self.corridor),
+            ("terrain", &self.terrain),
+        ] {
+            if !(0.0..=MAX_DENSITY).contains(&class.density) {
+                return Err(format!(
+                    "invalid {} density {}, expected 0 to {} a cell",
+                    name, class.density, MAX_DENSITY
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn of(&self, kind: RoomKind) -> &ScatterClass {
+        match kind {
+            RoomKind::Room => &self.room,
+            RoomKind::Corridor => &self.corridor,
+        }
+    }
+}
+
+/// SplitMix64, a small seeded random number generator, so scattering needs
+/// no randomness from outside.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to 1.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// An index from 0 up to `len`.
+    fn index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+}
+
+/// How many decorations an area of `cells` gets, rounding the fraction up as
+/// often as it is of one.
+fn decoration_count(rng: &mut Rng, cells: f64, density: f64) -> usize {
+    let expected = (cells * density).max(0.0);
+    let count = expected.floor();
+    count as usize + usize::from(rng.next_f64() < expected - count)
+}
+
+/// Places up to `count` decorations of `class` at random within `bounds`
+/// where `fits`, turned and mirrored at random.
+fn place(
+    rng: &mut Rng,
+    class: &ScatterClass,
+    count: usize,
+    (min, max): (Vector2, Vector2),
+    fits: impl Fn(Vector2) -> bool,
+) -> Vec<Value> {
+    if class.assets.is_empty() {
+        return Vec::new();
+    }
+    let mut decorations = Vec::new();
+    for _ in 0..count {
+        let position = (0..MAX_ATTEMPTS)
+            .map(|_| Vector2 {
+                x: min.x + rng.next_f64() * (max.x - min.x),
+                y: min.y + rng.next_f64() * (max.y - min.y),
+            })
+            .find(|&p| fits(p));
+        let Some(position) = position else {
+            continue;
+        };
+        let texture = &class.assets[rng.index(class.assets.len())];
+        let mut node = object_node(position, texture);
+        node["rotation"] = (rng.next_f64() * TAU).into();
+        node["mirror"] = (rng.next_f64() < 0.5).into();
+        decorations.push(node);
+    }
+    decorations
+}
+
+/// Scatters decorations over the floors of the rooms and corridors walled in
+/// the ground level of a map, and over the terrain outside them, returning
+/// how many were placed.
+#[instrument(skip_all, fields(seed = settings.seed))]
+pub fn scatter(map: &mut Value, settings: &Scatter) -> Result<usize, Box<dyn std::error::Error>> {
+    settings.validate()?;
+    let mut rng = Rng(settings.seed);
+    // Walls looping around holes in floors run the other way
+    let floors: Vec<Vec<Vector2>> = nodes(&map["world"]["levels"]["0"], "walls")
+        .filter(|wall| is_loop(wall))
+        .filter_map(node_points)
+        .filter(|points| points.len() > 2 && signed_area(points) > 0.0)
+        .collect();
+    let mut decorations = Vec::new();
+    for floor in &floors {
+        let Some(area) = bounds(floor) else {
+            continue;
+        };
+        let class = settings.of(room_kind(floor));
+        let cells = square_units_to_cells(polygon_area(floor));
+        let count = decoration_count(&mut rng, cells, class.density);
+        decorations.extend(place(&mut rng, class, count, area, |p| {
+            contains_point(floor, p) && boundary_distance(floor, p) >= WALL_MARGIN
+        }));
+    }
+    let world = (
+        Vector2::default(),
+        Vector2 {
+            x: cells_to_units(map["world"]["width"].as_f64().unwrap_or_default()),
+            y: cells_to_units(map["world"]["height"].as_f64().unwrap_or_default()),
+        },
+    );
+    let floored: f64 = floors.iter().map(|floor| polygon_area(floor)).sum();
+    let cells = square_units_to_cells(world.1.x * world.1.y - floored);
+    let count = decoration_count(&mut rng, cells, settings.terrain.density);
+    decorations.extend(place(&mut rng, &settings.terrain, count, world, |p| {
+        floors
+            .iter()
+            .all(|floor| !contains_point(floor, p) && boundary_distance(floor, p) >= WALL_MARGIN)
+    }));
+    debug!("Scattering {} decorations", decorations.len());
+    let count = decorations.len();
+    for decoration in decorations {
+        append_node(map, "0", "objects", decoration)?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dungeondraft_v1::{wall_node, MapBuilder};
+
+    fn rectangle(width: f64, height: f64) -> Vec<Vector2> {
+        let (width, height) = (cells_to_units(width), cells_to_units(height));
+        vec![
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: width, y: 0.0 },
+            Vector2 {
+                x: width,
+                y: height,
+            },
+            Vector2 { x: 0.0, y: height },
+        ]
+    }
+
+    fn map() -> Value {
+        let mut builder = MapBuilder::new(10, 10).unwrap();
+        builder.add_node("walls", wall_node(&rectangle(4.0, 4.0), true));
+        builder.build()
+    }
+
+    #[test]
+    fn test_decoration_count() {
+        let mut rng = Rng(1);
+        assert_eq!(decoration_count(&mut rng, 20.0, 0.5), 10);
+        assert_eq!(decoration_count(&mut rng, 20.0, 0.0), 0);
+        let counts: usize = (0..1000)
+            .map(|_| decoration_count(&mut rng, 1.0, 0.25))
+            .sum();
+        assert!((200..300).contains(&counts));
+    }
+
+    #[test]
+    fn test_scatter() {
+        let settings = Scatter {
+            seed: 7,
+            ..Default::default()
+        }
+        .with_density(1.0);
+        let mut map = map();
+        let count = scatter(&mut map, &settings).unwrap();
+        let objects = map["world"]["levels"]["0"]["objects"].as_array().unwrap();
+        assert_eq!(objects.len(), count);
+        let room = rectangle(4.0, 4.0);
+        let in_room = objects
+            .iter()
+            .filter(|object| {
+                let position = object["position"].as_str().unwrap().parse().unwrap();
+                contains_point(&room, position)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(in_room.len(), 16);
+        assert!(in_room.iter().all(|object| settings
+            .room
+            .assets
+            .contains(&object["texture"].as_str().unwrap().to_string())));
+        // The same seed scatters the same decorations
+        let mut again = map();
+        scatter(&mut again, &settings).unwrap();
+        assert_eq!(
+            again["world"]["levels"]["0"]["objects"],
+            map["world"]["levels"]["0"]["objects"]
+        );
+    }
+
+    #[test]
+    fn test_validate_scatter() {
+        assert_eq!(Scatter::default().validate(), Ok(()));
+        assert_eq!(Scatter::default().with_density(0.0).validate(), Ok(()));
+        for density in [-0.5, 1e12, f64::INFINITY, f64::NAN] {
+            let settings = Scatter::default().with_density(density);
+            assert!(settings.validate().is_err(), "{}", density);
+            assert!(scatter(&mut map(), &settings).is_err());
+        }
+        assert_eq!(parse_density("0.5"), Ok(0.5));
+        assert!(parse_density("1e12").is_err());
+        assert!(parse_density("NaN").is_err());
+    }
+
+    #[test]
+    fn test_scatter_without_assets() {
+        let settings = Scatter {
+            terrain: ScatterClass::default(),
+            room: ScatterClass::default(),
+            ..Default::default()
+        };
+        let mut map = map();
+        assert_eq!(scatter(&mut map, &settings).unwrap(), 0);
+    }
+}
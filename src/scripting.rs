@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::Path;
+
+use rhai::{CallFnOptions, Dynamic, Engine, Scope, AST};
+use serde_json::Value;
+use tracing::debug;
+
+/// The function a script defines to filter or change detected shapes.
+const SHAPE_HOOK: &str = "shape";
+/// The function a script defines to filter or change generated nodes.
+const NODE_HOOK: &str = "node";
+
+/// A Rhai script of hooks run on detected shapes and generated nodes.
+///
+/// `fn shape(shape)` is given every detected shape, as a map of its `id`,
+/// `class`, `confidence`, `area` in cells, and `points` in cells, and
+/// `fn node(kind, node, index)` every node of a generated map, by collection
+/// such as `walls` or `lights` and its index in it. Either hook returns `false`
+/// to delete what it is given, a changed map to replace it, or anything else
+/// to keep it as it is.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    source: String,
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let source = fs::read_to_string(path)?;
+        Self::compile(&source).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn compile(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let engine = Engine::new();
+        let ast = engine.compile(source)?;
+        Ok(Self {
+            engine,
+            ast,
+            source: source.to_string(),
+        })
+    }
+
+    fn defines(&self, name: &str, arity: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|function| function.name == name && function.params.len() == arity)
+    }
+
+    /// Calls a hook with a JSON value, returning what replaces it, if anything.
+    fn call(
+        &self,
+        name: &str,
+        value: &Value,
+        extra: impl FnOnce(Dynamic) -> Vec<Dynamic>,
+    ) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        let args = extra(rhai::serde::to_dynamic(value)?);
+        // Only the hook runs, not the statements at the top of the script
+        let options = CallFnOptions::new().eval_ast(false);
+        let result: Dynamic =
+            self.engine
+                .call_fn_with_options(options, &mut Scope::new(), &self.ast, name, args)?;
+        if result.as_bool() == Ok(false) {
+            Ok(None)
+        } else if result.is_map() {
+            Ok(Some(rhai::serde::from_dynamic(&result)?))
+        } else {
+            Ok(Some(value.clone()))
+        }
+    }
+
+    pub fn has_shape_hook(&self) -> bool {
+        self.defines(SHAPE_HOOK, 1)
+    }
+
+    /// Runs the shape hook on a shape, returning what replaces it, if anything.
+    pub fn shape(&self, shape: &Value) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        self.call(SHAPE_HOOK, shape, |shape| vec![shape])
+    }
+
+    /// Runs the node hook on every node of every level of a map.
+    pub fn nodes(&self, map: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.defines(NODE_HOOK, 3) {
+            return Ok(());
+        }
+        let Some(levels) = map["world"]["levels"].as_object_mut() else {
+            return Ok(());
+        };
+        for level in levels.values_mut() {
+            let Some(collections) = level.as_object_mut() else {
+                continue;
+            };
+            for (kind, nodes) in collections.iter_mut() {
+                let Some(nodes) = nodes.as_array_mut() else {
+                    continue;
+                };
+                let mut kept = Vec::with_capacity(nodes.len());
+                for (index, node) in nodes.iter().enumerate() {
+                    match self.call(NODE_HOOK, node, |node| {
+                        vec![kind.as_str().into(), node, (index as i64).into()]
+                    })? {
+                        Some(node) => kept.push(node),
+                        None => debug!("Script deleted {} node {}", kind, index),
+                    }
+                }
+                *nodes = kept;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_shape_hook() {
+        let script = Script::compile(
+            "fn shape(s) { if s.area < 2.0 { return false; } s.class = \"room\"; s }",
+        )
+        .unwrap();
+        assert!(script.has_shape_hook());
+        assert_eq!(script.shape(&json!({ "area": 1.5 })).unwrap(), None);
+        assert_eq!(
+            script
+                .shape(&json!({ "area": 4.0, "class": "polygon" }))
+                .unwrap(),
+            Some(json!({ "area": 4.0, "class": "room" }))
+        );
+    }
+
+    #[test]
+    fn test_node_hook() {
+        let script = Script::compile(
+            r#"
+            fn node(kind, node, index) {
+                if kind == "lights" && index % 3 == 2 {
+                    node.intensity = 0.5;
+                    return node;
+                }
+                kind != "portals"
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(!script.has_shape_hook());
+        let mut map = json!({ "world": { "levels": { "0": {
+            "lights": [{ "intensity": 1.0 }, { "intensity": 1.0 }, { "intensity": 1.0 }],
+            "portals": [{ "radius": 128 }],
+            "environment": { "baked_lighting": true }
+        } } } });
+        script.nodes(&mut map).unwrap();
+        let level = &map["world"]["levels"]["0"];
+        assert_eq!(level["lights"][1]["intensity"], 1.0);
+        assert_eq!(level["lights"][2]["intensity"], 0.5);
+        assert_eq!(level["portals"], json!([]));
+        assert_eq!(level["environment"]["baked_lighting"], true);
+    }
+
+    #[test]
+    fn test_invalid_script() {
+        assert!(Script::compile("fn shape(s) {").is_err());
+    }
+}
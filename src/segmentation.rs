@@ -0,0 +1,302 @@
+use std::path::Path;
+
+use opencv::core::{self, Mat, Scalar, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+use tracing::{debug, instrument};
+use tract_onnx::prelude::*;
+
+use crate::coordinates::CoordinateMapper;
+use crate::doors::Door;
+use crate::dungeondraft_v1::Vector2;
+use crate::images::trace_outlines;
+
+/// The width and height of the images a segmentation model is given.
+const SEGMENTATION_SIZE: usize = 512;
+/// Regions of less than this share of a cell are noise in the segmentation.
+const MIN_REGION_AREA: f64 = 0.25;
+/// Outlines of regions are simplified to within this share of their perimeter.
+const REGION_EPSILON: f64 = 0.01;
+
+/// What a segmentation model classifies each pixel of a dungeon as, in the
+/// order of the channels of its output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentClass {
+    Wall,
+    Floor,
+    Door,
+    Water,
+}
+
+impl SegmentClass {
+    const ALL: [SegmentClass; 4] = [
+        SegmentClass::Wall,
+        SegmentClass::Floor,
+        SegmentClass::Door,
+        SegmentClass::Water,
+    ];
+}
+
+/// The regions a segmentation model finds in a dungeon, in map units.
+#[derive(Debug, Default)]
+pub struct Segments {
+    /// The floors of rooms, walled around their outlines
+    pub floors: Vec<Vec<Vector2>>,
+    pub doors: Vec<Door>,
+    pub water: Vec<Vec<Vector2>>,
+}
+
+/// An ONNX model of a dungeon's pixels, detecting its features in place of
+/// edge detection on maps drawn too artistically for it.
+///
+/// The model is given a 1x3x512x512 tensor of the image's RGB from 0 to 1, and
+/// returns a 1xCxHxW tensor of scores for the classes of `SegmentClass` in
+/// order, each pixel the class scoring highest.
+pub struct SegmentationModel {
+    name: String,
+    model: Arc<TypedRunnableModel>,
+}
+
+impl std::fmt::Debug for SegmentationModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SegmentationModel")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl PartialEq for SegmentationModel {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+/// Loads an ONNX model taking a square RGB image `size` pixels wide.
+pub fn load_model(
+    path: &Path,
+    size: usize,
+) -> Result<Arc<TypedRunnableModel>, Box<dyn std::error::Error>> {
+    Ok(tract_onnx::onnx()
+        .model_for_path(path)
+        .and_then(|model| {
+            model
+                .with_input_fact(0, f32::fact([1, 3, size, size]).into())?
+                .into_optimized()?
+                .into_runnable()
+        })
+        .map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+/// An image resized to a square `size` pixels wide, as a 1x3xSxS tensor of
+/// its RGB from 0 to 1.
+pub fn input_tensor(image: &Mat, size: usize) -> Result<Tensor, Box<dyn std::error::Error>> {
+    let mut resized = Mat::default();
+    imgproc::resize(
+        image,
+        &mut resized,
+        Size::new(size as i32, size as i32),
+        0.0,
+        0.0,
+        imgproc::INTER_AREA,
+    )?;
+    let mut rgb = Mat::default();
+    imgproc::cvt_color(&resized, &mut rgb, imgproc::COLOR_BGR2RGB, 0)?;
+    let pixels = rgb.data_bytes()?;
+    Ok(
+        tract_ndarray::Array4::from_shape_fn((1, 3, size, size), |(_, channel, y, x)| {
+            pixels[(y * size + x) * 3 + channel] as f32 / 255.0
+        })
+        .into(),
+    )
+}
+
+impl SegmentationModel {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            name: path.display().to_string(),
+            model: load_model(path, SEGMENTATION_SIZE)?,
+        })
+    }
+
+    /// The class of every pixel of an image, as a mask of their indices into
+    /// `SegmentClass::ALL` the size of the image.
+    fn classify(&self, image: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+        let input = input_tensor(image, SEGMENTATION_SIZE)?;
+        let outputs = self.model.run(tvec!(input.into()))?;
+        let scores = outputs[0].to_plain_array_view::<f32>()?;
+        let &[1, classes, rows, cols] = scores.shape() else {
+            return Err(format!(
+                "{} returned scores of shape {:?}",
+                self.name,
+                scores.shape()
+            )
+            .into());
+        };
+        if classes < SegmentClass::ALL.len() {
+            return Err(format!(
+                "{} scores {} classes, expected {}",
+                self.name,
+                classes,
+                SegmentClass::ALL.len()
+            )
+            .into());
+        }
+        let scores = scores
+            .as_slice()
+            .ok_or("segmentation scores are not contiguous")?;
+        let labels = best_classes(scores, SegmentClass::ALL.len(), rows * cols);
+        let labels = Mat::from_slice(&labels)?.reshape(1, rows as i32)?;
+        let mut classified = Mat::default();
+        imgproc::resize(
+            &labels,
+            &mut classified,
+            image.size()?,
+            0.0,
+            0.0,
+            imgproc::INTER_NEAREST,
+        )?;
+        Ok(classified)
+    }
+
+    /// Finds the floors, doors, and water of a dungeon image.
+    #[instrument(skip_all)]
+    pub fn segment(
+        &self,
+        image: &Mat,
+        mapper: &CoordinateMapper,
+    ) -> Result<Segments, Box<dyn std::error::Error>> {
+        let classified = self.classify(image)?;
+        let min_area = MIN_REGION_AREA * mapper.pixels_per_cell * mapper.pixels_per_cell;
+        let regions = |class: SegmentClass| -> Result<_, Box<dyn std::error::Error>> {
+            let index = SegmentClass::ALL
+                .iter()
+                .position(|c| *c == class)
+                .unwrap_or(0);
+            let mut mask = Mat::default();
+            core::compare(
+                &classified,
+                &Scalar::all(index as f64),
+                &mut mask,
+                core::CMP_EQ,
+            )?;
+            let outlines = trace_outlines(
+                &mask,
+                imgproc::RETR_EXTERNAL,
+                min_area,
+                REGION_EPSILON,
+                mapper,
+            )?;
+            Ok(outlines
+                .into_iter()
+                .filter(|outline| outline.len() > 2)
+                .collect::<Vec<_>>())
+        };
+        let segments = Segments {
+            floors: regions(SegmentClass::Floor)?,
+            doors: regions(SegmentClass::Door)?
+                .iter()
+                .map(|outline| door_across(outline))
+                .collect(),
+            water: regions(SegmentClass::Water)?,
+        };
+        debug!(
+            "Segmented {} floors, {} doors, and {} pools of water",
+            segments.floors.len(),
+            segments.doors.len(),
+            segments.water.len()
+        );
+        Ok(segments)
+    }
+}
+
+/// The index of the class scoring highest at every pixel, from scores laid
+/// out a class at a time.
+pub fn best_classes(scores: &[f32], classes: usize, pixels: usize) -> Vec<u8> {
+    (0..pixels)
+        .map(|pixel| {
+            (0..classes)
+                .max_by(|&a, &b| scores[a * pixels + pixel].total_cmp(&scores[b * pixels + pixel]))
+                .unwrap_or(0) as u8
+        })
+        .collect()
+}
+
+/// A door spanning the long side of the region it was segmented as, its leaf
+/// swung open to the side.
+pub fn door_across(outline: &[Vector2]) -> Door {
+    let (mut min, mut max) = (outline[0], outline[0]);
+    for p in outline {
+        min = Vector2 {
+            x: min.x.min(p.x),
+            y: min.y.min(p.y),
+        };
+        max = Vector2 {
+            x: max.x.max(p.x),
+            y: max.y.max(p.y),
+        };
+    }
+    let center = Vector2 {
+        x: (min.x + max.x) / 2.0,
+        y: (min.y + max.y) / 2.0,
+    };
+    let (width, height) = (max.x - min.x, max.y - min.y);
+    if width >= height {
+        let hinge = Vector2 {
+            x: min.x,
+            y: center.y,
+        };
+        Door {
+            hinge,
+            latch: Vector2 {
+                x: max.x,
+                y: center.y,
+            },
+            leaf: Vector2 {
+                x: hinge.x,
+                y: hinge.y + width,
+            },
+            secret: false,
+        }
+    } else {
+        let hinge = Vector2 {
+            x: center.x,
+            y: min.y,
+        };
+        Door {
+            hinge,
+            latch: Vector2 {
+                x: center.x,
+                y: max.y,
+            },
+            leaf: Vector2 {
+                x: hinge.x + height,
+                y: hinge.y,
+            },
+            secret: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_best_classes() {
+        // Two pixels scored for three classes, a class at a time
+        let scores = [0.1, 0.9, 0.5, 0.0, 0.2, 0.95];
+        assert_eq!(best_classes(&scores, 3, 2), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_door_across() {
+        let outline = [(0.0, 0.0), (256.0, 0.0), (256.0, 64.0), (0.0, 64.0)]
+            .iter()
+            .map(|&(x, y)| Vector2 { x, y })
+            .collect::<Vec<_>>();
+        let door = door_across(&outline);
+        assert_eq!(door.hinge, Vector2 { x: 0.0, y: 32.0 });
+        assert_eq!(door.latch, Vector2 { x: 256.0, y: 32.0 });
+        assert_eq!(door.leaf, Vector2 { x: 0.0, y: 288.0 });
+    }
+}
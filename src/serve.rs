@@ -0,0 +1,338 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use opencv::core::Vector;
+use opencv::imgcodecs::imencode;
+use opencv::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{error, info, info_span};
+
+use crate::biome_map::BiomeRule;
+use crate::dungeon::{try_generate_dungeon, DungeonOptions};
+use crate::dungeondraft_v1::serialize_map;
+use crate::images::read_image;
+use crate::remote::{extension_for, MAX_DOWNLOAD_BYTES};
+use crate::render::render_map;
+use crate::town::try_generate_town;
+use crate::uvtt::to_uvtt;
+use crate::world::try_generate_world;
+
+/// Pixels per grid cell of the map image sent with Universal VTT files made from GeoJSON.
+const UVTT_PIXELS_PER_CELL: f64 = 64.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    DungeondraftMap,
+    Uvtt,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::DungeondraftMap => "dungeondraft_map",
+            Format::Uvtt => "dd2vtt",
+        }
+    }
+}
+
+/// Decodes a key or value of a query string, with `+` for spaces and `%XX`
+/// for escaped bytes of UTF-8.
+fn percent_decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let escaped = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid escape in '{}'", s))?;
+                rest = &rest[2..];
+                escaped
+            }
+            _ => byte,
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| format!("invalid UTF-8 in '{}'", s))
+}
+
+/// The parameters of a `POST /generate` request, given in its query string.
+#[derive(Debug, PartialEq)]
+pub struct GenerateRequest {
+    pub mode: String,
+    pub format: Format,
+    pub dungeon: DungeonOptions,
+    pub buildings: Vec<String>,
+}
+
+impl GenerateRequest {
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        let mut request = GenerateRequest {
+            mode: "dungeon".to_string(),
+            format: Format::DungeondraftMap,
+            dungeon: DungeonOptions::default(),
+            buildings: Vec::new(),
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let (key, value) = (percent_decode(key)?, percent_decode(value)?);
+            let value = value.as_str();
+            match key.as_str() {
+                "mode" => request.mode = value.to_string(),
+                "format" => {
+                    request.format = match value {
+                        "dungeondraft_map" => Format::DungeondraftMap,
+                        "uvtt" => Format::Uvtt,
+                        _ => return Err(format!("unknown format '{}'", value)),
+                    }
+                }
+                "pixels_per_cell" => {
+                    request.dungeon.pixels_per_cell = value
+                        .parse()
+                        .map_err(|_| format!("invalid pixels_per_cell '{}'", value))?
+                }
+                "min_speckle_area" => {
+                    request.dungeon.min_speckle_area = value
+                        .parse()
+                        .map_err(|_| format!("invalid min_speckle_area '{}'", value))?
+                }
+                "min_confidence" => {
+                    request.dungeon.min_confidence = value
+                        .parse()
+                        .map_err(|_| format!("invalid min_confidence '{}'", value))?
+                }
+                "building" => request.buildings.push(value.to_string()),
+                _ => return Err(format!("unknown parameter '{}'", key)),
+            }
+        }
+        request.validate()?;
+        Ok(request)
+    }
+
+    /// Checks the mode is known and the options are in range, whether the
+    /// request came over HTTP or from a job file.
+    pub fn validate(&self) -> Result<(), String> {
+        if !matches!(self.mode.as_str(), "dungeon" | "world" | "town") {
+            return Err(format!("unknown mode '{}'", self.mode));
+        }
+        let pixels = self.dungeon.pixels_per_cell;
+        if pixels <= 0.0 || !pixels.is_finite() {
+            return Err(format!("invalid pixels_per_cell '{}'", pixels));
+        }
+        if !(0.0..=1.0).contains(&self.dungeon.min_confidence) {
+            return Err(format!(
+                "invalid min_confidence '{}', expected 0 to 1",
+                self.dungeon.min_confidence
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A failed request, with the HTTP status it is answered with.
+struct Failure(u16, String);
+
+type Reply = Response<Cursor<Vec<u8>>>;
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn failure(Failure(status, message): Failure) -> Reply {
+    Response::from_string(format!("{}\n", message))
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+/// Saves the uploaded body to a temporary file named for its content type.
+fn receive_upload(request: &mut Request, upload: &Path) -> Result<std::path::PathBuf, Failure> {
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .map(|h| {
+            h.value
+                .as_str()
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        })
+        .unwrap_or_default();
+    let extension = extension_for(&content_type)
+        .ok_or_else(|| Failure(415, format!("unsupported content type '{}'", content_type)))?;
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > MAX_DOWNLOAD_BYTES)
+    {
+        return Err(Failure(413, "upload is too large".to_string()));
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_DOWNLOAD_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| Failure(400, format!("failed to read upload: {}", e)))?;
+    if body.len() as u64 > MAX_DOWNLOAD_BYTES {
+        return Err(Failure(413, "upload is too large".to_string()));
+    }
+    let path = upload.with_extension(extension);
+    fs::write(&path, body).map_err(|e| Failure(500, e.to_string()))?;
+    Ok(path)
+}
+
+/// The PNG image sent with a Universal VTT file, and its pixels per grid cell.
+fn uvtt_image(input: &Path, map: &Value) -> Result<(Vec<u8>, u32), Box<dyn std::error::Error>> {
+    let width = map["world"]["width"].as_f64().unwrap_or(1.0).max(1.0);
+    let mut image = read_image(input, opencv::imgcodecs::IMREAD_COLOR).unwrap_or_default();
+    if image.empty() {
+        image = render_map(map, UVTT_PIXELS_PER_CELL, None)?;
+    }
+    let mut png = Vector::<u8>::new();
+    imencode(".png", &image, &mut png, &Vector::new())?;
+    Ok((png.to_vec(), (image.cols() as f64 / width).round() as u32))
+}
+
+/// Generates the map of an input file in the requested mode and format.
+pub fn generate_output(
+    input: &Path,
+    params: &GenerateRequest,
+    biomes: &[BiomeRule],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let map = match params.mode.as_str() {
+        "world" => try_generate_world(input, None, None, biomes, &params.dungeon.textures)?,
+        "town" => try_generate_town(
+            input,
+            &params.buildings,
+            &params.dungeon.textures,
+            &params.dungeon.wall_styles,
+        )?,
+        _ => try_generate_dungeon(input, &params.dungeon)?,
+    };
+    let mut body = Vec::new();
+    match params.format {
+        Format::DungeondraftMap => serialize_map(&mut body, &map)?,
+        Format::Uvtt => {
+            let (image, pixels_per_grid) = uvtt_image(input, &map)?;
+            serde_json::to_writer(&mut body, &to_uvtt(&map, pixels_per_grid, &image))?;
+        }
+    }
+    Ok(body)
+}
+
+fn generate(request: &mut Request, upload: &Path, biomes: &[BiomeRule]) -> Result<Reply, Failure> {
+    let query = request.url().split_once('?').map_or("", |(_, query)| query);
+    let params = GenerateRequest::from_query(query).map_err(|e| Failure(400, e))?;
+    let input = receive_upload(request, upload)?;
+    let result = generate_output(&input, &params, biomes);
+    let _ = fs::remove_file(&input);
+    let body = result.map_err(|e| Failure(422, format!("failed to generate the map: {}", e)))?;
+    Ok(Response::from_data(body)
+        .with_header(header("Content-Type", "application/json"))
+        .with_header(header(
+            "Content-Disposition",
+            &format!("attachment; filename=\"map.{}\"", params.format.extension()),
+        )))
+}
+
+/// Serves map generation over HTTP until the process is stopped.
+///
+/// `POST /generate` takes an image or GeoJSON upload, with the same options as
+/// the generate subcommand in its query string, and answers with the map.
+/// `GET /health` answers when the service is up.
+pub fn serve(
+    bind: IpAddr,
+    port: u16,
+    biomes: &[BiomeRule],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let address = SocketAddr::new(bind, port);
+    let server = Server::http(address).map_err(|e| e.to_string())?;
+    info!("Listening on {}", address);
+    let upload =
+        std::env::temp_dir().join(format!("dungeondraft-generator-{}", std::process::id()));
+    for (id, mut request) in server.incoming_requests().enumerate() {
+        let path = request
+            .url()
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let _span = info_span!("request", id, path = %path).entered();
+        let reply = match (request.method(), path.as_str()) {
+            (Method::Get, "/health") => Response::from_string("ok\n"),
+            (Method::Post, "/generate") => {
+                generate(&mut request, &upload, biomes).unwrap_or_else(failure)
+            }
+            _ => failure(Failure(404, format!("no such endpoint {}", path))),
+        };
+        info!(
+            "{} {} {}",
+            request.method(),
+            request.url(),
+            reply.status_code().0
+        );
+        if let Err(e) = request.respond(reply) {
+            error!("failed to respond: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_request() {
+        let request = GenerateRequest::from_query(
+            "mode=town&format=uvtt&building=house.png&building=shop.png",
+        )
+        .unwrap();
+        assert_eq!(request.mode, "town");
+        assert_eq!(request.format, Format::Uvtt);
+        assert_eq!(request.buildings, vec!["house.png", "shop.png"]);
+
+        let request = GenerateRequest::from_query(
+            "pixels_per_cell=100&min_speckle_area=0&min_confidence=0.8",
+        )
+        .unwrap();
+        assert_eq!(request.mode, "dungeon");
+        assert_eq!(request.dungeon.pixels_per_cell, 100.0);
+        assert_eq!(request.dungeon.min_speckle_area, 0);
+        assert_eq!(request.dungeon.min_confidence, 0.8);
+        assert_eq!(
+            GenerateRequest::from_query("").unwrap().format,
+            Format::DungeondraftMap
+        );
+    }
+
+    #[test]
+    fn test_invalid_generate_request() {
+        assert!(GenerateRequest::from_query("mode=space").is_err());
+        assert!(GenerateRequest::from_query("format=pdf").is_err());
+        assert!(GenerateRequest::from_query("pixels_per_cell=0").is_err());
+        assert!(GenerateRequest::from_query("min_confidence=2").is_err());
+        assert!(GenerateRequest::from_query("colour=red").is_err());
+        assert!(GenerateRequest::from_query("building=%zz").is_err());
+        assert!(GenerateRequest::from_query("building=%ff").is_err());
+    }
+
+    #[test]
+    fn test_decoded_generate_request() {
+        let request = GenerateRequest::from_query(
+            "m%6Fde=town&building=old+house.png&building=caf%C3%A9%2Fsign.png",
+        )
+        .unwrap();
+        assert_eq!(request.mode, "town");
+        assert_eq!(request.buildings, vec!["old house.png", "café/sign.png"]);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+use opencv::core::Mat;
+use opencv::imgproc;
+use opencv::types::VectorOfVec4i;
+use serde_json::Value;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{object_node, Vector2};
+use crate::geometry::distance;
+use crate::preprocess::ink_mask;
+use crate::town::skeletonize;
+
+/// The texture staircases are placed as.
+pub const STAIRS_TEXTURE: &str = "res://textures/objects/stairs.png";
+/// Treads are at least this share of a cell long, shorter strokes being
+/// letters and specks.
+const MIN_TREAD_LENGTH: f64 = 0.3;
+/// Treads are at most this many cells long, longer lines being walls.
+const MAX_TREAD_LENGTH: f64 = 2.5;
+/// The treads of a flight are at most this share of a cell apart, further
+/// apart than grid lines are not.
+const MAX_TREAD_SPACING: f64 = 0.5;
+/// The treads of a flight are parallel within this many radians, about 10 degrees.
+const MAX_TREAD_ANGLE: f64 = 0.17;
+/// Neighbouring treads are at least this share of each other's length, and
+/// line up along at least as much of it.
+const MIN_TREAD_OVERLAP: f64 = 0.75;
+/// A flight has at least this many treads, fewer being hatching or lettering.
+const MIN_TREADS: usize = 4;
+
+/// A line drawn in the image, from one end to the other in pixels.
+type Segment = (Vector2, Vector2);
+
+/// A staircase drawn as a flight of short parallel treads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Staircase {
+    /// The middle of the flight in map units
+    pub position: Vector2,
+    /// The direction the flight runs across its treads, in radians
+    pub rotation: f64,
+    pub treads: usize,
+}
+
+impl Staircase {
+    /// A staircase from the corners of its flight in map units, the first
+    /// two along a tread and the last back across the treads to the first.
+    fn new(outline: &[Vector2], treads: usize) -> Self {
+        let count = outline.len().max(1) as f64;
+        let position = Vector2 {
+            x: outline.iter().map(|p| p.x).sum::<f64>() / count,
+            y: outline.iter().map(|p| p.y).sum::<f64>() / count,
+        };
+        let rotation = (outline[3].y - outline[0].y).atan2(outline[3].x - outline[0].x);
+        Self {
+            position,
+            rotation,
+            treads,
+        }
+    }
+
+    /// A stairs object in the middle of the flight, turned to run along it.
+    pub fn node(&self) -> Value {
+        let mut node = object_node(self.position, STAIRS_TEXTURE);
+        node["rotation"] = self.rotation.into();
+        node
+    }
+}
+
+fn midpoint(s: &Segment) -> Vector2 {
+    Vector2 {
+        x: (s.0.x + s.1.x) / 2.0,
+        y: (s.0.y + s.1.y) / 2.0,
+    }
+}
+
+/// The unit vector along a segment.
+fn direction(s: &Segment) -> (f64, f64) {
+    let length = distance(s.0, s.1).max(f64::EPSILON);
+    ((s.1.x - s.0.x) / length, (s.1.y - s.0.y) / length)
+}
+
+/// Whether two segments could be neighbouring treads of a flight: about as
+/// long and parallel, side by side, and at most `max_spacing` apart.
+fn are_treads(a: &Segment, b: &Segment, max_spacing: f64) -> bool {
+    let (length_a, length_b) = (distance(a.0, a.1), distance(b.0, b.1));
+    if length_a.min(length_b) < MIN_TREAD_OVERLAP * length_a.max(length_b) {
+        return false;
+    }
+    let ((ax, ay), (bx, by)) = (direction(a), direction(b));
+    // Parallel whichever way round they were found
+    if (ax * by - ay * bx).abs() > MAX_TREAD_ANGLE.sin() {
+        return false;
+    }
+    let (from, to) = (midpoint(a), midpoint(b));
+    let (dx, dy) = (to.x - from.x, to.y - from.y);
+    let along = (dx * ax + dy * ay).abs();
+    let across = (dy * ax - dx * ay).abs();
+    along <= (1.0 - MIN_TREAD_OVERLAP) * length_a && across <= max_spacing
+}
+
+/// Groups segments into flights, each segment a neighbouring tread of
+/// another of its flight, by the indices of the segments.
+fn cluster_treads(segments: &[Segment], max_spacing: f64) -> Vec<Vec<usize>> {
+    fn root(groups: &mut [usize], mut i: usize) -> usize {
+        while groups[i] != i {
+            groups[i] = groups[groups[i]];
+            i = groups[i];
+        }
+        i
+    }
+    let mut groups: Vec<usize> = (0..segments.len()).collect();
+    for a in 0..segments.len() {
+        for b in a + 1..segments.len() {
+            if are_treads(&segments[a], &segments[b], max_spacing) {
+                let (root_a, root_b) = (root(&mut groups, a), root(&mut groups, b));
+                groups[root_b] = root_a;
+            }
+        }
+    }
+    let mut flights: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..segments.len() {
+        let group = root(&mut groups, i);
+        flights.entry(group).or_default().push(i);
+    }
+    flights.into_values().collect()
+}
+
+/// The corners of the rectangle around a flight of treads, lined up with its
+/// longest tread, and how many treads it has, unless it has too few.
+fn flight_outline(treads: &[Segment], max_spacing: f64) -> Option<(Vec<Vector2>, usize)> {
+    let longest = treads
+        .iter()
+        .max_by(|a, b| distance(a.0, a.1).total_cmp(&distance(b.0, b.1)))?;
+    let along = direction(longest);
+    let across = (-along.1, along.0);
+    let project = |p: &Vector2, (x, y): (f64, f64)| p.x * x + p.y * y;
+    // A thick stroke is found as several lines side by side, one tread
+    let mut offsets: Vec<f64> = treads
+        .iter()
+        .map(|s| project(&midpoint(s), across))
+        .collect();
+    offsets.sort_by(f64::total_cmp);
+    let count = 1 + offsets
+        .windows(2)
+        .filter(|pair| pair[1] - pair[0] > max_spacing / 4.0)
+        .count();
+    if count < MIN_TREADS {
+        return None;
+    }
+    let points: Vec<Vector2> = treads.iter().flat_map(|s| [s.0, s.1]).collect();
+    let range = |axis: (f64, f64)| {
+        points
+            .iter()
+            .map(|p| project(p, axis))
+            .fold((f64::MAX, f64::MIN), |(min, max), v| {
+                (min.min(v), max.max(v))
+            })
+    };
+    let ((a0, a1), (n0, n1)) = (range(along), range(across));
+    // The treads of a flight line up end to end, while the strokes hatched
+    // along a wall drift along themselves from one to the next
+    if a1 - a0 > distance(longest.0, longest.1) / MIN_TREAD_OVERLAP {
+        return None;
+    }
+    let corner = |a: f64, n: f64| Vector2 {
+        x: a * along.0 + n * across.0,
+        y: a * along.1 + n * across.1,
+    };
+    Some((
+        vec![
+            corner(a0, n0),
+            corner(a1, n0),
+            corner(a1, n1),
+            corner(a0, n1),
+        ],
+        count,
+    ))
+}
+
+/// Finds staircases drawn as flights of short parallel treads, the hatching
+/// maps draw stairs with, and returns them in map units.
+#[instrument(skip_all)]
+pub fn detect_stairs(
+    image: &Mat,
+    mapper: &CoordinateMapper,
+) -> Result<Vec<Staircase>, Box<dyn std::error::Error>> {
+    let pixels_per_cell = mapper.pixels_per_cell;
+    let min_length = MIN_TREAD_LENGTH * pixels_per_cell;
+    let mut lines = VectorOfVec4i::new();
+    imgproc::hough_lines_p(
+        &skeletonize(&ink_mask(image)?)?,
+        &mut lines,
+        1.0,
+        PI / 180.0,
+        (min_length / 2.0).max(1.0) as i32,
+        min_length,
+        2.0,
+    )?;
+    let segments: Vec<Segment> = lines
+        .iter()
+        .map(|l| {
+            (
+                Vector2 {
+                    x: l[0] as f64,
+                    y: l[1] as f64,
+                },
+                Vector2 {
+                    x: l[2] as f64,
+                    y: l[3] as f64,
+                },
+            )
+        })
+        .filter(|s| distance(s.0, s.1) <= MAX_TREAD_LENGTH * pixels_per_cell)
+        .collect();
+    let max_spacing = MAX_TREAD_SPACING * pixels_per_cell;
+    let stairs: Vec<Staircase> = cluster_treads(&segments, max_spacing)
+        .iter()
+        .filter_map(|flight| {
+            let treads: Vec<Segment> = flight.iter().map(|&i| segments[i]).collect();
+            let (outline, count) = flight_outline(&treads, max_spacing)?;
+            Some(Staircase::new(&mapper.points(&outline), count))
+        })
+        .collect();
+    for staircase in &stairs {
+        debug!(
+            "Detected a staircase of {} treads at {}",
+            staircase.treads, staircase.position
+        );
+    }
+    Ok(stairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(x0: f64, y0: f64, x1: f64, y1: f64) -> Segment {
+        (Vector2 { x: x0, y: y0 }, Vector2 { x: x1, y: y1 })
+    }
+
+    #[test]
+    fn test_stair_flights() {
+        // Five treads a cell of 64 pixels wide and 12 apart, drawn either way
+        // round, a stroke beside one of them, and a wall running past
+        let mut segments: Vec<Segment> = (0..5)
+            .map(|i| {
+                let y = 100.0 + 12.0 * i as f64;
+                match i % 2 {
+                    0 => segment(10.0, y, 74.0, y),
+                    _ => segment(74.0, y + 1.0, 10.0, y),
+                }
+            })
+            .collect();
+        segments.push(segment(11.0, 101.0, 73.0, 101.0));
+        segments.push(segment(0.0, 20.0, 150.0, 20.0));
+
+        let flights = cluster_treads(&segments, 32.0);
+        assert_eq!(flights, vec![vec![0, 1, 2, 3, 4, 5], vec![6]]);
+
+        let treads: Vec<Segment> = flights[0].iter().map(|&i| segments[i]).collect();
+        let (outline, count) = flight_outline(&treads, 32.0).unwrap();
+        assert_eq!(count, 5);
+        let stairs = Staircase::new(&outline, count);
+        assert!((stairs.position.x - 42.0).abs() < 1.5);
+        assert!((stairs.position.y - 124.0).abs() < 1.5);
+        // Running down the image, across the treads
+        assert!((stairs.rotation.abs() - PI / 2.0).abs() < 0.05);
+
+        // Three treads are too few for a flight
+        assert_eq!(flight_outline(&treads[..3], 32.0), None);
+    }
+
+    #[test]
+    fn test_wall_hatching() {
+        // Strokes half a cell long slanting off a wall every 12 pixels, as
+        // walls are hatched, are parallel and close enough to be treads
+        let hatching: Vec<Segment> = (0..15)
+            .map(|i| {
+                let x = 10.0 + 12.0 * i as f64;
+                segment(x, 100.0, x + 16.0, 100.0 + 16.0 * 3f64.sqrt())
+            })
+            .collect();
+        let flights = cluster_treads(&hatching, 32.0);
+        assert_eq!(flights, vec![(0..15).collect::<Vec<_>>()]);
+        assert_eq!(flight_outline(&hatching, 32.0), None);
+    }
+}
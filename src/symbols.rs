@@ -0,0 +1,255 @@
+use std::path::Path;
+
+use opencv::core::Mat;
+use opencv::prelude::*;
+use serde::Deserialize;
+use tracing::{debug, instrument};
+use tract_onnx::prelude::*;
+
+use crate::coordinates::CoordinateMapper;
+use crate::doors::Door;
+use crate::dungeondraft_v1::Vector2;
+use crate::segmentation::{door_across, input_tensor, load_model};
+
+/// The width and height of the images a symbol detection model is given.
+const DETECTION_SIZE: usize = 640;
+/// Symbols detected with a lower score than this are left out.
+const MIN_SYMBOL_SCORE: f32 = 0.5;
+/// Boxes of the same class overlapping more than this are the same symbol.
+const MAX_OVERLAP: f32 = 0.45;
+/// The symbols detected unless the config says otherwise, in the order of the
+/// model's classes, with the textures they are placed as.
+const SYMBOLS: &[(&str, Option<&str>)] = &[
+    ("door", None),
+    ("stairs", Some("res://textures/objects/stairs.png")),
+    ("chest", Some("res://textures/objects/chest.png")),
+    ("statue", Some("res://textures/objects/statue.png")),
+    ("trap", Some("res://textures/objects/trap.png")),
+];
+
+/// A class of symbol a detection model finds, from the `symbols` config.
+///
+/// Each class is placed as an object of its `texture`, or as a portal when a
+/// `door`, or not at all when neither.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SymbolClass {
+    pub class: String,
+    #[serde(default)]
+    pub texture: Option<String>,
+    #[serde(default)]
+    pub door: bool,
+}
+
+/// The symbols detected without a config.
+pub fn default_symbols() -> Vec<SymbolClass> {
+    SYMBOLS
+        .iter()
+        .map(|&(class, texture)| SymbolClass {
+            class: class.to_string(),
+            texture: texture.map(str::to_string),
+            door: class == "door",
+        })
+        .collect()
+}
+
+/// A box a detection model finds a symbol in, in the pixels it was given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub class: usize,
+    pub score: f32,
+    pub center: Vector2,
+    pub size: Vector2,
+}
+
+impl Detection {
+    /// The share of the union of two boxes that they overlap.
+    fn overlap(&self, other: &Detection) -> f32 {
+        let span = |a: f64, a_size: f64, b: f64, b_size: f64| {
+            ((a + a_size / 2.0).min(b + b_size / 2.0) - (a - a_size / 2.0).max(b - b_size / 2.0))
+                .max(0.0)
+        };
+        let intersection = span(self.center.x, self.size.x, other.center.x, other.size.x)
+            * span(self.center.y, self.size.y, other.center.y, other.size.y);
+        let union = self.size.x * self.size.y + other.size.x * other.size.y - intersection;
+        (intersection / union.max(f64::EPSILON)) as f32
+    }
+}
+
+/// The boxes of a YOLO style output of `4 + classes` rows, the center, width,
+/// and height of each box then its score for every class, by as many columns as
+/// it has boxes. Boxes scoring less than `min_score` are left out, as are boxes
+/// overlapping a better scoring box of the same class.
+pub fn detections(output: &[f32], classes: usize, min_score: f32) -> Vec<Detection> {
+    let boxes = output.len() / (4 + classes).max(1);
+    let at = |row: usize, column: usize| output[row * boxes + column];
+    let mut found: Vec<Detection> = (0..boxes)
+        .filter_map(|column| {
+            let (class, score) = (0..classes)
+                .map(|class| (class, at(4 + class, column)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))?;
+            (score >= min_score).then(|| Detection {
+                class,
+                score,
+                center: Vector2 {
+                    x: at(0, column) as f64,
+                    y: at(1, column) as f64,
+                },
+                size: Vector2 {
+                    x: at(2, column) as f64,
+                    y: at(3, column) as f64,
+                },
+            })
+        })
+        .collect();
+    found.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut kept: Vec<Detection> = Vec::new();
+    for detection in found {
+        if !kept
+            .iter()
+            .any(|k| k.class == detection.class && k.overlap(&detection) > MAX_OVERLAP)
+        {
+            kept.push(detection);
+        }
+    }
+    kept
+}
+
+/// The symbols a detection model finds in a dungeon, in map units.
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// The textures of objects and where they are placed
+    pub objects: Vec<(String, Vector2)>,
+    pub doors: Vec<Door>,
+}
+
+/// A YOLO style ONNX model of the symbols drawn on maps, such as doors,
+/// stairs, chests, statues, and traps.
+///
+/// The model is given a 1x3x640x640 tensor of the image's RGB from 0 to 1, and
+/// returns a 1x(4+C)xN tensor of boxes, or its transpose, scored for each of
+/// the classes of the `symbols` config in order.
+pub struct SymbolDetector {
+    name: String,
+    model: Arc<TypedRunnableModel>,
+    classes: Vec<SymbolClass>,
+}
+
+impl std::fmt::Debug for SymbolDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SymbolDetector")
+            .field("name", &self.name)
+            .field("classes", &self.classes)
+            .finish()
+    }
+}
+
+impl PartialEq for SymbolDetector {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.classes == other.classes
+    }
+}
+
+impl SymbolDetector {
+    pub fn load(
+        path: &Path,
+        classes: Vec<SymbolClass>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            name: path.display().to_string(),
+            model: load_model(path, DETECTION_SIZE)?,
+            classes,
+        })
+    }
+
+    /// Finds the symbols drawn in a dungeon image.
+    #[instrument(skip_all)]
+    pub fn detect(
+        &self,
+        image: &Mat,
+        mapper: &CoordinateMapper,
+    ) -> Result<Symbols, Box<dyn std::error::Error>> {
+        let input = input_tensor(image, DETECTION_SIZE)?;
+        let outputs = self.model.run(tvec!(input.into()))?;
+        let mut output = outputs[0].to_plain_array_view::<f32>()?.to_owned();
+        let rows = 4 + self.classes.len();
+        match *output.shape() {
+            [1, r, _] if r == rows => {}
+            // Boxes by rows rather than columns
+            [1, _, r] if r == rows => output = output.permuted_axes(&[0, 2, 1][..]),
+            _ => {
+                return Err(format!(
+                    "{} returned boxes of shape {:?}, expected {} rows for {} classes",
+                    self.name,
+                    output.shape(),
+                    rows,
+                    self.classes.len()
+                )
+                .into())
+            }
+        }
+        let output: Vec<f32> = output.iter().copied().collect();
+        let scale = Vector2 {
+            x: image.cols() as f64 / DETECTION_SIZE as f64,
+            y: image.rows() as f64 / DETECTION_SIZE as f64,
+        };
+        let mut symbols = Symbols::default();
+        for detection in detections(&output, self.classes.len(), MIN_SYMBOL_SCORE) {
+            let class = &self.classes[detection.class];
+            let corner = |sx: f64, sy: f64| {
+                mapper.pixels_to_units(
+                    (detection.center.x + sx * detection.size.x / 2.0) * scale.x,
+                    (detection.center.y + sy * detection.size.y / 2.0) * scale.y,
+                )
+            };
+            debug!(
+                "Detected a {} with score {:.2} at {}",
+                class.class,
+                detection.score,
+                corner(0.0, 0.0)
+            );
+            if class.door {
+                let outline = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                    .map(|(sx, sy)| corner(sx, sy));
+                symbols.doors.push(door_across(&outline));
+            } else if let Some(texture) = &class.texture {
+                symbols.objects.push((texture.clone(), corner(0.0, 0.0)));
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detections() {
+        // Three boxes of two classes, a box to a column
+        #[rustfmt::skip]
+        let output = [
+            100.0, 104.0, 300.0,
+            100.0, 100.0, 300.0,
+            40.0, 40.0, 20.0,
+            40.0, 40.0, 20.0,
+            0.9, 0.8, 0.1,
+            0.1, 0.2, 0.3,
+        ];
+        let found = detections(&output, 2, 0.5);
+        // The second box is the same symbol as the first, and the third
+        // scores too low
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].class, 0);
+        assert_eq!(found[0].center, Vector2 { x: 100.0, y: 100.0 });
+        assert_eq!(detections(&output, 2, 0.25).len(), 2);
+    }
+
+    #[test]
+    fn test_default_symbols() {
+        let symbols = default_symbols();
+        assert!(symbols[0].door && symbols[0].texture.is_none());
+        assert!(symbols[1..]
+            .iter()
+            .all(|symbol| !symbol.door && symbol.texture.is_some()));
+    }
+}
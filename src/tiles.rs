@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+
+use opencv::core::{self, Mat, Size};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc;
+use opencv::prelude::*;
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::assets::AssetResolver;
+use crate::dungeondraft_v1::read_map;
+use crate::print::layout_pages;
+use crate::render::render_map;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const TILE_PATH: &str = "{z}/{x}/{y}.png";
+
+#[derive(Debug)]
+pub struct TileOptions {
+    pub tile_size: u32,
+    pub pixels_per_cell: f64,
+    /// Number of zoom levels, or enough to fit the map in one tile when `None`
+    pub zoom_levels: Option<u32>,
+}
+
+/// One zoom level of the pyramid, where the highest zoom is full resolution.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ZoomLevel {
+    pub zoom: u32,
+    pub width: i32,
+    pub height: i32,
+    pub columns: i32,
+    pub rows: i32,
+}
+
+/// Describes the pyramid written by `export tiles` for viewers to load.
+#[derive(Debug, Serialize)]
+pub struct TileManifest {
+    pub tile_size: u32,
+    pub width: i32,
+    pub height: i32,
+    pub pixels_per_cell: f64,
+    /// Path of each tile relative to the manifest
+    pub tiles: &'static str,
+    pub levels: Vec<ZoomLevel>,
+}
+
+/// Counts the zoom levels needed for the smallest level to fit in a single tile.
+pub fn default_zoom_levels(width: i32, height: i32, tile_size: u32) -> u32 {
+    let mut levels = 1;
+    let mut size = width.max(height) as f64;
+    while size > tile_size as f64 {
+        size /= 2.0;
+        levels += 1;
+    }
+    levels
+}
+
+/// Computes the size of every zoom level, halving the image for each level below the top.
+pub fn zoom_levels(width: i32, height: i32, tile_size: u32, count: u32) -> Vec<ZoomLevel> {
+    let tile_size = tile_size as i32;
+    (0..count)
+        .map(|zoom| {
+            let scale = 0.5f64.powi((count - 1 - zoom) as i32);
+            let width = ((width as f64 * scale).round() as i32).max(1);
+            let height = ((height as f64 * scale).round() as i32).max(1);
+            ZoomLevel {
+                zoom,
+                width,
+                height,
+                columns: (width + tile_size - 1) / tile_size,
+                rows: (height + tile_size - 1) / tile_size,
+            }
+        })
+        .collect()
+}
+
+/// Renders a map and splits it into a tile pyramid with a manifest in `output_dir`.
+///
+/// Returns the path of the manifest.
+pub fn try_export_tiles(
+    map_path: &Path,
+    output_dir: &Path,
+    options: &TileOptions,
+    resolver: Option<&AssetResolver>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let map = read_map(map_path)?;
+    let image = render_map(&map, options.pixels_per_cell, resolver)?;
+    let count = options
+        .zoom_levels
+        .unwrap_or_else(|| default_zoom_levels(image.cols(), image.rows(), options.tile_size));
+    if count == 0 {
+        return Err("at least one zoom level is required".into());
+    }
+    let levels = zoom_levels(image.cols(), image.rows(), options.tile_size, count);
+
+    let tile_size = options.tile_size as i32;
+    for level in &levels {
+        let mut scaled = Mat::default();
+        imgproc::resize(
+            &image,
+            &mut scaled,
+            Size::new(level.width, level.height),
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        let tiles = layout_pages(level.width, level.height, tile_size, tile_size);
+        info!(
+            "Writing {} tiles for zoom level {}",
+            tiles.len(),
+            level.zoom
+        );
+        for tile in tiles {
+            let dir = output_dir
+                .join(level.zoom.to_string())
+                .join(tile.col.to_string());
+            std::fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("{}.png", tile.row));
+            debug!("Writing {}", path.display());
+            imwrite(
+                path.to_str().ok_or("invalid output path")?,
+                &Mat::roi(&scaled, tile.rect)?,
+                &core::Vector::new(),
+            )?;
+        }
+    }
+
+    let manifest = TileManifest {
+        tile_size: options.tile_size,
+        width: image.cols(),
+        height: image.rows(),
+        pixels_per_cell: options.pixels_per_cell,
+        tiles: TILE_PATH,
+        levels,
+    };
+    let path = output_dir.join(MANIFEST_FILE);
+    std::fs::write(&path, serde_json::to_string_pretty(&manifest)?)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_zoom_levels() {
+        assert_eq!(default_zoom_levels(200, 100, 256), 1);
+        assert_eq!(default_zoom_levels(512, 300, 256), 2);
+        assert_eq!(default_zoom_levels(1000, 2000, 256), 4);
+    }
+
+    #[test]
+    fn test_zoom_levels() {
+        let levels = zoom_levels(1000, 600, 256, 3);
+        assert_eq!(levels.len(), 3);
+        assert_eq!(
+            levels[0],
+            ZoomLevel {
+                zoom: 0,
+                width: 250,
+                height: 150,
+                columns: 1,
+                rows: 1
+            }
+        );
+        assert_eq!((levels[1].width, levels[1].columns), (500, 2));
+        assert_eq!((levels[2].width, levels[2].height), (1000, 600));
+        assert_eq!((levels[2].columns, levels[2].rows), (4, 3));
+    }
+}
@@ -0,0 +1,358 @@
+use std::path::Path;
+
+use opencv::core::{self, Mat, Point, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::VectorOfVec4i;
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::coordinates::{units_to_cells, CoordinateMapper};
+use crate::dungeondraft_v1::{
+    object_node, path_node, roof_node, MapBuilder, Textures, Vector2, WallBuilder, WallStyles,
+    UNITS_PER_CELL,
+};
+use crate::geometry::bounds;
+use crate::images::{read_image, trace_outlines};
+
+/// Image pixels per grid cell for town maps.
+const TOWN_PIXELS_PER_CELL: f64 = 16.0;
+/// Pixels brighter than this are streets.
+const STREET_THRESHOLD: f64 = 200.0;
+/// Blocks smaller than one cell are noise.
+const MIN_BLOCK_AREA: f64 = TOWN_PIXELS_PER_CELL * TOWN_PIXELS_PER_CELL;
+/// Every cell is 5 ft when projecting GeoJSON coordinates.
+const METERS_PER_CELL: f64 = 1.524;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+const ROAD_WIDTH: f64 = UNITS_PER_CELL;
+
+/// Roads and building blocks in map units.
+#[derive(Debug, Default)]
+pub struct StreetNetwork {
+    /// Map size in cells
+    pub width: u32,
+    pub height: u32,
+    pub roads: Vec<Vec<Vector2>>,
+    pub blocks: Vec<Vec<Vector2>>,
+}
+
+fn lon_lat(value: &Value) -> Option<(f64, f64)> {
+    let coordinates = value.as_array()?;
+    Some((
+        coordinates.first()?.as_f64()?,
+        coordinates.get(1)?.as_f64()?,
+    ))
+}
+
+fn coordinates(value: &Value) -> Vec<(f64, f64)> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(lon_lat)
+        .collect()
+}
+
+/// The outer ring of a GeoJSON polygon, without the repeated closing point.
+fn outer_ring(polygon: &Value) -> Vec<(f64, f64)> {
+    let mut ring = coordinates(&polygon[0]);
+    if ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    ring
+}
+
+/// Reads roads from the line strings and blocks from the polygons of a GeoJSON
+/// feature collection, projecting longitude and latitude onto 5 ft cells.
+pub fn parse_geojson(value: &Value) -> Result<StreetNetwork, String> {
+    let features = value
+        .get("features")
+        .and_then(Value::as_array)
+        .ok_or("expected a GeoJSON FeatureCollection")?;
+    let (mut lines, mut polygons) = (Vec::new(), Vec::new());
+    for feature in features {
+        let geometry = &feature["geometry"];
+        let parts = geometry["coordinates"].as_array().into_iter().flatten();
+        match geometry["type"].as_str() {
+            Some("LineString") => lines.push(coordinates(&geometry["coordinates"])),
+            Some("MultiLineString") => lines.extend(parts.map(coordinates)),
+            Some("Polygon") => polygons.push(outer_ring(&geometry["coordinates"])),
+            Some("MultiPolygon") => polygons.extend(parts.map(outer_ring)),
+            _ => {}
+        }
+    }
+
+    let all: Vec<(f64, f64)> = lines.iter().chain(&polygons).flatten().copied().collect();
+    if all.is_empty() {
+        return Err("no streets or blocks found in GeoJSON".to_string());
+    }
+    let min_lon = all.iter().map(|c| c.0).fold(f64::INFINITY, f64::min);
+    let min_lat = all.iter().map(|c| c.1).fold(f64::INFINITY, f64::min);
+    let max_lat = all.iter().map(|c| c.1).fold(f64::NEG_INFINITY, f64::max);
+    // Degrees of longitude shrink away from the equator
+    let lon_scale = ((min_lat + max_lat) / 2.0).to_radians().cos();
+    let units_per_degree = METERS_PER_DEGREE / METERS_PER_CELL * UNITS_PER_CELL;
+    let project = |line: Vec<(f64, f64)>| -> Vec<Vector2> {
+        line.into_iter()
+            .map(|(lon, lat)| Vector2 {
+                x: (lon - min_lon) * lon_scale * units_per_degree,
+                y: (max_lat - lat) * units_per_degree,
+            })
+            .collect()
+    };
+
+    let roads: Vec<Vec<Vector2>> = lines.into_iter().map(project).collect();
+    let blocks: Vec<Vec<Vector2>> = polygons.into_iter().map(project).collect();
+    let all: Vec<Vector2> = roads.iter().chain(&blocks).flatten().copied().collect();
+    let (_, max) = bounds(&all).unwrap_or_default();
+    Ok(StreetNetwork {
+        width: units_to_cells(max.x).ceil().max(1.0) as u32,
+        height: units_to_cells(max.y).ceil().max(1.0) as u32,
+        roads,
+        blocks,
+    })
+}
+
+/// Thins a binary mask down to lines one pixel wide.
+pub fn skeletonize(mask: &Mat) -> Result<Mat, Box<dyn std::error::Error>> {
+    let element = imgproc::get_structuring_element(
+        imgproc::MORPH_CROSS,
+        Size::new(3, 3),
+        Point::new(-1, -1),
+    )?;
+    let border = imgproc::morphology_default_border_value()?;
+    let mut skeleton = Mat::zeros(mask.rows(), mask.cols(), core::CV_8UC1)?.to_mat()?;
+    let mut current = mask.try_clone()?;
+    while core::count_non_zero(&current)? > 0 {
+        let mut eroded = Mat::default();
+        imgproc::erode(
+            &current,
+            &mut eroded,
+            &element,
+            Point::new(-1, -1),
+            1,
+            core::BORDER_CONSTANT,
+            border,
+        )?;
+        let mut opened = Mat::default();
+        imgproc::dilate(
+            &eroded,
+            &mut opened,
+            &element,
+            Point::new(-1, -1),
+            1,
+            core::BORDER_CONSTANT,
+            border,
+        )?;
+        let mut residue = Mat::default();
+        core::subtract(&current, &opened, &mut residue, &core::no_array(), -1)?;
+        let mut joined = Mat::default();
+        core::bitwise_or(&skeleton, &residue, &mut joined, &core::no_array())?;
+        skeleton = joined;
+        current = eroded;
+    }
+    Ok(skeleton)
+}
+
+/// Finds bright streets and the dark blocks between them in a town map image.
+pub fn detect_streets(image: &Mat) -> Result<StreetNetwork, Box<dyn std::error::Error>> {
+    let mut gray = Mat::default();
+    imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    let mut streets = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut streets,
+        STREET_THRESHOLD,
+        255.0,
+        imgproc::THRESH_BINARY,
+    )?;
+    let mapper = CoordinateMapper::new(TOWN_PIXELS_PER_CELL);
+    let to_units = |p: Point| mapper.pixels_to_units(p.x as f64, p.y as f64);
+
+    let mut lines = VectorOfVec4i::new();
+    imgproc::hough_lines_p(
+        &skeletonize(&streets)?,
+        &mut lines,
+        1.0,
+        std::f64::consts::PI / 180.0,
+        20,
+        TOWN_PIXELS_PER_CELL,
+        TOWN_PIXELS_PER_CELL / 4.0,
+    )?;
+    let roads = lines
+        .iter()
+        .map(|l| {
+            vec![
+                to_units(Point::new(l[0], l[1])),
+                to_units(Point::new(l[2], l[3])),
+            ]
+        })
+        .collect();
+
+    let mut ground = Mat::default();
+    core::bitwise_not(&streets, &mut ground, &core::no_array())?;
+    let blocks = trace_outlines(
+        &ground,
+        imgproc::RETR_EXTERNAL,
+        MIN_BLOCK_AREA,
+        0.01,
+        &mapper,
+    )?;
+
+    let (width, height) = mapper.map_size(image.cols(), image.rows());
+    Ok(StreetNetwork {
+        width,
+        height,
+        roads,
+        blocks,
+    })
+}
+
+/// Turns roads into paths and blocks into walled, roofed building footprints.
+///
+/// When building textures are given, one is placed in the middle of each block in turn.
+/// The walls of the buildings take the boundary style of `walls`.
+pub fn build_town(
+    network: &StreetNetwork,
+    buildings: &[String],
+    textures: &Textures,
+    walls: &WallStyles,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut builder = MapBuilder::new(network.width, network.height)?;
+    for road in network.roads.iter().filter(|road| road.len() > 1) {
+        builder.add_node("paths", path_node(road, &textures.road, ROAD_WIDTH));
+    }
+    let blocks: Vec<&Vec<Vector2>> = network.blocks.iter().filter(|b| b.len() > 2).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        builder.add_wall(
+            WallBuilder::new()
+                .points(block)
+                .closed(true)
+                .texture(&textures.wall)
+                .style(&walls.boundary),
+        )?;
+        builder.add_node("roofs.roofs", roof_node(block, &textures.roof));
+        if buildings.is_empty() {
+            continue;
+        }
+        if let Some((min, max)) = bounds(block) {
+            let center = Vector2 {
+                x: (min.x + max.x) / 2.0,
+                y: (min.y + max.y) / 2.0,
+            };
+            builder.add_node(
+                "objects",
+                object_node(center, &buildings[i % buildings.len()]),
+            );
+        }
+    }
+    info!(
+        "Generated {} roads and {} buildings",
+        network.roads.len(),
+        blocks.len()
+    );
+    Ok(builder.build())
+}
+
+/// Generates a town map from a town image or a GeoJSON file of streets and blocks.
+pub fn try_generate_town(
+    input: &Path,
+    buildings: &[String],
+    textures: &Textures,
+    walls: &WallStyles,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let is_geojson = input
+        .extension()
+        .is_some_and(|ext| ext == "geojson" || ext == "json");
+    let network = if is_geojson {
+        debug!("Reading streets from {}", input.display());
+        let value: Value = serde_json::from_str(&std::fs::read_to_string(input)?)?;
+        parse_geojson(&value)?
+    } else {
+        debug!("Detecting streets in {}", input.display());
+        let image = read_image(input, opencv::imgcodecs::IMREAD_COLOR)?;
+        if image.empty() {
+            return Err(format!("failed to read image {}", input.display()).into());
+        }
+        detect_streets(&image)?
+    };
+    build_town(&network, buildings, textures, walls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dungeondraft_v1::WallStyle;
+    use serde_json::json;
+
+    fn geojson() -> Value {
+        json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "properties": { "highway": "residential" },
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [ [0.0, 0.0], [0.0001, 0.0] ]
+                    }
+                },
+                {
+                    "type": "Feature",
+                    "properties": { "building": "yes" },
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [ [
+                            [0.0, -0.0001], [0.0001, -0.0001], [0.0001, -0.0002],
+                            [0.0, -0.0002], [0.0, -0.0001]
+                        ] ]
+                    }
+                }
+            ]
+        })
+    }
+
+    #[test]
+    fn test_parse_geojson() {
+        let network = parse_geojson(&geojson()).unwrap();
+        assert_eq!(network.roads.len(), 1);
+        assert_eq!(network.blocks.len(), 1);
+        // The closing point is dropped
+        assert_eq!(network.blocks[0].len(), 4);
+        // 0.0001 degrees is about 11 m, or 8 cells at the equator
+        assert_eq!(network.width, 8);
+        assert_eq!(network.height, 15);
+        assert_eq!(network.roads[0][0], Vector2 { x: 0.0, y: 0.0 });
+    }
+
+    #[test]
+    fn test_parse_geojson_without_features() {
+        assert!(parse_geojson(&json!({ "type": "Feature" })).is_err());
+        assert!(parse_geojson(&json!({ "features": [] })).is_err());
+    }
+
+    #[test]
+    fn test_build_town() {
+        let network = parse_geojson(&geojson()).unwrap();
+        let buildings = vec!["res://textures/objects/house.png".to_string()];
+        let textures = Textures {
+            road: "res://packs/town/paths/cobbles.png".to_string(),
+            ..Default::default()
+        };
+        let walls = WallStyles {
+            boundary: WallStyle {
+                outline: Some("ff202020".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let map = build_town(&network, &buildings, &textures, &walls).unwrap();
+        let level = &map["world"]["levels"]["0"];
+        assert_eq!(level["paths"].as_array().unwrap().len(), 1);
+        assert_eq!(level["paths"][0]["texture"], textures.road.as_str());
+        assert_eq!(level["walls"][0]["loop"], true);
+        assert_eq!(level["walls"][0]["outline_color"], "ff202020");
+        assert_eq!(level["roofs"]["roofs"].as_array().unwrap().len(), 1);
+        assert_eq!(level["objects"][0]["texture"], buildings[0].as_str());
+    }
+}
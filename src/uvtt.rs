@@ -0,0 +1,140 @@
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::coordinates::units_to_cells;
+use crate::dungeondraft_v1::{is_loop, node_points, node_position, nodes, Vector2, UNITS_PER_CELL};
+
+/// The version of the Universal VTT format written.
+const UVTT_FORMAT: f64 = 0.3;
+const AMBIENT_LIGHT: &str = "ffffffff";
+
+fn grid_point(p: Vector2) -> Value {
+    json!({ "x": units_to_cells(p.x), "y": units_to_cells(p.y) })
+}
+
+/// The ends of the doorway a portal spans, across its width at its rotation.
+pub fn portal_span(portal: &Value) -> Option<(Vector2, Vector2)> {
+    let position = node_position(portal)?;
+    let rotation = portal
+        .get("rotation")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0);
+    let radius = portal
+        .get("radius")
+        .and_then(Value::as_f64)
+        .unwrap_or(UNITS_PER_CELL / 2.0);
+    let (dx, dy) = (rotation.cos() * radius, rotation.sin() * radius);
+    Some((
+        Vector2 {
+            x: position.x - dx,
+            y: position.y - dy,
+        },
+        Vector2 {
+            x: position.x + dx,
+            y: position.y + dy,
+        },
+    ))
+}
+
+/// Converts the first level of a map to Universal VTT, as read by Foundry,
+/// Arkenforge, and other virtual tabletops, with `image` as the PNG map image.
+pub fn to_uvtt(map: &Value, pixels_per_grid: u32, image: &[u8]) -> Value {
+    let world = &map["world"];
+    let level = &world["levels"]["0"];
+
+    let line_of_sight: Vec<Value> = nodes(level, "walls")
+        .filter_map(|wall| {
+            let mut points = node_points(wall)?;
+            if is_loop(wall) {
+                points.push(*points.first()?);
+            }
+            Some(Value::Array(points.into_iter().map(grid_point).collect()))
+        })
+        .collect();
+
+    let portals: Vec<Value> = nodes(level, "portals")
+        .chain(nodes(level, "walls").flat_map(|wall| nodes(wall, "portals")))
+        .filter_map(|portal| {
+            let (from, to) = portal_span(portal)?;
+            Some(json!({
+                "position": grid_point(node_position(portal)?),
+                "bounds": [grid_point(from), grid_point(to)],
+                "rotation": portal.get("rotation").and_then(Value::as_f64).unwrap_or(0.0),
+                "closed": portal.get("closed").and_then(Value::as_bool).unwrap_or(true),
+                "freestanding": portal.get("wall_id").is_none(),
+            }))
+        })
+        .collect();
+
+    let lights: Vec<Value> = nodes(level, "lights")
+        .filter_map(|light| {
+            Some(json!({
+                "position": grid_point(node_position(light)?),
+                "range": light.get("range").and_then(Value::as_f64).unwrap_or(1.0),
+                "intensity": light.get("intensity").and_then(Value::as_f64).unwrap_or(1.0),
+                "color": light.get("color").and_then(Value::as_str).unwrap_or(AMBIENT_LIGHT),
+                "shadows": light.get("shadows").and_then(Value::as_bool).unwrap_or(true),
+            }))
+        })
+        .collect();
+
+    json!({
+        "format": UVTT_FORMAT,
+        "resolution": {
+            "map_origin": { "x": 0, "y": 0 },
+            "map_size": { "x": world["width"], "y": world["height"] },
+            "pixels_per_grid": pixels_per_grid,
+        },
+        "line_of_sight": line_of_sight,
+        "portals": portals,
+        "lights": lights,
+        "environment": {
+            "baked_lighting": false,
+            "ambient_light": AMBIENT_LIGHT,
+        },
+        "image": base64::engine::general_purpose::STANDARD.encode(image),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_uvtt() {
+        let map = json!({
+            "world": {
+                "width": 4,
+                "height": 2,
+                "levels": {
+                    "0": {
+                        "walls": [
+                            {
+                                "loop": true,
+                                "points": "PoolVector2Array( 0, 0, 512, 0, 512, 512 )",
+                                "portals": [
+                                    { "wall_id": "0", "position": "Vector2( 256, 0 )", "rotation": 0, "radius": 128 }
+                                ]
+                            }
+                        ],
+                        "lights": [
+                            { "position": "Vector2( 256, 256 )", "range": 3, "color": "ffffb25c" }
+                        ]
+                    }
+                }
+            }
+        });
+        let uvtt = to_uvtt(&map, 64, b"png");
+        assert_eq!(uvtt["resolution"]["map_size"], json!({ "x": 4, "y": 2 }));
+        let wall = uvtt["line_of_sight"][0].as_array().unwrap();
+        assert_eq!(wall.len(), 4);
+        assert_eq!(wall[3], json!({ "x": 0.0, "y": 0.0 }));
+        assert_eq!(
+            uvtt["portals"][0]["bounds"],
+            json!([{ "x": 0.5, "y": 0.0 }, { "x": 1.5, "y": 0.0 }])
+        );
+        assert_eq!(uvtt["portals"][0]["freestanding"], false);
+        assert_eq!(uvtt["lights"][0]["position"], json!({ "x": 1.0, "y": 1.0 }));
+        assert_eq!(uvtt["image"], "cG5n");
+    }
+}
@@ -0,0 +1,324 @@
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+
+use crate::assets::{asset_references, pack_id, AssetResolver};
+use crate::dungeondraft_v1::{id_string, node_id, node_points};
+use crate::geometry::polygon_area;
+use crate::pack::asset_name;
+
+/// Wall id used by portals that are not attached to a wall.
+const NO_WALL_ID: &str = "-1";
+const MIN_PATTERN_AREA: f64 = 1e-6;
+
+#[derive(Debug, PartialEq)]
+pub enum IssueKind {
+    MissingTagNode,
+    DetachedPortal,
+    ZeroAreaPattern,
+    MissingAsset,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub level: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            IssueKind::MissingTagNode => "missing-tag-node",
+            IssueKind::DetachedPortal => "detached-portal",
+            IssueKind::ZeroAreaPattern => "zero-area-pattern",
+            IssueKind::MissingAsset => "missing-asset",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.level {
+            Some(level) => write!(f, "[{}] level {}: {}", self.kind, level, self.message),
+            None => write!(f, "[{}] {}", self.kind, self.message),
+        }
+    }
+}
+
+fn collect_node_ids(value: &Value, ids: &mut HashSet<String>) {
+    match value {
+        Value::Object(fields) => {
+            if let Some(id) = node_id(value) {
+                ids.insert(id);
+            }
+            fields.values().for_each(|v| collect_node_ids(v, ids));
+        }
+        Value::Array(values) => values.iter().for_each(|v| collect_node_ids(v, ids)),
+        _ => {}
+    }
+}
+
+/// Checks `tags` objects mapping a tag name to the node ids carrying it.
+fn check_tags(
+    tags: &mut Value,
+    level: Option<&str>,
+    node_ids: &HashSet<String>,
+    fix: bool,
+    issues: &mut Vec<Issue>,
+) {
+    let Some(tags) = tags.as_object_mut() else {
+        return;
+    };
+    for (tag, nodes) in tags.iter_mut() {
+        let Some(nodes) = nodes.as_array_mut() else {
+            continue;
+        };
+        nodes.retain(|node| {
+            let Some(id) = id_string(node) else {
+                return true;
+            };
+            if node_ids.contains(&id) {
+                return true;
+            }
+            issues.push(Issue {
+                kind: IssueKind::MissingTagNode,
+                level: level.map(str::to_string),
+                message: format!("tag '{}' references missing node {}", tag, id),
+            });
+            !fix
+        });
+    }
+}
+
+fn check_portals(
+    portals: &mut Value,
+    level: &str,
+    wall_ids: &HashSet<String>,
+    fix: bool,
+    issues: &mut Vec<Issue>,
+) {
+    let Some(portals) = portals.as_array_mut() else {
+        return;
+    };
+    portals.retain(|portal| {
+        let Some(wall_id) = portal.get("wall_id").and_then(id_string) else {
+            return true;
+        };
+        if wall_id == NO_WALL_ID || wall_ids.contains(&wall_id) {
+            return true;
+        }
+        let portal_id = node_id(portal).unwrap_or_default();
+        issues.push(Issue {
+            kind: IssueKind::DetachedPortal,
+            level: Some(level.to_string()),
+            message: format!(
+                "portal {} is attached to missing wall {}",
+                portal_id, wall_id
+            ),
+        });
+        !fix
+    });
+}
+
+fn check_patterns(patterns: &mut Value, level: &str, fix: bool, issues: &mut Vec<Issue>) {
+    let Some(patterns) = patterns.as_array_mut() else {
+        return;
+    };
+    patterns.retain(|pattern| {
+        let Some(points) = node_points(pattern) else {
+            return true;
+        };
+        if polygon_area(&points) > MIN_PATTERN_AREA {
+            return true;
+        }
+        let pattern_id = node_id(pattern).unwrap_or_default();
+        issues.push(Issue {
+            kind: IssueKind::ZeroAreaPattern,
+            level: Some(level.to_string()),
+            message: format!("pattern {} has zero area", pattern_id),
+        });
+        !fix
+    });
+}
+
+/// Finds portals detached from any wall, pattern shapes with zero area, and
+/// tags referencing missing nodes. When `fix` is set, the offending entries
+/// are pruned from the map as they are found, tags last so those of pruned
+/// nodes go too.
+pub fn find_orphans(map: &mut Value, fix: bool) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let Some(world) = map.get_mut("world") else {
+        return issues;
+    };
+
+    for (name, level) in world
+        .get_mut("levels")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flatten()
+    {
+        let wall_ids: HashSet<String> = level
+            .get("walls")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(node_id)
+            .collect();
+        if let Some(walls) = level.get_mut("walls").and_then(Value::as_array_mut) {
+            for wall in walls.iter_mut() {
+                if let Some(portals) = wall.get_mut("portals") {
+                    check_portals(portals, name, &wall_ids, fix, &mut issues);
+                }
+            }
+        }
+        if let Some(portals) = level.get_mut("portals") {
+            check_portals(portals, name, &wall_ids, fix, &mut issues);
+        }
+
+        if let Some(patterns) = level.get_mut("patterns") {
+            check_patterns(patterns, name, fix, &mut issues);
+        }
+    }
+
+    let mut node_ids = HashSet::new();
+    collect_node_ids(world, &mut node_ids);
+
+    if let Some(tags) = world.get_mut("tags") {
+        check_tags(tags, None, &node_ids, fix, &mut issues);
+    }
+    for (name, level) in world
+        .get_mut("levels")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(tags) = level.get_mut("tags") {
+            check_tags(tags, Some(name), &node_ids, fix, &mut issues);
+        }
+    }
+    issues
+}
+
+/// Finds the asset references of a map that none of the detected asset
+/// packs or default assets has. A pack that wasn't detected at all is
+/// reported once rather than for each of its assets.
+pub fn find_missing_assets(map: &Value, resolver: &AssetResolver) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut unknown_packs = BTreeSet::new();
+    for reference in asset_references(map).into_keys() {
+        let missing = match pack_id(&reference) {
+            Some(id) if !resolver.knows_pack(id) => {
+                unknown_packs.insert(id.to_string());
+                false
+            }
+            Some(_) => !resolver.exists(&reference),
+            None => resolver.has_default_assets() && !resolver.exists(&reference),
+        };
+        if missing {
+            issues.push(Issue {
+                kind: IssueKind::MissingAsset,
+                level: None,
+                message: format!("{} ({}) was not found", asset_name(&reference), reference),
+            });
+        }
+    }
+    for id in unknown_packs {
+        issues.push(Issue {
+            kind: IssueKind::MissingAsset,
+            level: None,
+            message: format!("asset pack {} was not found", id),
+        });
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map() -> Value {
+        json!({
+            "world": {
+                "tags": { "doors": ["2", "9", "4"] },
+                "levels": {
+                    "0": {
+                        "walls": [
+                            { "node_id": "1", "portals": [ { "node_id": "2", "wall_id": "1" } ] }
+                        ],
+                        "portals": [
+                            { "node_id": "3", "wall_id": "-1" },
+                            { "node_id": "4", "wall_id": "7" }
+                        ],
+                        "patterns": [
+                            { "node_id": "5", "points": "PoolVector2Array( 0, 0, 256, 0, 256, 256 )" },
+                            { "node_id": "6", "points": "PoolVector2Array( 0, 0, 256, 0, 512, 0 )" }
+                        ]
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let mut map = map();
+        let issues = find_orphans(&mut map, false);
+        let kinds: Vec<_> = issues.iter().map(|i| &i.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                &IssueKind::DetachedPortal,
+                &IssueKind::ZeroAreaPattern,
+                &IssueKind::MissingTagNode
+            ]
+        );
+        assert_eq!(map["world"]["tags"]["doors"], json!(["2", "9", "4"]));
+    }
+
+    #[test]
+    fn test_fix_orphans() {
+        let mut map = map();
+        // The tag of the detached portal goes with it
+        assert_eq!(find_orphans(&mut map, true).len(), 4);
+        assert_eq!(map["world"]["tags"]["doors"], json!(["2"]));
+        let level = &map["world"]["levels"]["0"];
+        assert_eq!(level["portals"].as_array().unwrap().len(), 1);
+        assert_eq!(level["patterns"].as_array().unwrap().len(), 1);
+        assert_eq!(level["walls"][0]["portals"].as_array().unwrap().len(), 1);
+        assert!(find_orphans(&mut map, false).is_empty());
+    }
+
+    #[test]
+    fn test_find_missing_assets() {
+        let dir = std::env::temp_dir().join(format!("ddgen-validate-{}", std::process::id()));
+        let pack = dir.join("Tavern");
+        std::fs::create_dir_all(pack.join("textures/objects")).unwrap();
+        std::fs::write(pack.join("pack.json"), r#"{ "id": "TAVERN01" }"#).unwrap();
+        std::fs::write(pack.join("textures/objects/chair.png"), "").unwrap();
+        let map = json!({
+            "world": { "levels": { "0": { "objects": [
+                { "texture": "res://packs/TAVERN01/textures/objects/chair.png" },
+                { "texture": "res://packs/TAVERN01/textures/objects/Bar_Stool.png" },
+                { "texture": "res://packs/CRYPT02/textures/objects/coffin.png" },
+                { "texture": "res://packs/CRYPT02/textures/objects/urn.png" },
+                { "texture": "res://textures/objects/barrel.png" }
+            ] } } }
+        });
+
+        let resolver = AssetResolver::detect(std::slice::from_ref(&dir));
+        let issues = find_missing_assets(&map, &resolver);
+        let messages: Vec<_> = issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Bar Stool (res://packs/TAVERN01/textures/objects/Bar_Stool.png) was not found",
+                "asset pack CRYPT02 was not found",
+            ]
+        );
+        assert!(issues.iter().all(|i| i.kind == IssueKind::MissingAsset));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::f64::consts::PI;
+
+use opencv::core::Mat;
+use opencv::imgproc;
+use opencv::types::VectorOfVec4i;
+use tracing::{debug, instrument};
+
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::Vector2;
+use crate::geometry::distance;
+use crate::preprocess::ink_mask;
+use crate::town::skeletonize;
+
+/// Lines shorter than this share of a cell are letters and specks.
+const MIN_LINE_LENGTH: f64 = 0.25;
+/// Lines within this many radians of parallel, about 3 degrees, may be merged.
+const MAX_COLLINEAR_ANGLE: f64 = 0.05;
+/// Lines whose ends are within this many pixels of another's line lie along it.
+const MAX_COLLINEAR_OFFSET: f64 = 3.0;
+
+/// A line found in the image, from one end to the other in pixels.
+type Segment = (Vector2, Vector2);
+
+/// The unit vector along a segment.
+fn direction(s: &Segment) -> (f64, f64) {
+    let length = distance(s.0, s.1).max(f64::EPSILON);
+    ((s.1.x - s.0.x) / length, (s.1.y - s.0.y) / length)
+}
+
+/// Two segments lying along the same line and overlapping, or at most `gap`
+/// apart end to end, as one segment spanning both.
+fn join(a: &Segment, b: &Segment, gap: f64) -> Option<Segment> {
+    // Measured along the longer, whose direction is the more certain
+    let (a, b) = match distance(a.0, a.1) >= distance(b.0, b.1) {
+        true => (a, b),
+        false => (b, a),
+    };
+    let (ux, uy) = direction(a);
+    let (vx, vy) = direction(b);
+    if (ux * vy - uy * vx).abs() > MAX_COLLINEAR_ANGLE.sin() {
+        return None;
+    }
+    let offset = |p: Vector2| ((p.y - a.0.y) * ux - (p.x - a.0.x) * uy).abs();
+    if offset(b.0) > MAX_COLLINEAR_OFFSET || offset(b.1) > MAX_COLLINEAR_OFFSET {
+        return None;
+    }
+    let along = |p: Vector2| (p.x - a.0.x) * ux + (p.y - a.0.y) * uy;
+    let (start, end) = (0.0, distance(a.0, a.1));
+    let (b0, b1) = (along(b.0).min(along(b.1)), along(b.0).max(along(b.1)));
+    if b0 > end + gap || b1 < start - gap {
+        return None;
+    }
+    let at = |t: f64| Vector2 {
+        x: a.0.x + ux * t,
+        y: a.0.y + uy * t,
+    };
+    Some((at(start.min(b0)), at(end.max(b1))))
+}
+
+/// Merges the segments lying along the same line, such as a wall broken in
+/// pieces by the lines crossing it, into one each.
+fn merge_collinear(mut segments: Vec<Segment>, gap: f64) -> Vec<Segment> {
+    let mut i = 0;
+    while i < segments.len() {
+        let mut grown = false;
+        let mut j = i + 1;
+        while j < segments.len() {
+            match join(&segments[i], &segments[j], gap) {
+                Some(joined) => {
+                    segments[i] = joined;
+                    segments.remove(j);
+                    grown = true;
+                }
+                None => j += 1,
+            }
+        }
+        // A longer segment may now reach those it was too short for
+        if !grown {
+            i += 1;
+        }
+    }
+    segments
+}
+
+/// Chains segments meeting end to end, within `snap` pixels, into polylines,
+/// each running between ends and junctions of the line graph, and whether
+/// each closes into a loop.
+fn polylines(segments: &[Segment], snap: f64) -> Vec<(Vec<Vector2>, bool)> {
+    // The ends of the segments, those close together being one node
+    let mut nodes: Vec<Vec<Vector2>> = Vec::new();
+    let mut node_of = |p: Vector2| match nodes
+        .iter()
+        .position(|ends| ends.iter().any(|end| distance(*end, p) <= snap))
+    {
+        Some(node) => {
+            nodes[node].push(p);
+            node
+        }
+        None => {
+            nodes.push(vec![p]);
+            nodes.len() - 1
+        }
+    };
+    let mut edges: Vec<(usize, usize)> = Vec::new();
+    for (a, b) in segments {
+        let edge = (node_of(*a), node_of(*b));
+        let reversed = (edge.1, edge.0);
+        if edge.0 != edge.1 && !edges.contains(&edge) && !edges.contains(&reversed) {
+            edges.push(edge);
+        }
+    }
+    let position = |node: usize| {
+        let ends = &nodes[node];
+        Vector2 {
+            x: ends.iter().map(|p| p.x).sum::<f64>() / ends.len() as f64,
+            y: ends.iter().map(|p| p.y).sum::<f64>() / ends.len() as f64,
+        }
+    };
+    let mut links: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    for (index, &(a, b)) in edges.iter().enumerate() {
+        links[a].push(index);
+        links[b].push(index);
+    }
+
+    let mut used = vec![false; edges.len()];
+    let walk = |start: usize, first: usize, used: &mut [bool]| {
+        let (mut node, mut edge) = (start, first);
+        let mut path = vec![start];
+        loop {
+            used[edge] = true;
+            let (a, b) = edges[edge];
+            node = if a == node { b } else { a };
+            path.push(node);
+            if links[node].len() != 2 {
+                break;
+            }
+            match links[node].iter().find(|&&next| !used[next]) {
+                Some(&next) => edge = next,
+                None => break,
+            }
+        }
+        path
+    };
+    let mut paths = Vec::new();
+    // Lines start at their ends and junctions, then the rest are loops
+    for node in (0..nodes.len()).filter(|&node| links[node].len() != 2) {
+        for &edge in &links[node] {
+            if !used[edge] {
+                paths.push(walk(node, edge, &mut used));
+            }
+        }
+    }
+    for edge in 0..edges.len() {
+        if !used[edge] {
+            paths.push(walk(edges[edge].0, edge, &mut used));
+        }
+    }
+    paths
+        .into_iter()
+        .map(|mut path| {
+            let closed = path.len() > 3 && path.first() == path.last();
+            if closed {
+                path.pop();
+            }
+            (path.into_iter().map(position).collect(), closed)
+        })
+        .collect()
+}
+
+/// Traces the walls of a clean, CAD-like map from the straight lines of its
+/// linework, merging the lines along the same wall and joining walls meeting
+/// within `max_gap` pixels. Returns the walls closing into rooms and those
+/// standing on their own, in map units.
+#[instrument(skip_all)]
+pub fn trace_wall_lines(
+    image: &Mat,
+    max_gap: f64,
+    mapper: &CoordinateMapper,
+) -> Result<(Vec<Vec<Vector2>>, Vec<Vec<Vector2>>), Box<dyn std::error::Error>> {
+    let min_length = MIN_LINE_LENGTH * mapper.pixels_per_cell;
+    let mut lines = VectorOfVec4i::new();
+    imgproc::hough_lines_p(
+        &skeletonize(&ink_mask(image)?)?,
+        &mut lines,
+        1.0,
+        PI / 180.0,
+        (min_length / 2.0).max(1.0) as i32,
+        min_length,
+        max_gap,
+    )?;
+    let segments: Vec<Segment> = lines
+        .iter()
+        .map(|l| {
+            (
+                Vector2 {
+                    x: l[0] as f64,
+                    y: l[1] as f64,
+                },
+                Vector2 {
+                    x: l[2] as f64,
+                    y: l[3] as f64,
+                },
+            )
+        })
+        .collect();
+    let count = segments.len();
+    let segments = merge_collinear(segments, max_gap);
+    debug!("Merged {} lines into {} walls", count, segments.len());
+    let (mut rooms, mut walls) = (Vec::new(), Vec::new());
+    for (points, closed) in polylines(&segments, max_gap) {
+        match closed {
+            true => rooms.push(mapper.points(&points)),
+            false => walls.push(mapper.points(&points)),
+        }
+    }
+    Ok((rooms, walls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: f64, y: f64) -> Vector2 {
+        Vector2 { x, y }
+    }
+
+    #[test]
+    fn test_merge_collinear() {
+        // The top of a room broken where a door crossed it, drawn both ways
+        // round, a wall beside it, and one at right angles
+        let segments = vec![
+            (v(0.0, 0.0), v(40.0, 0.0)),
+            (v(100.0, 1.0), v(52.0, 0.0)),
+            (v(0.0, 20.0), v(100.0, 20.0)),
+            (v(0.0, 0.0), v(0.0, 100.0)),
+        ];
+        let merged = merge_collinear(segments.clone(), 16.0);
+        assert_eq!(merged.len(), 3);
+        let (a, b) = merged[0];
+        assert!(a.x.min(b.x).abs() < 1.0 && (a.x.max(b.x) - 100.0).abs() < 1.0);
+        // Further apart than the gap they stay two
+        assert_eq!(merge_collinear(segments, 8.0).len(), 4);
+    }
+
+    #[test]
+    fn test_polylines() {
+        // A room whose corners are a few pixels apart, and a wall on its own
+        let segments = [
+            (v(0.0, 0.0), v(100.0, 0.0)),
+            (v(102.0, 2.0), v(100.0, 100.0)),
+            (v(100.0, 100.0), v(0.0, 100.0)),
+            (v(0.0, 97.0), v(0.0, 3.0)),
+            (v(200.0, 0.0), v(200.0, 100.0)),
+        ];
+        let traced = polylines(&segments, 8.0);
+        assert_eq!(traced.len(), 2);
+        let (wall, closed) = &traced[0];
+        assert!(!closed);
+        assert_eq!(wall, &vec![v(200.0, 0.0), v(200.0, 100.0)]);
+        let (room, closed) = &traced[1];
+        assert!(closed);
+        assert_eq!(room.len(), 4);
+        assert_eq!(room[0], v(0.0, 1.5));
+    }
+}
@@ -0,0 +1,357 @@
+use std::path::Path;
+
+use opencv::core::{self, Mat, Size, Vec3b};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::{VectorOfPoint, VectorOfVectorOfPoint};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::biome_map::{nearest_color, read_biome_map, read_biome_regions, BiomeRule};
+use crate::coordinates::CoordinateMapper;
+use crate::dungeondraft_v1::{
+    object_node, path_node, splat_bytes, textured, wall_node, MapBuilder, Terrain, Textures,
+    Vector2, SPLAT_PER_CELL,
+};
+use crate::geometry::distance;
+use crate::heightmap::{cliffs, elevation_slots, read_heightmap, ELEVATION_BANDS};
+use crate::images::read_image;
+
+/// Image pixels per grid cell, world maps covering far more ground per cell than dungeons.
+const WORLD_PIXELS_PER_CELL: f64 = 32.0;
+/// The grid is mostly hidden at world scale.
+const WORLD_GRID_COLOR: &str = "1f000000";
+/// Pixels darker than this are treated as ink for settlements and routes.
+const INK_THRESHOLD: f64 = 80.0;
+/// Markers smaller than this many pixels are noise.
+const MIN_MARKER_AREA: f64 = 9.0;
+const ROUTE_WIDTH: f64 = 32.0;
+
+/// A terrain texture and the color it is drawn with on world map images.
+pub struct Biome {
+    pub name: &'static str,
+    /// RGB
+    pub color: [u8; 3],
+    pub texture: &'static str,
+}
+
+/// One biome per terrain texture slot.
+pub const BIOMES: &[Biome] = &[
+    Biome {
+        name: "grassland",
+        color: [96, 160, 72],
+        texture: "res://textures/terrain/terrain_grass.png",
+    },
+    Biome {
+        name: "desert",
+        color: [214, 192, 132],
+        texture: "res://textures/terrain/terrain_sand.png",
+    },
+    Biome {
+        name: "mountains",
+        color: [128, 118, 108],
+        texture: "res://textures/terrain/terrain_gravel.png",
+    },
+    Biome {
+        name: "tundra",
+        color: [236, 240, 242],
+        texture: "res://textures/terrain/terrain_snow.png",
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Marker {
+    Settlement,
+    Route,
+}
+
+/// Picks the biome whose color is closest to an RGB color.
+pub fn classify_biome(color: [u8; 3], biomes: &[Biome]) -> usize {
+    let colors: Vec<[u8; 3]> = biomes.iter().map(|biome| biome.color).collect();
+    nearest_color(color, &colors)
+}
+
+/// Round, compact ink marks are settlements and long thin ones are routes.
+pub fn classify_marker(area: f64, perimeter: f64, pixels_per_cell: f64) -> Option<Marker> {
+    if area < MIN_MARKER_AREA || perimeter <= 0.0 {
+        return None;
+    }
+    // 1 for a circle, approaching 0 for a line
+    let compactness = 4.0 * std::f64::consts::PI * area / (perimeter * perimeter);
+    if compactness >= 0.5 && area <= (2.0 * pixels_per_cell).powi(2) {
+        Some(Marker::Settlement)
+    } else if compactness < 0.2 && perimeter >= 2.0 * pixels_per_cell {
+        Some(Marker::Route)
+    } else {
+        None
+    }
+}
+
+/// Follows the outline of a thin stroke from its start to its far end.
+///
+/// The outline of a stroke runs down one side and back up the other, so only
+/// the points up to the one farthest from the start are kept.
+pub fn stroke_path(outline: &[Vector2]) -> Vec<Vector2> {
+    let Some(&start) = outline.first() else {
+        return Vec::new();
+    };
+    let far_end = (0..outline.len())
+        .max_by(|&a, &b| distance(start, outline[a]).total_cmp(&distance(start, outline[b])))
+        .unwrap_or(0);
+    outline[..=far_end].to_vec()
+}
+
+fn to_units(point: core::Point) -> Vector2 {
+    CoordinateMapper::new(WORLD_PIXELS_PER_CELL).pixels_to_units(point.x as f64, point.y as f64)
+}
+
+/// Classifies every terrain splat pixel by the biome color of the image beneath it.
+pub fn biome_slots(image: &Mat, size: Size) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut splat = Mat::default();
+    imgproc::resize(image, &mut splat, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+    let mut slots = Vec::with_capacity((size.width * size.height) as usize);
+    for row in 0..size.height {
+        for col in 0..size.width {
+            let bgr = splat.at_2d::<Vec3b>(row, col)?;
+            slots.push(classify_biome([bgr[2], bgr[1], bgr[0]], BIOMES));
+        }
+    }
+    Ok(slots)
+}
+
+/// Writes the terrain splat, with `textures` naming the texture of each slot.
+pub fn paint_terrain(
+    builder: &mut MapBuilder,
+    slots: &[usize],
+    textures: &[(&str, &str)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let level = builder.level_mut();
+    let mut terrain: Terrain = serde_json::from_value(level["terrain"].take())?;
+    for (i, (name, texture)) in textures.iter().enumerate() {
+        debug!("Terrain slot {} is {}", i + 1, name);
+        terrain.set_texture(i, texture)?;
+    }
+    terrain.splat = splat_bytes(slots);
+    level["terrain"] = serde_json::to_value(terrain)?;
+    Ok(())
+}
+
+fn add_markers(
+    builder: &mut MapBuilder,
+    image: &Mat,
+    textures: &Textures,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut gray = Mat::default();
+    imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    let mut ink = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut ink,
+        INK_THRESHOLD,
+        255.0,
+        imgproc::THRESH_BINARY_INV,
+    )?;
+
+    let mut contours = VectorOfVectorOfPoint::new();
+    imgproc::find_contours(
+        &ink,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        core::Point::new(0, 0),
+    )?;
+
+    let (mut settlements, mut routes) = (0, 0);
+    for contour in contours.iter() {
+        let area = imgproc::contour_area(&contour, false)?;
+        let perimeter = imgproc::arc_length(&contour, true)?;
+        match classify_marker(area, perimeter, WORLD_PIXELS_PER_CELL) {
+            Some(Marker::Settlement) => {
+                let rect = imgproc::bounding_rect(&contour)?;
+                let center = to_units(core::Point::new(
+                    rect.x + rect.width / 2,
+                    rect.y + rect.height / 2,
+                ));
+                debug!("Settlement at {}", center);
+                builder.add_node("objects", object_node(center, &textures.settlement));
+                settlements += 1;
+            }
+            Some(Marker::Route) => {
+                let mut approx = VectorOfPoint::new();
+                imgproc::approx_poly_dp(&contour, &mut approx, 2.0, true)?;
+                let outline: Vec<Vector2> = approx.iter().map(to_units).collect();
+                builder.add_node(
+                    "paths",
+                    path_node(&stroke_path(&outline), &textures.route, ROUTE_WIDTH),
+                );
+                routes += 1;
+            }
+            None => {}
+        }
+    }
+    info!("Detected {} settlements and {} routes", settlements, routes);
+    Ok(())
+}
+
+/// Where the terrain of a world map comes from.
+pub enum TerrainSource<'a> {
+    /// The biome colors of the map image itself
+    Image,
+    /// Elevation bands, with cliff walls between them
+    Heightmap(&'a Mat),
+    /// A painted biome map whose colors are assigned by rules
+    BiomeMap(&'a Mat, &'a [BiomeRule]),
+}
+
+/// Generates a world map where the terrain is painted from `terrain`,
+/// settlement marks become objects, and routes become paths.
+pub fn generate_world(
+    image: &Mat,
+    terrain: TerrainSource,
+    textures: &Textures,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let mapper = CoordinateMapper::new(WORLD_PIXELS_PER_CELL);
+    let (width, height) = mapper.map_size(image.cols(), image.rows());
+    debug!("Generating a {}x{} cell world map", width, height);
+
+    let mut builder = MapBuilder::new(width, height)?;
+    builder.world_mut()["grid"]["color"] = WORLD_GRID_COLOR.into();
+    let splat_size = Size::new(
+        (width * SPLAT_PER_CELL) as i32,
+        (height * SPLAT_PER_CELL) as i32,
+    );
+    match terrain {
+        TerrainSource::Image => {
+            let terrain: Vec<_> = BIOMES.iter().map(|b| (b.name, b.texture)).collect();
+            paint_terrain(&mut builder, &biome_slots(image, splat_size)?, &terrain)?;
+        }
+        TerrainSource::Heightmap(heightmap) => {
+            let terrain: Vec<_> = ELEVATION_BANDS
+                .iter()
+                .map(|b| (b.name, b.texture))
+                .collect();
+            paint_terrain(
+                &mut builder,
+                &elevation_slots(heightmap, splat_size)?,
+                &terrain,
+            )?;
+            let cliffs = cliffs(heightmap, &mapper)?;
+            info!("Traced {} cliffs", cliffs.len());
+            for cliff in cliffs {
+                builder.add_node("walls", textured(wall_node(&cliff, true), &textures.cliff));
+            }
+        }
+        TerrainSource::BiomeMap(biome_map, rules) => {
+            let regions = read_biome_regions(biome_map, rules, width, splat_size)?;
+            let terrain: Vec<_> = regions
+                .terrain
+                .iter()
+                .map(|t| (t.as_str(), t.as_str()))
+                .collect();
+            paint_terrain(&mut builder, &regions.slots, &terrain)?;
+            info!(
+                "Painted {} water and {} material regions",
+                regions.water.len(),
+                regions.materials.len()
+            );
+            for water in &regions.water {
+                builder.add_water(water);
+            }
+            for (texture, material) in &regions.materials {
+                builder.add_material(texture, material);
+            }
+        }
+    }
+    add_markers(&mut builder, image, textures)?;
+    Ok(builder.build())
+}
+
+/// Generates a world map from an image, with the terrain optionally read
+/// from a heightmap or from a biome map painted with the configured rules.
+pub fn try_generate_world(
+    image_path: &Path,
+    heightmap_path: Option<&Path>,
+    biome_map_path: Option<&Path>,
+    rules: &[BiomeRule],
+    textures: &Textures,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    debug!("Generating a world map from {}", image_path.display());
+    let image = read_image(image_path, opencv::imgcodecs::IMREAD_COLOR)?;
+    if image.empty() {
+        return Err(format!("failed to read image {}", image_path.display()).into());
+    }
+    match (heightmap_path, biome_map_path) {
+        (Some(path), _) => {
+            let heightmap = read_heightmap(path, image.size()?)?;
+            generate_world(&image, TerrainSource::Heightmap(&heightmap), textures)
+        }
+        (None, Some(path)) => {
+            let biome_map = read_biome_map(path)?;
+            generate_world(&image, TerrainSource::BiomeMap(&biome_map, rules), textures)
+        }
+        (None, None) => generate_world(&image, TerrainSource::Image, textures),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_biome() {
+        assert_eq!(classify_biome([90, 170, 80], BIOMES), 0);
+        assert_eq!(classify_biome([220, 200, 140], BIOMES), 1);
+        assert_eq!(classify_biome([255, 255, 255], BIOMES), 3);
+    }
+
+    #[test]
+    fn test_splat_bytes() {
+        assert_eq!(splat_bytes(&[0, 2]), vec![255, 0, 0, 0, 0, 0, 255, 0]);
+    }
+
+    #[test]
+    fn test_paint_terrain() {
+        let mut builder = MapBuilder::new(1, 1).unwrap();
+        let textures: Vec<_> = BIOMES.iter().map(|b| (b.name, b.texture)).collect();
+        paint_terrain(&mut builder, &[1; 16], &textures).unwrap();
+        let map = builder.build();
+        let terrain: Terrain =
+            serde_json::from_value(map["world"]["levels"]["0"]["terrain"].clone()).unwrap();
+        assert_eq!(terrain.texture_2, BIOMES[1].texture);
+        assert_eq!(terrain.splat.len(), 16 * 4);
+        assert_eq!(terrain.splat[..4], [0, 255, 0, 0]);
+
+        let mut builder = MapBuilder::new(1, 1).unwrap();
+        let too_many = [("a", "a"), ("b", "b"), ("c", "c"), ("d", "d"), ("e", "e")];
+        assert!(paint_terrain(&mut builder, &[0; 16], &too_many).is_err());
+    }
+
+    #[test]
+    fn test_classify_marker() {
+        // A circle with a radius of 8 pixels
+        let (r, pi) = (8.0, std::f64::consts::PI);
+        assert_eq!(
+            classify_marker(pi * r * r, 2.0 * pi * r, 32.0),
+            Some(Marker::Settlement)
+        );
+        // A 200x3 pixel stroke
+        assert_eq!(classify_marker(600.0, 406.0, 32.0), Some(Marker::Route));
+        assert_eq!(classify_marker(4.0, 8.0, 32.0), None);
+        // A large filled region
+        assert_eq!(classify_marker(40000.0, 800.0, 32.0), None);
+    }
+
+    #[test]
+    fn test_stroke_path() {
+        let outline = vec![
+            Vector2 { x: 0.0, y: 0.0 },
+            Vector2 { x: 50.0, y: 0.0 },
+            Vector2 { x: 100.0, y: 0.0 },
+            Vector2 { x: 100.0, y: 2.0 },
+            Vector2 { x: 50.0, y: 2.0 },
+            Vector2 { x: 0.0, y: 2.0 },
+        ];
+        assert_eq!(stroke_path(&outline), outline[..4].to_vec());
+        assert!(stroke_path(&[]).is_empty());
+    }
+}